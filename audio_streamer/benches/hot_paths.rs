@@ -0,0 +1,168 @@
+use audio_streamer::capture::{accumulate_and_emit, Accumulator};
+use audio_streamer::network::send_to_all;
+use audio_streamer::player::{fill_output, PlaybackQueue};
+use audio_streamer::protocol::{decode_packet, encode_packet, PacketHeader};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Counts heap allocations so the capture benchmarks can report them
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations_per_call(calls: usize, mut f: impl FnMut()) -> f64 {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..calls {
+        f();
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / calls as f64
+}
+
+// Interleaved sample counts: a small device buffer, one full datagram, and a large capture buffer
+const BUFFER_SIZES: [usize; 3] = [64, 360, 4096];
+
+fn samples(len: usize) -> Vec<f32> {
+    (0..len).map(|i| (i as f32 * 0.01).sin()).collect()
+}
+
+fn packets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet");
+    let header = PacketHeader::default();
+
+    for size in BUFFER_SIZES {
+        let input = samples(size);
+        let packet = encode_packet(&header, &input);
+        group.throughput(Throughput::Elements(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("encode", size), &input, |b, input| {
+            b.iter(|| encode_packet(black_box(&header), black_box(input)))
+        });
+        group.bench_with_input(BenchmarkId::new("decode", size), &packet, |b, packet| {
+            b.iter(|| decode_packet(black_box(packet)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn playback(c: &mut Criterion) {
+    let mut group = c.benchmark_group("playback");
+
+    for size in BUFFER_SIZES {
+        let input = samples(size);
+        group.throughput(Throughput::Elements(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("fill_f32", size), &input, |b, input| {
+            let mut out = vec![0.0f32; size];
+            b.iter(|| {
+                let mut queue = PlaybackQueue::default();
+                queue.push(input.clone());
+                fill_output(&mut queue, black_box(&mut out));
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("fill_i16", size), &input, |b, input| {
+            let mut out = vec![0i16; size];
+            b.iter(|| {
+                let mut queue = PlaybackQueue::default();
+                queue.push(input.clone());
+                fill_output(&mut queue, black_box(&mut out));
+            })
+        });
+    }
+    group.finish();
+}
+
+// A capture callback of 64 stereo i16 frames accumulated into 360-sample buffers
+fn capture(c: &mut Criterion) {
+    const BUFFER_SIZE: usize = 360;
+    let mut group = c.benchmark_group("capture");
+    let input: Vec<i16> = (0..128).map(|i| (i * 97) as i16).collect();
+    group.throughput(Throughput::Elements(input.len() as u64));
+
+    let mut pending = Vec::with_capacity(BUFFER_SIZE);
+    let vec_per_callback = allocations_per_call(10_000, || {
+        black_box(accumulate_and_emit(&mut pending, &input, BUFFER_SIZE));
+    });
+    // The consumer hands every buffer back, as the sender does
+    let mut accumulator = Accumulator::new(BUFFER_SIZE);
+    let pool = accumulator.pool();
+    let preallocated = allocations_per_call(10_000, || {
+        accumulator.push(&input, |buffer| pool.recycle(black_box(buffer)));
+    });
+    println!(
+        "capture allocations per callback: accumulate_and_emit {:.2}, Accumulator {:.2}",
+        vec_per_callback, preallocated
+    );
+
+    group.bench_function("accumulate_and_emit", |b| {
+        let mut pending = Vec::with_capacity(BUFFER_SIZE);
+        b.iter(|| accumulate_and_emit(&mut pending, black_box(&input), BUFFER_SIZE))
+    });
+    group.bench_function("accumulator", |b| {
+        let mut accumulator = Accumulator::new(BUFFER_SIZE);
+        let pool = accumulator.pool();
+        b.iter(|| accumulator.push(black_box(&input), |buffer| pool.recycle(black_box(buffer))))
+    });
+    group.finish();
+}
+
+// One full datagram to every listener, one send_to each or batched with sendmmsg
+fn fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fan_out");
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap();
+    let packet = vec![0u8; 1472];
+    let socket = runtime
+        .block_on(tokio::net::UdpSocket::bind("127.0.0.1:0"))
+        .unwrap();
+
+    for clients in [8, 64] {
+        // Listeners that never read; the kernel drops what overflows their buffers
+        let listeners: Vec<std::net::UdpSocket> = (0..clients)
+            .map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let destinations: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        group.throughput(Throughput::Elements(clients as u64));
+
+        for (name, batched) in [("send_to", false), ("sendmmsg", true)] {
+            group.bench_with_input(
+                BenchmarkId::new(name, clients),
+                &destinations,
+                |b, destinations| {
+                    b.iter(|| {
+                        runtime.block_on(send_to_all(
+                            &socket,
+                            black_box(&packet),
+                            destinations,
+                            batched,
+                        ))
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, packets, playback, capture, fan_out);
+criterion_main!(benches);
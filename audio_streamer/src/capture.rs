@@ -1,7 +1,14 @@
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Host, Sample, SampleFormat, SizedSample};
-use std::sync::Arc;
-use tokio::sync::mpsc;
+use cpal::{
+    FromSample, Host, Sample, SampleFormat, SizedSample, SupportedBufferSize,
+    SupportedStreamConfig, SupportedStreamConfigRange,
+};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
 
 #[cfg(target_os = "macos")]
 use {
@@ -16,8 +23,18 @@ use {
     std::sync::mpsc as std_mpsc,
 };
 
+use crate::dsp::{Agc, AgcConfig, NoiseGate, NoiseGateConfig, Resampler};
+pub use crate::sample::sample_to_f32;
+use crate::source::AudioSource;
 use crate::Result;
 
+/// Sender, receiver and the cpal stream that must be kept alive while capturing.
+pub type CaptureChannels = (
+    mpsc::Sender<Vec<f32>>,
+    mpsc::Receiver<Vec<f32>>,
+    cpal::Stream,
+);
+
 #[derive(Debug)]
 pub enum DeviceType {
     Physical,
@@ -33,18 +50,75 @@ pub struct DeviceInfo {
     pub device_type: DeviceType,
 }
 
+/// One supported configuration range reported by a device.
+#[derive(Clone, Debug)]
+pub struct SupportedConfig {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub sample_format: SampleFormat,
+    /// Buffer size range in frames, when the backend reports one
+    pub buffer_size: Option<(u32, u32)>,
+}
+
+#[derive(Debug)]
+pub struct DetailedDeviceInfo {
+    pub info: DeviceInfo,
+    /// Empty for entries that aren't backed by a regular input device
+    pub supported_configs: Vec<SupportedConfig>,
+}
+
 pub struct AudioCapture {
     host: Host,
     config: CaptureConfig,
+    dropped_buffers: Arc<AtomicU64>,
+    // f32 bits of the linear input gain, read by the capture callbacks
+    input_gain: Arc<AtomicU32>,
+    // Size of the emitted buffers, read by the capture callbacks
+    buffer_size: Arc<AtomicU32>,
+    // Channels of the emitted buffers, which new buffer sizes must fit
+    channels: Arc<AtomicU32>,
+    buffer_pool: BufferPool,
     #[cfg(target_os = "macos")]
     screen_capture: Option<SCStream>,
 }
 
 #[derive(Clone, Debug)]
 pub struct CaptureConfig {
+    /// Rate of the emitted buffers. Devices running at another rate are
+    /// resampled to it, e.g. down to 16000 for low-bandwidth voice.
     pub sample_rate: u32,
     pub channels: u16,
+    /// Samples in each emitted buffer, across all channels. Can be changed
+    /// while capturing with `AudioCapture::set_buffer_size`. Independent of
+    /// the device's period, which is left to the backend.
     pub buffer_size: u32,
+    /// Number of buffers the capture channel can hold before the consumer falls
+    /// behind. Larger values absorb bursts at the cost of added latency
+    /// (each slot is `buffer_size` samples); smaller values bound latency but
+    /// make drops more likely when the network side stalls.
+    pub channel_capacity: usize,
+    /// Sample format to request from the device instead of its default. Falls
+    /// back to the default config when the device doesn't offer it.
+    pub preferred_format: Option<SampleFormat>,
+    /// When a device's stream can't be opened in the chosen config, e.g. a
+    /// sample format capture can't convert, retry once with the device's
+    /// default config, or failing that its closest config in a convertible
+    /// format, instead of giving up. The fallback always has the channel
+    /// count of the config that failed.
+    pub format_fallback: bool,
+    /// Zero-based device channels to keep, in output order. Extracts e.g. a
+    /// single mic from a multichannel interface; `None` keeps every channel.
+    pub channel_selection: Option<Vec<u16>>,
+    /// Attenuate hiss between speech on microphone inputs. Unlike the
+    /// sender's silence gate this still sends every buffer.
+    pub noise_gate: Option<NoiseGateConfig>,
+    /// Normalize the level of quiet or inconsistent inputs over time. Runs
+    /// after the noise gate so gated hiss isn't amplified.
+    pub agc: Option<AgcConfig>,
+    /// Audio host to capture through, e.g. "JACK" or "ASIO", see
+    /// `select_host`. `None` uses the platform's default host.
+    pub host: Option<String>,
 }
 
 impl Default for CaptureConfig {
@@ -53,6 +127,13 @@ impl Default for CaptureConfig {
             sample_rate: 48000,
             channels: 2,
             buffer_size: 480, // 10ms buffer at 48kHz (reduced from 4096)
+            channel_capacity: 32,
+            preferred_format: None,
+            format_fallback: false,
+            channel_selection: None,
+            noise_gate: None,
+            agc: None,
+            host: None,
         }
     }
 }
@@ -75,27 +156,566 @@ impl SCStreamOutputTrait for AudioStreamOutput {
     }
 }
 
+// Hand a buffer to the consumer without ever blocking the real-time audio thread.
+// When the channel is full the buffer is dropped and counted instead.
+fn send_or_drop(tx: &mpsc::Sender<Vec<f32>>, buffer: Vec<f32>, dropped: &AtomicU64) {
+    if let Err(TrySendError::Full(_)) = tx.try_send(buffer) {
+        dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Converts incoming device samples to f32, appends them to `buffer` and
+/// returns every complete `buffer_size` chunk now available. Leftover samples
+/// stay in `buffer` for the next call.
+pub fn accumulate_and_emit<T>(
+    buffer: &mut Vec<f32>,
+    incoming: &[T],
+    buffer_size: usize,
+) -> Vec<Vec<f32>>
+where
+    T: Sample,
+    f32: FromSample<T>,
+{
+    buffer.extend(incoming.iter().copied().map(sample_to_f32));
+
+    let mut chunks = Vec::new();
+    while buffer.len() >= buffer_size {
+        chunks.push(buffer.drain(..buffer_size).collect());
+    }
+    chunks
+}
+
+// Buffers must hold whole frames, or everything downstream that works frame
+// by frame, such as the resampler and per-channel filters, would mix up the
+// channels
+fn check_buffer_size(samples: usize, channels: usize) -> Result<()> {
+    if samples == 0 || !samples.is_multiple_of(channels) {
+        return Err(crate::AudioStreamerError::ConfigError(format!(
+            "Capture buffer size {} is not a whole number of {}-channel frames",
+            samples, channels
+        )));
+    }
+    Ok(())
+}
+
+// Most spare buffers a pool holds; more than a capture channel queues
+const POOL_CAPACITY: usize = 64;
+
+/// Spare buffers handed back by the consumers of an [`Accumulator`], which
+/// refills them instead of allocating. Clones share the same buffers. Never
+/// blocks: when the pool is busy or empty the accumulator allocates, and
+/// when it is full returned buffers are freed.
+#[derive(Clone, Debug, Default)]
+pub struct BufferPool(Arc<Mutex<Vec<Vec<f32>>>>);
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hands `buffer` back for reuse.
+    pub fn recycle(&self, mut buffer: Vec<f32>) {
+        if let Ok(mut spare) = self.0.try_lock() {
+            if spare.len() < POOL_CAPACITY {
+                buffer.clear();
+                spare.push(buffer);
+            }
+        }
+    }
+
+    // An empty buffer with room for `capacity` samples, reused when possible
+    fn take(&self, capacity: usize) -> Vec<f32> {
+        let mut buffer = self
+            .0
+            .try_lock()
+            .ok()
+            .and_then(|mut spare| spare.pop())
+            .unwrap_or_default();
+        buffer.reserve(capacity);
+        buffer
+    }
+}
+
+/// Real-time safe counterpart to `accumulate_and_emit` for capture callbacks.
+/// Samples are converted straight into a preallocated buffer, and each
+/// emitted buffer, whose ownership moves to the consumer, is replaced from
+/// the accumulator's [`BufferPool`]. A callback allocates nothing once
+/// consumers hand their buffers back to it.
+pub struct Accumulator {
+    buffer: Vec<f32>,
+    buffer_size: usize,
+    // Samples per interleaved frame; buffers always hold whole frames
+    channels: usize,
+    pool: BufferPool,
+}
+
+impl Accumulator {
+    pub fn new(buffer_size: usize) -> Self {
+        Self::with_channels(buffer_size, 1)
+    }
+
+    /// Like `new`, for interleaved frames of `channels` samples. The buffer
+    /// size is rounded down to whole frames, and up to at least one.
+    pub fn with_channels(buffer_size: usize, channels: usize) -> Self {
+        let channels = channels.max(1);
+        let buffer_size = (buffer_size - buffer_size % channels).max(channels);
+        Self {
+            buffer: Vec::with_capacity(buffer_size),
+            buffer_size,
+            channels,
+            pool: BufferPool::new(),
+        }
+    }
+
+    /// Takes replacement buffers from `pool`, e.g. one shared with a
+    /// consumer, instead of a pool of its own.
+    pub fn with_pool(mut self, pool: BufferPool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Pool to hand emitted buffers back to.
+    pub fn pool(&self) -> BufferPool {
+        self.pool.clone()
+    }
+
+    /// Appends `samples`, handing each completed `buffer_size` buffer to `emit`.
+    pub fn extend(
+        &mut self,
+        samples: impl IntoIterator<Item = f32>,
+        mut emit: impl FnMut(Vec<f32>),
+    ) {
+        let mut samples = samples.into_iter();
+        loop {
+            let space = self.buffer_size - self.buffer.len();
+            self.buffer.extend(samples.by_ref().take(space));
+            if self.buffer.len() < self.buffer_size {
+                return;
+            }
+            emit(self.take_full());
+        }
+    }
+
+    /// Converts device samples and appends them, see `extend`.
+    pub fn push<T>(&mut self, mut incoming: &[T], mut emit: impl FnMut(Vec<f32>))
+    where
+        T: Sample,
+        f32: FromSample<T>,
+    {
+        while !incoming.is_empty() {
+            let space = self.buffer_size - self.buffer.len();
+            let (head, rest) = incoming.split_at(space.min(incoming.len()));
+            self.buffer.extend(head.iter().copied().map(sample_to_f32));
+            incoming = rest;
+            if self.buffer.len() == self.buffer_size {
+                emit(self.take_full());
+            }
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Changes the size of the buffers emitted from now on. Samples already
+    /// pending are kept, and any that fill buffers of the new size are
+    /// handed to `emit` right away. Fails unless the size is a whole number
+    /// of frames.
+    pub fn set_buffer_size(
+        &mut self,
+        buffer_size: usize,
+        mut emit: impl FnMut(Vec<f32>),
+    ) -> Result<()> {
+        check_buffer_size(buffer_size, self.channels)?;
+        self.buffer_size = buffer_size;
+        while self.buffer.len() >= self.buffer_size {
+            let rest = self.buffer.split_off(self.buffer_size);
+            emit(std::mem::replace(&mut self.buffer, rest));
+        }
+        let space = self.buffer_size - self.buffer.len();
+        self.buffer.reserve(space);
+        Ok(())
+    }
+
+    fn take_full(&mut self) -> Vec<f32> {
+        let next = self.pool.take(self.buffer_size);
+        std::mem::replace(&mut self.buffer, next)
+    }
+
+    /// Like `push`, keeping only the `selection` channels of interleaved frames
+    /// of `device_channels` samples, without an intermediate buffer.
+    pub fn push_selected<T>(
+        &mut self,
+        incoming: &[T],
+        device_channels: usize,
+        selection: &[u16],
+        emit: impl FnMut(Vec<f32>),
+    ) where
+        T: Sample,
+        f32: FromSample<T>,
+    {
+        let samples = incoming.chunks_exact(device_channels).flat_map(|frame| {
+            selection
+                .iter()
+                .map(move |&channel| sample_to_f32(frame[channel as usize]))
+        });
+        self.extend(samples, emit);
+    }
+
+    /// Samples waiting for the next buffer to fill.
+    pub fn pending(&self) -> &[f32] {
+        &self.buffer
+    }
+}
+
+/// Ring of the most recent captured audio, so a consumer that starts sending
+/// part-way through, e.g. when a silence gate opens, can lead in with what
+/// came just before instead of clipping the start of a word. Each buffer is
+/// kept with the frame position of its first frame.
+pub struct PreRoll {
+    buffers: VecDeque<(u64, Vec<f32>)>,
+    channels: usize,
+    max_samples: usize,
+    held: usize,
+}
+
+impl PreRoll {
+    pub fn new(duration: Duration, sample_rate: u32, channels: u16) -> Self {
+        let channels = channels.max(1) as usize;
+        let frames = (duration.as_secs_f64() * sample_rate as f64).round() as usize;
+        Self {
+            buffers: VecDeque::new(),
+            channels,
+            max_samples: frames * channels,
+            held: 0,
+        }
+    }
+
+    /// Appends a buffer starting at frame `position`, discarding the oldest
+    /// audio beyond the configured duration.
+    pub fn push(&mut self, position: u64, samples: Vec<f32>) {
+        self.held += samples.len();
+        self.buffers.push_back((position, samples));
+        while self.held > self.max_samples {
+            let excess = self.held - self.max_samples;
+            let Some((start, front)) = self.buffers.front_mut() else {
+                break;
+            };
+            if front.len() <= excess {
+                self.held -= front.len();
+                self.buffers.pop_front();
+            } else {
+                // Trim whole frames so the channels stay aligned
+                let frames = excess.div_ceil(self.channels);
+                let trimmed = (frames * self.channels).min(front.len());
+                front.drain(..trimmed);
+                *start += frames as u64;
+                self.held -= trimmed;
+            }
+        }
+    }
+
+    /// Removes and returns the audio held, oldest first.
+    pub fn drain(&mut self) -> impl Iterator<Item = (u64, Vec<f32>)> + '_ {
+        self.held = 0;
+        self.buffers.drain(..)
+    }
+
+    /// Samples currently held, across all channels.
+    pub fn held_samples(&self) -> usize {
+        self.held
+    }
+}
+
+// Convert a buffer to the capture rate when the device runs at another one,
+// into a buffer from `pool`, which gets the original back
+fn resample(resampler: &mut Option<Resampler>, buffer: Vec<f32>, pool: &BufferPool) -> Vec<f32> {
+    match resampler {
+        Some(resampler) => {
+            let mut resampled = pool.take(0);
+            resampler.process_into(&buffer, &mut resampled);
+            pool.recycle(buffer);
+            resampled
+        }
+        None => buffer,
+    }
+}
+
+// Scale a buffer by the current input gain, skipping the work at unity
+fn apply_gain(buffer: &mut [f32], gain: &AtomicU32) {
+    let gain = f32::from_bits(gain.load(Ordering::Relaxed));
+    if gain != 1.0 {
+        for sample in buffer {
+            *sample *= gain;
+        }
+    }
+}
+
+// What the input callback does with each device buffer: channel selection,
+// conversion and accumulation into emitted buffers, then gain, noise gate,
+// AGC and resampling on each of those. Kept out of the cpal callback so
+// tests can push audio through exactly the same steps.
+pub(crate) struct InputProcessor {
+    accumulator: Accumulator,
+    // Picked up from `AudioCapture::set_buffer_size` on the next call
+    buffer_size: Arc<AtomicU32>,
+    input_gain: Arc<AtomicU32>,
+    device_channels: usize,
+    selection: Option<Vec<u16>>,
+    noise_gate: Option<NoiseGate>,
+    agc: Option<Agc>,
+    resampler: Option<Resampler>,
+}
+
+impl InputProcessor {
+    pub(crate) fn process<T>(&mut self, data: &[T], mut output: impl FnMut(Vec<f32>))
+    where
+        T: Sample,
+        f32: FromSample<T>,
+    {
+        let Self {
+            accumulator,
+            buffer_size,
+            input_gain,
+            device_channels,
+            selection,
+            noise_gate,
+            agc,
+            resampler,
+        } = self;
+        let pool = accumulator.pool();
+        let mut emit = |mut buffer: Vec<f32>| {
+            apply_gain(&mut buffer, input_gain);
+            if let Some(gate) = noise_gate {
+                gate.process(&mut buffer);
+            }
+            if let Some(agc) = agc {
+                agc.process(&mut buffer);
+            }
+            output(resample(resampler, buffer, &pool));
+        };
+        let size = buffer_size.load(Ordering::Relaxed) as usize;
+        if size != accumulator.buffer_size() {
+            // Already checked against the channels by `set_buffer_size`
+            let _ = accumulator.set_buffer_size(size, &mut emit);
+        }
+        match selection {
+            Some(selection) => {
+                accumulator.push_selected(data, *device_channels, selection, &mut emit)
+            }
+            None => accumulator.push(data, &mut emit),
+        }
+    }
+}
+
+/// Repackages buffers of any size into frames of exactly `frame_len` samples,
+/// carrying the remainder over to the next frame. For consumers such as Opus
+/// encoders that need specific frame sizes regardless of the device buffer
+/// size. A trailing partial frame is discarded when `rx` closes.
+pub fn framed(mut rx: mpsc::Receiver<Vec<f32>>, frame_len: usize) -> mpsc::Receiver<Vec<f32>> {
+    let (tx, framed_rx) = mpsc::channel(32);
+    tokio::spawn(async move {
+        let mut pending = Vec::with_capacity(frame_len);
+        while let Some(buffer) = rx.recv().await {
+            for frame in accumulate_and_emit(&mut pending, &buffer, frame_len) {
+                if tx.send(frame).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    framed_rx
+}
+
+/// Copies every buffer from `source` to each output, scaled by that output's
+/// gain. Outputs that fall behind lose buffers instead of stalling the
+/// producer or the other outputs. The task ends once the source ends or every
+/// output is gone.
+pub fn fan_out<S>(
+    mut source: S,
+    mut outputs: Vec<(mpsc::Sender<Vec<f32>>, f32)>,
+) -> tokio::task::JoinHandle<()>
+where
+    S: AudioSource + Send + 'static,
+{
+    tokio::spawn(async move {
+        while let Some(buffer) = source.next_buffer().await {
+            outputs.retain(|(tx, gain)| {
+                let scaled = buffer.iter().map(|sample| sample * gain).collect();
+                match tx.try_send(scaled) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        log::debug!("Fan-out output full, dropping buffer");
+                        true
+                    }
+                    Err(TrySendError::Closed(_)) => false,
+                }
+            });
+            source.recycle(buffer);
+            if outputs.is_empty() {
+                break;
+            }
+        }
+    })
+}
+
+// Channels in the buffers a capture with `config` emits, until a device's
+// actual channel count is known
+fn emitted_channels(config: &CaptureConfig) -> usize {
+    config
+        .channel_selection
+        .as_ref()
+        .map_or(config.channels as usize, Vec::len)
+        .max(1)
+}
+
+// Sample formats `build_stream` converts
+const CAPTURE_FORMATS: [SampleFormat; 3] =
+    [SampleFormat::F32, SampleFormat::I16, SampleFormat::U16];
+
+/// Picks the config to retry with after `failed` couldn't be opened: the
+/// device's `default` config unless that is what failed, otherwise one of the
+/// `supported` configs in a format capture converts, preferably at the
+/// default rate. Only configs with the channel count of `failed` qualify,
+/// since consumers already expect the stream to have that many channels.
+pub fn fallback_config(
+    failed: &SupportedStreamConfig,
+    default: SupportedStreamConfig,
+    supported: &[SupportedStreamConfigRange],
+) -> Option<SupportedStreamConfig> {
+    let usable =
+        |format, channels| CAPTURE_FORMATS.contains(&format) && channels == failed.channels();
+    if &default != failed && usable(default.sample_format(), default.channels()) {
+        return Some(default);
+    }
+    let rate = default.sample_rate();
+    let convertible: Vec<_> = supported
+        .iter()
+        .filter(|c| usable(c.sample_format(), c.channels()))
+        .collect();
+    convertible
+        .iter()
+        .find(|c| c.min_sample_rate() <= rate && rate <= c.max_sample_rate())
+        .map(|c| c.with_sample_rate(rate))
+        .or_else(|| convertible.first().map(|c| c.with_max_sample_rate()))
+}
+
+/// Extracts the `selection` channels from interleaved frames of
+/// `device_channels` samples, producing frames of `selection.len()` samples.
+pub fn select_channels<T: Copy>(data: &[T], device_channels: usize, selection: &[u16]) -> Vec<T> {
+    let mut selected = Vec::with_capacity(data.len() / device_channels * selection.len());
+    for frame in data.chunks_exact(device_channels) {
+        selected.extend(selection.iter().map(|&channel| frame[channel as usize]));
+    }
+    selected
+}
+
+/// Names of the audio hosts compiled in and available on this system, e.g.
+/// "ALSA" and "JACK", the default host first.
+pub fn available_hosts() -> Vec<String> {
+    let default = cpal::default_host().id();
+    let mut hosts = cpal::available_hosts();
+    hosts.sort_by_key(|id| *id != default);
+    hosts.iter().map(|id| id.name().to_string()).collect()
+}
+
+/// The audio host with this name, ignoring case, or the default host for
+/// `None`. Fails for hosts not compiled in or not running, e.g. JACK without
+/// a JACK server.
+pub fn select_host(name: Option<&str>) -> Result<Host> {
+    let Some(name) = name else {
+        return Ok(cpal::default_host());
+    };
+    let id = cpal::ALL_HOSTS
+        .iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            crate::AudioStreamerError::ConfigError(format!(
+                "Unknown audio host {:?}, available: {}",
+                name,
+                available_hosts().join(", ")
+            ))
+        })?;
+    cpal::host_from_id(*id).map_err(|e| {
+        crate::AudioStreamerError::DeviceError(format!("Audio host {}: {}", id.name(), e))
+    })
+}
+
 impl AudioCapture {
     pub fn new() -> Result<Self> {
         let host = cpal::default_host();
+        let config = CaptureConfig::default();
         Ok(Self {
             host,
-            config: CaptureConfig::default(),
+            buffer_size: Arc::new(AtomicU32::new(config.buffer_size)),
+            channels: Arc::new(AtomicU32::new(emitted_channels(&config) as u32)),
+            config,
+            dropped_buffers: Arc::new(AtomicU64::new(0)),
+            input_gain: Arc::new(AtomicU32::new(1.0f32.to_bits())),
+            buffer_pool: BufferPool::new(),
             #[cfg(target_os = "macos")]
             screen_capture: None,
         })
     }
 
     pub fn with_config(config: CaptureConfig) -> Result<Self> {
-        let host = cpal::default_host();
+        let host = select_host(config.host.as_deref())?;
         Ok(Self {
             host,
+            buffer_size: Arc::new(AtomicU32::new(config.buffer_size)),
+            channels: Arc::new(AtomicU32::new(emitted_channels(&config) as u32)),
             config,
+            dropped_buffers: Arc::new(AtomicU64::new(0)),
+            input_gain: Arc::new(AtomicU32::new(1.0f32.to_bits())),
+            buffer_pool: BufferPool::new(),
             #[cfg(target_os = "macos")]
             screen_capture: None,
         })
     }
 
+    /// Number of captured buffers discarded because the consumer fell behind.
+    pub fn dropped_buffers(&self) -> u64 {
+        self.dropped_buffers.load(Ordering::Relaxed)
+    }
+
+    /// Pool the capture callbacks take their buffers from. Hand captured
+    /// buffers back to it once done with them, e.g. by sending through a
+    /// `source::Recycling`, and capture stops allocating.
+    pub fn buffer_pool(&self) -> BufferPool {
+        self.buffer_pool.clone()
+    }
+
+    /// Sets a fixed linear gain applied to captured samples before any other
+    /// processing. Takes effect immediately, including on running captures.
+    pub fn set_input_gain(&self, gain: f32) {
+        self.input_gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn input_gain(&self) -> f32 {
+        f32::from_bits(self.input_gain.load(Ordering::Relaxed))
+    }
+
+    /// Changes how many samples go into each emitted buffer, and so the
+    /// packets sent, without restarting the broadcast. Running captures
+    /// switch on their next device callback, keeping their channel and any
+    /// samples already accumulated. The device's own period is left as it
+    /// was opened, so latency only drops while the new size is still above
+    /// it. The size must be a whole number of frames of the captured
+    /// channels.
+    pub fn set_buffer_size(&self, samples: u32) -> Result<()> {
+        check_buffer_size(
+            samples as usize,
+            self.channels.load(Ordering::Relaxed) as usize,
+        )?;
+        self.buffer_size.store(samples, Ordering::Relaxed);
+        log::info!("Capture buffer size set to {} samples", samples);
+        Ok(())
+    }
+
+    pub fn buffer_size(&self) -> u32 {
+        self.buffer_size.load(Ordering::Relaxed)
+    }
+
     fn is_virtual_device(name: &str) -> bool {
         let virtual_device_keywords = [
             "BlackHole",
@@ -114,11 +734,16 @@ impl AudioCapture {
         let default_device = self.host.default_input_device();
 
         // Add system audio capture option first on supported platforms
-        #[cfg(any(windows, target_os = "macos"))]
+        #[cfg(any(windows, target_os = "macos", target_os = "linux"))]
         {
             devices.push(DeviceInfo {
                 #[cfg(windows)]
                 name: "System Audio (Windows)".to_string(),
+                #[cfg(target_os = "linux")]
+                name: match self.system_audio_source() {
+                    Some(source) => format!("System Audio (via {})", source),
+                    None => "System Audio (requires snd-aloop or a monitor source)".to_string(),
+                },
                 #[cfg(target_os = "macos")]
                 name: if self.screen_capture.is_some() {
                     "System Audio (macOS)".to_string()
@@ -152,7 +777,7 @@ impl AudioCapture {
                 name,
                 is_default,
                 index: index
-                    + if cfg!(any(windows, target_os = "macos")) {
+                    + if cfg!(any(windows, target_os = "macos", target_os = "linux")) {
                         1
                     } else {
                         0
@@ -161,8 +786,8 @@ impl AudioCapture {
             });
         }
 
-        // Add virtual device hint if none found and not on Windows/macOS
-        #[cfg(not(any(windows, target_os = "macos")))]
+        // Add virtual device hint if none found and not on Windows/macOS/Linux
+        #[cfg(not(any(windows, target_os = "macos", target_os = "linux")))]
         if !devices
             .iter()
             .any(|d| matches!(d.device_type, DeviceType::Virtual))
@@ -178,14 +803,61 @@ impl AudioCapture {
         Ok(devices)
     }
 
-    pub fn start_capture_with_device(
-        &self,
-        device_index: usize,
-    ) -> Result<(
-        mpsc::Sender<Vec<f32>>,
-        mpsc::Receiver<Vec<f32>>,
-        cpal::Stream,
-    )> {
+    /// Like `list_input_devices`, but also reports the sample rates, channel
+    /// counts and buffer sizes each device supports.
+    pub fn list_input_devices_detailed(&self) -> Result<Vec<DetailedDeviceInfo>> {
+        let devices: Vec<_> = self.host.input_devices()?.collect();
+        let offset = if cfg!(any(windows, target_os = "macos", target_os = "linux")) {
+            1
+        } else {
+            0
+        };
+
+        Ok(self
+            .list_input_devices()?
+            .into_iter()
+            .map(|info| {
+                let supported_configs = match info.device_type {
+                    DeviceType::SystemAudio => Vec::new(),
+                    _ => info
+                        .index
+                        .checked_sub(offset)
+                        .and_then(|index| devices.get(index))
+                        .map(Self::supported_configs)
+                        .unwrap_or_default(),
+                };
+                DetailedDeviceInfo {
+                    info,
+                    supported_configs,
+                }
+            })
+            .collect())
+    }
+
+    fn supported_configs(device: &cpal::Device) -> Vec<SupportedConfig> {
+        let configs = match device.supported_input_configs() {
+            Ok(configs) => configs,
+            Err(e) => {
+                log::warn!("Failed to query supported configs: {}", e);
+                return Vec::new();
+            }
+        };
+
+        configs
+            .map(|c| SupportedConfig {
+                channels: c.channels(),
+                min_sample_rate: c.min_sample_rate().0,
+                max_sample_rate: c.max_sample_rate().0,
+                sample_format: c.sample_format(),
+                buffer_size: match *c.buffer_size() {
+                    SupportedBufferSize::Range { min, max } => Some((min, max)),
+                    SupportedBufferSize::Unknown => None,
+                },
+            })
+            .collect()
+    }
+
+    pub fn start_capture_with_device(&self, device_index: usize) -> Result<CaptureChannels> {
         #[cfg(windows)]
         if device_index == 0 {
             return self.start_wasapi_loopback();
@@ -196,8 +868,13 @@ impl AudioCapture {
             return self.start_screen_capture();
         }
 
+        #[cfg(target_os = "linux")]
+        if device_index == 0 {
+            return self.start_linux_system_audio();
+        }
+
         let mut devices = self.host.input_devices()?;
-        let adjusted_index = if cfg!(any(windows, target_os = "macos")) {
+        let adjusted_index = if cfg!(any(windows, target_os = "macos", target_os = "linux")) {
             device_index - 1
         } else {
             device_index
@@ -207,42 +884,158 @@ impl AudioCapture {
             crate::AudioStreamerError::DeviceError("Selected device not found".into())
         })?;
 
-        let config = device.default_input_config()?;
-        let (tx, rx) = mpsc::channel(32);
+        let mut config = self.select_input_config(&device)?;
+        self.validate_channel_selection(config.channels())?;
+        let (tx, rx) = mpsc::channel(self.config.channel_capacity);
         let tx = Arc::new(tx);
 
-        let err_fn = |err| eprintln!("An error occurred on the audio stream: {}", err);
+        let stream = match self.open_input_stream(&device, &config, &tx) {
+            Err(e) if self.config.format_fallback => {
+                let supported: Vec<_> = device.supported_input_configs()?.collect();
+                let fallback = fallback_config(&config, device.default_input_config()?, &supported)
+                    .ok_or(e)?;
+                log::warn!(
+                    "Could not capture with {:?}, falling back to {:?}",
+                    config,
+                    fallback
+                );
+                self.validate_channel_selection(fallback.channels())?;
+                let stream = self.open_input_stream(&device, &fallback, &tx)?;
+                config = fallback;
+                stream
+            }
+            result => result?,
+        };
+        if self.is_bit_exact(&config) {
+            log::info!(
+                "Capturing f32 at {}Hz unchanged, bit-exact to listeners of a raw-f32 stream",
+                config.sample_rate().0
+            );
+        }
 
-        let stream = match config.sample_format() {
+        stream.play()?;
+        Ok((tx.as_ref().clone(), rx, stream))
+    }
+
+    fn open_input_stream(
+        &self,
+        device: &cpal::Device,
+        config: &SupportedStreamConfig,
+        tx: &Arc<mpsc::Sender<Vec<f32>>>,
+    ) -> Result<cpal::Stream> {
+        let err_fn = |err| eprintln!("An error occurred on the audio stream: {}", err);
+        let stream_config = config.config();
+        match config.sample_format() {
             SampleFormat::F32 => {
-                self.build_stream::<f32>(&device, &config.into(), tx.clone(), err_fn)?
+                self.build_stream::<f32>(device, &stream_config, tx.clone(), err_fn)
             }
             SampleFormat::I16 => {
-                self.build_stream::<i16>(&device, &config.into(), tx.clone(), err_fn)?
+                self.build_stream::<i16>(device, &stream_config, tx.clone(), err_fn)
             }
             SampleFormat::U16 => {
-                self.build_stream::<u16>(&device, &config.into(), tx.clone(), err_fn)?
-            }
-            _ => {
-                return Err(crate::AudioStreamerError::DeviceError(
-                    "Unsupported sample format".into(),
-                ))
+                self.build_stream::<u16>(device, &stream_config, tx.clone(), err_fn)
             }
+            format => Err(crate::AudioStreamerError::DeviceError(format!(
+                "Unsupported sample format {:?}",
+                format
+            ))),
+        }
+    }
+
+    /// Whether capturing from a device in `device_config` hands on its
+    /// samples unchanged: f32 at the configured rate, with unity gain and no
+    /// noise gate or AGC. Channel selection only picks samples, so it keeps
+    /// them exact too. Sent as raw-f32 or zstd, such a stream reaches
+    /// listeners bit for bit; other device formats are converted to f32
+    /// exactly, but have less precision to begin with.
+    pub fn is_bit_exact(&self, device_config: &SupportedStreamConfig) -> bool {
+        device_config.sample_format() == SampleFormat::F32
+            && device_config.sample_rate().0 == self.config.sample_rate
+            && self.input_gain() == 1.0
+            && self.config.noise_gate.is_none()
+            && self.config.agc.is_none()
+    }
+
+    fn resampler(&self, device_rate: u32, channels: u16) -> Option<Resampler> {
+        if device_rate == self.config.sample_rate {
+            return None;
+        }
+        log::info!(
+            "Resampling capture from {}Hz to {}Hz",
+            device_rate,
+            self.config.sample_rate
+        );
+        Some(Resampler::new(
+            device_rate,
+            self.config.sample_rate,
+            channels,
+        ))
+    }
+
+    fn validate_channel_selection(&self, device_channels: u16) -> Result<()> {
+        let Some(selection) = &self.config.channel_selection else {
+            return Ok(());
+        };
+        if selection.is_empty() {
+            return Err(crate::AudioStreamerError::ConfigError(
+                "Channel selection is empty".into(),
+            ));
+        }
+        if let Some(&channel) = selection.iter().find(|&&c| c >= device_channels) {
+            return Err(crate::AudioStreamerError::ConfigError(format!(
+                "Channel {} selected but the device only has {} channels",
+                channel, device_channels
+            )));
+        }
+        Ok(())
+    }
+
+    fn select_input_config(&self, device: &cpal::Device) -> Result<SupportedStreamConfig> {
+        let default_config = device.default_input_config()?;
+        let preferred = match self.config.preferred_format {
+            Some(format) if format != default_config.sample_format() => format,
+            _ => return Ok(default_config),
         };
 
-        stream.play()?;
-        Ok((tx.as_ref().clone(), rx, stream))
+        // Prefer a config matching the default channel count and rate, then any with the format
+        let candidates: Vec<_> = device
+            .supported_input_configs()?
+            .filter(|c| c.sample_format() == preferred)
+            .collect();
+        let rate = default_config.sample_rate();
+        let selected = candidates
+            .iter()
+            .find(|c| {
+                c.channels() == default_config.channels()
+                    && c.min_sample_rate() <= rate
+                    && rate <= c.max_sample_rate()
+            })
+            .map(|c| c.with_sample_rate(rate))
+            .or_else(|| candidates.first().map(|c| c.with_max_sample_rate()));
+
+        match selected {
+            Some(config) => {
+                log::info!(
+                    "Using preferred sample format {:?}: {:?}",
+                    preferred,
+                    config
+                );
+                Ok(config)
+            }
+            None => {
+                log::info!(
+                    "Device does not support {:?}, falling back to default {:?}",
+                    preferred,
+                    default_config.sample_format()
+                );
+                Ok(default_config)
+            }
+        }
     }
 
     #[cfg(target_os = "macos")]
-    fn start_screen_capture(
-        &self,
-    ) -> Result<(
-        mpsc::Sender<Vec<f32>>,
-        mpsc::Receiver<Vec<f32>>,
-        cpal::Stream,
-    )> {
-        let (tx, rx) = mpsc::channel(32);
+    fn start_screen_capture(&self) -> Result<CaptureChannels> {
+        let (tx, rx) = mpsc::channel(self.config.channel_capacity);
         let tx = Arc::new(tx);
         let tx_clone = tx.clone();
 
@@ -303,6 +1096,44 @@ impl AudioCapture {
         Ok((tx.as_ref().clone(), rx, dummy_stream))
     }
 
+    /// Captures system audio from the best source `find_system_audio_source`
+    /// finds, e.g. a PulseAudio monitor or the `snd-aloop` loopback card.
+    /// Whatever the system plays into the loopback's first subdevice comes
+    /// back out of its second, so with the default output pointed at it this
+    /// records system audio without PulseAudio or PipeWire.
+    #[cfg(target_os = "linux")]
+    fn start_linux_system_audio(&self) -> Result<CaptureChannels> {
+        let names = self.input_device_names()?;
+        let index = find_system_audio_source(&names).ok_or_else(|| {
+            crate::AudioStreamerError::DeviceError(
+                "No system audio source found; load the ALSA loopback with \
+                 `sudo modprobe snd-aloop`, or install a virtual cable or monitor source"
+                    .into(),
+            )
+        })?;
+
+        log::info!("Starting system audio capture on device: {}", names[index]);
+        // Regular devices are listed after the system audio entry
+        self.start_capture_with_device(index + 1)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn input_device_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .host
+            .input_devices()?
+            .map(|device| device.name().unwrap_or_default())
+            .collect())
+    }
+
+    // Name of the device the system audio entry would capture from
+    #[cfg(target_os = "linux")]
+    fn system_audio_source(&self) -> Option<String> {
+        let mut names = self.input_device_names().ok()?;
+        let index = find_system_audio_source(&names)?;
+        Some(names.swap_remove(index))
+    }
+
     #[cfg(target_os = "macos")]
     unsafe fn get_screen_capture_stream(
         &self,
@@ -338,8 +1169,16 @@ impl AudioCapture {
         T: Sample + SizedSample + Send + Sync + 'static,
         f32: cpal::FromSample<T>,
     {
-        let mut samples_buffer = Vec::with_capacity(self.config.buffer_size as usize);
-        let buffer_size = self.config.buffer_size;
+        let mut accumulator =
+            Accumulator::with_channels(self.buffer_size() as usize, config.channels as usize)
+                .with_pool(self.buffer_pool());
+        self.channels
+            .store(config.channels as u32, Ordering::Relaxed);
+        let buffer_size = self.buffer_size.clone();
+        let dropped_buffers = self.dropped_buffers.clone();
+        let input_gain = self.input_gain.clone();
+        let mut resampler = self.resampler(config.sample_rate.0, config.channels);
+        let pool = self.buffer_pool();
 
         log::info!(
             "Starting Windows loopback capture with config: {:?}",
@@ -350,28 +1189,18 @@ impl AudioCapture {
         let stream = device.build_input_stream(
             config,
             move |data: &[T], _: &cpal::InputCallbackInfo| {
-                let mut new_samples = Vec::with_capacity(data.len());
-                for &sample in data.iter() {
-                    new_samples.push(f32::from_sample(sample));
-                }
-
-                samples_buffer.extend(new_samples.drain(..));
-
-                if samples_buffer.len() >= buffer_size as usize {
-                    let buffer_to_send = samples_buffer
-                        .drain(..buffer_size as usize)
-                        .collect::<Vec<f32>>();
+                let mut emit = |mut buffer_to_send: Vec<f32>| {
+                    apply_gain(&mut buffer_to_send, &input_gain);
 
                     // Enhanced logging for audio data
                     let max_amplitude = buffer_to_send
                         .iter()
                         .fold(0.0f32, |max, &x| max.max(x.abs()));
-                    
-                    let rms = (buffer_to_send.iter()
-                        .map(|&x| x * x)
-                        .sum::<f32>() / buffer_to_send.len() as f32)
+
+                    let rms = (buffer_to_send.iter().map(|&x| x * x).sum::<f32>()
+                        / buffer_to_send.len() as f32)
                         .sqrt();
-                        
+
                     if max_amplitude > 0.01 {
                         log::debug!(
                             "Captured audio data - Max amplitude: {:.3}, RMS: {:.3}, Buffer size: {}",
@@ -387,10 +1216,15 @@ impl AudioCapture {
                         );
                     }
 
-                    if let Err(e) = tx.blocking_send(buffer_to_send) {
-                        log::error!("Failed to send captured audio data: {}", e);
-                    }
+                    let buffer_to_send = resample(&mut resampler, buffer_to_send, &pool);
+                    send_or_drop(&tx, buffer_to_send, &dropped_buffers);
+                };
+                let size = buffer_size.load(Ordering::Relaxed) as usize;
+                if size != accumulator.buffer_size() {
+                    // Already checked against the channels by `set_buffer_size`
+                    let _ = accumulator.set_buffer_size(size, &mut emit);
                 }
+                accumulator.push(data, emit);
             },
             error_fn,
             None,
@@ -400,25 +1234,22 @@ impl AudioCapture {
     }
 
     #[cfg(windows)]
-    fn start_wasapi_loopback(
-        &self,
-    ) -> Result<(
-        mpsc::Sender<Vec<f32>>,
-        mpsc::Receiver<Vec<f32>>,
-        cpal::Stream,
-    )> {
+    fn start_wasapi_loopback(&self) -> Result<CaptureChannels> {
         use cpal::traits::HostTrait;
 
         let device = self.host.default_output_device().ok_or_else(|| {
             crate::AudioStreamerError::DeviceError("No output device found".into())
         })?;
 
-        log::info!("Starting WASAPI loopback capture on device: {}", device.name()?);
-        
+        log::info!(
+            "Starting WASAPI loopback capture on device: {}",
+            device.name()?
+        );
+
         let config = device.default_output_config()?;
         log::info!("Using WASAPI config: {:?}", config);
-        
-        let (tx, rx) = mpsc::channel(32);
+
+        let (tx, rx) = mpsc::channel(self.config.channel_capacity);
         let tx: Arc<mpsc::Sender<Vec<f32>>> = Arc::new(tx);
 
         let err_fn = |err| log::error!("WASAPI stream error: {}", err);
@@ -455,25 +1286,15 @@ impl AudioCapture {
         T: Sample + SizedSample + Send + Sync + 'static,
         f32: cpal::FromSample<T>,
     {
-        let mut samples_buffer = Vec::with_capacity(self.config.buffer_size as usize);
-        let buffer_size = self.config.buffer_size;
+        let dropped_buffers = self.dropped_buffers.clone();
+        let mut processor = self.input_processor(config.sample_rate.0, config.channels);
+        self.channels
+            .store(processor.accumulator.channels as u32, Ordering::Relaxed);
 
         let stream = device.build_input_stream(
             config,
             move |data: &[T], _: &cpal::InputCallbackInfo| {
-                let mut new_samples = Vec::with_capacity(data.len());
-                for &sample in data.iter() {
-                    new_samples.push(f32::from_sample(sample));
-                }
-
-                samples_buffer.extend(new_samples.drain(..));
-
-                if samples_buffer.len() >= buffer_size as usize {
-                    let buffer_to_send = samples_buffer
-                        .drain(..buffer_size as usize)
-                        .collect::<Vec<f32>>();
-                    let _ = tx.blocking_send(buffer_to_send);
-                }
+                processor.process(data, |buffer| send_or_drop(&tx, buffer, &dropped_buffers));
             },
             error_fn,
             None,
@@ -482,16 +1303,381 @@ impl AudioCapture {
         Ok(stream)
     }
 
+    // Processing for a device delivering `device_channels` at `device_rate`
+    pub(crate) fn input_processor(&self, device_rate: u32, device_channels: u16) -> InputProcessor {
+        let selection = self.config.channel_selection.clone();
+        let output_channels = selection
+            .as_ref()
+            .map_or(device_channels, |selection| selection.len() as u16);
+        InputProcessor {
+            accumulator: Accumulator::with_channels(
+                self.buffer_size() as usize,
+                output_channels as usize,
+            )
+            .with_pool(self.buffer_pool()),
+            buffer_size: self.buffer_size.clone(),
+            input_gain: self.input_gain.clone(),
+            device_channels: device_channels as usize,
+            selection,
+            noise_gate: self
+                .config
+                .noise_gate
+                .as_ref()
+                .map(|gate| NoiseGate::new(gate, device_rate, output_channels)),
+            agc: self
+                .config
+                .agc
+                .as_ref()
+                .map(|agc| Agc::new(agc, device_rate, output_channels)),
+            resampler: self.resampler(device_rate, output_channels),
+        }
+    }
+
     // Keep the old method for backward compatibility, using default device
-    pub fn start_capture(
-        &self,
-    ) -> Result<(
-        mpsc::Sender<Vec<f32>>,
-        mpsc::Receiver<Vec<f32>>,
-        cpal::Stream,
-    )> {
+    pub fn start_capture(&self) -> Result<CaptureChannels> {
         let devices = self.list_input_devices()?;
         let default_index = devices.iter().position(|d| d.is_default).unwrap_or(0);
         self.start_capture_with_device(default_index)
     }
+
+    /// Starts capturing from the given device and exposes the captured buffers as a
+    /// `Stream`. The returned cpal stream must be kept alive for as long as the
+    /// sample stream is polled.
+    pub fn capture_stream(
+        &self,
+        device_index: usize,
+    ) -> Result<(impl Stream<Item = Vec<f32>>, cpal::Stream)> {
+        let (_tx, rx, stream) = self.start_capture_with_device(device_index)?;
+        Ok((ReceiverStream::new(rx), stream))
+    }
+}
+
+/// Picks the device to record system audio from: a PulseAudio or PipeWire
+/// monitor source first, as it captures whatever is playing with no setup,
+/// then the capture side of an `snd-aloop` card, then a virtual cable.
+#[cfg(target_os = "linux")]
+fn find_system_audio_source(names: &[String]) -> Option<usize> {
+    names
+        .iter()
+        .position(|name| name.ends_with(".monitor") || name.starts_with("Monitor of "))
+        .or_else(|| find_alsa_loopback(names.iter().map(String::as_str)))
+        .or_else(|| {
+            names
+                .iter()
+                .position(|name| AudioCapture::is_virtual_device(name))
+        })
+}
+
+/// Picks the capture side of an `snd-aloop` card from ALSA device names such
+/// as `plughw:CARD=Loopback,DEV=1`. Audio played into `DEV=0` is captured from
+/// `DEV=1`, and `plughw` is preferred because it converts rates and formats.
+#[cfg(target_os = "linux")]
+fn find_alsa_loopback<'a>(names: impl IntoIterator<Item = &'a str>) -> Option<usize> {
+    names
+        .into_iter()
+        .enumerate()
+        .filter(|(_, name)| name.contains("CARD=Loopback"))
+        .map(|(index, name)| {
+            let score = name.contains("DEV=1") as u8 * 2 + name.starts_with("plughw:") as u8;
+            (score, std::cmp::Reverse(index))
+        })
+        .max()
+        .map(|(_, std::cmp::Reverse(index))| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::SampleRate;
+
+    #[test]
+    fn hosts_are_selected_by_name() {
+        let default = cpal::default_host().id();
+        assert_eq!(available_hosts()[0], default.name());
+        let host = select_host(Some(&default.name().to_uppercase())).unwrap();
+        assert_eq!(host.id(), default);
+        assert_eq!(select_host(None).unwrap().id(), default);
+        assert!(select_host(Some("no-such-host")).is_err());
+    }
+
+    #[test]
+    fn accumulate_holds_partial_buffers() {
+        let mut buffer = Vec::new();
+        assert!(accumulate_and_emit(&mut buffer, &[0.1f32, 0.2, 0.3], 4).is_empty());
+        assert_eq!(buffer.len(), 3);
+
+        let chunks = accumulate_and_emit(&mut buffer, &[0.4f32, 0.5], 4);
+        assert_eq!(chunks, vec![vec![0.1, 0.2, 0.3, 0.4]]);
+        assert_eq!(buffer, vec![0.5]);
+    }
+
+    #[test]
+    fn accumulate_emits_every_full_chunk() {
+        let mut buffer = Vec::new();
+        let incoming = [0.0f32; 10];
+        let chunks = accumulate_and_emit(&mut buffer, &incoming, 4);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|chunk| chunk.len() == 4));
+        assert_eq!(buffer.len(), 2);
+    }
+
+    #[test]
+    fn accumulate_converts_integer_samples() {
+        let mut buffer = Vec::new();
+        let chunks = accumulate_and_emit(&mut buffer, &[0i16, i16::MIN, 16384], 3);
+        assert_eq!(chunks, vec![vec![0.0, -1.0, 0.5]]);
+
+        let chunks = accumulate_and_emit(&mut buffer, &[32768u16, 0], 2);
+        assert_eq!(chunks, vec![vec![0.0, -1.0]]);
+    }
+
+    #[test]
+    fn only_unprocessed_f32_at_the_stream_rate_is_bit_exact() {
+        let capture = AudioCapture::new().unwrap();
+        let device = |rate, format| {
+            SupportedStreamConfig::new(2, SampleRate(rate), SupportedBufferSize::Unknown, format)
+        };
+        assert!(capture.is_bit_exact(&device(48000, SampleFormat::F32)));
+        assert!(!capture.is_bit_exact(&device(48000, SampleFormat::I16)));
+        assert!(!capture.is_bit_exact(&device(44100, SampleFormat::F32)));
+
+        capture.set_input_gain(0.5);
+        assert!(!capture.is_bit_exact(&device(48000, SampleFormat::F32)));
+    }
+
+    #[test]
+    fn accumulator_emits_full_buffers_and_keeps_the_rest() {
+        let mut accumulator = Accumulator::new(4);
+        let mut emitted = Vec::new();
+        accumulator.push(&[0.1f32, 0.2, 0.3], |buffer| emitted.push(buffer));
+        assert!(emitted.is_empty());
+
+        accumulator.push(&[0.4f32, 0.5], |buffer| emitted.push(buffer));
+        assert_eq!(emitted, vec![vec![0.1, 0.2, 0.3, 0.4]]);
+        assert_eq!(accumulator.pending(), &[0.5]);
+    }
+
+    #[test]
+    fn accumulator_refills_from_recycled_buffers() {
+        let mut accumulator = Accumulator::new(2);
+        let mut emitted = Vec::new();
+        accumulator.push(&[0.1f32, 0.2], |buffer| emitted.push(buffer));
+        let recycled = emitted.pop().unwrap();
+        let address = recycled.as_ptr();
+        accumulator.pool().recycle(recycled);
+
+        accumulator.push(&[0.3f32, 0.4, 0.5, 0.6], |buffer| emitted.push(buffer));
+        assert_eq!(emitted, vec![vec![0.3, 0.4], vec![0.5, 0.6]]);
+        assert_eq!(emitted[1].as_ptr(), address);
+    }
+
+    #[test]
+    fn accumulator_buffer_size_changes_keep_pending_samples() {
+        let mut accumulator = Accumulator::new(4);
+        let mut emitted = Vec::new();
+        accumulator.push(&[0.1f32, 0.2, 0.3], |buffer| emitted.push(buffer));
+
+        accumulator
+            .set_buffer_size(2, |buffer| emitted.push(buffer))
+            .unwrap();
+        assert_eq!(emitted, vec![vec![0.1, 0.2]]);
+        assert_eq!(accumulator.pending(), &[0.3]);
+
+        accumulator
+            .set_buffer_size(3, |buffer| emitted.push(buffer))
+            .unwrap();
+        accumulator.push(&[0.4f32, 0.5, 0.6], |buffer| emitted.push(buffer));
+        assert_eq!(emitted, vec![vec![0.1, 0.2], vec![0.3, 0.4, 0.5]]);
+        assert_eq!(accumulator.pending(), &[0.6]);
+
+        let mut stereo = Accumulator::with_channels(5, 2);
+        assert_eq!(stereo.buffer_size(), 4);
+        assert!(stereo.set_buffer_size(3, |_| {}).is_err());
+        assert_eq!(stereo.buffer_size(), 4);
+
+        let capture = AudioCapture::new().unwrap();
+        assert!(capture.set_buffer_size(0).is_err());
+        // Half a stereo frame would swap the channels of every later buffer
+        assert!(matches!(
+            capture.set_buffer_size(241),
+            Err(crate::AudioStreamerError::ConfigError(_))
+        ));
+        capture.set_buffer_size(240).unwrap();
+        assert_eq!(capture.buffer_size(), 240);
+    }
+
+    #[test]
+    fn accumulator_selects_channels_inline() {
+        let mut accumulator = Accumulator::new(4);
+        let mut emitted = Vec::new();
+        // Three-channel frames, keeping channels 2 and 0
+        let data = [1i16, 2, 3, 4, 5, 6, 7, 8, 9];
+        accumulator.push_selected(&data, 3, &[2, 0], |buffer| emitted.push(buffer));
+        let expected: Vec<f32> = [3i16, 1, 6, 4].iter().map(|&s| sample_to_f32(s)).collect();
+        assert_eq!(emitted, vec![expected]);
+        assert_eq!(accumulator.pending().len(), 2);
+    }
+
+    #[test]
+    fn fallback_config_prefers_the_default_then_a_convertible_format() {
+        let config = |format| {
+            SupportedStreamConfig::new(2, SampleRate(48000), SupportedBufferSize::Unknown, format)
+        };
+        let range = |channels, max, format| {
+            SupportedStreamConfigRange::new(
+                channels,
+                SampleRate(8000),
+                SampleRate(max),
+                SupportedBufferSize::Unknown,
+                format,
+            )
+        };
+        let supported = [
+            range(2, 96000, SampleFormat::I32),
+            range(1, 48000, SampleFormat::I16),
+            range(2, 44100, SampleFormat::U16),
+            range(2, 48000, SampleFormat::F32),
+        ];
+
+        let default = config(SampleFormat::F32);
+        let failed = config(SampleFormat::I16);
+        assert_eq!(
+            fallback_config(&failed, default.clone(), &supported),
+            Some(default)
+        );
+
+        // A default in a format capture can't convert is passed over
+        let default = config(SampleFormat::I32);
+        assert_eq!(
+            fallback_config(&default, default.clone(), &supported),
+            Some(config(SampleFormat::F32))
+        );
+        assert_eq!(
+            fallback_config(&default, default.clone(), &supported[..3]),
+            Some(SupportedStreamConfig::new(
+                2,
+                SampleRate(44100),
+                SupportedBufferSize::Unknown,
+                SampleFormat::U16
+            ))
+        );
+        // Never a config with another channel count
+        assert_eq!(
+            fallback_config(&default, default.clone(), &supported[..2]),
+            None
+        );
+        let mono_default = SupportedStreamConfig::new(
+            1,
+            SampleRate(48000),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        );
+        assert_eq!(
+            fallback_config(&default, mono_default, &supported),
+            Some(config(SampleFormat::F32))
+        );
+    }
+
+    #[test]
+    fn select_channels_extracts_from_interleaved_frames() {
+        let data = [0, 1, 2, 3, 10, 11, 12, 13];
+        assert_eq!(select_channels(&data, 4, &[2]), vec![2, 12]);
+        assert_eq!(select_channels(&data, 4, &[3, 0]), vec![3, 0, 13, 10]);
+    }
+
+    #[tokio::test]
+    async fn framed_repackages_across_buffer_boundaries() {
+        let (tx, rx) = mpsc::channel(8);
+        let mut frames = framed(rx, 4);
+
+        tx.send(vec![1.0, 2.0, 3.0]).await.unwrap();
+        tx.send(vec![4.0, 5.0, 6.0, 7.0, 8.0, 9.0]).await.unwrap();
+        drop(tx);
+
+        assert_eq!(frames.recv().await, Some(vec![1.0, 2.0, 3.0, 4.0]));
+        assert_eq!(frames.recv().await, Some(vec![5.0, 6.0, 7.0, 8.0]));
+        assert_eq!(frames.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn fan_out_scales_each_output_and_never_blocks() {
+        let (tx, rx) = mpsc::channel(8);
+        let (loud_tx, mut loud_rx) = mpsc::channel(8);
+        let (quiet_tx, mut quiet_rx) = mpsc::channel(1);
+        let task = fan_out(rx, vec![(loud_tx, 1.0), (quiet_tx, 0.5)]);
+
+        tx.send(vec![0.5, -1.0]).await.unwrap();
+        tx.send(vec![1.0, 1.0]).await.unwrap();
+        drop(tx);
+        task.await.unwrap();
+
+        assert_eq!(loud_rx.recv().await, Some(vec![0.5, -1.0]));
+        assert_eq!(loud_rx.recv().await, Some(vec![1.0, 1.0]));
+        // The full quiet output dropped the second buffer rather than blocking
+        assert_eq!(quiet_rx.recv().await, Some(vec![0.25, -0.5]));
+        assert_eq!(quiet_rx.recv().await, None);
+    }
+
+    #[test]
+    fn input_gain_scales_samples() {
+        let gain = AtomicU32::new(0.5f32.to_bits());
+        let mut buffer = [1.0, -0.5];
+        apply_gain(&mut buffer, &gain);
+        assert_eq!(buffer, [0.5, -0.25]);
+    }
+
+    #[test]
+    fn pre_roll_keeps_the_most_recent_audio() {
+        // 4 stereo frames at 1kHz
+        let mut pre_roll = PreRoll::new(Duration::from_millis(4), 1000, 2);
+        pre_roll.push(0, vec![0.0; 6]);
+        pre_roll.push(3, vec![1.0; 6]);
+        assert_eq!(pre_roll.held_samples(), 8);
+
+        let held: Vec<_> = pre_roll.drain().collect();
+        assert_eq!(held, vec![(2, vec![0.0; 2]), (3, vec![1.0; 6])]);
+        assert_eq!(pre_roll.held_samples(), 0);
+
+        let mut disabled = PreRoll::new(Duration::ZERO, 48000, 2);
+        disabled.push(0, vec![1.0; 4]);
+        assert_eq!(disabled.drain().count(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn system_audio_prefers_monitors_then_loopbacks_then_cables() {
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        let devices = names(&[
+            "default",
+            "VB-CABLE Output",
+            "hw:CARD=Loopback,DEV=1",
+            "alsa_output.pci-0000_00_1f.3.analog-stereo.monitor",
+        ]);
+        assert_eq!(find_system_audio_source(&devices), Some(3));
+        assert_eq!(find_system_audio_source(&devices[..3]), Some(2));
+        assert_eq!(find_system_audio_source(&devices[..2]), Some(1));
+        assert_eq!(find_system_audio_source(&devices[..1]), None);
+        let described = names(&["Monitor of Built-in Audio Analog Stereo"]);
+        assert_eq!(find_system_audio_source(&described), Some(0));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn alsa_loopback_prefers_the_capture_subdevice() {
+        let names = [
+            "default",
+            "hw:CARD=Loopback,DEV=0",
+            "hw:CARD=Loopback,DEV=1",
+            "plughw:CARD=Loopback,DEV=1",
+            "plughw:CARD=PCH,DEV=0",
+        ];
+        assert_eq!(find_alsa_loopback(names), Some(3));
+        assert_eq!(find_alsa_loopback(["sysdefault:CARD=Loopback"]), Some(0));
+        assert_eq!(find_alsa_loopback(["default", "hw:CARD=PCH,DEV=0"]), None);
+    }
 }
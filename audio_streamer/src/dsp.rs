@@ -0,0 +1,821 @@
+use std::f64::consts::PI;
+use std::time::Duration;
+
+use crate::{AudioStreamerError, Result};
+
+#[derive(Clone, Debug)]
+pub struct NoiseGateConfig {
+    /// Buffers whose RMS falls below this level are attenuated
+    pub threshold: f32,
+    /// Time to fade back in once the signal crosses the threshold
+    pub attack: Duration,
+    /// Time to fade out once the signal drops below the threshold
+    pub release: Duration,
+}
+
+impl Default for NoiseGateConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0.01,
+            attack: Duration::from_millis(5),
+            release: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Root mean square level of a buffer.
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum: f32 = samples.iter().map(|s| s * s).sum();
+    (sum / samples.len() as f32).sqrt()
+}
+
+/// Attenuates interleaved buffers whose RMS falls below a threshold. The gain
+/// ramps linearly per frame over the attack/release times, so opening and
+/// closing don't click.
+#[derive(Clone, Debug)]
+pub struct NoiseGate {
+    threshold: f32,
+    attack_step: f32,
+    release_step: f32,
+    channels: usize,
+    gain: f32,
+}
+
+impl NoiseGate {
+    pub fn new(config: &NoiseGateConfig, sample_rate: u32, channels: u16) -> Self {
+        let step = |time: Duration| {
+            let frames = time.as_secs_f32() * sample_rate as f32;
+            if frames < 1.0 {
+                1.0
+            } else {
+                1.0 / frames
+            }
+        };
+        Self {
+            threshold: config.threshold,
+            attack_step: step(config.attack),
+            release_step: step(config.release),
+            channels: channels.max(1) as usize,
+            gain: 1.0,
+        }
+    }
+
+    /// Current gain, from 0.0 (closed) to 1.0 (open).
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        let (target, step) = if rms(buffer) >= self.threshold {
+            (1.0, self.attack_step)
+        } else {
+            (0.0, self.release_step)
+        };
+
+        for frame in buffer.chunks_mut(self.channels) {
+            self.gain = if self.gain < target {
+                (self.gain + step).min(target)
+            } else {
+                (self.gain - step).max(target)
+            };
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AgcConfig {
+    /// RMS level the gain steers towards
+    pub target_level: f32,
+    /// Upper limit on amplification, so near-silence isn't boosted into noise
+    pub max_gain: f32,
+    /// Buffers quieter than this leave the gain unchanged
+    pub noise_floor: f32,
+    /// Time constant for reducing gain when the signal gets louder
+    pub attack: Duration,
+    /// Time constant for raising gain when the signal gets quieter. Keep this
+    /// much slower than `attack` to avoid audible pumping.
+    pub release: Duration,
+}
+
+impl Default for AgcConfig {
+    fn default() -> Self {
+        Self {
+            target_level: 0.1,
+            max_gain: 10.0,
+            noise_floor: 0.001,
+            attack: Duration::from_millis(50),
+            release: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Automatic gain control that slowly normalizes interleaved buffers towards
+/// a target RMS. Gain changes are smoothed with one-pole filters and ramped
+/// across each buffer, and the output is clamped to full scale.
+#[derive(Clone, Debug)]
+pub struct Agc {
+    config: AgcConfig,
+    sample_rate: u32,
+    channels: usize,
+    gain: f32,
+}
+
+impl Agc {
+    pub fn new(config: &AgcConfig, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            config: config.clone(),
+            sample_rate,
+            channels: channels.max(1) as usize,
+            gain: 1.0,
+        }
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        let frames = buffer.len() / self.channels;
+        if frames == 0 {
+            return;
+        }
+
+        let start_gain = self.gain;
+        let level = rms(buffer);
+        if level > self.config.noise_floor {
+            let desired = (self.config.target_level / level).min(self.config.max_gain);
+            let time_constant = if desired < self.gain {
+                self.config.attack
+            } else {
+                self.config.release
+            };
+            let elapsed = frames as f32 / self.sample_rate as f32;
+            let coefficient = 1.0 - (-elapsed / time_constant.as_secs_f32()).exp();
+            self.gain += (desired - self.gain) * coefficient;
+        }
+
+        let step = (self.gain - start_gain) / frames as f32;
+        for (i, frame) in buffer.chunks_mut(self.channels).enumerate() {
+            let gain = start_gain + step * (i + 1) as f32;
+            for sample in frame {
+                *sample = (*sample * gain).clamp(-1.0, 1.0);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BandKind {
+    /// Boosts or cuts everything below the corner frequency
+    LowShelf,
+    /// Boosts or cuts around the centre frequency, width set by `q`
+    Peaking,
+    /// Boosts or cuts everything above the corner frequency
+    HighShelf,
+}
+
+#[derive(Clone, Debug)]
+pub struct EqBand {
+    pub kind: BandKind,
+    /// Centre or corner frequency in Hz
+    pub frequency: f32,
+    pub q: f32,
+    /// Initial gain in dB, 0.0 is flat
+    pub gain_db: f32,
+}
+
+/// Equalizer layout. The default is a flat five-band tone control: shelves
+/// at 100Hz and 8kHz with peaking bands at 300Hz, 1kHz and 3kHz.
+#[derive(Clone, Debug)]
+pub struct EqConfig {
+    pub bands: Vec<EqBand>,
+}
+
+impl Default for EqConfig {
+    fn default() -> Self {
+        let band = |kind, frequency| EqBand {
+            kind,
+            frequency,
+            q: std::f32::consts::FRAC_1_SQRT_2,
+            gain_db: 0.0,
+        };
+        Self {
+            bands: vec![
+                band(BandKind::LowShelf, 100.0),
+                band(BandKind::Peaking, 300.0),
+                band(BandKind::Peaking, 1000.0),
+                band(BandKind::Peaking, 3000.0),
+                band(BandKind::HighShelf, 8000.0),
+            ],
+        }
+    }
+}
+
+// Normalized biquad coefficients (a0 = 1)
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Coefficients {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Coefficients {
+    // RBJ audio EQ cookbook
+    fn new(band: &EqBand, gain_db: f32, sample_rate: u32) -> Self {
+        // Keep the frequency below Nyquist so the filter stays stable at any rate
+        let nyquist = sample_rate as f64 / 2.0;
+        let frequency = (band.frequency as f64).clamp(1.0, nyquist * 0.9);
+        let q = (band.q as f64).max(0.01);
+
+        let a = 10f64.powf(gain_db as f64 / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match band.kind {
+            BandKind::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            BandKind::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            BandKind::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+        };
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    // RBJ low-pass
+    fn low_pass(frequency: f64, q: f64, sample_rate: u32) -> Self {
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: (1.0 - cos) / 2.0 / a0,
+            b1: (1.0 - cos) / a0,
+            b2: (1.0 - cos) / 2.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+        }
+    }
+
+    // One sample through transposed direct form II
+    fn run(&self, x: f64, state: &mut [f64; 2]) -> f64 {
+        let y = self.b0 * x + state[0];
+        state[0] = self.b1 * x - self.a1 * y + state[1];
+        state[1] = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+struct Band {
+    config: EqBand,
+    gain_db: f32,
+    coefficients: Coefficients,
+    // Transposed direct form II state, two values per channel
+    state: Vec<[f64; 2]>,
+}
+
+/// N-band equalizer over interleaved buffers, one biquad per band and
+/// channel. Bands at 0dB are bypassed, so a flat EQ passes audio through
+/// untouched.
+///
+/// Each active band costs five multiplies and four adds per sample, e.g.
+/// about 2.4M multiply-adds per second for five bands of 48kHz stereo.
+pub struct Equalizer {
+    bands: Vec<Band>,
+    sample_rate: u32,
+    channels: usize,
+}
+
+impl Equalizer {
+    pub fn new(config: &EqConfig, sample_rate: u32, channels: u16) -> Self {
+        let channels = channels.max(1) as usize;
+        let bands = config
+            .bands
+            .iter()
+            .map(|band| Band {
+                config: band.clone(),
+                gain_db: band.gain_db,
+                coefficients: Coefficients::new(band, band.gain_db, sample_rate),
+                state: vec![[0.0; 2]; channels],
+            })
+            .collect();
+        Self {
+            bands,
+            sample_rate,
+            channels,
+        }
+    }
+
+    pub fn band_count(&self) -> usize {
+        self.bands.len()
+    }
+
+    pub fn band_gain(&self, band: usize) -> Option<f32> {
+        self.bands.get(band).map(|b| b.gain_db)
+    }
+
+    /// Sets a band's gain in dB. Filter state is kept, so audio running
+    /// through the band continues without a reset.
+    pub fn set_band_gain(&mut self, band: usize, db: f32) -> Result<()> {
+        let sample_rate = self.sample_rate;
+        let count = self.bands.len();
+        let band = self.bands.get_mut(band).ok_or_else(|| {
+            AudioStreamerError::ConfigError(format!(
+                "EQ band {} out of range, there are {} bands",
+                band, count
+            ))
+        })?;
+        if band.gain_db != db {
+            band.gain_db = db;
+            band.coefficients = Coefficients::new(&band.config, db, sample_rate);
+        }
+        Ok(())
+    }
+
+    /// Recomputes every band for a new sample rate and clears the filter
+    /// state, which belongs to the old rate.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        for band in &mut self.bands {
+            band.coefficients = Coefficients::new(&band.config, band.gain_db, sample_rate);
+            band.state.fill([0.0; 2]);
+        }
+    }
+
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        for band in self.bands.iter_mut().filter(|band| band.gain_db != 0.0) {
+            let c = band.coefficients;
+            for frame in buffer.chunks_mut(self.channels) {
+                for (sample, state) in frame.iter_mut().zip(band.state.iter_mut()) {
+                    *sample = c.run(*sample as f64, state) as f32;
+                }
+            }
+        }
+    }
+}
+
+/// Emphasis coefficients are fixed point in units of `1 / EMPHASIS_SCALE`,
+/// so 9500 is 0.95. They travel in packet headers as is.
+pub const EMPHASIS_SCALE: u16 = 10_000;
+
+/// Typical pre-emphasis coefficient for speech, 0.95
+pub const DEFAULT_EMPHASIS: u16 = 9_500;
+
+/// Converts a coefficient such as 0.95 to fixed point, rounding to the
+/// nearest step. Out of range values saturate, so `check_emphasis` rejects
+/// them.
+pub fn emphasis_from_f32(coefficient: f32) -> u16 {
+    (coefficient * EMPHASIS_SCALE as f32).round() as u16
+}
+
+// Fixed-point coefficient as the filters use it
+fn emphasis_to_f32(coefficient: u16) -> f32 {
+    coefficient as f32 / EMPHASIS_SCALE as f32
+}
+
+/// Fails unless `coefficient` is usable by [`PreEmphasis`] and
+/// [`DeEmphasis`]: above 0 and below `EMPHASIS_SCALE`, i.e. 1.0, where
+/// de-emphasis would never settle.
+pub fn check_emphasis(coefficient: u16) -> Result<()> {
+    if coefficient > 0 && coefficient < EMPHASIS_SCALE {
+        Ok(())
+    } else {
+        Err(AudioStreamerError::ConfigError(format!(
+            "Emphasis coefficient must be between 0 and 1, got {}",
+            emphasis_to_f32(coefficient)
+        )))
+    }
+}
+
+/// One-pole high-frequency boost, `y[n] = (x[n] - a * x[n-1]) / (1 + a)`,
+/// applied before encoding so treble survives lossy codecs and quantization.
+/// The `1 + a` divisor is the filter's gain at Nyquist, so full-scale input
+/// stays within ±1.0 rather than clipping in raw-i16 or FLAC. Lows are cut
+/// rather than highs raised, so the level drops on bass-heavy material.
+/// [`DeEmphasis`] with the same coefficient restores the original.
+pub struct PreEmphasis {
+    coefficient: f32,
+    channels: usize,
+    // Last input sample of each channel
+    previous: Vec<f32>,
+}
+
+impl PreEmphasis {
+    pub fn new(coefficient: u16, channels: u16) -> Self {
+        let channels = channels.max(1) as usize;
+        Self {
+            coefficient: emphasis_to_f32(coefficient),
+            channels,
+            previous: vec![0.0; channels],
+        }
+    }
+
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        for frame in buffer.chunks_mut(self.channels) {
+            for (sample, previous) in frame.iter_mut().zip(self.previous.iter_mut()) {
+                let input = *sample;
+                *sample = (input - self.coefficient * *previous) / (1.0 + self.coefficient);
+                *previous = input;
+            }
+        }
+    }
+}
+
+/// Inverse of [`PreEmphasis`], `y[n] = (1 + a) * x[n] + a * y[n-1]`, applied
+/// on playback.
+pub struct DeEmphasis {
+    // Fixed point, as announced
+    announced: u16,
+    coefficient: f32,
+    channels: usize,
+    // Last output sample of each channel
+    previous: Vec<f32>,
+}
+
+impl DeEmphasis {
+    pub fn new(coefficient: u16, channels: u16) -> Self {
+        let channels = channels.max(1) as usize;
+        Self {
+            announced: coefficient,
+            coefficient: emphasis_to_f32(coefficient),
+            channels,
+            previous: vec![0.0; channels],
+        }
+    }
+
+    /// Whether this filter undoes pre-emphasis with `coefficient` on
+    /// `channels` channels.
+    pub fn matches(&self, coefficient: u16, channels: u16) -> bool {
+        self.announced == coefficient && self.channels == channels.max(1) as usize
+    }
+
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        for frame in buffer.chunks_mut(self.channels) {
+            for (sample, previous) in frame.iter_mut().zip(self.previous.iter_mut()) {
+                *sample = (1.0 + self.coefficient) * *sample + self.coefficient * *previous;
+                *previous = *sample;
+            }
+        }
+    }
+}
+
+// Section Qs of a 4th-order Butterworth low-pass
+const BUTTERWORTH_Q: [f64; 2] = [0.541_196, 1.306_563];
+
+/// Streaming sample rate converter for interleaved buffers, keeping its
+/// place between calls so consecutive buffers join seamlessly.
+///
+/// Uses linear interpolation, preceded when downsampling by a 4th-order
+/// low-pass at 90% of the new Nyquist so treble doesn't alias. That suits
+/// voice and bandwidth saving rather than critical listening. Equal rates
+/// pass through untouched.
+pub struct Resampler {
+    channels: usize,
+    // Input frames advanced per output frame
+    step: f64,
+    // Position of the next output frame, where 0 is `previous` and 1 is
+    // the first frame of the next input
+    position: f64,
+    previous: Vec<f32>,
+    anti_alias: Vec<(Coefficients, Vec<[f64; 2]>)>,
+    filtered: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: u16) -> Self {
+        let channels = channels.max(1) as usize;
+        let anti_alias = if to_rate < from_rate {
+            let cutoff = to_rate as f64 * 0.45;
+            BUTTERWORTH_Q
+                .iter()
+                .map(|&q| {
+                    (
+                        Coefficients::low_pass(cutoff, q, from_rate),
+                        vec![[0.0; 2]; channels],
+                    )
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Self {
+            channels,
+            step: from_rate as f64 / to_rate.max(1) as f64,
+            position: 1.0,
+            previous: vec![0.0; channels],
+            anti_alias,
+            filtered: Vec::new(),
+        }
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.step == 1.0
+    }
+
+    /// Converts the next input buffer. Output lengths vary by a frame from
+    /// call to call as the fractional position carries over. Allocates the
+    /// output, so real-time callers use `process_into`.
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        let mut output = Vec::new();
+        self.process_into(input, &mut output);
+        output
+    }
+
+    /// Like `process`, but replaces the contents of `output`, which only
+    /// allocates when it lacks the capacity.
+    pub fn process_into(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        output.clear();
+        if self.is_passthrough() {
+            output.extend_from_slice(input);
+            return;
+        }
+
+        let channels = self.channels;
+        let mut filtered = std::mem::take(&mut self.filtered);
+        let input = if self.anti_alias.is_empty() {
+            input
+        } else {
+            filtered.clear();
+            filtered.extend_from_slice(input);
+            for (coefficients, state) in &mut self.anti_alias {
+                for frame in filtered.chunks_mut(channels) {
+                    for (sample, state) in frame.iter_mut().zip(state.iter_mut()) {
+                        *sample = coefficients.run(*sample as f64, state) as f32;
+                    }
+                }
+            }
+            &filtered
+        };
+
+        let frames = input.len() / channels;
+        output.reserve((frames as f64 / self.step) as usize * channels + channels);
+        while (self.position as usize) < frames {
+            let index = self.position as usize;
+            let t = (self.position - index as f64) as f32;
+            let before = match index {
+                0 => &self.previous[..],
+                _ => &input[(index - 1) * channels..index * channels],
+            };
+            let after = &input[index * channels..(index + 1) * channels];
+            output.extend(before.iter().zip(after).map(|(a, b)| a + (b - a) * t));
+            self.position += self.step;
+        }
+
+        if frames > 0 {
+            self.previous
+                .copy_from_slice(&input[(frames - 1) * channels..frames * channels]);
+            self.position -= frames as f64;
+        }
+        self.filtered = filtered;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise_gate_fades_out_quiet_buffers_and_back_in() {
+        let config = NoiseGateConfig {
+            threshold: 0.1,
+            attack: Duration::from_millis(1),
+            release: Duration::from_millis(2),
+        };
+        // 1000Hz mono: attack takes 1 frame, release 2 frames
+        let mut gate = NoiseGate::new(&config, 1000, 1);
+
+        let mut quiet = vec![0.05; 4];
+        gate.process(&mut quiet);
+        assert_eq!(quiet, vec![0.025, 0.0, 0.0, 0.0]);
+        assert_eq!(gate.gain(), 0.0);
+
+        let mut loud = vec![0.5; 2];
+        gate.process(&mut loud);
+        assert_eq!(loud, vec![0.5, 0.5]);
+        assert_eq!(gate.gain(), 1.0);
+    }
+
+    #[test]
+    fn agc_converges_on_target_level_within_max_gain() {
+        let config = AgcConfig {
+            target_level: 0.2,
+            max_gain: 4.0,
+            noise_floor: 0.001,
+            attack: Duration::from_millis(10),
+            release: Duration::from_millis(50),
+        };
+        let mut agc = Agc::new(&config, 1000, 1);
+
+        // A steady 0.1 signal needs a gain of 2
+        for _ in 0..50 {
+            agc.process(&mut [0.1; 10]);
+        }
+        assert!((agc.gain() - 2.0).abs() < 0.01);
+
+        // Too quiet to reach the target: capped at max_gain
+        for _ in 0..50 {
+            agc.process(&mut [0.01; 10]);
+        }
+        assert!((agc.gain() - 4.0).abs() < 0.01);
+
+        // Silence below the noise floor holds the gain
+        let mut silence = [0.0; 10];
+        agc.process(&mut silence);
+        assert!((agc.gain() - 4.0).abs() < 0.01);
+        assert_eq!(silence, [0.0; 10]);
+    }
+
+    fn sine(frequency: f32, sample_rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (2.0 * std::f32::consts::PI * frequency * i as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    // Steady-state peak, skipping the filter's settling time
+    fn peak(samples: &[f32]) -> f32 {
+        samples[samples.len() / 2..]
+            .iter()
+            .fold(0.0f32, |max, &x| max.max(x.abs()))
+    }
+
+    #[test]
+    fn flat_equalizer_passes_audio_through() {
+        let mut eq = Equalizer::new(&EqConfig::default(), 48_000, 2);
+        let original = sine(440.0, 48_000, 512);
+        let mut buffer = original.clone();
+        eq.process(&mut buffer);
+        assert_eq!(buffer, original);
+    }
+
+    #[test]
+    fn peaking_band_boosts_its_frequency_only() {
+        let config = EqConfig {
+            bands: vec![EqBand {
+                kind: BandKind::Peaking,
+                frequency: 1000.0,
+                q: 1.0,
+                gain_db: 0.0,
+            }],
+        };
+        let mut eq = Equalizer::new(&config, 48_000, 1);
+        eq.set_band_gain(0, 6.0).unwrap();
+        assert!(eq.set_band_gain(1, 6.0).is_err());
+
+        let mut centre = sine(1000.0, 48_000, 9600);
+        eq.process(&mut centre);
+        assert!((peak(&centre) - 1.995).abs() < 0.02);
+
+        let mut eq = Equalizer::new(&config, 48_000, 1);
+        eq.set_band_gain(0, 6.0).unwrap();
+        let mut far = sine(15_000.0, 48_000, 9600);
+        eq.process(&mut far);
+        assert!((peak(&far) - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn shelves_stay_stable_after_sample_rate_changes() {
+        let mut config = EqConfig::default();
+        for band in &mut config.bands {
+            band.gain_db = 12.0;
+        }
+        let mut eq = Equalizer::new(&config, 48_000, 2);
+        // 8kHz high shelf sits close to Nyquist at 16kHz
+        eq.set_sample_rate(16_000);
+
+        let mut buffer = sine(50.0, 16_000, 32_000);
+        eq.process(&mut buffer);
+        assert!(buffer.iter().all(|x| x.is_finite()));
+        assert!(peak(&buffer) < 10.0);
+    }
+
+    #[test]
+    fn resampler_keeps_tones_continuous_across_buffers() {
+        let input = sine(1000.0, 16_000, 1600);
+        let mut whole = Resampler::new(16_000, 48_000, 1);
+        let expected = whole.process(&input);
+        // The output past the last input frame waits for the next buffer
+        assert_eq!(expected.len(), 4798);
+
+        // Odd buffer sizes carry the fractional position between calls
+        let mut streaming = Resampler::new(16_000, 48_000, 1);
+        let output: Vec<f32> = input
+            .chunks(37)
+            .flat_map(|chunk| streaming.process(chunk))
+            .collect();
+        assert_eq!(output.len(), expected.len());
+        assert!(output
+            .iter()
+            .zip(&expected)
+            .all(|(a, b)| (a - b).abs() < 1e-4));
+        assert!((peak(&output) - 1.0).abs() < 0.01);
+
+        // A reused output buffer gives the same audio without reallocating
+        let mut reusing = Resampler::new(16_000, 48_000, 1);
+        let mut buffer = Vec::with_capacity(4800);
+        let address = buffer.as_ptr();
+        let mut output = Vec::new();
+        for chunk in input.chunks(400) {
+            reusing.process_into(chunk, &mut buffer);
+            output.extend_from_slice(&buffer);
+        }
+        assert_eq!(output.len(), expected.len());
+        assert!(output
+            .iter()
+            .zip(&expected)
+            .all(|(a, b)| (a - b).abs() < 1e-4));
+        assert_eq!(buffer.as_ptr(), address);
+
+        let mut same = Resampler::new(48_000, 48_000, 2);
+        assert!(same.is_passthrough());
+        assert_eq!(same.process(&[0.5, -0.5]), vec![0.5, -0.5]);
+    }
+
+    #[test]
+    fn downsampling_filters_treble_above_the_new_nyquist() {
+        let mut voice = Resampler::new(48_000, 16_000, 1);
+        let output = voice.process(&sine(1000.0, 48_000, 9600));
+        assert_eq!(output.len(), 3200);
+        assert!((peak(&output) - 1.0).abs() < 0.05);
+
+        // 12kHz would alias to 4kHz at 16kHz without the low-pass
+        let mut voice = Resampler::new(48_000, 16_000, 1);
+        let output = voice.process(&sine(12_000.0, 48_000, 9600));
+        assert!(peak(&output) < 0.2);
+    }
+
+    #[test]
+    fn emphasis_round_trip_is_flat() {
+        let mut pre = PreEmphasis::new(DEFAULT_EMPHASIS, 2);
+        let mut de = DeEmphasis::new(DEFAULT_EMPHASIS, 2);
+        for frequency in [100.0, 1000.0, 8000.0] {
+            let mono = sine(frequency, 48_000, 4800);
+            let original: Vec<f32> = mono.iter().flat_map(|&x| [x, -0.5 * x]).collect();
+            let mut buffer = original.clone();
+            // Odd-sized chunks check the filters keep their state across buffers
+            for chunk in buffer.chunks_mut(126) {
+                pre.process(chunk);
+                de.process(chunk);
+            }
+            assert!(buffer
+                .iter()
+                .zip(&original)
+                .all(|(a, b)| (a - b).abs() < 1e-4));
+        }
+
+        // Pre-emphasis alone tilts the spectrum towards the highs
+        let mut pre = PreEmphasis::new(DEFAULT_EMPHASIS, 1);
+        let mut low = sine(100.0, 48_000, 4800);
+        pre.process(&mut low);
+        let mut pre = PreEmphasis::new(DEFAULT_EMPHASIS, 1);
+        let mut high = sine(16_000.0, 48_000, 4800);
+        pre.process(&mut high);
+        assert!(peak(&low) < 0.1);
+        assert!(peak(&high) > 10.0 * peak(&low));
+
+        // Full-scale treble stays within range for the i16 and FLAC codecs
+        let mut pre = PreEmphasis::new(DEFAULT_EMPHASIS, 1);
+        let mut nyquist: Vec<f32> = (0..480).map(|i| [1.0, -1.0][i % 2]).collect();
+        pre.process(&mut nyquist);
+        assert!(peak(&nyquist) <= 1.0);
+
+        assert_eq!(emphasis_from_f32(0.95), DEFAULT_EMPHASIS);
+        assert!(check_emphasis(DEFAULT_EMPHASIS).is_ok());
+        assert!(check_emphasis(emphasis_from_f32(1.0)).is_err());
+        assert!(check_emphasis(emphasis_from_f32(0.0)).is_err());
+        assert!(check_emphasis(emphasis_from_f32(-0.5)).is_err());
+    }
+}
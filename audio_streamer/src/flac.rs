@@ -0,0 +1,128 @@
+//! Lossless FLAC payloads, one FLAC frame per network packet.
+//!
+//! Samples are quantized to 16 bits, so the stream is lossless relative to CD
+//! quality rather than to the captured f32 samples. Each packet carries a bare
+//! frame without the `fLaC` stream header; frame headers are self-describing
+//! (channel count, bit depth and block size), so packets decode independently
+//! and a lost packet never affects its neighbours.
+
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, StreamInfo};
+use flacenc::error::{Verified, Verify};
+use flacenc::source::{Fill, FrameBuf};
+use std::io::Cursor;
+
+use crate::{AudioStreamerError, Result};
+
+const BITS_PER_SAMPLE: usize = 16;
+const SCALE: f32 = 32768.0;
+
+/// Smallest block FLAC can encode. Shorter buffers must be sent as PCM.
+pub const MIN_FRAMES: usize = flacenc::constant::MIN_BLOCK_SIZE;
+
+fn encoding_error(e: impl std::fmt::Display) -> AudioStreamerError {
+    AudioStreamerError::EncodingError(format!("FLAC: {}", e))
+}
+
+pub struct FlacEncoder {
+    config: Verified<flacenc::config::Encoder>,
+    stream_info: StreamInfo,
+    channels: usize,
+    frame_number: usize,
+}
+
+impl FlacEncoder {
+    pub fn new(channels: u16, sample_rate: u32) -> Result<Self> {
+        let config = flacenc::config::Encoder::default()
+            .into_verified()
+            .map_err(|(_, e)| encoding_error(e))?;
+        let stream_info = StreamInfo::new(sample_rate as usize, channels as usize, BITS_PER_SAMPLE)
+            .map_err(encoding_error)?;
+        Ok(Self {
+            config,
+            stream_info,
+            channels: channels as usize,
+            frame_number: 0,
+        })
+    }
+
+    /// Whether `samples` holds enough frames for a FLAC block.
+    pub fn can_encode(&self, samples: &[f32]) -> bool {
+        samples.len() / self.channels >= MIN_FRAMES
+    }
+
+    /// Encodes one buffer of interleaved samples as a single FLAC frame.
+    /// Fails for buffers shorter than `MIN_FRAMES` frames.
+    pub fn encode(&mut self, samples: &[f32]) -> Result<Vec<u8>> {
+        let frames = samples.len() / self.channels;
+        let quantized: Vec<i32> = samples[..frames * self.channels]
+            .iter()
+            .map(|&sample| (sample * SCALE).round().clamp(-SCALE, SCALE - 1.0) as i32)
+            .collect();
+
+        let mut frame_buf = FrameBuf::with_size(self.channels, frames).map_err(encoding_error)?;
+        frame_buf
+            .fill_interleaved(&quantized)
+            .map_err(encoding_error)?;
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &frame_buf,
+            self.frame_number,
+            &self.stream_info,
+        )
+        .map_err(|e| encoding_error(format!("{:?}", e)))?;
+        // Frame numbers are 31 bits in the frame header
+        self.frame_number = (self.frame_number + 1) % (1 << 31);
+
+        let mut sink = ByteSink::new();
+        frame.write(&mut sink).map_err(encoding_error)?;
+        Ok(sink.as_slice().to_vec())
+    }
+}
+
+/// Decodes a single FLAC frame back to interleaved f32 samples.
+pub fn decode(payload: &[u8]) -> Result<Vec<f32>> {
+    let mut reader = claxon::frame::FrameReader::new(Cursor::new(payload));
+    let block = reader
+        .read_next_or_eof(Vec::new())
+        .map_err(encoding_error)?
+        .ok_or_else(|| encoding_error("empty payload"))?;
+
+    let mut samples = Vec::with_capacity(block.len() as usize);
+    for frame in 0..block.duration() {
+        for channel in 0..block.channels() {
+            samples.push(block.sample(channel, frame) as f32 / SCALE);
+        }
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::SineSource;
+
+    #[test]
+    fn frames_round_trip_losslessly_at_16_bits() {
+        let mut source = SineSource::new(440.0, 0.5, 48000, 2).with_buffer_size(360);
+        let mut encoder = FlacEncoder::new(2, 48000).unwrap();
+
+        for _ in 0..3 {
+            let samples = source.next_buffer();
+            let payload = encoder.encode(&samples).unwrap();
+            assert!(payload.len() < samples.len() * 4);
+
+            let decoded = decode(&payload).unwrap();
+            assert_eq!(decoded.len(), samples.len());
+            for (a, b) in decoded.iter().zip(&samples) {
+                assert!((a - b).abs() <= 0.5 / SCALE);
+            }
+        }
+    }
+
+    #[test]
+    fn short_buffers_are_rejected() {
+        let mut encoder = FlacEncoder::new(2, 48000).unwrap();
+        assert!(encoder.encode(&[0.0; 2 * (MIN_FRAMES - 1)]).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+//! Best-effort device hotplug notifications.
+//!
+//! cpal has no change notifications, so the watcher polls the input and output
+//! device lists on a background thread and reports differences by name. This
+//! works on every host cpal supports, at the cost of up to one poll interval of
+//! delay. Devices that share a name are indistinguishable to it.
+
+use cpal::traits::{DeviceTrait, HostTrait};
+use std::collections::BTreeSet;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceDirection {
+    Input,
+    Output,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HotplugEvent {
+    Added {
+        name: String,
+        direction: DeviceDirection,
+    },
+    Removed {
+        name: String,
+        direction: DeviceDirection,
+    },
+}
+
+fn device_names(host: &cpal::Host, direction: DeviceDirection) -> BTreeSet<String> {
+    let devices = match direction {
+        DeviceDirection::Input => host.input_devices().map(|d| d.collect::<Vec<_>>()),
+        DeviceDirection::Output => host.output_devices().map(|d| d.collect::<Vec<_>>()),
+    };
+    match devices {
+        Ok(devices) => devices.iter().filter_map(|d| d.name().ok()).collect(),
+        Err(e) => {
+            log::warn!("Failed to list {:?} devices: {}", direction, e);
+            BTreeSet::new()
+        }
+    }
+}
+
+fn diff(
+    previous: &BTreeSet<String>,
+    current: &BTreeSet<String>,
+    direction: DeviceDirection,
+) -> Vec<HotplugEvent> {
+    let removed = previous
+        .difference(current)
+        .map(|name| HotplugEvent::Removed {
+            name: name.clone(),
+            direction,
+        });
+    let added = current
+        .difference(previous)
+        .map(|name| HotplugEvent::Added {
+            name: name.clone(),
+            direction,
+        });
+    removed.chain(added).collect()
+}
+
+/// Starts polling the default host's devices every `interval` and returns a
+/// channel of changes. Refresh `list_input_devices` when an event arrives.
+/// The watcher stops once the receiver is dropped.
+pub fn watch_devices(interval: Duration) -> mpsc::Receiver<HotplugEvent> {
+    let (tx, rx) = mpsc::channel(32);
+    std::thread::spawn(move || {
+        let host = cpal::default_host();
+        let directions = [DeviceDirection::Input, DeviceDirection::Output];
+        let mut known = directions.map(|direction| device_names(&host, direction));
+
+        while !tx.is_closed() {
+            std::thread::sleep(interval);
+            for (direction, previous) in directions.iter().zip(known.iter_mut()) {
+                let current = device_names(&host, *direction);
+                for event in diff(previous, &current, *direction) {
+                    log::info!("Device change: {:?}", event);
+                    if tx.blocking_send(event).is_err() {
+                        return;
+                    }
+                }
+                *previous = current;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_reports_removed_and_added_devices() {
+        let previous: BTreeSet<String> = ["Built-in Mic", "USB Interface"].map(String::from).into();
+        let current: BTreeSet<String> = ["Built-in Mic", "Headset"].map(String::from).into();
+
+        assert_eq!(
+            diff(&previous, &current, DeviceDirection::Input),
+            vec![
+                HotplugEvent::Removed {
+                    name: "USB Interface".into(),
+                    direction: DeviceDirection::Input,
+                },
+                HotplugEvent::Added {
+                    name: "Headset".into(),
+                    direction: DeviceDirection::Input,
+                },
+            ]
+        );
+    }
+}
@@ -1,6 +1,22 @@
 pub mod capture;
+pub mod dsp;
+#[cfg(feature = "flac")]
+pub mod flac;
+pub mod hotplug;
+pub mod metrics;
 pub mod network;
 pub mod player;
+pub mod protocol;
+pub mod replay;
+#[cfg(feature = "rodio")]
+pub mod rodio;
+pub mod runtime;
+pub mod sample;
+pub mod sink;
+pub mod source;
+pub mod wav;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 use cpal::StreamError;
 use thiserror::Error;
@@ -11,7 +27,7 @@ pub enum AudioStreamerError {
     DeviceError(String),
 
     #[error("Network error: {0}")]
-    NetworkError(String),
+    NetworkError(#[from] NetworkError),
 
     #[error("Encoding error: {0}")]
     EncodingError(String),
@@ -35,6 +51,46 @@ pub enum AudioStreamerError {
     AddressError(#[from] std::net::AddrParseError),
 }
 
+/// Network failures callers may want to handle individually.
+#[derive(Error, Debug)]
+pub enum NetworkError {
+    #[error("Server discovery timed out")]
+    DiscoveryTimeout,
+
+    #[error("No server found")]
+    ServerNotFound,
+
+    #[error("No ping replies from {0}")]
+    NoPingReplies(std::net::IpAddr),
+
+    #[error("No throughput bench replies from {0}")]
+    NoBenchReplies(std::net::IpAddr),
+
+    #[error("Server {0} rejected this listener")]
+    Rejected(std::net::SocketAddr),
+
+    #[error("Server {addr} is incompatible: {reason}")]
+    Incompatible {
+        addr: std::net::SocketAddr,
+        reason: String,
+    },
+
+    #[error("Failed to send to {addr}: {source}")]
+    SendFailed {
+        addr: std::net::SocketAddr,
+        source: std::io::Error,
+    },
+
+    #[error("Port {port} is already in use ({addr}); stop its other user or pick another")]
+    AddressInUse { addr: String, port: u16 },
+
+    #[error("Failed to bind {addr}: {source}")]
+    BindFailed {
+        addr: String,
+        source: std::io::Error,
+    },
+}
+
 pub type Result<T> = std::result::Result<T, AudioStreamerError>;
 
 // Convert CPAL errors to our error type
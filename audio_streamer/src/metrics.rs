@@ -0,0 +1,371 @@
+use serde::ser::{SerializeSeq, SerializeStruct};
+use serde::{Serialize, Serializer};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{AudioStreamerError, Result};
+
+/// Fixed-bucket histogram of durations. Each bucket counts values up to and
+/// including its upper bound; a final overflow bucket catches everything larger.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Histogram {
+    bounds: Vec<Duration>,
+    counts: Vec<u64>,
+}
+
+impl Histogram {
+    /// `bounds` are the bucket upper bounds and are sorted on construction.
+    pub fn new(mut bounds: Vec<Duration>) -> Self {
+        bounds.sort();
+        let counts = vec![0; bounds.len() + 1];
+        Self { bounds, counts }
+    }
+
+    pub fn record(&mut self, value: Duration) {
+        let index = self.bounds.partition_point(|&bound| bound < value);
+        self.counts[index] += 1;
+    }
+
+    /// Yields `(upper_bound, count)` pairs, with `None` for the overflow bucket.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.bounds
+            .iter()
+            .map(|&bound| Some(bound))
+            .chain(std::iter::once(None))
+            .zip(self.counts.iter().copied())
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+// Exported as a list of `{"le_ms": bound, "count": n}` buckets, with a null
+// bound for the overflow bucket
+impl Serialize for Histogram {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        struct Bucket(Option<Duration>, u64);
+
+        impl Serialize for Bucket {
+            fn serialize<S: Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                let mut bucket = serializer.serialize_struct("Bucket", 2)?;
+                bucket.serialize_field("le_ms", &self.0.map(|d| d.as_secs_f64() * 1000.0))?;
+                bucket.serialize_field("count", &self.1)?;
+                bucket.end()
+            }
+        }
+
+        let mut seq = serializer.serialize_seq(Some(self.counts.len()))?;
+        for (bound, count) in self.buckets() {
+            seq.serialize_element(&Bucket(bound, count))?;
+        }
+        seq.end()
+    }
+}
+
+/// Fill level of an audio buffer: how many received buffers (packets) it
+/// holds and how long they take to play.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct BufferLevel {
+    pub packets: usize,
+    #[serde(rename = "duration_ms", serialize_with = "millis")]
+    pub duration: Duration,
+}
+
+impl BufferLevel {
+    /// Level of `packets` buffers holding `samples` interleaved samples in total.
+    pub fn new(packets: usize, samples: usize, sample_rate: u32, channels: u16) -> Self {
+        let samples_per_second = sample_rate as f64 * channels.max(1) as f64;
+        Self {
+            packets,
+            duration: Duration::from_secs_f64(samples as f64 / samples_per_second),
+        }
+    }
+}
+
+/// Live fill level of a buffer, updated by whoever fills or drains it and
+/// readable from any thread. Clones share the same level.
+#[derive(Clone, Debug, Default)]
+pub struct BufferGauge(Arc<Mutex<BufferLevel>>);
+
+impl BufferGauge {
+    pub fn level(&self) -> BufferLevel {
+        *self.0.lock().unwrap()
+    }
+
+    pub(crate) fn set(&self, level: BufferLevel) {
+        *self.0.lock().unwrap() = level;
+    }
+}
+
+fn millis<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+// Timestamps are exported as fractional seconds since the Unix epoch
+fn unix_seconds<S: Serializer>(
+    time: &SystemTime,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    serializer.serialize_f64(seconds)
+}
+
+fn optional_unix_seconds<S: Serializer>(
+    time: &Option<SystemTime>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => unix_seconds(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Writes `metrics` to `path` as pretty-printed JSON, replacing the file.
+pub fn write_json(path: impl AsRef<Path>, metrics: &impl Serialize) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, metrics)
+        .map_err(|e| AudioStreamerError::EncodingError(format!("Metrics JSON: {}", e)))?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Default inter-arrival buckets, fine-grained around typical 5-10ms packet spacing.
+pub fn default_jitter_buckets() -> Vec<Duration> {
+    [1, 2, 5, 10, 20, 50, 100]
+        .into_iter()
+        .map(Duration::from_millis)
+        .collect()
+}
+
+/// Snapshot of what the receiver has seen so far.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReceiverMetrics {
+    #[serde(serialize_with = "unix_seconds")]
+    pub started: SystemTime,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Packets that arrived but could not be decoded
+    pub decode_errors: u64,
+    /// Packets dropped as copies of ones already received
+    pub duplicates: u64,
+    /// Buffers discarded because the consumer fell behind
+    pub dropped_buffers: u64,
+    /// Datagrams missing from `AudioReceiver::raw_packets` because its
+    /// channel was full
+    pub raw_packets_dropped: u64,
+    /// Time between consecutive packet arrivals
+    pub inter_arrival: Histogram,
+    /// Sender timestamp to arrival. Only meaningful when both clocks are
+    /// synchronized; packets that appear to arrive before they were sent
+    /// count as zero.
+    pub latency: Histogram,
+    /// Decoded audio waiting in the output channel for the consumer, as of
+    /// the latest packet. The duration assumes queued buffers are the size of
+    /// the latest one.
+    pub queued: BufferLevel,
+    /// How long the latest audio packet plays for
+    #[serde(rename = "packet_duration_ms", serialize_with = "millis")]
+    pub packet_duration: Duration,
+    /// Audio queued in the player, when tracked with
+    /// `AudioReceiver::track_playback`
+    pub playback_buffered: Option<BufferLevel>,
+}
+
+impl ReceiverMetrics {
+    pub fn new(jitter_buckets: Vec<Duration>) -> Self {
+        Self {
+            started: SystemTime::now(),
+            packets_received: 0,
+            bytes_received: 0,
+            decode_errors: 0,
+            duplicates: 0,
+            dropped_buffers: 0,
+            raw_packets_dropped: 0,
+            inter_arrival: Histogram::new(jitter_buckets.clone()),
+            latency: Histogram::new(jitter_buckets),
+            queued: BufferLevel::default(),
+            packet_duration: Duration::ZERO,
+            playback_buffered: None,
+        }
+    }
+}
+
+/// When a listener started and stopped receiving audio, and how sending to
+/// it went.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ClientSession {
+    pub addr: SocketAddr,
+    #[serde(serialize_with = "unix_seconds")]
+    pub joined: SystemTime,
+    /// `None` while the listener is still receiving
+    #[serde(serialize_with = "optional_unix_seconds")]
+    pub left: Option<SystemTime>,
+    pub packets_sent: u64,
+    /// Sends the OS refused, e.g. with the listener's network unreachable.
+    /// A listener whose every send fails while others' succeed is the one
+    /// with the problem.
+    pub send_errors: u64,
+}
+
+/// Snapshot of what the sender has sent so far.
+#[derive(Clone, Debug, Serialize)]
+pub struct SenderMetrics {
+    #[serde(serialize_with = "unix_seconds")]
+    pub started: SystemTime,
+    /// Datagrams sent, counting one per unicast listener and one per send
+    /// to the multicast group
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub send_errors: u64,
+    /// Every listener that joined, in order
+    pub clients: Vec<ClientSession>,
+}
+
+impl Default for SenderMetrics {
+    fn default() -> Self {
+        Self {
+            started: SystemTime::now(),
+            packets_sent: 0,
+            bytes_sent: 0,
+            send_errors: 0,
+            clients: Vec::new(),
+        }
+    }
+}
+
+impl SenderMetrics {
+    pub(crate) fn client_joined(&mut self, addr: SocketAddr) {
+        self.clients.push(ClientSession {
+            addr,
+            joined: SystemTime::now(),
+            left: None,
+            packets_sent: 0,
+            send_errors: 0,
+        });
+    }
+
+    pub(crate) fn client_left(&mut self, addr: SocketAddr) {
+        let now = SystemTime::now();
+        for session in &mut self.clients {
+            if session.addr == addr && session.left.is_none() {
+                session.left = Some(now);
+                log::debug!(
+                    "Sent {} packets to {} with {} failures",
+                    session.packets_sent,
+                    addr,
+                    session.send_errors
+                );
+            }
+        }
+    }
+
+    /// Counts a send to `addr`, in the totals and its current session:
+    /// `sent` bytes, or `None` when the send failed.
+    pub(crate) fn record_send(&mut self, addr: SocketAddr, sent: Option<usize>) {
+        let session = self
+            .clients
+            .iter_mut()
+            .rev()
+            .find(|session| session.addr == addr && session.left.is_none());
+        match sent {
+            Some(len) => {
+                self.packets_sent += 1;
+                self.bytes_sent += len as u64;
+                if let Some(session) = session {
+                    session.packets_sent += 1;
+                }
+            }
+            None => {
+                self.send_errors += 1;
+                if let Some(session) = session {
+                    session.send_errors += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_by_upper_bound() {
+        let mut histogram =
+            Histogram::new(vec![Duration::from_millis(10), Duration::from_millis(5)]);
+        histogram.record(Duration::from_millis(5));
+        histogram.record(Duration::from_millis(7));
+        histogram.record(Duration::from_millis(50));
+        histogram.record(Duration::from_millis(60));
+
+        let buckets: Vec<_> = histogram.buckets().collect();
+        assert_eq!(
+            buckets,
+            vec![
+                (Some(Duration::from_millis(5)), 1),
+                (Some(Duration::from_millis(10)), 1),
+                (None, 2),
+            ]
+        );
+        assert_eq!(histogram.total(), 4);
+    }
+
+    #[test]
+    fn metrics_export_as_json() {
+        let mut metrics = SenderMetrics {
+            packets_sent: 3,
+            ..SenderMetrics::default()
+        };
+        let client = "127.0.0.1:50001".parse().unwrap();
+        metrics.client_joined(client);
+        metrics.record_send(client, Some(100));
+        metrics.record_send(client, None);
+        metrics.client_left(client);
+        // Sends after leaving only count in the totals
+        metrics.record_send(client, Some(100));
+        assert_eq!((metrics.packets_sent, metrics.send_errors), (5, 1));
+
+        let path = std::env::temp_dir().join(format!("sender-metrics-{}.json", std::process::id()));
+        write_json(&path, &metrics).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(json["packets_sent"], 5);
+        assert_eq!(json["clients"][0]["addr"], "127.0.0.1:50001");
+        assert_eq!(json["clients"][0]["packets_sent"], 1);
+        assert_eq!(json["clients"][0]["send_errors"], 1);
+        assert!(json["clients"][0]["left"].as_f64().unwrap() > 0.0);
+
+        let mut receiver = ReceiverMetrics::new(vec![Duration::from_millis(5)]);
+        receiver.latency.record(Duration::from_millis(2));
+        let json = serde_json::to_value(&receiver).unwrap();
+        assert_eq!(
+            json["latency"],
+            serde_json::json!([{"le_ms": 5.0, "count": 1}, {"le_ms": null, "count": 0}])
+        );
+
+        receiver.queued = BufferLevel::new(2, 960, 48_000, 2);
+        let json = serde_json::to_value(&receiver).unwrap();
+        assert_eq!(
+            json["queued"],
+            serde_json::json!({"packets": 2, "duration_ms": 10.0})
+        );
+        assert_eq!(json["playback_buffered"], serde_json::Value::Null);
+    }
+}
@@ -1,285 +1,5171 @@
-use std::collections::HashSet;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use crate::capture::PreRoll;
+use crate::dsp::{check_emphasis, emphasis_from_f32, DeEmphasis, PreEmphasis};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tokio::sync::{mpsc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::Interest;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch, Mutex, Notify};
 use tokio::time::{self, Duration};
 
-use crate::Result;
+use crate::metrics::{
+    default_jitter_buckets, write_json, BufferGauge, BufferLevel, ReceiverMetrics, SenderMetrics,
+};
+use crate::protocol::{
+    decode_packet, encode_packet, Capabilities, Codec, PacketEncoder, PacketHeader, StreamFormat,
+    Transport, HEADER_SIZE,
+};
+use crate::runtime::{run_on, AudioRuntime};
+use crate::sink::{spawn_sink, AudioSink};
+use crate::source::{AudioSource, Rebuffered};
+use crate::{AudioStreamerError, NetworkError, Result};
 
 const MAX_DATAGRAM_SIZE: usize = 1472; // Standard MTU minus IP and UDP headers
-const AUDIO_HEADER_SIZE: usize = 8; // 4 bytes for sequence number, 4 bytes for timestamp
+
+// Smallest datagram every IPv4 host must accept, and a 9000-byte jumbo frame
+// minus headers; bounds for `NetworkConfig::max_datagram_size`
+const MIN_DATAGRAM_SIZE: usize = 548;
+const MAX_JUMBO_DATAGRAM_SIZE: usize = 8972;
+
 const DISCOVERY_PORT: u16 = 50000;
 const DEFAULT_STREAM_PORT: u16 = 50001;
 const DISCOVERY_INTERVAL: Duration = Duration::from_secs(1);
 const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+// Keepalives while paused, well inside a listener's stall timeout
+const PAUSE_KEEPALIVE_INTERVAL: Duration = Duration::from_millis(250);
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+const PING_INTERVAL: Duration = Duration::from_millis(200);
+// Time for in-flight bench probes to land before asking for the count
+const BENCH_DRAIN: Duration = Duration::from_millis(200);
+const BENCH_RESULT_ATTEMPTS: u32 = 3;
+// Clients tracked by the server's bench counters before they're reset
+const MAX_BENCH_CLIENTS: usize = 64;
 
 pub struct AudioSender {
     socket: Arc<UdpSocket>,
     discovery_socket: Arc<UdpSocket>,
     clients: Arc<Mutex<HashSet<SocketAddr>>>,
+    // Clients that joined the multicast group, which need no copy of their own
+    multicast_members: Arc<Mutex<HashSet<SocketAddr>>>,
+    // Discovery sockets of listeners that found us, for control messages
+    listeners: Arc<Mutex<HashSet<SocketAddr>>>,
+    metrics: Arc<std::sync::Mutex<SenderMetrics>>,
+    // Current format, starting from the config; `start_sending` picks up
+    // changes when `format_changed` is set
+    format: Arc<std::sync::Mutex<StreamFormat>>,
+    format_changed: AtomicBool,
+    muted: AtomicBool,
+    paused: AtomicBool,
+    resumed: Notify,
+    // Sequence number of the next packet
+    sequence: AtomicU32,
     stream_port: u16,
+    // IPv4 listeners are sent to through their IPv4-mapped address
+    dual_stack: bool,
+    // Where announcements and the shutdown notice are broadcast
+    broadcast_addrs: Vec<Ipv4Addr>,
+    config: SenderConfig,
 }
 
-pub struct AudioReceiver {
-    socket: Arc<UdpSocket>,
-    discovery_socket: Arc<UdpSocket>,
-    server_addr: Arc<Mutex<Option<SocketAddr>>>,
+#[derive(Clone, Debug)]
+pub struct SenderConfig {
+    /// Address for the stream socket (default: "0.0.0.0:50001"). `[::]`
+    /// serves IPv4 and IPv6 listeners alike. Discovery is IPv4 only, so it
+    /// can't be combined with a specific IPv6 address.
+    pub bind_addr: Option<String>,
+    pub network: NetworkConfig,
+    /// Port to answer discovery requests on and broadcast announcements to
+    pub discovery_port: u16,
+    /// Refuse new listeners once this many are registered
+    pub max_clients: Option<usize>,
+    /// Encrypt the stream. Not supported yet: enabling it fails
+    /// `with_config` with a `ConfigError` rather than sending in the clear.
+    pub encryption: bool,
+    /// Most payload bits per second to send. Nothing throttles sending yet,
+    /// so `with_config` fails with a `ConfigError` unless the codec's
+    /// uncompressed payload rate at the configured format fits under it.
+    pub max_bitrate: Option<u32>,
+    /// Drop discovered listeners not heard from in this long. Listeners don't
+    /// send heartbeats yet, so setting it fails `with_config` with a
+    /// `ConfigError`; they are removed when they send `LEAVE` instead.
+    pub heartbeat_timeout: Option<Duration>,
+    pub silence_gate: Option<SilenceGateConfig>,
+    /// Channel count of the audio being sent, used to count frames for the
+    /// presentation clock
+    pub channels: u16,
+    /// Sample rate of the audio being sent, recorded in FLAC frame headers
+    pub sample_rate: u32,
+    /// Payload encoding. FLAC and zstd require the features of the same name.
+    pub codec: Codec,
+    /// Boost treble with a `dsp::PreEmphasis` of this fixed-point
+    /// coefficient before encoding, e.g. `dsp::DEFAULT_EMPHASIS`, so it
+    /// survives lossy links. Every packet header carries it, so static and
+    /// discovering listeners alike undo it.
+    pub pre_emphasis: Option<u16>,
+    /// Samples (across all channels) carried by each packet, regrouping the
+    /// source's buffers whatever their size. Smaller packets lower latency,
+    /// larger ones cut per-packet overhead. Rounded down to whole frames and
+    /// capped at what fits in one datagram. `None` sends each source buffer
+    /// as it arrives.
+    pub samples_per_packet: Option<usize>,
+    /// Hand each packet to the OS for every listener in one `sendmmsg` call
+    /// instead of one `send_to` per listener, cutting syscall overhead with
+    /// many listeners. Only Linux and Android batch; elsewhere packets are
+    /// sent one by one regardless. See `send_to_all`.
+    pub batch_sends: bool,
+    /// Listeners to send to from the start, for fixed installations
+    pub static_clients: Vec<SocketAddr>,
+    /// Offer listeners this multicast group, e.g. `239.255.77.1:50001`, and
+    /// send the stream to it once for all that join instead of a copy each,
+    /// so sender bandwidth no longer grows with the audience. Listeners
+    /// receive the group on their stream socket, so the port must be theirs.
+    /// Static clients and listeners that don't join still get their own copy.
+    /// The group is sent out of the interface its members are reached
+    /// through, or the one the stream socket is bound to. Takes an IPv4
+    /// stream socket, so it can't be combined with a `[::]` bind address.
+    pub multicast_group: Option<SocketAddrV4>,
+    /// IP TTL of the multicast stream. The default of 1 keeps it on the
+    /// local segment; raise it for routers that forward the group.
+    pub multicast_ttl: u32,
+    /// Port of a WebSocket endpoint serving the same stream, e.g. a
+    /// `websocket::WebSocketSender`, announced to listeners in discovery
+    pub websocket_port: Option<u16>,
+    /// Name shown to listeners scanning the network, e.g. "Living room"
+    pub name: Option<String>,
+    /// Answer discovery requests and announce the server. When disabled only
+    /// static and manually added clients receive audio. With static clients,
+    /// a discovery port taken by another process turns discovery off with a
+    /// warning instead of failing; see `AudioSender::discovery_enabled`.
+    pub discovery: bool,
+    /// IPv4 addresses of the interfaces to announce the server on, e.g. the
+    /// LAN but not a VPN. Each gets the announcement on its subnet's
+    /// broadcast address. Empty broadcasts to 255.255.255.255, leaving the
+    /// OS to pick the interface. Replies to discovery requests are unaffected.
+    pub announce_interfaces: Vec<Ipv4Addr>,
+    /// Decides whether a new listener may register through discovery.
+    /// Rejected listeners get a `REJECTED` reply. Static and manually added
+    /// clients are not filtered.
+    pub client_filter: Option<ClientFilter>,
+    /// Runtime to bind the sockets and run discovery on, e.g. an
+    /// `AudioRuntime`'s, instead of the one `with_config` is awaited on. Run
+    /// `start_sending` there too, with `AudioRuntime::run`.
+    pub runtime: Option<Handle>,
 }
 
-impl AudioSender {
-    pub async fn new(bind_addr: Option<&str>) -> Result<Self> {
-        let bind_addr = bind_addr
-            .map(|addr| addr.to_string())
-            .unwrap_or_else(|| format!("0.0.0.0:{}", DEFAULT_STREAM_PORT));
+/// Predicate over the discovery address of a listener asking to register,
+/// returning whether to accept it.
+#[derive(Clone)]
+pub struct ClientFilter(Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>);
 
-        // Create and configure UDP socket
-        let socket = UdpSocket::bind(&bind_addr).await?;
+impl ClientFilter {
+    pub fn new(filter: impl Fn(SocketAddr) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(filter))
+    }
 
-        #[cfg(target_os = "macos")]
-        {
-            use std::os::unix::io::AsRawFd;
-            let fd = socket.as_raw_fd();
-            unsafe {
-                let optval: libc::c_int = 1;
-                libc::setsockopt(
-                    fd,
-                    libc::SOL_SOCKET,
-                    libc::SO_TIMESTAMP,
-                    &optval as *const _ as *const libc::c_void,
-                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-                );
-            }
+    pub fn accepts(&self, addr: SocketAddr) -> bool {
+        (self.0)(addr)
+    }
+}
+
+impl std::fmt::Debug for ClientFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ClientFilter")
+    }
+}
+
+impl SenderConfig {
+    pub fn builder() -> SenderConfigBuilder {
+        SenderConfigBuilder::default()
+    }
+}
+
+impl Default for SenderConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: None,
+            network: NetworkConfig::default(),
+            discovery_port: DISCOVERY_PORT,
+            max_clients: None,
+            encryption: false,
+            max_bitrate: None,
+            heartbeat_timeout: None,
+            silence_gate: None,
+            channels: 2,
+            sample_rate: 48000,
+            codec: Codec::Pcm,
+            pre_emphasis: None,
+            samples_per_packet: None,
+            batch_sends: false,
+            static_clients: Vec::new(),
+            multicast_group: None,
+            multicast_ttl: 1,
+            websocket_port: None,
+            name: None,
+            discovery: true,
+            announce_interfaces: Vec::new(),
+            client_filter: None,
+            runtime: None,
         }
+    }
+}
 
-        let socket = Arc::new(socket);
-        let stream_port = socket.local_addr()?.port();
+#[derive(Clone, Debug, Default)]
+pub struct SenderConfigBuilder {
+    config: SenderConfig,
+}
 
-        // Set up discovery socket
-        let discovery_socket = UdpSocket::bind(format!("0.0.0.0:{}", DISCOVERY_PORT)).await?;
-        discovery_socket.set_broadcast(true)?;
-        let discovery_socket = Arc::new(discovery_socket);
+impl SenderConfigBuilder {
+    pub fn bind_addr(mut self, addr: impl Into<String>) -> Self {
+        self.config.bind_addr = Some(addr.into());
+        self
+    }
 
-        let clients = Arc::new(Mutex::new(HashSet::new()));
+    pub fn network(mut self, network: NetworkConfig) -> Self {
+        self.config.network = network;
+        self
+    }
 
-        let sender = Self {
-            socket,
-            discovery_socket,
-            clients,
-            stream_port,
-        };
+    pub fn discovery_port(mut self, port: u16) -> Self {
+        self.config.discovery_port = port;
+        self
+    }
 
-        sender.start_discovery_service().await?;
-        Ok(sender)
+    pub fn discovery_interval(mut self, interval: Duration) -> Self {
+        self.config.network.discovery_interval = interval;
+        self
     }
 
-    async fn start_discovery_service(&self) -> Result<()> {
-        let discovery_socket = self.discovery_socket.clone();
-        let clients = self.clients.clone();
-        let stream_port = self.stream_port;
+    pub fn discovery_ttl(mut self, ttl: u32) -> Self {
+        self.config.network.discovery_ttl = Some(ttl);
+        self
+    }
 
-        // Handle incoming discovery requests
-        let discovery_socket_clone = discovery_socket.clone();
-        tokio::spawn(async move {
-            let mut buf = [0u8; 64];
-            loop {
-                match discovery_socket_clone.recv_from(&mut buf).await {
-                    Ok((_, client_addr)) => {
-                        let response = format!("SERVER:{}", stream_port);
-                        if let Err(e) = discovery_socket_clone
-                            .send_to(response.as_bytes(), client_addr)
-                            .await
-                        {
-                            log::error!("Failed to send discovery response: {}", e);
-                            continue;
-                        }
-                        clients
-                            .lock()
-                            .await
-                            .insert(SocketAddr::new(client_addr.ip(), stream_port));
-                    }
-                    Err(e) => log::error!("Discovery receive error: {}", e),
-                }
-            }
-        });
+    pub fn fallback_to_any_port(mut self, fallback: bool) -> Self {
+        self.config.network.fallback_to_any_port = fallback;
+        self
+    }
 
-        // Broadcast server presence periodically
-        let broadcast_addr = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)),
-            DISCOVERY_PORT,
-        );
+    pub fn max_datagram_size(mut self, bytes: usize) -> Self {
+        self.config.network.max_datagram_size = bytes;
+        self
+    }
 
-        tokio::spawn(async move {
-            let mut interval = time::interval(DISCOVERY_INTERVAL);
-            loop {
-                interval.tick().await;
-                let announcement = format!("SERVER:{}", stream_port);
-                if let Err(e) = discovery_socket
-                    .send_to(announcement.as_bytes(), broadcast_addr)
-                    .await
-                {
-                    log::error!("Failed to broadcast server presence: {}", e);
-                }
-            }
-        });
+    pub fn max_clients(mut self, max: usize) -> Self {
+        self.config.max_clients = Some(max);
+        self
+    }
 
-        Ok(())
+    pub fn encryption(mut self, encryption: bool) -> Self {
+        self.config.encryption = encryption;
+        self
     }
 
-    pub async fn start_sending(&self, mut rx: mpsc::Receiver<Vec<f32>>) -> Result<()> {
-        log::info!("Starting audio sender on port {}", self.stream_port);
+    pub fn max_bitrate(mut self, bits_per_second: u32) -> Self {
+        self.config.max_bitrate = Some(bits_per_second);
+        self
+    }
 
-        while let Some(samples) = rx.recv().await {
-            let timestamp = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u32;
+    pub fn heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.config.heartbeat_timeout = Some(timeout);
+        self
+    }
+
+    pub fn silence_gate(mut self, gate: SilenceGateConfig) -> Self {
+        self.config.silence_gate = Some(gate);
+        self
+    }
 
-            // Convert samples to bytes efficiently
-            let mut packet = Vec::with_capacity(AUDIO_HEADER_SIZE + samples.len() * 4);
-            packet.extend_from_slice(&[0u8; 4]); // Unused sequence number
-            packet.extend_from_slice(&timestamp.to_le_bytes());
+    pub fn channels(mut self, channels: u16) -> Self {
+        self.config.channels = channels;
+        self
+    }
 
-            // Add samples directly to packet
-            for sample in samples {
-                packet.extend_from_slice(&sample.to_le_bytes());
-            }
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.config.sample_rate = sample_rate;
+        self
+    }
 
-            // Send to all clients
-            let clients = self.clients.lock().await.clone();
-            for client in clients {
-                if let Err(e) = self.socket.send_to(&packet, client).await {
-                    log::error!("Failed to send to client {}: {}", client, e);
-                }
-            }
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.config.codec = codec;
+        self
+    }
+
+    /// Pre-emphasis with a coefficient between 0 and 1, e.g. 0.95
+    pub fn pre_emphasis(mut self, coefficient: f32) -> Self {
+        self.config.pre_emphasis = Some(emphasis_from_f32(coefficient));
+        self
+    }
+
+    pub fn samples_per_packet(mut self, samples: usize) -> Self {
+        self.config.samples_per_packet = Some(samples);
+        self
+    }
+
+    pub fn batch_sends(mut self, enabled: bool) -> Self {
+        self.config.batch_sends = enabled;
+        self
+    }
+
+    pub fn static_clients(mut self, clients: Vec<SocketAddr>) -> Self {
+        self.config.static_clients = clients;
+        self
+    }
+
+    pub fn multicast_group(mut self, group: SocketAddrV4) -> Self {
+        self.config.multicast_group = Some(group);
+        self
+    }
+
+    pub fn multicast_ttl(mut self, ttl: u32) -> Self {
+        self.config.multicast_ttl = ttl;
+        self
+    }
+
+    pub fn websocket_port(mut self, port: u16) -> Self {
+        self.config.websocket_port = Some(port);
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.config.name = Some(name.into());
+        self
+    }
+
+    pub fn discovery(mut self, enabled: bool) -> Self {
+        self.config.discovery = enabled;
+        self
+    }
+
+    pub fn announce_interfaces(mut self, interfaces: Vec<Ipv4Addr>) -> Self {
+        self.config.announce_interfaces = interfaces;
+        self
+    }
+
+    pub fn client_filter(
+        mut self,
+        filter: impl Fn(SocketAddr) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.config.client_filter = Some(ClientFilter::new(filter));
+        self
+    }
+
+    pub fn runtime(mut self, runtime: &AudioRuntime) -> Self {
+        self.config.runtime = Some(runtime.handle().clone());
+        self
+    }
+
+    pub fn build(self) -> SenderConfig {
+        self.config
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SilenceGateConfig {
+    /// Buffers whose peak stays below this level count as silence
+    pub threshold: f32,
+    /// How long the signal must stay silent before sending stops
+    pub hold: Duration,
+    /// While gated, send an empty keepalive packet this often so listeners
+    /// don't treat the stream as stalled. `None` sends nothing while gated.
+    pub keepalive_interval: Option<Duration>,
+    /// How much of the audio held back while gated to send ahead of the
+    /// buffer that reopens the gate, so the start of a sound isn't clipped
+    pub pre_roll: Duration,
+}
+
+impl Default for SilenceGateConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0.001,
+            hold: Duration::from_millis(500),
+            keepalive_interval: Some(Duration::from_millis(100)),
+            pre_roll: Duration::ZERO,
         }
-        Ok(())
     }
 }
 
-impl AudioReceiver {
-    pub async fn new(bind_addr: Option<&str>) -> Result<Self> {
-        let bind_addr = bind_addr
-            .map(|addr| addr.to_string())
-            .unwrap_or_else(|| format!("0.0.0.0:{}", DEFAULT_STREAM_PORT));
+pub struct AudioReceiver {
+    socket: Arc<UdpSocket>,
+    discovery_socket: Arc<UdpSocket>,
+    server_addr: Arc<Mutex<Option<SocketAddr>>>,
+    // Snapshot of the current server for the receive loop to check every
+    // packet against without waiting on `server_addr`
+    stream_source: watch::Sender<Option<StreamSource>>,
+    server_format: std::sync::Mutex<Option<StreamFormat>>,
+    servers: std::sync::Mutex<ServerRegistry>,
+    state: watch::Sender<ConnectionState>,
+    metrics: Arc<std::sync::Mutex<ReceiverMetrics>>,
+    raw_packets: std::sync::Mutex<Option<mpsc::Sender<RawPacket>>>,
+    // Multicast group joined for the current server, the interface, and the
+    // server's control address
+    multicast_group: std::sync::Mutex<Option<(Ipv4Addr, Ipv4Addr, SocketAddr)>>,
+    // Servers whose group traffic never reached us, kept on unicast
+    unicast_only: std::sync::Mutex<HashSet<SocketAddr>>,
+    // Receive state for `recv_buffer`, kept between calls
+    pull: Mutex<Option<ReceiveState>>,
+    playback_gauge: std::sync::Mutex<Option<BufferGauge>>,
+    config: ReceiverConfig,
+}
 
-        // Create and configure UDP socket
-        let socket = UdpSocket::bind(&bind_addr).await?;
+/// A datagram exactly as it arrived, before any decoding.
+#[derive(Clone, Debug)]
+pub struct RawPacket {
+    /// Header and payload bytes
+    pub data: Vec<u8>,
+    pub source: SocketAddr,
+    pub arrival: SystemTime,
+}
 
-        #[cfg(target_os = "macos")]
-        {
-            use std::os::unix::io::AsRawFd;
-            let fd = socket.as_raw_fd();
-            unsafe {
-                let optval: libc::c_int = 1;
-                libc::setsockopt(
-                    fd,
-                    libc::SOL_SOCKET,
-                    libc::SO_TIMESTAMP,
-                    &optval as *const _ as *const libc::c_void,
-                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-                );
-            }
+#[derive(Clone, Debug)]
+pub struct ReceiverConfig {
+    /// Address for the stream socket (default: "0.0.0.0:50001"). `[::]`
+    /// receives from IPv4 and IPv6 senders alike. Discovery is IPv4 only, so
+    /// it can't be combined with a specific IPv6 address.
+    pub bind_addr: Option<String>,
+    pub network: NetworkConfig,
+    /// Port the sender answers discovery requests and pings on
+    pub discovery_port: u16,
+    /// Where discovery requests and scans are sent (default: the IPv4
+    /// broadcast address). A unicast address finds only the sender there,
+    /// e.g. `127.0.0.1` on a host or CI runner where broadcasts don't loop
+    /// back.
+    pub discovery_addr: Ipv4Addr,
+    /// How long `discover_server` waits for an answer
+    pub discovery_timeout: Duration,
+    /// Silence after which a receiving connection is reported as stalled
+    pub stall_timeout: Duration,
+    /// Upper bounds of the packet inter-arrival histogram buckets
+    pub jitter_buckets: Vec<Duration>,
+    /// Sample rate of the incoming stream, used to compute presentation times
+    /// when the server doesn't announce its format, e.g. a third-party or
+    /// older sender
+    pub sample_rate: u32,
+    /// Channel count of the incoming stream when the server doesn't announce
+    /// its format
+    pub channels: u16,
+    pub overflow_policy: OverflowPolicy,
+    /// Under `OverflowPolicy::DropOldest`, also drop the oldest held-back
+    /// audio once more than this much waits for the consumer, e.g. to keep
+    /// a latency target, rather than a full channel's worth. Estimated from
+    /// the latest packet's duration.
+    pub max_queued: Option<Duration>,
+    /// Retry policy for `reconnect`
+    pub reconnect: ReconnectConfig,
+    /// Local port for discovery, ping and LEAVE messages, which the server
+    /// answers to. `None` uses an ephemeral port; fix it so a firewall can
+    /// allow the replies.
+    pub control_port: Option<u16>,
+    /// Join the server's multicast group when it offers one, so it can send
+    /// the stream once for all its listeners. Takes a stream socket bound to
+    /// `0.0.0.0` on the group's port; otherwise the listener stays on unicast.
+    /// When the stream stalls after joining, e.g. on a network that doesn't
+    /// forward the group, the listener asks for its own copy again and stays
+    /// on unicast with that server.
+    pub multicast: bool,
+    /// Runtime to bind the sockets on, e.g. an `AudioRuntime`'s, instead of
+    /// the one `with_config` is awaited on. Run `start_receiving` there too,
+    /// with `AudioRuntime::run`.
+    pub runtime: Option<Handle>,
+}
+
+/// A received buffer with its presentation time on the sender's clock. See
+/// the `protocol` module docs for the sync contract.
+#[derive(Clone, Debug)]
+pub struct TimedBuffer {
+    pub samples: Vec<f32>,
+    pub presentation_time: SystemTime,
+}
+
+// Receive path state that persists from one datagram to the next
+struct ReceiveState {
+    buf: Vec<u8>,
+    // Where the current server streams from, and whether a packet from
+    // elsewhere has been reported since it last changed
+    stream_source: watch::Receiver<Option<StreamSource>>,
+    reported_stranger: bool,
+    last_arrival: Option<SystemTime>,
+    raw_packets: Option<mpsc::Sender<RawPacket>>,
+    // Undoes the server's announced pre-emphasis
+    de_emphasis: Option<DeEmphasis>,
+    sequences: SequenceWindow,
+}
+
+// Where the current server's audio is accepted from: its stream port on any
+// address it has answered discovery from, e.g. a multi-homed server that
+// answered from one interface and later from another
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct StreamSource {
+    port: u16,
+    addrs: Vec<IpAddr>,
+    name: Option<String>,
+}
+
+impl StreamSource {
+    fn new(server: &DiscoveredServer) -> Self {
+        Self {
+            port: server.addr.port(),
+            addrs: vec![server.addr.ip()],
+            name: server.name.clone(),
         }
+    }
 
-        let socket = Arc::new(socket);
+    fn accepts(&self, source: SocketAddr) -> bool {
+        source.port() == self.port && self.addrs.contains(&source.ip())
+    }
 
-        // Set up discovery socket
-        let discovery_socket = UdpSocket::bind("0.0.0.0:0").await?;
-        discovery_socket.set_broadcast(true)?;
-        let discovery_socket = Arc::new(discovery_socket);
+    // Goes by the stream port and name a server answers discovery with, so
+    // unnamed servers can't be told apart
+    fn is_same_server(&self, server: &DiscoveredServer) -> bool {
+        server.addr.port() == self.port && self.name.is_some() && server.name == self.name
+    }
 
-        Ok(Self {
-            socket,
-            discovery_socket,
-            server_addr: Arc::new(Mutex::new(None)),
-        })
+    // Returns whether the address is new
+    fn add(&mut self, addr: IpAddr) -> bool {
+        if self.addrs.contains(&addr) {
+            return false;
+        }
+        self.addrs.push(addr);
+        true
     }
+}
 
-    pub async fn start_receiving(&self, tx: mpsc::Sender<Vec<f32>>) -> Result<()> {
-        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
-        log::info!("Starting audio receiver on {:?}", self.socket.local_addr()?);
+// Packets a duplicate can trail the newest one by and still be recognized
+const SEQUENCE_WINDOW: u32 = 64;
 
-        loop {
-            let (len, _) = self.socket.recv_from(&mut buf).await?;
+// Sequence numbers recently received from the current sender, for dropping
+// packets the network delivered twice
+#[derive(Debug, Default)]
+struct SequenceWindow {
+    source: Option<SocketAddr>,
+    highest: u32,
+    // Bit n is set once `highest - n` has been received
+    seen: u64,
+}
 
-            if len < AUDIO_HEADER_SIZE {
-                continue;
-            }
+impl SequenceWindow {
+    fn is_duplicate(&mut self, source: SocketAddr, sequence: u32) -> bool {
+        // Senders that don't number their packets leave it at zero
+        if sequence == 0 {
+            return false;
+        }
+        if self.source != Some(source) {
+            self.restart(source, sequence);
+            return false;
+        }
+        let ahead = sequence.wrapping_sub(self.highest);
+        if ahead == 0 {
+            return true;
+        }
+        if (ahead as i32) > 0 {
+            self.seen = if ahead >= SEQUENCE_WINDOW {
+                1
+            } else {
+                self.seen << ahead | 1
+            };
+            self.highest = sequence;
+            return false;
+        }
+        let behind = self.highest.wrapping_sub(sequence);
+        if behind >= SEQUENCE_WINDOW {
+            // Too old to be a late copy, so the sender has started over
+            self.restart(source, sequence);
+            return false;
+        }
+        let bit = 1 << behind;
+        let duplicate = self.seen & bit != 0;
+        self.seen |= bit;
+        duplicate
+    }
+
+    fn restart(&mut self, source: SocketAddr, sequence: u32) {
+        self.source = Some(source);
+        self.highest = sequence;
+        self.seen = 1;
+    }
+}
+
+enum Received {
+    Audio(PacketHeader, Vec<f32>),
+    // Nothing arrived within the stall timeout
+    Stalled,
+}
+
+// Where `receive_loop` delivers decoded audio
+enum AudioOutput {
+    Samples(mpsc::Sender<Vec<f32>>),
+    Timed(mpsc::Sender<TimedBuffer>, u32),
+}
+
+impl AudioOutput {
+    fn capacity(&self) -> usize {
+        match self {
+            AudioOutput::Samples(tx) => tx.max_capacity(),
+            AudioOutput::Timed(tx, _) => tx.max_capacity(),
+        }
+    }
 
-            // Convert audio data to samples immediately
-            let samples: Vec<f32> = buf[AUDIO_HEADER_SIZE..len]
-                .chunks_exact(4)
-                .map(|chunk| {
-                    let mut bytes = [0u8; 4];
-                    bytes.copy_from_slice(chunk);
-                    f32::from_le_bytes(bytes)
+    // Buffers waiting in the channel for the consumer
+    fn queued(&self) -> usize {
+        match self {
+            AudioOutput::Samples(tx) => tx.max_capacity() - tx.capacity(),
+            AudioOutput::Timed(tx, _) => tx.max_capacity() - tx.capacity(),
+        }
+    }
+
+    async fn deliver(&self, header: &PacketHeader, samples: Vec<f32>) -> bool {
+        match self {
+            AudioOutput::Samples(tx) => tx.send(samples).await.is_ok(),
+            AudioOutput::Timed(tx, sample_rate) => tx
+                .send(TimedBuffer {
+                    samples,
+                    presentation_time: header.presentation_time(*sample_rate),
                 })
-                .collect();
+                .await
+                .is_ok(),
+        }
+    }
 
-            // Send samples immediately
-            if let Err(e) = tx.send(samples).await {
-                log::error!("Failed to send samples to player: {}", e);
-                break;
+    // Waits for room in the channel, then sends the buffer `next` yields at
+    // that moment, if any. Returns false once the consumer has gone away.
+    async fn deliver_next(&self, next: impl FnOnce() -> Option<(PacketHeader, Vec<f32>)>) -> bool {
+        match self {
+            AudioOutput::Samples(tx) => {
+                let Ok(permit) = tx.reserve().await else {
+                    return false;
+                };
+                if let Some((_, samples)) = next() {
+                    permit.send(samples);
+                }
+            }
+            AudioOutput::Timed(tx, sample_rate) => {
+                let Ok(permit) = tx.reserve().await else {
+                    return false;
+                };
+                if let Some((header, samples)) = next() {
+                    permit.send(TimedBuffer {
+                        samples,
+                        presentation_time: header.presentation_time(*sample_rate),
+                    });
+                }
             }
         }
-
-        Ok(())
+        true
     }
 
-    pub fn local_addr(&self) -> Result<SocketAddr> {
-        Ok(self.socket.local_addr()?)
+    // Non-blocking delivery; a full channel hands the samples back
+    fn try_deliver(
+        &self,
+        header: &PacketHeader,
+        samples: Vec<f32>,
+    ) -> std::result::Result<(), TrySendError<Vec<f32>>> {
+        match self {
+            AudioOutput::Samples(tx) => tx.try_send(samples),
+            AudioOutput::Timed(tx, sample_rate) => tx
+                .try_send(TimedBuffer {
+                    samples,
+                    presentation_time: header.presentation_time(*sample_rate),
+                })
+                .map_err(|e| match e {
+                    TrySendError::Full(buffer) => TrySendError::Full(buffer.samples),
+                    TrySendError::Closed(buffer) => TrySendError::Closed(buffer.samples),
+                }),
+        }
     }
+}
 
-    pub async fn server_addr(&self) -> Result<SocketAddr> {
-        self.server_addr
-            .lock()
-            .await
-            .ok_or_else(|| crate::AudioStreamerError::NetworkError("No server found".into()))
+// Buffers waiting for room in the consumer's channel under
+// `OverflowPolicy::DropOldest`: the receive loop adds to the back, dropping
+// from the front when full, and `forward` moves them on as room appears
+#[derive(Default)]
+struct Backlog {
+    buffers: std::sync::Mutex<VecDeque<(PacketHeader, Vec<f32>)>>,
+    added: Notify,
+    // How many buffers may wait below the channel's capacity for
+    // `ReceiverConfig::max_queued`, and how often to look for room under it
+    limit: std::sync::Mutex<Option<(usize, Duration)>>,
+}
+
+impl Backlog {
+    fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
     }
 
-    pub async fn discover_server(&self) -> Result<()> {
-        let broadcast_addr = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)),
-            DISCOVERY_PORT,
-        );
+    fn set_limit(&self, limit: Option<(usize, Duration)>) {
+        *self.limit.lock().unwrap() = limit;
+    }
 
-        // Send discovery request
-        let request = "DISCOVER";
-        self.discovery_socket
-            .send_to(request.as_bytes(), broadcast_addr)
-            .await?;
+    fn limit(&self, output: &AudioOutput) -> (usize, Option<Duration>) {
+        match *self.limit.lock().unwrap() {
+            Some((limit, recheck)) if limit < output.capacity() => (limit, Some(recheck)),
+            _ => (output.capacity(), None),
+        }
+    }
 
-        // Wait for server response
-        let mut buf = [0u8; 64];
-        let timeout = time::sleep(DISCOVERY_TIMEOUT);
-        tokio::pin!(timeout);
+    // Queues a buffer, first dropping the oldest held ones so that together
+    // with the channel no more than the limit waits, or one buffer when the
+    // channel already holds that many. Returns how many were dropped.
+    fn push(&self, output: &AudioOutput, header: PacketHeader, samples: Vec<f32>) -> usize {
+        let (limit, _) = self.limit(output);
+        let room = limit.saturating_sub(output.queued()).max(1);
+        let mut buffers = self.buffers.lock().unwrap();
+        let mut dropped = 0;
+        while buffers.len() >= room {
+            buffers.pop_front();
+            dropped += 1;
+        }
+        buffers.push_back((header, samples));
+        drop(buffers);
+        self.added.notify_one();
+        dropped
+    }
 
+    // Moves buffers to the channel as the consumer makes room, taking the
+    // oldest only once there is room so it can still be dropped until then.
+    // Returns once the consumer has gone away.
+    async fn forward(&self, output: &AudioOutput) {
         loop {
-            tokio::select! {
-                result = self.discovery_socket.recv_from(&mut buf) => {
-                    match result {
-                        Ok((len, addr)) => {
-                            let response = String::from_utf8_lossy(&buf[..len]);
-                            if let Some(port_str) = response.strip_prefix("SERVER:") {
-                                if let Ok(port) = port_str.trim().parse::<u16>() {
-                                    let server_addr = SocketAddr::new(addr.ip(), port);
-                                    *self.server_addr.lock().await = Some(server_addr);
-                                    break;
-                                }
-                            }
-                        }
-                        Err(e) => log::error!("Discovery receive error: {}", e),
+            if self.len() == 0 {
+                self.added.notified().await;
+                continue;
+            }
+            if let (limit, Some(recheck)) = self.limit(output) {
+                // The channel only says when it has room below its capacity,
+                // so look again for room below the limit
+                if output.queued() >= limit {
+                    tokio::select! {
+                        () = self.added.notified() => {}
+                        () = time::sleep(recheck) => {}
                     }
+                    continue;
                 }
-                _ = &mut timeout => {
-                    return Err(crate::AudioStreamerError::NetworkError(
-                        "Server discovery timeout".into()
-                    ));
-                }
             }
+            if !output
+                .deliver_next(|| self.buffers.lock().unwrap().pop_front())
+                .await
+            {
+                return;
+            }
+        }
+    }
+
+    // Hands over what fits in the channel without waiting
+    fn flush(&self, output: &AudioOutput) {
+        let mut buffers = self.buffers.lock().unwrap();
+        while let Some((header, samples)) = buffers.pop_front() {
+            if output.try_deliver(&header, samples).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// What the receiver does when the consumer's channel is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for room. Nothing is lost, but a slow consumer backs packets up
+    /// in the socket buffer and latency grows without bound.
+    #[default]
+    Block,
+    /// Keep receiving, holding back what doesn't fit and handing it over as
+    /// the consumer makes room. When more arrives than the channel's capacity
+    /// or `ReceiverConfig::max_queued` allows to wait, the oldest held-back
+    /// audio is dropped first, so latency stays within about that much.
+    /// Drops are counted in the metrics.
+    DropOldest,
+}
+
+/// Announcements the server sends listeners outside the audio stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// The server is shutting down
+    ServerDown,
+    /// Audio from now on uses this format. Reconfigure the player, e.g. with
+    /// `AudioPlayer::reconfigure`, which also flushes audio in the old format.
+    FormatChanged(StreamFormat),
+    /// The broadcast is paused; only keepalives arrive until it resumes
+    Paused,
+    Resumed,
+}
+
+/// How long to wait between failed discovery attempts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backoff {
+    Fixed(Duration),
+    /// `initial`, then growing by `step` per attempt up to `max`
+    Linear {
+        initial: Duration,
+        step: Duration,
+        max: Duration,
+    },
+    /// `initial`, then doubling per attempt up to `max`
+    Exponential {
+        initial: Duration,
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    /// Delay after the given failed attempt, counting from 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Linear { initial, step, max } => initial
+                .saturating_add(step.saturating_mul(attempt))
+                .min(max),
+            Backoff::Exponential { initial, max } => initial
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(max),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReconnectConfig {
+    pub backoff: Backoff,
+    /// Fraction of each delay, from 0.0 to 1.0, randomly taken off so many
+    /// listeners losing the same server don't all retry in lockstep
+    pub jitter: f32,
+    /// Give up after this many failed attempts. `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            backoff: Backoff::Exponential {
+                initial: Duration::from_millis(500),
+                max: Duration::from_secs(30),
+            },
+            jitter: 0.2,
+            max_attempts: None,
         }
+    }
+}
+
+impl ReconnectConfig {
+    fn jittered_delay(&self, attempt: u32) -> Duration {
+        use std::hash::{BuildHasher, Hasher};
+
+        // RandomState is seeded per instance, which is random enough to spread retries
+        let random = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish();
+        let fraction = (random >> 11) as f64 / (1u64 << 53) as f64;
+        let jitter = self.jitter.clamp(0.0, 1.0) as f64;
+        self.backoff.delay(attempt).mul_f64(1.0 - jitter * fraction)
+    }
+}
+
+/// A sender found by `AudioReceiver::discover_servers`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredServer {
+    /// Address the sender streams from
+    pub addr: SocketAddr,
+    pub name: Option<String>,
+    pub format: Option<StreamFormat>,
+    /// `None` for senders too old to announce them
+    pub capabilities: Option<Capabilities>,
+}
 
+/// The session a listener has settled on, as returned by
+/// `AudioReceiver::session_info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub server_addr: SocketAddr,
+    pub server_name: Option<String>,
+    /// The announced format, or the configured one for servers that don't
+    /// announce theirs
+    pub format: StreamFormat,
+    /// Whether `format` came from the server rather than the configuration
+    pub announced: bool,
+    pub transport: Transport,
+    /// Where the server also serves the stream over [`Transport::WebSocket`],
+    /// when it announces that
+    pub websocket_addr: Option<SocketAddr>,
+}
+
+impl std::fmt::Display for SessionInfo {
+    /// e.g. "Living Room (192.168.1.20:50001), 48000Hz stereo pcm"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.server_name {
+            Some(name) => write!(f, "{} ({})", name, self.server_addr)?,
+            None => write!(f, "{}", self.server_addr)?,
+        }
+        write!(f, ", {}Hz ", self.format.sample_rate)?;
+        match self.format.channels {
+            1 => write!(f, "mono")?,
+            2 => write!(f, "stereo")?,
+            channels => write!(f, "{} channels", channels)?,
+        }
+        write!(f, " {}", self.format.codec)?;
         Ok(())
     }
 }
+
+// What a sender has said about itself ahead of its address
+#[derive(Debug, Default)]
+struct PendingServer {
+    name: Option<String>,
+    format: Option<StreamFormat>,
+    capabilities: Option<Capabilities>,
+}
+
+// Senders heard from on the discovery socket. Each names itself, its format
+// and capabilities just before its address, so those wait in `pending` until
+// then.
+#[derive(Debug, Default)]
+struct ServerRegistry {
+    servers: Vec<DiscoveredServer>,
+    pending: HashMap<IpAddr, PendingServer>,
+}
+
+impl ServerRegistry {
+    // Returns the sender an address announcement completes
+    fn record(&mut self, from: SocketAddr, message: &[u8]) -> Option<DiscoveredServer> {
+        if let Some(format) = StreamFormat::parse_message(message) {
+            self.pending.entry(from.ip()).or_default().format = Some(format);
+            return None;
+        }
+        if let Some(capabilities) = Capabilities::parse_message(message) {
+            self.pending.entry(from.ip()).or_default().capabilities = Some(capabilities);
+            return None;
+        }
+        let message = String::from_utf8_lossy(message);
+        if let Some(name) = message.strip_prefix("NAME:") {
+            self.pending.entry(from.ip()).or_default().name = Some(name.to_string());
+            return None;
+        }
+        if message == "SERVER_DOWN" {
+            self.servers.retain(|server| server.addr.ip() != from.ip());
+            return None;
+        }
+
+        let port = message
+            .strip_prefix("SERVER:")?
+            .trim()
+            .parse::<u16>()
+            .ok()?;
+        let pending = self.pending.remove(&from.ip()).unwrap_or_default();
+        let addr = SocketAddr::new(from.ip(), port);
+        let known = self.servers.iter().position(|server| server.addr == addr);
+        // Announcements don't carry the name, so keep the one learnt earlier
+        let name = pending
+            .name
+            .or_else(|| known.and_then(|i| self.servers[i].name.clone()));
+        let server = DiscoveredServer {
+            addr,
+            name,
+            format: pending.format,
+            capabilities: pending.capabilities,
+        };
+        match known {
+            Some(i) => self.servers[i] = server.clone(),
+            None => self.servers.push(server.clone()),
+        }
+        Some(server)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PingStats {
+    pub sent: u32,
+    pub received: u32,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+}
+
+/// Settings for `AudioReceiver::bench`.
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    /// Send rates to step through, in bits per second
+    pub rates: Vec<u64>,
+    /// How long each rate is held
+    pub step_duration: Duration,
+    /// UDP payload size of each probe packet, up to 1472 bytes
+    pub packet_size: usize,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            rates: [1, 2, 4, 8, 16, 32]
+                .iter()
+                .map(|mbps| mbps * 1_000_000)
+                .collect(),
+            step_duration: Duration::from_secs(1),
+            // Same size as a 360-sample PCM audio packet
+            packet_size: HEADER_SIZE + 360 * 4,
+        }
+    }
+}
+
+/// Outcome of one rate in a throughput bench.
+#[derive(Clone, Copy, Debug)]
+pub struct BenchStep {
+    /// Rate the probes were sent at, in bits per second
+    pub target_bps: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Rate the server received probes at, in bits per second
+    pub received_bps: u64,
+}
+
+impl BenchStep {
+    /// Fraction of probe packets that didn't arrive, from 0.0 to 1.0.
+    pub fn loss(&self) -> f64 {
+        if self.packets_sent == 0 {
+            return 0.0;
+        }
+        1.0 - self.packets_received.min(self.packets_sent) as f64 / self.packets_sent as f64
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchReport {
+    pub steps: Vec<BenchStep>,
+}
+
+impl BenchReport {
+    /// Highest rate received by the server in a step that lost at most
+    /// `max_loss` of its packets.
+    pub fn sustainable_bps(&self, max_loss: f64) -> Option<u64> {
+        self.steps
+            .iter()
+            .filter(|step| step.loss() <= max_loss)
+            .map(|step| step.received_bps)
+            .max()
+    }
+}
+
+// Probe packets received from one client in its current bench step
+struct BenchCounter {
+    step: u64,
+    packets: u64,
+    bytes: u64,
+}
+
+// Probe packets are `BENCH:<step>:` followed by padding
+fn parse_bench_probe(packet: &[u8]) -> Option<u64> {
+    let rest = packet.strip_prefix(b"BENCH:")?;
+    let end = rest.iter().take(20).position(|&b| b == b':')?;
+    std::str::from_utf8(&rest[..end]).ok()?.parse().ok()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// No server known yet, or discovery failed
+    Disconnected,
+    /// Discovery request sent, waiting for a server to answer
+    Discovering,
+    /// Server found but no audio received yet
+    Connected,
+    /// Audio packets are arriving
+    Receiving,
+    /// Audio was arriving but nothing has been received for the stall timeout
+    Stalled,
+    /// `reconnect` is waiting before its next discovery attempt
+    Backoff {
+        /// Failed attempts so far
+        attempt: u32,
+        retry_in: Duration,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    /// Requested `SO_RCVBUF` size in bytes for the stream socket
+    pub recv_buffer_size: usize,
+    /// Requested `SO_SNDBUF` size in bytes for the stream socket
+    pub send_buffer_size: usize,
+    /// How often a sender announces itself on the local network
+    pub discovery_interval: Duration,
+    /// IP TTL for discovery traffic. `Some(1)` confines announcements to the
+    /// local segment; larger values let them cross routers that forward them.
+    /// `None` keeps the OS default.
+    pub discovery_ttl: Option<u32>,
+    /// When the stream socket's port is taken, bind an ephemeral port on the
+    /// same address instead of failing. The port actually used is reported by
+    /// `local_addr`.
+    pub fallback_to_any_port: bool,
+    /// Largest audio datagram sent or received, headers included. Raise it up
+    /// to 8972 bytes on networks with jumbo frames to send fewer, larger
+    /// packets; senders and listeners need the same setting, as a listener
+    /// drops datagrams bigger than its own limit and counts them as decode
+    /// errors.
+    pub max_datagram_size: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            recv_buffer_size: 1024 * 1024, // 1 MiB, well above typical OS defaults
+            send_buffer_size: 1024 * 1024,
+            discovery_interval: DISCOVERY_INTERVAL,
+            discovery_ttl: None,
+            fallback_to_any_port: false,
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+        }
+    }
+}
+
+async fn bind_discovery_socket(port: u16) -> Result<UdpSocket> {
+    let addr = format!("0.0.0.0:{}", port);
+    Ok(UdpSocket::bind(&addr)
+        .await
+        .map_err(|source| bind_error(&addr, source))?)
+}
+
+// Broadcast-capable socket for discovery and control messages
+fn configure_discovery_socket(socket: &UdpSocket, config: &NetworkConfig) -> Result<()> {
+    socket.set_broadcast(true)?;
+    if let Some(ttl) = config.discovery_ttl {
+        socket.set_ttl(ttl)?;
+        socket.set_multicast_ttl_v4(ttl)?;
+    }
+    Ok(())
+}
+
+// Resolves a bind address given as an address or a host name, e.g.
+// "localhost:50001", to the first address it names
+async fn resolve_bind_addr(bind_addr: &str) -> Result<SocketAddr> {
+    let mut addrs = lookup_host(bind_addr)
+        .await
+        .map_err(|source| NetworkError::BindFailed {
+            addr: bind_addr.to_string(),
+            source,
+        })?;
+    addrs.next().ok_or_else(|| {
+        AudioStreamerError::ConfigError(format!("{} doesn't resolve to any address", bind_addr))
+    })
+}
+
+// Both sockets bind all interfaces, so a fixed port equal to the stream
+// socket's would fail with an unhelpful address-in-use error
+fn check_port_conflict(stream_addr: SocketAddr, port: u16, name: &str) -> Result<()> {
+    if port != 0 && port == stream_addr.port() {
+        return Err(AudioStreamerError::ConfigError(format!(
+            "The {} port {} is also the stream port; use a different port for each",
+            name, port
+        )));
+    }
+    Ok(())
+}
+
+// Discovery is IPv4 broadcast, so the peers it finds are only reachable from a
+// stream socket on IPv4 or on the dual-stack `[::]`
+fn check_discovery_family(stream_addr: SocketAddr) -> Result<()> {
+    match stream_addr.ip() {
+        IpAddr::V6(ip) if !ip.is_unspecified() && ip.to_ipv4_mapped().is_none() => {
+            Err(AudioStreamerError::ConfigError(format!(
+                "Discovery only works over IPv4, so it can't be used with the IPv6 \
+                 address {}; bind to [::] to serve IPv4 and IPv6 together, or \
+                 disable discovery and connect over IPv6 directly",
+                ip
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_datagram_size(size: usize) -> Result<()> {
+    if !(MIN_DATAGRAM_SIZE..=MAX_JUMBO_DATAGRAM_SIZE).contains(&size) {
+        return Err(AudioStreamerError::ConfigError(format!(
+            "Maximum datagram size {} is outside {}..={} bytes",
+            size, MIN_DATAGRAM_SIZE, MAX_JUMBO_DATAGRAM_SIZE
+        )));
+    }
+    Ok(())
+}
+
+// Senders stream every codec compiled in and never require encryption
+fn capabilities(
+    format: StreamFormat,
+    transport: Transport,
+    multicast: Option<SocketAddrV4>,
+    websocket: Option<u16>,
+) -> Capabilities {
+    Capabilities {
+        codecs: Codec::available(),
+        encryption_required: false,
+        transport: Some(transport),
+        format,
+        multicast,
+        websocket,
+        not_understood: Vec::new(),
+    }
+}
+
+// Local IPv4 address the OS routes to `peer` from, so a multicast group is
+// joined on the interface the server is reached through
+fn interface_towards(peer: IpAddr) -> Ipv4Addr {
+    let local = std::net::UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+        socket.connect((peer, DISCOVERY_PORT))?;
+        socket.local_addr()
+    });
+    match local {
+        Ok(SocketAddr::V4(local)) => *local.ip(),
+        _ => Ipv4Addr::UNSPECIFIED,
+    }
+}
+
+// Sends multicast out of the interface `member` is reached through, unless
+// the stream socket is bound to one; otherwise the OS picks the default
+// route's, which needn't be the listeners'
+fn route_group_towards(socket: &UdpSocket, member: IpAddr) -> std::io::Result<()> {
+    let interface = match socket.local_addr()?.ip() {
+        IpAddr::V4(bound) if !bound.is_unspecified() => bound,
+        _ => interface_towards(member),
+    };
+    socket2::SockRef::from(socket).set_multicast_if_v4(&interface)
+}
+
+// Address to send to a peer at from a socket of either family
+fn stream_destination(dual_stack: bool, peer: SocketAddr) -> SocketAddr {
+    match peer {
+        SocketAddr::V4(v4) if dual_stack => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        _ => peer,
+    }
+}
+
+// Create the stream socket with the configured buffer sizes applied before binding
+fn bind_stream_socket(addr: SocketAddr, config: &NetworkConfig) -> Result<UdpSocket> {
+    check_datagram_size(config.max_datagram_size)?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    // Some systems (Windows, BSDs) default IPv6 sockets to IPv6 only
+    if let SocketAddr::V6(v6) = addr {
+        if v6.ip().is_unspecified() {
+            socket.set_only_v6(false).map_err(|e| {
+                AudioStreamerError::ConfigError(format!(
+                    "This system can't serve IPv4 and IPv6 on one socket ({}); \
+                     bind to 0.0.0.0 for IPv4 or to a specific IPv6 address",
+                    e
+                ))
+            })?;
+        }
+    }
+
+    if let Err(e) = socket.set_recv_buffer_size(config.recv_buffer_size) {
+        log::warn!("Failed to set receive buffer size: {}", e);
+    }
+    if let Err(e) = socket.set_send_buffer_size(config.send_buffer_size) {
+        log::warn!("Failed to set send buffer size: {}", e);
+    }
+    log::info!(
+        "Socket buffers granted by OS: recv={} bytes, send={} bytes",
+        socket.recv_buffer_size()?,
+        socket.send_buffer_size()?
+    );
+
+    // Ask the kernel to stamp each datagram on arrival, read back in `recv_timestamped`
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        let fd = socket.as_raw_fd();
+        unsafe {
+            let optval: libc::c_int = 1;
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_TIMESTAMP,
+                &optval as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+        }
+    }
+
+    socket.set_nonblocking(true)?;
+    match socket.bind(&addr.into()) {
+        Err(e) if e.kind() == ErrorKind::AddrInUse && config.fallback_to_any_port => {
+            log::warn!("{} is in use, binding an ephemeral port instead", addr);
+            let fallback = SocketAddr::new(addr.ip(), 0);
+            socket
+                .bind(&fallback.into())
+                .map_err(|source| bind_error(&fallback.to_string(), source))?;
+        }
+        result => result.map_err(|source| bind_error(&addr.to_string(), source))?,
+    }
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+// Name the port when it's taken, the usual reason a bind fails, rather than
+// passing on the OS's terse message
+pub(crate) fn bind_error(addr: &str, source: std::io::Error) -> NetworkError {
+    let port = addr.parse::<SocketAddr>().map(|addr| addr.port());
+    match port {
+        Ok(port) if source.kind() == ErrorKind::AddrInUse => NetworkError::AddressInUse {
+            addr: addr.to_string(),
+            port,
+        },
+        _ => NetworkError::BindFailed {
+            addr: addr.to_string(),
+            source,
+        },
+    }
+}
+
+// Subnet broadcast address of each chosen interface, looked up by its address
+fn broadcast_addrs_for(
+    interfaces: &[Ipv4Addr],
+    available: &[(Ipv4Addr, Ipv4Addr)],
+) -> Result<Vec<Ipv4Addr>> {
+    interfaces
+        .iter()
+        .map(|interface| {
+            available
+                .iter()
+                .find(|(addr, _)| addr == interface)
+                .map(|&(_, broadcast)| broadcast)
+                .ok_or_else(|| {
+                    AudioStreamerError::ConfigError(format!(
+                        "No broadcast-capable interface has the address {}",
+                        interface
+                    ))
+                })
+        })
+        .collect()
+}
+
+// Address and subnet broadcast address of every IPv4 interface that can broadcast
+#[cfg(unix)]
+fn interface_broadcasts() -> Result<Vec<(Ipv4Addr, Ipv4Addr)>> {
+    let mut interfaces = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut interfaces) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut found = Vec::new();
+    let mut current = interfaces;
+    while !current.is_null() {
+        let interface = unsafe { &*current };
+        current = interface.ifa_next;
+        let broadcast = interface.ifa_flags & libc::IFF_BROADCAST as libc::c_uint != 0;
+        if !broadcast || interface.ifa_addr.is_null() || interface.ifa_netmask.is_null() {
+            continue;
+        }
+        if unsafe { (*interface.ifa_addr).sa_family } != libc::AF_INET as libc::sa_family_t {
+            continue;
+        }
+        let ipv4 = |addr: *const libc::sockaddr| {
+            let addr = unsafe { &*(addr as *const libc::sockaddr_in) };
+            Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))
+        };
+        let addr = ipv4(interface.ifa_addr);
+        let netmask = ipv4(interface.ifa_netmask);
+        found.push((addr, Ipv4Addr::from(u32::from(addr) | !u32::from(netmask))));
+    }
+    unsafe { libc::freeifaddrs(interfaces) };
+    Ok(found)
+}
+
+#[cfg(not(unix))]
+fn interface_broadcasts() -> Result<Vec<(Ipv4Addr, Ipv4Addr)>> {
+    Err(AudioStreamerError::ConfigError(
+        "Choosing the interfaces to announce on isn't supported on this platform".to_string(),
+    ))
+}
+
+// Receive a datagram together with the kernel's SO_TIMESTAMP arrival time,
+// falling back to the userspace clock when no timestamp was attached
+#[cfg(unix)]
+async fn recv_timestamped(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, SystemTime)> {
+    use std::os::unix::io::AsRawFd;
+    let fd = socket.as_raw_fd();
+    socket
+        .async_io(Interest::READABLE, || recvmsg_timestamped(fd, buf))
+        .await
+}
+
+#[cfg(not(unix))]
+async fn recv_timestamped(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, SystemTime)> {
+    let (len, addr) = socket.recv_from(buf).await?;
+    Ok((len, addr, SystemTime::now()))
+}
+
+#[cfg(unix)]
+fn recvmsg_timestamped(
+    fd: std::os::unix::io::RawFd,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, SystemTime)> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // u64 storage keeps the control buffer aligned for cmsghdr
+    let mut control = [0u64; 8];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if len < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut arrival = SystemTime::now();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMP {
+                let tv = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timeval);
+                arrival = UNIX_EPOCH
+                    + Duration::from_secs(tv.tv_sec as u64)
+                    + Duration::from_micros(tv.tv_usec as u64);
+                break;
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    let addr = unsafe { socket2::SockAddr::new(addr, msg.msg_namelen) }
+        .as_socket()
+        .ok_or_else(|| std::io::Error::other("Unsupported source address family"))?;
+
+    Ok((len as usize, addr, arrival))
+}
+
+impl AudioSender {
+    pub async fn new(bind_addr: Option<&str>) -> Result<Self> {
+        Self::with_config(SenderConfig {
+            bind_addr: bind_addr.map(str::to_string),
+            ..SenderConfig::default()
+        })
+        .await
+    }
+
+    pub async fn with_network_config(
+        bind_addr: Option<&str>,
+        network: NetworkConfig,
+    ) -> Result<Self> {
+        Self::with_config(SenderConfig {
+            bind_addr: bind_addr.map(str::to_string),
+            network,
+            ..SenderConfig::default()
+        })
+        .await
+    }
+
+    pub async fn with_config(config: SenderConfig) -> Result<Self> {
+        // Sockets register with, and tasks spawn on, the runtime they're made on
+        match config.runtime.clone() {
+            Some(runtime) => run_on(&runtime, Self::build(config)).await?,
+            None => Self::build(config).await,
+        }
+    }
+
+    async fn build(mut config: SenderConfig) -> Result<Self> {
+        // Surface an unavailable codec now rather than when sending starts
+        PacketEncoder::new(config.codec, config.channels, config.sample_rate)?;
+        if let Some(coefficient) = config.pre_emphasis {
+            check_emphasis(coefficient)?;
+        }
+        if config.encryption {
+            return Err(AudioStreamerError::ConfigError(
+                "Stream encryption is not supported yet".to_string(),
+            ));
+        }
+        if let Some(max) = config.max_bitrate {
+            // Zstd falls back to plain f32 payloads when it doesn't shrink them
+            let bits = match config.codec {
+                Codec::PcmI16 | Codec::Flac => 16,
+                Codec::Pcm | Codec::Zstd | Codec::Opus => 32,
+            };
+            let bitrate = u64::from(config.sample_rate) * u64::from(config.channels) * bits;
+            if bitrate > u64::from(max) {
+                return Err(AudioStreamerError::ConfigError(format!(
+                    "A {} stream needs up to {} bit/s, over the {} bit/s cap, and sending can't be throttled yet",
+                    config.codec, bitrate, max
+                )));
+            }
+        }
+        if config.heartbeat_timeout.is_some() {
+            return Err(AudioStreamerError::ConfigError(
+                "Listeners don't send heartbeats yet, so a heartbeat timeout can't be enforced"
+                    .to_string(),
+            ));
+        }
+        if let Some(group) = config.multicast_group {
+            if !group.ip().is_multicast() {
+                return Err(AudioStreamerError::ConfigError(format!(
+                    "{} is not a multicast address",
+                    group.ip()
+                )));
+            }
+        }
+
+        let bind_addr = config
+            .bind_addr
+            .clone()
+            .unwrap_or_else(|| format!("0.0.0.0:{}", DEFAULT_STREAM_PORT));
+        let bind_addr = resolve_bind_addr(&bind_addr).await?;
+        if config.discovery {
+            check_port_conflict(bind_addr, config.discovery_port, "discovery")?;
+            check_discovery_family(bind_addr)?;
+        }
+        if let Some(group) = config.multicast_group {
+            if bind_addr.is_ipv6() {
+                return Err(AudioStreamerError::ConfigError(format!(
+                    "Multicast group {} needs an IPv4 stream socket, not {}",
+                    group, bind_addr
+                )));
+            }
+        }
+        let broadcast_addrs = if config.announce_interfaces.is_empty() {
+            vec![Ipv4Addr::BROADCAST]
+        } else {
+            broadcast_addrs_for(&config.announce_interfaces, &interface_broadcasts()?)?
+        };
+
+        let socket = Arc::new(bind_stream_socket(bind_addr, &config.network)?);
+        let stream_addr = socket.local_addr()?;
+        let stream_port = stream_addr.port();
+        if config.multicast_group.is_some() {
+            socket.set_multicast_ttl_v4(config.multicast_ttl)?;
+        }
+
+        // Set up discovery socket, on an ephemeral port when nobody will discover us
+        let discovery_port = if config.discovery {
+            config.discovery_port
+        } else {
+            0
+        };
+        let discovery_socket = match bind_discovery_socket(discovery_port).await {
+            // Static clients can still be streamed to without discovery
+            Err(e) if discovery_port != 0 && !config.static_clients.is_empty() => {
+                log::warn!(
+                    "Discovery disabled, streaming to static clients only: {}",
+                    e
+                );
+                config.discovery = false;
+                bind_discovery_socket(0).await?
+            }
+            result => result?,
+        };
+        configure_discovery_socket(&discovery_socket, &config.network)?;
+        let discovery_socket = Arc::new(discovery_socket);
+
+        let clients = Arc::new(Mutex::new(
+            config
+                .static_clients
+                .iter()
+                .copied()
+                .collect::<HashSet<_>>(),
+        ));
+        let listeners = Arc::new(Mutex::new(HashSet::new()));
+        let mut metrics = SenderMetrics::default();
+        for &client in &config.static_clients {
+            metrics.client_joined(client);
+        }
+
+        let sender = Self {
+            socket,
+            discovery_socket,
+            clients,
+            multicast_members: Arc::new(Mutex::new(HashSet::new())),
+            listeners,
+            metrics: Arc::new(std::sync::Mutex::new(metrics)),
+            format: Arc::new(std::sync::Mutex::new(StreamFormat {
+                sample_rate: config.sample_rate,
+                channels: config.channels,
+                codec: config.codec,
+            })),
+            format_changed: AtomicBool::new(false),
+            muted: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            resumed: Notify::new(),
+            sequence: AtomicU32::new(1),
+            stream_port,
+            dual_stack: stream_addr.is_ipv6(),
+            broadcast_addrs,
+            config,
+        };
+
+        if sender.config.discovery {
+            sender.start_discovery_service().await?;
+        }
+        Ok(sender)
+    }
+
+    async fn start_discovery_service(&self) -> Result<()> {
+        let socket = self.socket.clone();
+        let discovery_socket = self.discovery_socket.clone();
+        let clients = self.clients.clone();
+        let multicast_members = self.multicast_members.clone();
+        let listeners = self.listeners.clone();
+        let metrics = self.metrics.clone();
+        let stream_port = self.stream_port;
+        let discovery_port = self.config.discovery_port;
+        let discovery_interval = self.config.network.discovery_interval;
+        let max_clients = self.config.max_clients;
+        let client_filter = self.config.client_filter.clone();
+        let name = self.config.name.clone();
+        let format = self.format.clone();
+        let transport = self.transport();
+        let multicast_group = self.config.multicast_group;
+        let websocket_port = self.config.websocket_port;
+
+        // Handle incoming discovery requests
+        let discovery_socket_clone = discovery_socket.clone();
+        tokio::spawn(async move {
+            // Large enough for full-size bench probes
+            let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+            let mut bench = HashMap::new();
+            loop {
+                match discovery_socket_clone.recv_from(&mut buf).await {
+                    Ok((len, client_addr)) => {
+                        // Count throughput probes without decoding them as text
+                        if let Some(step) = parse_bench_probe(&buf[..len]) {
+                            if bench.len() >= MAX_BENCH_CLIENTS && !bench.contains_key(&client_addr)
+                            {
+                                bench.clear();
+                            }
+                            let counter = bench.entry(client_addr).or_insert(BenchCounter {
+                                step,
+                                packets: 0,
+                                bytes: 0,
+                            });
+                            if counter.step != step {
+                                *counter = BenchCounter {
+                                    step,
+                                    packets: 0,
+                                    bytes: 0,
+                                };
+                            }
+                            counter.packets += 1;
+                            counter.bytes += len as u64;
+                            continue;
+                        }
+
+                        let message = String::from_utf8_lossy(&buf[..len]);
+
+                        // Report what arrived of a bench step; repeatable in case
+                        // the reply is lost
+                        if let Some(step) = message.strip_prefix("BENCH_END:") {
+                            let (packets, bytes) = match bench.get(&client_addr) {
+                                Some(counter) if step.parse() == Ok(counter.step) => {
+                                    (counter.packets, counter.bytes)
+                                }
+                                _ => (0, 0),
+                            };
+                            let response = format!("BENCH_RESULT:{}:{}:{}", step, packets, bytes);
+                            if let Err(e) = discovery_socket_clone
+                                .send_to(response.as_bytes(), client_addr)
+                                .await
+                            {
+                                log::error!("Failed to send bench result: {}", e);
+                            }
+                            continue;
+                        }
+
+                        // Echo pings straight back so the client can time the round trip
+                        if let Some(payload) = message.strip_prefix("PING:") {
+                            let response = format!("PONG:{}", payload);
+                            if let Err(e) = discovery_socket_clone
+                                .send_to(response.as_bytes(), client_addr)
+                                .await
+                            {
+                                log::error!("Failed to send ping response: {}", e);
+                            }
+                            continue;
+                        }
+
+                        // Listener is shutting down, stop sending to it right away
+                        if message == "LEAVE" {
+                            let client = SocketAddr::new(client_addr.ip(), stream_port);
+                            if clients.lock().await.remove(&client) {
+                                log::info!("Client {} left", client);
+                                metrics.lock().unwrap().client_left(client);
+                            }
+                            multicast_members.lock().await.remove(&client);
+                            listeners.lock().await.remove(&client_addr);
+                            continue;
+                        }
+
+                        // Listener joined the multicast group, so its own copy can stop
+                        if message == "MULTICAST" {
+                            let client = SocketAddr::new(client_addr.ip(), stream_port);
+                            if multicast_group.is_some()
+                                && clients.lock().await.contains(&client)
+                                && multicast_members.lock().await.insert(client)
+                            {
+                                log::info!("Client {} now receives through multicast", client);
+                                if let Err(e) = route_group_towards(&socket, client.ip()) {
+                                    log::warn!("Failed to pick the multicast interface: {}", e);
+                                }
+                            }
+                            continue;
+                        }
+
+                        // Scans learn what we send without registering a listener
+                        if message == "SCAN" {
+                            let mut replies: Vec<String> =
+                                name.iter().map(|name| format!("NAME:{}", name)).collect();
+                            let current = *format.lock().unwrap();
+                            replies.push(current.to_message());
+                            replies.push(
+                                capabilities(current, transport, multicast_group, websocket_port)
+                                    .to_message(),
+                            );
+                            replies.push(format!("SERVER:{}", stream_port));
+                            for reply in replies {
+                                if let Err(e) = discovery_socket_clone
+                                    .send_to(reply.as_bytes(), client_addr)
+                                    .await
+                                {
+                                    log::error!("Failed to send scan response: {}", e);
+                                    break;
+                                }
+                            }
+                            continue;
+                        }
+
+                        if message != "DISCOVER" {
+                            continue;
+                        }
+
+                        let client = SocketAddr::new(client_addr.ip(), stream_port);
+                        if let Some(filter) = &client_filter {
+                            let registered = clients.lock().await.contains(&client);
+                            if !registered && !filter.accepts(client_addr) {
+                                log::warn!("Refusing {}: rejected by client filter", client);
+                                if let Err(e) = discovery_socket_clone
+                                    .send_to(b"REJECTED", client_addr)
+                                    .await
+                                {
+                                    log::error!("Failed to send rejection: {}", e);
+                                }
+                                continue;
+                            }
+                        }
+                        if let Some(max) = max_clients {
+                            let clients = clients.lock().await;
+                            if clients.len() >= max && !clients.contains(&client) {
+                                log::warn!("Refusing {}: client limit {} reached", client, max);
+                                continue;
+                            }
+                        }
+
+                        // The name, format and capabilities go first so the listener
+                        // knows them on finding us
+                        let mut replies: Vec<String> =
+                            name.iter().map(|name| format!("NAME:{}", name)).collect();
+                        let current = *format.lock().unwrap();
+                        replies.push(current.to_message());
+                        replies.push(
+                            capabilities(current, transport, multicast_group, websocket_port)
+                                .to_message(),
+                        );
+                        replies.push(format!("SERVER:{}", stream_port));
+                        let mut sent = Ok(0);
+                        for message in replies {
+                            sent = discovery_socket_clone
+                                .send_to(message.as_bytes(), client_addr)
+                                .await;
+                        }
+                        if let Err(e) = sent {
+                            log::error!("Failed to send discovery response: {}", e);
+                            continue;
+                        }
+                        if clients.lock().await.insert(client) {
+                            metrics.lock().unwrap().client_joined(client);
+                        }
+                        // It confirms with MULTICAST again if it rejoins the group
+                        multicast_members.lock().await.remove(&client);
+                        listeners.lock().await.insert(client_addr);
+                    }
+                    Err(e) => log::error!("Discovery receive error: {}", e),
+                }
+            }
+        });
+
+        // Broadcast server presence periodically, on each chosen interface
+        let broadcast_addrs: Vec<SocketAddr> = self
+            .broadcast_addrs
+            .iter()
+            .map(|&ip| SocketAddr::new(ip.into(), discovery_port))
+            .collect();
+
+        let format = self.format.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(discovery_interval);
+            loop {
+                interval.tick().await;
+                let current = *format.lock().unwrap();
+                let announced = current.to_message();
+                let capable =
+                    capabilities(current, transport, multicast_group, websocket_port).to_message();
+                let announcement = format!("SERVER:{}", stream_port);
+                for &broadcast_addr in &broadcast_addrs {
+                    for message in [&announced, &capable, &announcement] {
+                        if let Err(e) = discovery_socket
+                            .send_to(message.as_bytes(), broadcast_addr)
+                            .await
+                        {
+                            log::error!(
+                                "Failed to broadcast server presence to {}: {}",
+                                broadcast_addr,
+                                e
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Format currently being sent.
+    pub fn format(&self) -> StreamFormat {
+        *self.format.lock().unwrap()
+    }
+
+    /// What discovery tells listeners this sender supports.
+    pub fn capabilities(&self) -> Capabilities {
+        capabilities(
+            self.format(),
+            self.transport(),
+            self.config.multicast_group,
+            self.config.websocket_port,
+        )
+    }
+
+    fn transport(&self) -> Transport {
+        if self.dual_stack {
+            Transport::UdpV6
+        } else {
+            Transport::UdpV4
+        }
+    }
+
+    /// Switches the stream to a new format, e.g. after changing capture
+    /// device, and tells listeners that found us through discovery so they
+    /// can reconfigure. Call before sending buffers in the new format; the
+    /// presentation clock restarts with the first of them. Static clients
+    /// aren't notified.
+    pub async fn change_format(&self, format: StreamFormat) -> Result<()> {
+        PacketEncoder::new(format.codec, format.channels, format.sample_rate)?;
+        *self.format.lock().unwrap() = format;
+        self.format_changed.store(true, Ordering::Release);
+        log::info!(
+            "Stream format changed to {}Hz, {} channels, {}",
+            format.sample_rate,
+            format.channels,
+            format.codec
+        );
+
+        self.notify_listeners(&format.to_message(), "format change")
+            .await;
+        Ok(())
+    }
+
+    // Send a control message to every listener that found us through discovery
+    async fn notify_listeners(&self, message: &str, event: &str) {
+        let listeners = self.listeners.lock().await.clone();
+        for listener in listeners {
+            if let Err(e) = self
+                .discovery_socket
+                .send_to(message.as_bytes(), listener)
+                .await
+            {
+                log::warn!("Failed to notify {} of {}: {}", listener, event, e);
+            }
+        }
+    }
+
+    /// Registers a listener directly, without it going through discovery.
+    pub async fn add_client(&self, addr: SocketAddr) {
+        if self.clients.lock().await.insert(addr) {
+            self.metrics.lock().unwrap().client_joined(addr);
+        }
+    }
+
+    /// Returns a snapshot of the send statistics gathered so far.
+    pub fn metrics(&self) -> SenderMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Writes the statistics gathered so far to `path` as JSON.
+    pub fn export_metrics(&self, path: impl AsRef<Path>) -> Result<()> {
+        write_json(path, &self.metrics())
+    }
+
+    /// Encodes and sends every buffer from `source` to the listeners,
+    /// returning once the source ends.
+    pub async fn start_sending<S: AudioSource + Send>(&self, source: S) -> Result<()> {
+        log::info!("Starting audio sender on port {}", self.stream_port);
+
+        match self.config.samples_per_packet {
+            Some(samples) => {
+                let channels = self.format().channels;
+                let frame = channels.max(1) as usize;
+                let max_samples =
+                    max_packet_samples(channels, self.config.network.max_datagram_size);
+                let samples = (samples / frame * frame).clamp(frame, max_samples);
+                log::debug!("Sending {} samples per packet", samples);
+                self.send_loop(Rebuffered::new(source, samples)).await
+            }
+            None => self.send_loop(source).await,
+        }
+    }
+
+    async fn send_loop<S: AudioSource>(&self, mut source: S) -> Result<()> {
+        let mut last_loud = Instant::now();
+        let mut last_sent = Instant::now();
+        let mut gated = false;
+
+        // Presentation clock: the session epoch plus frames produced since
+        let mut epoch_us = now_us();
+        let mut format = self.format();
+        let mut position = 0u64;
+        let mut encoder = PacketEncoder::new(format.codec, format.channels, format.sample_rate)?;
+        let new_pre_roll = |format: &StreamFormat| {
+            self.config
+                .silence_gate
+                .as_ref()
+                .filter(|gate| !gate.pre_roll.is_zero())
+                .map(|gate| PreRoll::new(gate.pre_roll, format.sample_rate, format.channels))
+        };
+        let mut pre_roll = new_pre_roll(&format);
+        let new_emphasis = |format: &StreamFormat| {
+            self.config
+                .pre_emphasis
+                .map(|coefficient| PreEmphasis::new(coefficient, format.channels))
+        };
+        let mut emphasis = new_emphasis(&format);
+
+        loop {
+            if self.paused.load(Ordering::Acquire) {
+                self.wait_while_paused(epoch_us, position).await;
+                // Listeners would hold the audio after the break back by its
+                // length, so it starts a new clock
+                epoch_us = now_us();
+                position = 0;
+            }
+            let Some(mut samples) = source.next_buffer().await else {
+                break;
+            };
+
+            // Positions count frames at the old rate, so a new format starts a new clock
+            if self.format_changed.swap(false, Ordering::Acquire) {
+                format = self.format();
+                encoder = PacketEncoder::new(format.codec, format.channels, format.sample_rate)?;
+                epoch_us = now_us();
+                position = 0;
+                pre_roll = new_pre_roll(&format);
+                emphasis = new_emphasis(&format);
+            }
+
+            let channels = format.channels.max(1) as u64;
+            let sample_position = position;
+            position += samples.len() as u64 / channels;
+
+            let muted = self.muted.load(Ordering::Relaxed);
+            if muted {
+                samples.fill(0.0);
+            }
+
+            // Gate on the level as captured, since pre-emphasis cuts the lows
+            let peak = samples.iter().fold(0.0f32, |max, &x| max.max(x.abs()));
+            if let Some(emphasis) = emphasis.as_mut() {
+                emphasis.process(&mut samples);
+            }
+
+            if let Some(gate) = self.config.silence_gate.as_ref().filter(|_| !muted) {
+                if peak >= gate.threshold {
+                    last_loud = Instant::now();
+                    if gated {
+                        log::debug!("Silence gate opened");
+                        gated = false;
+                        if let Some(pre_roll) = pre_roll.as_mut() {
+                            for (held_position, held) in pre_roll.drain() {
+                                self.send_samples(
+                                    &mut encoder,
+                                    epoch_us,
+                                    held_position,
+                                    &held,
+                                    format.channels,
+                                )
+                                .await;
+                                source.recycle(held);
+                            }
+                        }
+                    }
+                } else if last_loud.elapsed() >= gate.hold {
+                    if !gated {
+                        log::debug!("Silence gate closed");
+                        gated = true;
+                    }
+                    // Header-only packets keep listeners from stalling without the bandwidth
+                    if let Some(interval) = gate.keepalive_interval {
+                        if last_sent.elapsed() >= interval {
+                            let header = self.next_header(epoch_us, position);
+                            self.send_to_clients(&encode_packet(&header, &[])).await;
+                            last_sent = Instant::now();
+                        }
+                    }
+                    if let Some(pre_roll) = pre_roll.as_mut() {
+                        pre_roll.push(sample_position, samples);
+                    }
+                    continue;
+                }
+            }
+
+            self.send_samples(
+                &mut encoder,
+                epoch_us,
+                sample_position,
+                &samples,
+                format.channels,
+            )
+            .await;
+            source.recycle(samples);
+            last_sent = Instant::now();
+        }
+        Ok(())
+    }
+
+    async fn send_samples(
+        &self,
+        encoder: &mut PacketEncoder,
+        epoch_us: u64,
+        position: u64,
+        samples: &[f32],
+        channels: u16,
+    ) {
+        // Buffers too big for one datagram, e.g. after a device glitch, would
+        // be dropped or truncated whole, so they go out as several packets
+        let max_samples = max_packet_samples(channels, self.config.network.max_datagram_size);
+        if samples.len() > max_samples {
+            log::debug!(
+                "Splitting {} samples into {} packets",
+                samples.len(),
+                samples.len().div_ceil(max_samples)
+            );
+        }
+        let mut chunk_position = position;
+        for chunk in samples.chunks(max_samples) {
+            let header = self.next_header(epoch_us, chunk_position);
+            self.send_to_clients(&encoder.encode(&header, chunk)).await;
+            chunk_position += chunk.len() as u64 / channels.max(1) as u64;
+        }
+    }
+
+    // Numbers packets from 1, skipping 0 on wrapping, which listeners take
+    // to mean an unnumbered packet
+    fn next_header(&self, epoch_us: u64, sample_position: u64) -> PacketHeader {
+        let mut sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        if sequence == 0 {
+            sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        }
+        PacketHeader {
+            sequence,
+            pre_emphasis: self.config.pre_emphasis,
+            ..packet_header(epoch_us, sample_position)
+        }
+    }
+
+    // Keep listeners connected with header-only packets until resumed
+    async fn wait_while_paused(&self, epoch_us: u64, position: u64) {
+        log::info!("Sending paused");
+        while self.paused.load(Ordering::Acquire) {
+            let header = self.next_header(epoch_us, position);
+            self.send_to_clients(&encode_packet(&header, &[])).await;
+            let _ = time::timeout(PAUSE_KEEPALIVE_INTERVAL, self.resumed.notified()).await;
+        }
+        log::info!("Sending resumed");
+    }
+
+    /// Stops taking buffers from the source until `resume`, e.g. for a
+    /// break. Unlike `mute` no audio is sent, while discovery carries on and
+    /// clients stay registered: listeners get keepalives so they don't stall,
+    /// and those that found us through discovery are sent `PAUSED`. A live
+    /// source keeps capturing meanwhile, dropping what its channel can't hold.
+    pub async fn pause(&self) {
+        if !self.paused.swap(true, Ordering::AcqRel) {
+            self.notify_listeners("PAUSED", "pause").await;
+        }
+    }
+
+    /// Continues a paused broadcast on a new presentation clock, sending
+    /// `RESUMED` to listeners that found us through discovery.
+    pub async fn resume(&self) {
+        if self.paused.swap(false, Ordering::AcqRel) {
+            self.resumed.notify_one();
+            self.notify_listeners("RESUMED", "resumption").await;
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Whether listeners can find us, false when discovery was turned off in
+    /// the config or because its port was already taken.
+    pub fn discovery_enabled(&self) -> bool {
+        self.config.discovery
+    }
+
+    /// Replaces the config's silence gate, taking effect when sending starts
+    pub fn set_silence_gate(&mut self, gate: Option<SilenceGateConfig>) {
+        self.config.silence_gate = gate;
+    }
+
+    /// Sends silence in place of the audio until `unmute`, e.g. as a privacy
+    /// mute. Packets keep flowing at the usual rate, bypassing the silence
+    /// gate, so listeners stay in sync instead of seeing the stream stall.
+    pub fn mute(&self) {
+        self.muted.store(true, Ordering::Relaxed);
+        log::info!("Sender muted");
+    }
+
+    pub fn unmute(&self) {
+        self.muted.store(false, Ordering::Relaxed);
+        log::info!("Sender unmuted");
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    /// Tells listeners the server is going away so they can stop waiting for
+    /// audio. Call on graceful shutdown, after sending has stopped.
+    pub async fn shutdown(&self) {
+        self.notify_listeners("SERVER_DOWN", "shutdown").await;
+        self.listeners.lock().await.clear();
+        self.multicast_members.lock().await.clear();
+
+        for &ip in &self.broadcast_addrs {
+            let broadcast_addr = SocketAddr::new(ip.into(), self.config.discovery_port);
+            if let Err(e) = self
+                .discovery_socket
+                .send_to(b"SERVER_DOWN", broadcast_addr)
+                .await
+            {
+                log::warn!("Failed to broadcast shutdown to {}: {}", broadcast_addr, e);
+            }
+        }
+        let clients: Vec<_> = self.clients.lock().await.drain().collect();
+        let mut metrics = self.metrics.lock().unwrap();
+        for client in clients {
+            metrics.client_left(client);
+        }
+    }
+
+    async fn send_to_clients(&self, packet: &[u8]) {
+        let members = self.multicast_members.lock().await.clone();
+        let mut clients: Vec<SocketAddr> = self
+            .clients
+            .lock()
+            .await
+            .iter()
+            .filter(|client| !members.contains(client))
+            .copied()
+            .collect();
+        // One copy to the group serves every member
+        if let Some(group) = self.config.multicast_group {
+            if !members.is_empty() {
+                clients.push(SocketAddr::V4(group));
+            }
+        }
+        let destinations: Vec<SocketAddr> = clients
+            .iter()
+            .map(|&client| stream_destination(self.dual_stack, client))
+            .collect();
+        let results =
+            send_to_all(&self.socket, packet, &destinations, self.config.batch_sends).await;
+        let mut metrics = self.metrics.lock().unwrap();
+        for (client, result) in clients.into_iter().zip(results) {
+            if let Err(e) = &result {
+                log::error!("Failed to send to client {}: {}", client, e);
+            }
+            metrics.record_send(client, result.ok());
+        }
+    }
+}
+
+/// Sends `packet` to each destination, returning the outcome for each in
+/// order. With `batched` set, the packets go out in as few `sendmmsg` calls
+/// as possible on Linux and Android, and one `send_to` each elsewhere.
+pub async fn send_to_all(
+    socket: &UdpSocket,
+    packet: &[u8],
+    destinations: &[SocketAddr],
+    batched: bool,
+) -> Vec<std::io::Result<usize>> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if batched {
+        return send_batch(socket, packet, destinations).await;
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = batched;
+
+    let mut results = Vec::with_capacity(destinations.len());
+    for &destination in destinations {
+        results.push(socket.send_to(packet, destination).await);
+    }
+    results
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+async fn send_batch(
+    socket: &UdpSocket,
+    packet: &[u8],
+    destinations: &[SocketAddr],
+) -> Vec<std::io::Result<usize>> {
+    use std::os::unix::io::AsRawFd;
+    let fd = socket.as_raw_fd();
+    let addrs: Vec<socket2::SockAddr> = destinations.iter().map(|&addr| addr.into()).collect();
+
+    // A call stops at the first message that fails, which the next call
+    // then reports, so that one is recorded and skipped
+    let mut results = Vec::with_capacity(addrs.len());
+    while results.len() < addrs.len() {
+        let remaining = &addrs[results.len()..];
+        match socket
+            .async_io(Interest::WRITABLE, || sendmmsg(fd, packet, remaining))
+            .await
+        {
+            Ok(sent) => results.extend(sent.into_iter().map(Ok)),
+            Err(e) => results.push(Err(e)),
+        }
+    }
+    results
+}
+
+// Sends `packet` to as many of `addrs` as one `sendmmsg` call manages,
+// returning the bytes sent to each
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sendmmsg(
+    fd: std::os::unix::io::RawFd,
+    packet: &[u8],
+    addrs: &[socket2::SockAddr],
+) -> std::io::Result<Vec<usize>> {
+    // Sending only reads the payload, so every message can share one iovec
+    let mut iov = libc::iovec {
+        iov_base: packet.as_ptr() as *mut libc::c_void,
+        iov_len: packet.len(),
+    };
+    let mut messages: Vec<libc::mmsghdr> = addrs
+        .iter()
+        .map(|addr| {
+            let mut message: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            message.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            message.msg_hdr.msg_namelen = addr.len();
+            message.msg_hdr.msg_iov = &mut iov;
+            message.msg_hdr.msg_iovlen = 1;
+            message
+        })
+        .collect();
+
+    let sent = unsafe { libc::sendmmsg(fd, messages.as_mut_ptr(), messages.len() as _, 0) };
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(messages[..sent as usize]
+        .iter()
+        .map(|message| message.msg_len as usize)
+        .collect())
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+// Most samples, in whole frames, whose PCM packet fits in a datagram of
+// `datagram_size` bytes
+fn max_packet_samples(channels: u16, datagram_size: usize) -> usize {
+    let channels = channels.max(1) as usize;
+    ((datagram_size - HEADER_SIZE) / 4 / channels * channels).max(channels)
+}
+
+#[cfg(test)]
+fn build_packet(epoch_us: u64, sample_position: u64, samples: &[f32]) -> Vec<u8> {
+    encode_packet(&packet_header(epoch_us, sample_position), samples)
+}
+
+pub(crate) fn packet_header(epoch_us: u64, sample_position: u64) -> PacketHeader {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u32;
+
+    PacketHeader {
+        sequence: 0,
+        timestamp_ms: timestamp,
+        epoch_us,
+        sample_position,
+        pre_emphasis: None,
+    }
+}
+
+impl ReceiverConfig {
+    pub fn builder() -> ReceiverConfigBuilder {
+        ReceiverConfigBuilder::default()
+    }
+}
+
+impl Default for ReceiverConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: None,
+            network: NetworkConfig::default(),
+            discovery_port: DISCOVERY_PORT,
+            discovery_addr: Ipv4Addr::BROADCAST,
+            discovery_timeout: DISCOVERY_TIMEOUT,
+            stall_timeout: STALL_TIMEOUT,
+            jitter_buckets: default_jitter_buckets(),
+            sample_rate: 48000,
+            channels: 2,
+            overflow_policy: OverflowPolicy::Block,
+            max_queued: None,
+            reconnect: ReconnectConfig::default(),
+            control_port: None,
+            multicast: true,
+            runtime: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReceiverConfigBuilder {
+    config: ReceiverConfig,
+}
+
+impl ReceiverConfigBuilder {
+    pub fn bind_addr(mut self, addr: impl Into<String>) -> Self {
+        self.config.bind_addr = Some(addr.into());
+        self
+    }
+
+    pub fn network(mut self, network: NetworkConfig) -> Self {
+        self.config.network = network;
+        self
+    }
+
+    pub fn discovery_port(mut self, port: u16) -> Self {
+        self.config.discovery_port = port;
+        self
+    }
+
+    pub fn discovery_addr(mut self, addr: Ipv4Addr) -> Self {
+        self.config.discovery_addr = addr;
+        self
+    }
+
+    pub fn multicast(mut self, enabled: bool) -> Self {
+        self.config.multicast = enabled;
+        self
+    }
+
+    pub fn discovery_timeout(mut self, timeout: Duration) -> Self {
+        self.config.discovery_timeout = timeout;
+        self
+    }
+
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.config.stall_timeout = timeout;
+        self
+    }
+
+    pub fn jitter_buckets(mut self, buckets: Vec<Duration>) -> Self {
+        self.config.jitter_buckets = buckets;
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.config.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn channels(mut self, channels: u16) -> Self {
+        self.config.channels = channels;
+        self
+    }
+
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.config.overflow_policy = policy;
+        self
+    }
+
+    pub fn max_queued(mut self, max: Duration) -> Self {
+        self.config.max_queued = Some(max);
+        self
+    }
+
+    pub fn reconnect(mut self, reconnect: ReconnectConfig) -> Self {
+        self.config.reconnect = reconnect;
+        self
+    }
+
+    pub fn control_port(mut self, port: u16) -> Self {
+        self.config.control_port = Some(port);
+        self
+    }
+
+    pub fn fallback_to_any_port(mut self, fallback: bool) -> Self {
+        self.config.network.fallback_to_any_port = fallback;
+        self
+    }
+
+    pub fn max_datagram_size(mut self, bytes: usize) -> Self {
+        self.config.network.max_datagram_size = bytes;
+        self
+    }
+
+    pub fn runtime(mut self, runtime: &AudioRuntime) -> Self {
+        self.config.runtime = Some(runtime.handle().clone());
+        self
+    }
+
+    pub fn build(self) -> ReceiverConfig {
+        self.config
+    }
+}
+
+impl AudioReceiver {
+    pub async fn new(bind_addr: Option<&str>) -> Result<Self> {
+        Self::with_config(ReceiverConfig {
+            bind_addr: bind_addr.map(str::to_string),
+            ..ReceiverConfig::default()
+        })
+        .await
+    }
+
+    pub async fn with_config(config: ReceiverConfig) -> Result<Self> {
+        // Sockets register with the runtime they're made on
+        match config.runtime.clone() {
+            Some(runtime) => run_on(&runtime, Self::build(config)).await?,
+            None => Self::build(config).await,
+        }
+    }
+
+    async fn build(config: ReceiverConfig) -> Result<Self> {
+        let bind_addr = config
+            .bind_addr
+            .clone()
+            .unwrap_or_else(|| format!("0.0.0.0:{}", DEFAULT_STREAM_PORT));
+
+        let bind_addr = resolve_bind_addr(&bind_addr).await?;
+
+        let control_port = config.control_port.unwrap_or(0);
+        check_port_conflict(bind_addr, control_port, "control")?;
+
+        let socket = Arc::new(bind_stream_socket(bind_addr, &config.network)?);
+
+        // Set up discovery socket
+        let control_addr = format!("0.0.0.0:{}", control_port);
+        let discovery_socket = UdpSocket::bind(&control_addr)
+            .await
+            .map_err(|source| bind_error(&control_addr, source))?;
+        configure_discovery_socket(&discovery_socket, &config.network)?;
+        let discovery_socket = Arc::new(discovery_socket);
+
+        Ok(Self {
+            socket,
+            discovery_socket,
+            server_addr: Arc::new(Mutex::new(None)),
+            stream_source: watch::Sender::new(None),
+            server_format: std::sync::Mutex::new(None),
+            servers: std::sync::Mutex::new(ServerRegistry::default()),
+            state: watch::channel(ConnectionState::Disconnected).0,
+            metrics: Arc::new(std::sync::Mutex::new(ReceiverMetrics::new(
+                config.jitter_buckets.clone(),
+            ))),
+            raw_packets: std::sync::Mutex::new(None),
+            multicast_group: std::sync::Mutex::new(None),
+            unicast_only: std::sync::Mutex::new(HashSet::new()),
+            pull: Mutex::new(None),
+            playback_gauge: std::sync::Mutex::new(None),
+            config,
+        })
+    }
+
+    /// Returns a snapshot of the receive statistics gathered so far.
+    pub fn metrics(&self) -> ReceiverMetrics {
+        let mut metrics = self.metrics.lock().unwrap().clone();
+        if let Some(gauge) = self.playback_gauge.lock().unwrap().as_ref() {
+            metrics.playback_buffered = Some(gauge.level());
+        }
+        metrics
+    }
+
+    /// Writes the statistics gathered so far to `path` as JSON.
+    pub fn export_metrics(&self, path: impl AsRef<Path>) -> Result<()> {
+        write_json(path, &self.metrics())
+    }
+
+    /// Includes the player's buffer level, e.g. from
+    /// `AudioPlayer::buffer_gauge`, in the metrics and their JSON export.
+    pub fn track_playback(&self, gauge: BufferGauge) {
+        *self.playback_gauge.lock().unwrap() = Some(gauge);
+    }
+
+    /// Returns a channel that receives a copy of every datagram before it is
+    /// decoded, for dumping or protocol analysis. Must be called before
+    /// `start_receiving`. Packets are dropped if the channel isn't drained,
+    /// and counted in `ReceiverMetrics::raw_packets_dropped`.
+    pub fn raw_packets(&self) -> mpsc::Receiver<RawPacket> {
+        let (tx, rx) = mpsc::channel(256);
+        *self.raw_packets.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
+
+    /// Returns a watch channel that is notified whenever the connection state changes.
+    pub fn subscribe_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        self.state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state;
+            changed
+        });
+    }
+
+    /// Receives audio into `tx` until the channel closes. See
+    /// `receive_until` and `receive_for` for bounded sessions.
+    pub async fn start_receiving(&self, tx: mpsc::Sender<Vec<f32>>) -> Result<()> {
+        self.receive_loop(AudioOutput::Samples(tx)).await
+    }
+
+    /// Like `start_receiving`, but also returns cleanly once `stop`
+    /// completes, e.g. on a shutdown signal. Returns the statistics gathered
+    /// over the receiver's lifetime.
+    pub async fn receive_until(
+        &self,
+        tx: mpsc::Sender<Vec<f32>>,
+        stop: impl Future<Output = ()>,
+    ) -> Result<ReceiverMetrics> {
+        tokio::select! {
+            result = self.receive_loop(AudioOutput::Samples(tx)) => result?,
+            _ = stop => log::info!("Stopped receiving"),
+        }
+        Ok(self.metrics())
+    }
+
+    /// Receives into `sink` until `stop` completes, then returns it flushed.
+    /// The sink runs on a blocking thread; if it fails, receiving stops and
+    /// its error is returned.
+    pub async fn receive_into<S>(&self, sink: S, stop: impl Future<Output = ()>) -> Result<S>
+    where
+        S: AudioSink + Send + 'static,
+    {
+        let (tx, handle) = spawn_sink(sink);
+        self.receive_until(tx, stop).await?;
+        handle
+            .await
+            .map_err(|e| AudioStreamerError::StreamError(e.to_string()))?
+    }
+
+    /// Receives for at most `duration`, e.g. for time-bounded recordings.
+    pub async fn receive_for(
+        &self,
+        tx: mpsc::Sender<Vec<f32>>,
+        duration: Duration,
+    ) -> Result<ReceiverMetrics> {
+        self.receive_until(tx, time::sleep(duration)).await
+    }
+
+    /// Like `start_receiving`, but tags each buffer with the presentation time
+    /// of its first frame for aligning playback with video.
+    pub async fn start_receiving_timed(&self, tx: mpsc::Sender<TimedBuffer>) -> Result<()> {
+        let sample_rate = self
+            .server_format()
+            .map_or(self.config.sample_rate, |format| format.sample_rate);
+        self.receive_loop(AudioOutput::Timed(tx, sample_rate)).await
+    }
+
+    /// Format the server announced when it was discovered, kept up to date
+    /// by `next_control_message`. `None` before discovery, or for servers
+    /// that don't announce one, which are taken to send PCM at the
+    /// configured `sample_rate` and `channels`, 48kHz stereo by default.
+    pub fn server_format(&self) -> Option<StreamFormat> {
+        *self.server_format.lock().unwrap()
+    }
+
+    /// Everything known about the current server, for showing to users or
+    /// logging. Fails with `ServerNotFound` before discovery.
+    pub async fn session_info(&self) -> Result<SessionInfo> {
+        let server_addr = self.server_addr().await?;
+        let server = self
+            .servers()
+            .into_iter()
+            .find(|server| server.addr == server_addr);
+        let websocket_addr = server
+            .as_ref()
+            .and_then(|server| server.capabilities.as_ref()?.websocket)
+            .map(|port| SocketAddr::new(server_addr.ip(), port));
+        let server_name = server.and_then(|server| server.name);
+        let announced = self.server_format();
+        let format = announced.unwrap_or(StreamFormat {
+            sample_rate: self.config.sample_rate,
+            channels: self.config.channels,
+            codec: Codec::Pcm,
+        });
+        let transport = match server_addr {
+            SocketAddr::V4(_) => Transport::UdpV4,
+            SocketAddr::V6(_) => Transport::UdpV6,
+        };
+        Ok(SessionInfo {
+            server_addr,
+            server_name,
+            format,
+            announced: announced.is_some(),
+            transport,
+            websocket_addr,
+        })
+    }
+
+    /// Waits for the next buffer of audio and returns it, for callers that
+    /// drive receiving themselves instead of handing `start_receiving` a
+    /// channel. Cancel safe, so it can sit in a `select!` alongside other
+    /// work. Don't use it while a channel-based receive is running; both
+    /// read the same socket.
+    pub async fn recv_buffer(&self) -> Result<Vec<f32>> {
+        let mut pull = self.pull.lock().await;
+        let state = pull.get_or_insert_with(|| self.receive_state());
+        loop {
+            if let Received::Audio(_, samples) = self.next_packet(state).await? {
+                return Ok(samples);
+            }
+        }
+    }
+
+    fn receive_state(&self) -> ReceiveState {
+        ReceiveState {
+            // One spare byte shows when a datagram was cut short to fit
+            buf: vec![0u8; self.config.network.max_datagram_size + 1],
+            stream_source: self.stream_source.subscribe(),
+            reported_stranger: false,
+            last_arrival: None,
+            raw_packets: self.raw_packets.lock().unwrap().take(),
+            de_emphasis: None,
+            sequences: SequenceWindow::default(),
+        }
+    }
+
+    async fn receive_loop(&self, output: AudioOutput) -> Result<()> {
+        log::info!("Starting audio receiver on {:?}", self.socket.local_addr()?);
+        let mut state = self.receive_state();
+        let backlog = Backlog::default();
+        let dropping = self.config.overflow_policy == OverflowPolicy::DropOldest;
+
+        let result = tokio::select! {
+            result = self.receive_packets(&mut state, &output, &backlog) => result,
+            () = backlog.forward(&output), if dropping => {
+                log::error!("Failed to send samples to player: channel closed");
+                Ok(())
+            }
+        };
+        // Audio still held back goes to the consumer if there's room for it
+        backlog.flush(&output);
+        result
+    }
+
+    async fn receive_packets(
+        &self,
+        state: &mut ReceiveState,
+        output: &AudioOutput,
+        backlog: &Backlog,
+    ) -> Result<()> {
+        // Size of the latest buffer, to estimate how long the queued ones last
+        let mut packet_samples = 0;
+
+        loop {
+            let (header, samples) = match self.next_packet(state).await? {
+                Received::Audio(header, samples) => (header, samples),
+                Received::Stalled => {
+                    // The consumer keeps draining while nothing arrives
+                    self.record_queued(output.queued() + backlog.len(), packet_samples);
+                    continue;
+                }
+            };
+
+            // Send samples immediately
+            packet_samples = samples.len();
+            match self.config.overflow_policy {
+                OverflowPolicy::Block => {
+                    if !output.deliver(&header, samples).await {
+                        log::error!("Failed to send samples to player: channel closed");
+                        return Ok(());
+                    }
+                }
+                OverflowPolicy::DropOldest => {
+                    backlog.set_limit(self.queue_limit(packet_samples));
+                    let dropped = backlog.push(output, header, samples);
+                    if dropped > 0 {
+                        self.metrics.lock().unwrap().dropped_buffers += dropped as u64;
+                        log::debug!("Player channel full, dropped {} oldest buffers", dropped);
+                    }
+                }
+            }
+            self.record_queued(output.queued() + backlog.len(), packet_samples);
+        }
+    }
+
+    // Reads datagrams until one carries audio from the current server, or
+    // nothing has arrived within the stall timeout, updating the connection
+    // state and statistics on the way
+    async fn next_packet(&self, state: &mut ReceiveState) -> Result<Received> {
+        loop {
+            let (len, source, arrival) = match time::timeout(
+                self.config.stall_timeout,
+                recv_timestamped(&self.socket, &mut state.buf),
+            )
+            .await
+            {
+                Ok(result) => result?,
+                Err(_) => {
+                    if self.state() == ConnectionState::Receiving {
+                        log::warn!("No audio received for {:?}", self.config.stall_timeout);
+                        self.set_state(ConnectionState::Stalled);
+                    }
+                    self.fall_back_to_unicast().await;
+                    return Ok(Received::Stalled);
+                }
+            };
+            self.set_state(ConnectionState::Receiving);
+            // Dual-stack sockets see IPv4 senders as IPv4-mapped addresses
+            let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+
+            // After `switch_to`, audio still arriving from the old server is dropped
+            if state.stream_source.has_changed().unwrap_or(false) {
+                state.reported_stranger = false;
+            }
+            let rejected_by = state
+                .stream_source
+                .borrow_and_update()
+                .as_ref()
+                .filter(|server| !server.accepts(source))
+                .cloned();
+            if let Some(server) = rejected_by {
+                if state.reported_stranger {
+                    log::trace!("Ignoring packet from {}, not the current server", source);
+                } else {
+                    log::debug!(
+                        "Ignoring packets from {}, not the current server's port {} on {:?}",
+                        source,
+                        server.port,
+                        server.addrs
+                    );
+                    state.reported_stranger = true;
+                }
+                continue;
+            }
+
+            // A truncated datagram would decode to a partial frame and swap
+            // the channels of everything after it
+            if len > self.config.network.max_datagram_size {
+                log::debug!(
+                    "Dropping {}+ byte datagram, over the {} byte limit",
+                    len,
+                    self.config.network.max_datagram_size
+                );
+                self.metrics.lock().unwrap().decode_errors += 1;
+                continue;
+            }
+
+            let data = &state.buf[..len];
+            if let Some(raw_tx) = &state.raw_packets {
+                let packet = RawPacket {
+                    data: data.to_vec(),
+                    source,
+                    arrival,
+                };
+                if let Err(TrySendError::Full(_)) = raw_tx.try_send(packet) {
+                    log::debug!("Raw packet channel is full, dropping a copy");
+                    self.metrics.lock().unwrap().raw_packets_dropped += 1;
+                }
+            }
+
+            let (header, mut samples) = match decode_packet(data) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    log::debug!("Dropping packet: {}", e);
+                    self.metrics.lock().unwrap().decode_errors += 1;
+                    continue;
+                }
+            };
+            if state.sequences.is_duplicate(source, header.sequence) {
+                log::trace!("Dropping duplicate of packet {}", header.sequence);
+                self.metrics.lock().unwrap().duplicates += 1;
+                continue;
+            }
+
+            {
+                let mut metrics = self.metrics.lock().unwrap();
+                metrics.packets_received += 1;
+                metrics.bytes_received += len as u64;
+                if let Some(previous) = state.last_arrival {
+                    let gap = arrival.duration_since(previous).unwrap_or_default();
+                    metrics.inter_arrival.record(gap);
+                }
+            }
+            state.last_arrival = Some(arrival);
+
+            // Sender stamps packets with wall-clock milliseconds truncated to u32
+            let sent_ms = header.timestamp_ms;
+            let arrival_ms = arrival
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u32;
+            let latency_ms = arrival_ms.wrapping_sub(sent_ms) as i32;
+            log::trace!("Packet latency: {}ms", latency_ms);
+            self.metrics
+                .lock()
+                .unwrap()
+                .latency
+                .record(Duration::from_millis(latency_ms.max(0) as u64));
+
+            // Header-only packets are keepalives from a silence-gated sender
+            if samples.is_empty() {
+                continue;
+            }
+            match header.pre_emphasis {
+                Some(coefficient) => {
+                    let channels = self
+                        .server_format()
+                        .map_or(self.config.channels, |format| format.channels);
+                    let de_emphasis = match state.de_emphasis.take() {
+                        Some(filter) if filter.matches(coefficient, channels) => filter,
+                        _ => DeEmphasis::new(coefficient, channels),
+                    };
+                    state.de_emphasis.insert(de_emphasis).process(&mut samples);
+                }
+                None => state.de_emphasis = None,
+            }
+            return Ok(Received::Audio(header, samples));
+        }
+    }
+
+    // How many buffers of the latest size fit in `max_queued`, and how long
+    // one lasts
+    fn queue_limit(&self, packet_samples: usize) -> Option<(usize, Duration)> {
+        let max = self.config.max_queued?;
+        let format = self.server_format();
+        let sample_rate = format.map_or(self.config.sample_rate, |format| format.sample_rate);
+        let channels = format.map_or(self.config.channels, |format| format.channels);
+        let packet = BufferLevel::new(1, packet_samples, sample_rate, channels).duration;
+        if packet.is_zero() {
+            return None;
+        }
+        let fits = (max.as_secs_f64() / packet.as_secs_f64()) as usize;
+        Some((fits.max(1), packet))
+    }
+
+    fn record_queued(&self, packets: usize, packet_samples: usize) {
+        let format = self.server_format();
+        let sample_rate = format.map_or(self.config.sample_rate, |format| format.sample_rate);
+        let channels = format.map_or(self.config.channels, |format| format.channels);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.queued = BufferLevel::new(packets, packets * packet_samples, sample_rate, channels);
+        metrics.packet_duration =
+            BufferLevel::new(1, packet_samples, sample_rate, channels).duration;
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub async fn server_addr(&self) -> Result<SocketAddr> {
+        self.server_addr
+            .lock()
+            .await
+            .ok_or_else(|| NetworkError::ServerNotFound.into())
+    }
+
+    /// Tells the server to stop streaming to us. Call on shutdown so the
+    /// sender doesn't keep transmitting to a listener that has gone away.
+    pub async fn leave(&self) -> Result<()> {
+        let server = self.server_addr().await?;
+        let control_addr = SocketAddr::new(server.ip(), self.config.discovery_port);
+        self.discovery_socket
+            .send_to(b"LEAVE", control_addr)
+            .await
+            .map_err(|source| NetworkError::SendFailed {
+                addr: control_addr,
+                source,
+            })?;
+        self.leave_multicast();
+        self.set_state(ConnectionState::Disconnected);
+        Ok(())
+    }
+
+    /// Listens on the discovery socket until the current server announces it
+    /// is shutting down, then forgets it and reports `Disconnected`.
+    pub async fn wait_for_server_down(&self) -> Result<()> {
+        loop {
+            if self.next_control_message().await? == ControlMessage::ServerDown {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Waits for the next control message from the server. Use this instead
+    /// of `wait_for_server_down` to also hear about format changes; both
+    /// read the same socket, so don't run them at the same time.
+    pub async fn next_control_message(&self) -> Result<ControlMessage> {
+        let server = self.server_addr().await?;
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (len, addr) = self.discovery_socket.recv_from(&mut buf).await?;
+            // Other senders' scan replies and shutdowns keep `servers` current
+            let seen = self.servers.lock().unwrap().record(addr, &buf[..len]);
+            if let Some(seen) = seen {
+                // The current server answering from another of its addresses
+                self.stream_source.send_if_modified(|source| {
+                    source.as_mut().is_some_and(|source| {
+                        source.is_same_server(&seen) && source.add(seen.addr.ip())
+                    })
+                });
+            }
+            if addr.ip() != server.ip() {
+                continue;
+            }
+            if &buf[..len] == b"SERVER_DOWN" {
+                log::info!("Server {} is shutting down", server);
+                *self.server_addr.lock().await = None;
+                self.stream_source.send_replace(None);
+                self.set_state(ConnectionState::Disconnected);
+                return Ok(ControlMessage::ServerDown);
+            }
+            match &buf[..len] {
+                b"PAUSED" => return Ok(ControlMessage::Paused),
+                b"RESUMED" => return Ok(ControlMessage::Resumed),
+                _ => {}
+            }
+            if let Some(format) = StreamFormat::parse_message(&buf[..len]) {
+                // Announcements repeat the format alongside every SERVER message
+                let previous = self.server_format.lock().unwrap().replace(format);
+                if previous == Some(format) {
+                    continue;
+                }
+                log::info!("Server {} changed format: {:?}", server, format);
+                return Ok(ControlMessage::FormatChanged(format));
+            }
+        }
+    }
+
+    /// Sends `count` timestamped pings to the server's discovery port and
+    /// measures the round trip of each echoed reply.
+    pub async fn ping(&self, server: IpAddr, count: u32) -> Result<PingStats> {
+        let server_addr = SocketAddr::new(server, self.config.discovery_port);
+        let start = Instant::now();
+        let mut rtts = Vec::with_capacity(count as usize);
+        let mut buf = [0u8; 64];
+
+        for seq in 0..count {
+            let sent_us = start.elapsed().as_micros();
+            let request = format!("PING:{}:{}", seq, sent_us);
+            self.discovery_socket
+                .send_to(request.as_bytes(), server_addr)
+                .await
+                .map_err(|source| NetworkError::SendFailed {
+                    addr: server_addr,
+                    source,
+                })?;
+
+            let deadline = time::sleep(PING_TIMEOUT);
+            tokio::pin!(deadline);
+
+            loop {
+                tokio::select! {
+                    result = self.discovery_socket.recv_from(&mut buf) => {
+                        let (len, from) = result?;
+                        // Anyone on the network can reach the discovery socket
+                        if (from.ip().to_canonical(), from.port())
+                            != (server.to_canonical(), server_addr.port())
+                        {
+                            continue;
+                        }
+                        let response = String::from_utf8_lossy(&buf[..len]);
+                        let Some((reply_seq, reply_us)) = response
+                            .strip_prefix("PONG:")
+                            .and_then(|payload| payload.split_once(':'))
+                        else {
+                            continue;
+                        };
+                        // Ignore late replies to earlier pings
+                        if reply_seq.parse::<u32>().ok() != Some(seq) {
+                            continue;
+                        }
+                        // A timestamp we haven't reached yet isn't our ping's echo
+                        let Some(rtt) = reply_us
+                            .trim()
+                            .parse::<u64>()
+                            .ok()
+                            .and_then(|reply_us| {
+                                start.elapsed().checked_sub(Duration::from_micros(reply_us))
+                            })
+                        else {
+                            continue;
+                        };
+                        log::info!("Ping {} to {}: {:?}", seq, server, rtt);
+                        rtts.push(rtt);
+                        break;
+                    }
+                    _ = &mut deadline => {
+                        log::warn!("Ping {} to {} timed out", seq, server);
+                        break;
+                    }
+                }
+            }
+
+            time::sleep(PING_INTERVAL).await;
+        }
+
+        if rtts.is_empty() {
+            return Err(NetworkError::NoPingReplies(server).into());
+        }
+
+        Ok(PingStats {
+            sent: count,
+            received: rtts.len() as u32,
+            min: *rtts.iter().min().unwrap(),
+            avg: rtts.iter().sum::<Duration>() / rtts.len() as u32,
+            max: *rtts.iter().max().unwrap(),
+        })
+    }
+
+    /// Measures what the link to the server can carry by sending probe
+    /// packets to its discovery port at each rate in `config.rates`, then
+    /// asking the server how many arrived. Stops early if the server stops
+    /// answering, e.g. once the link is saturated.
+    pub async fn bench(&self, server: IpAddr, config: &BenchConfig) -> Result<BenchReport> {
+        if !(16..=MAX_DATAGRAM_SIZE).contains(&config.packet_size) {
+            return Err(AudioStreamerError::ConfigError(format!(
+                "Bench packet size must be 16-{} bytes",
+                MAX_DATAGRAM_SIZE
+            )));
+        }
+
+        let server_addr = SocketAddr::new(server, self.config.discovery_port);
+        let packet_bits = (config.packet_size * 8) as f64;
+        let mut packet = vec![0u8; config.packet_size];
+        let mut steps = Vec::with_capacity(config.rates.len());
+
+        for (step, &rate) in config.rates.iter().enumerate() {
+            let header = format!("BENCH:{}:", step);
+            packet[..header.len()].copy_from_slice(header.as_bytes());
+
+            // Timers are too coarse to space packets individually, so each
+            // tick sends whatever the target rate says is due
+            let start = Instant::now();
+            let mut ticker = time::interval(Duration::from_millis(1));
+            let mut sent = 0u64;
+            loop {
+                ticker.tick().await;
+                let elapsed = start.elapsed().min(config.step_duration);
+                let due = (elapsed.as_secs_f64() * rate as f64 / packet_bits) as u64;
+                while sent < due {
+                    // A full send buffer is loss like any other
+                    if let Err(e) = self.discovery_socket.send_to(&packet, server_addr).await {
+                        log::debug!("Bench probe to {} failed: {}", server_addr, e);
+                    }
+                    sent += 1;
+                }
+                if elapsed == config.step_duration {
+                    break;
+                }
+            }
+            time::sleep(BENCH_DRAIN).await;
+
+            let Some((received, bytes)) = self.bench_result(server_addr, step).await? else {
+                log::warn!("No bench result from {} at {} bit/s", server, rate);
+                break;
+            };
+            let result = BenchStep {
+                target_bps: rate,
+                packets_sent: sent,
+                packets_received: received,
+                received_bps: (bytes as f64 * 8.0 / config.step_duration.as_secs_f64()) as u64,
+            };
+            log::info!(
+                "Bench to {} at {} bit/s: {:.1}% loss",
+                server,
+                rate,
+                result.loss() * 100.0
+            );
+            steps.push(result);
+        }
+
+        if steps.is_empty() {
+            return Err(NetworkError::NoBenchReplies(server).into());
+        }
+        Ok(BenchReport { steps })
+    }
+
+    // Asks the server how many probes of `step` it received, as
+    // (packets, bytes); `None` when it doesn't answer
+    async fn bench_result(
+        &self,
+        server_addr: SocketAddr,
+        step: usize,
+    ) -> Result<Option<(u64, u64)>> {
+        let request = format!("BENCH_END:{}", step);
+        let prefix = format!("BENCH_RESULT:{}:", step);
+        let mut buf = [0u8; 64];
+
+        for _ in 0..BENCH_RESULT_ATTEMPTS {
+            self.discovery_socket
+                .send_to(request.as_bytes(), server_addr)
+                .await
+                .map_err(|source| NetworkError::SendFailed {
+                    addr: server_addr,
+                    source,
+                })?;
+
+            let deadline = time::sleep(PING_TIMEOUT);
+            tokio::pin!(deadline);
+
+            loop {
+                tokio::select! {
+                    result = self.discovery_socket.recv_from(&mut buf) => {
+                        let (len, addr) = result?;
+                        if addr != server_addr {
+                            continue;
+                        }
+                        let response = String::from_utf8_lossy(&buf[..len]);
+                        let Some((packets, bytes)) = response
+                            .strip_prefix(prefix.as_str())
+                            .and_then(|counts| counts.split_once(':'))
+                        else {
+                            continue;
+                        };
+                        if let (Ok(packets), Ok(bytes)) = (packets.parse(), bytes.trim().parse()) {
+                            return Ok(Some((packets, bytes)));
+                        }
+                    }
+                    _ = &mut deadline => break,
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Repeats `discover_server` until a server answers, waiting between
+    /// failures as set by `ReceiverConfig::reconnect`. While waiting the
+    /// state is `ConnectionState::Backoff`. Returns the last error once
+    /// `max_attempts` is reached.
+    pub async fn reconnect(&self) -> Result<()> {
+        // Retrying can't fix the socket family
+        check_discovery_family(self.socket.local_addr()?)?;
+        let policy = &self.config.reconnect;
+        let mut attempt = 0;
+        loop {
+            let error = match self.discover_server().await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            attempt += 1;
+            if policy.max_attempts.is_some_and(|max| attempt >= max) {
+                self.set_state(ConnectionState::Disconnected);
+                return Err(error);
+            }
+
+            let retry_in = policy.jittered_delay(attempt - 1);
+            log::info!(
+                "Discovery attempt {} failed ({}), retrying in {:?}",
+                attempt,
+                error,
+                retry_in
+            );
+            self.set_state(ConnectionState::Backoff { attempt, retry_in });
+            time::sleep(retry_in).await;
+        }
+    }
+
+    /// Asks every sender on the network to describe itself and collects the
+    /// replies for `duration`, without registering with any of them or
+    /// changing the connection state. Useful to check that a sender is
+    /// visible before listening.
+    pub async fn discover_servers(&self, duration: Duration) -> Result<Vec<DiscoveredServer>> {
+        let broadcast_addr = SocketAddr::new(
+            IpAddr::V4(self.config.discovery_addr),
+            self.config.discovery_port,
+        );
+        self.scan(broadcast_addr, duration).await
+    }
+
+    async fn scan(&self, target: SocketAddr, duration: Duration) -> Result<Vec<DiscoveredServer>> {
+        self.discovery_socket
+            .send_to(b"SCAN", target)
+            .await
+            .map_err(|source| NetworkError::SendFailed {
+                addr: target,
+                source,
+            })?;
+
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let deadline = time::sleep(duration);
+        tokio::pin!(deadline);
+        let mut servers: Vec<DiscoveredServer> = Vec::new();
+
+        loop {
+            tokio::select! {
+                result = self.discovery_socket.recv_from(&mut buf) => {
+                    let (len, addr) = match result {
+                        Ok(received) => received,
+                        Err(e) => {
+                            log::debug!("Scan receive error: {}", e);
+                            continue;
+                        }
+                    };
+                    let server = self.servers.lock().unwrap().record(addr, &buf[..len]);
+                    if let Some(server) = server {
+                        if !servers.iter().any(|found| found.addr == server.addr) {
+                            servers.push(server);
+                        }
+                    }
+                }
+                _ = &mut deadline => break,
+            }
+        }
+        Ok(servers)
+    }
+
+    /// Senders heard from so far, kept up to date whenever the discovery
+    /// socket is read: by discovery, scans and `next_control_message`.
+    /// Senders that announce their shutdown are removed.
+    pub fn servers(&self) -> Vec<DiscoveredServer> {
+        self.servers.lock().unwrap().servers.clone()
+    }
+
+    /// Asks every sender on the network to describe itself without waiting
+    /// for the replies, which are added to `servers` as a loop on
+    /// `next_control_message` reads them.
+    pub async fn refresh_servers(&self) -> Result<()> {
+        let broadcast_addr = SocketAddr::new(
+            IpAddr::V4(self.config.discovery_addr),
+            self.config.discovery_port,
+        );
+        self.discovery_socket
+            .send_to(b"SCAN", broadcast_addr)
+            .await
+            .map_err(|source| NetworkError::SendFailed {
+                addr: broadcast_addr,
+                source,
+            })?;
+        Ok(())
+    }
+
+    /// Leaves the current server and registers with the one streaming from
+    /// `server`, e.g. one listed by `servers`. Audio still arriving from the
+    /// old server is dropped from then on, but whatever was already passed
+    /// on stays queued, so flush the player, or reconfigure it if
+    /// `server_format` changed.
+    pub async fn switch_to(&self, server: SocketAddr) -> Result<()> {
+        let current = *self.server_addr.lock().await;
+        if current == Some(server) {
+            return Ok(());
+        }
+        if current.is_some() {
+            self.leave().await?;
+        }
+        let control_addr = SocketAddr::new(server.ip(), self.config.discovery_port);
+        self.discover_at(control_addr, Some(server)).await?;
+        log::info!("Switched to server {}", server);
+        Ok(())
+    }
+
+    pub async fn discover_server(&self) -> Result<()> {
+        let broadcast_addr = SocketAddr::new(
+            IpAddr::V4(self.config.discovery_addr),
+            self.config.discovery_port,
+        );
+        self.discover_at(broadcast_addr, None).await
+    }
+
+    // Registers with the first server to answer a DISCOVER sent to `target`,
+    // or only with `expected` when given
+    async fn discover_at(&self, target: SocketAddr, expected: Option<SocketAddr>) -> Result<()> {
+        check_discovery_family(self.socket.local_addr()?)?;
+        self.set_state(ConnectionState::Discovering);
+
+        // Send discovery request
+        let request = "DISCOVER";
+        self.discovery_socket
+            .send_to(request.as_bytes(), target)
+            .await
+            .map_err(|source| NetworkError::SendFailed {
+                addr: target,
+                source,
+            })?;
+
+        // Wait for server response
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let timeout = time::sleep(self.config.discovery_timeout);
+        tokio::pin!(timeout);
+        // Last server passed over for being incompatible, reported on timeout
+        let mut incompatible = None;
+
+        loop {
+            tokio::select! {
+                result = self.discovery_socket.recv_from(&mut buf) => {
+                    match result {
+                        Ok((len, addr)) => {
+                            if &buf[..len] == b"REJECTED" {
+                                self.set_state(ConnectionState::Disconnected);
+                                return Err(NetworkError::Rejected(addr).into());
+                            }
+                            let server = self.servers.lock().unwrap().record(addr, &buf[..len]);
+                            let Some(server) = server else {
+                                continue;
+                            };
+                            if expected.is_some_and(|expected| expected != server.addr) {
+                                continue;
+                            }
+                            let reason = server
+                                .capabilities
+                                .as_ref()
+                                .and_then(Capabilities::incompatibility);
+                            if let Some(reason) = reason {
+                                log::warn!("Skipping server {}: {}", server.addr, reason);
+                                incompatible = Some(NetworkError::Incompatible {
+                                    addr: server.addr,
+                                    reason,
+                                });
+                                continue;
+                            }
+                            *self.server_addr.lock().await = Some(server.addr);
+                            self.stream_source.send_if_modified(|source| match source {
+                                Some(source) if source.is_same_server(&server) => {
+                                    source.add(server.addr.ip())
+                                }
+                                _ => {
+                                    *source = Some(StreamSource::new(&server));
+                                    true
+                                }
+                            });
+                            *self.server_format.lock().unwrap() = server.format;
+                            self.join_multicast(&server, addr).await;
+                            self.set_state(ConnectionState::Connected);
+                            break;
+                        }
+                        Err(e) => log::error!("Discovery receive error: {}", e),
+                    }
+                }
+                _ = &mut timeout => {
+                    self.set_state(ConnectionState::Disconnected);
+                    return Err(incompatible.unwrap_or(NetworkError::DiscoveryTimeout).into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Receives `server` through its multicast group when it offers one, then
+    // tells it through `control_addr` to stop sending our own copy
+    async fn join_multicast(&self, server: &DiscoveredServer, control_addr: SocketAddr) {
+        self.leave_multicast();
+        let group = match &server.capabilities {
+            Some(capabilities) if self.config.multicast => capabilities.multicast,
+            _ => None,
+        };
+        let Some(group) = group else {
+            return;
+        };
+        if self.unicast_only.lock().unwrap().contains(&server.addr) {
+            log::debug!("Staying on unicast: group {} didn't reach us before", group);
+            return;
+        }
+        let bound = self.socket.local_addr().ok();
+        if bound != Some(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), group.port())) {
+            log::warn!(
+                "Staying on unicast: multicast group {} needs the stream socket bound to 0.0.0.0:{}",
+                group,
+                group.port()
+            );
+            return;
+        }
+        let interface = interface_towards(server.addr.ip());
+        if let Err(e) = self.socket.join_multicast_v4(*group.ip(), interface) {
+            log::warn!(
+                "Staying on unicast: failed to join multicast group {}: {}",
+                group,
+                e
+            );
+            return;
+        }
+        *self.multicast_group.lock().unwrap() = Some((*group.ip(), interface, control_addr));
+        if let Err(e) = self
+            .discovery_socket
+            .send_to(b"MULTICAST", control_addr)
+            .await
+        {
+            log::warn!("Failed to tell {} about multicast: {}", server.addr, e);
+        }
+        log::info!(
+            "Receiving {} through multicast group {}",
+            server.addr,
+            group
+        );
+    }
+
+    // Called on a stall: if we're in a group, its traffic may not be reaching
+    // us, so leave it and register again, which restores our unicast copy
+    async fn fall_back_to_unicast(&self) {
+        let Some((_, _, control_addr)) = *self.multicast_group.lock().unwrap() else {
+            return;
+        };
+        let Some(server) = *self.server_addr.lock().await else {
+            return;
+        };
+        self.leave_multicast();
+        self.unicast_only.lock().unwrap().insert(server);
+        log::warn!("No audio through multicast, asking {} for unicast", server);
+        if let Err(e) = self
+            .discovery_socket
+            .send_to(b"DISCOVER", control_addr)
+            .await
+        {
+            log::warn!("Failed to ask {} for unicast: {}", server, e);
+        }
+    }
+
+    fn leave_multicast(&self) {
+        if let Some((group, interface, _)) = self.multicast_group.lock().unwrap().take() {
+            if let Err(e) = self.socket.leave_multicast_v4(group, interface) {
+                log::debug!("Failed to leave multicast group {}: {}", group, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{AudioCapture, CaptureConfig};
+    use crate::sink::BufferSink;
+    use crate::source::{FileSource, SineSource};
+
+    // Sender on loopback with ephemeral ports that never announces itself
+    // unasked, with `configure` applied on top
+    async fn loopback_sender(
+        configure: impl FnOnce(SenderConfigBuilder) -> SenderConfigBuilder,
+    ) -> AudioSender {
+        let config = SenderConfig::builder()
+            .bind_addr("127.0.0.1:0")
+            .discovery_port(0)
+            .discovery_interval(Duration::from_secs(3600));
+        AudioSender::with_config(configure(config).build())
+            .await
+            .unwrap()
+    }
+
+    // Receiver on loopback with an ephemeral port, with `configure` applied on top
+    async fn loopback_receiver(
+        configure: impl FnOnce(ReceiverConfigBuilder) -> ReceiverConfigBuilder,
+    ) -> Arc<AudioReceiver> {
+        let config = ReceiverConfig::builder().bind_addr("127.0.0.1:0");
+        Arc::new(
+            AudioReceiver::with_config(configure(config).build())
+                .await
+                .unwrap(),
+        )
+    }
+
+    // Sender and receiver on loopback with ephemeral ports; discovery is bypassed
+    async fn loopback_pair() -> (AudioSender, Arc<AudioReceiver>) {
+        loopback_pair_with(|config| config, |config| config).await
+    }
+
+    // `loopback_pair` with `sender` and `receiver` applied to their configs
+    async fn loopback_pair_with(
+        sender: impl FnOnce(SenderConfigBuilder) -> SenderConfigBuilder,
+        receiver: impl FnOnce(ReceiverConfigBuilder) -> ReceiverConfigBuilder,
+    ) -> (AudioSender, Arc<AudioReceiver>) {
+        let sender = loopback_sender(sender).await;
+        let receiver = loopback_receiver(receiver).await;
+        sender.add_client(receiver.local_addr().unwrap()).await;
+        (sender, receiver)
+    }
+
+    #[tokio::test]
+    async fn sine_survives_loopback_round_trip() {
+        let (sender, receiver) = loopback_pair().await;
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        // 360 samples keeps each packet within MAX_DATAGRAM_SIZE
+        let source = SineSource::new(440.0, 0.5, 48000, 2).with_buffer_size(360);
+        let mut expected = source.clone();
+        tokio::spawn(async move { sender.start_sending(source.spawn()).await });
+
+        for _ in 0..10 {
+            let received = time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("timed out waiting for audio")
+                .unwrap();
+            let reference = expected.next_buffer();
+            assert_eq!(received.len(), reference.len());
+            for (a, b) in received.iter().zip(&reference) {
+                assert!((a - b).abs() < 1e-6);
+            }
+        }
+        assert_eq!(receiver.state(), ConnectionState::Receiving);
+
+        let metrics = receiver.metrics();
+        assert!(metrics.packets_received >= 10);
+        assert_eq!(metrics.inter_arrival.total(), metrics.packets_received - 1);
+    }
+
+    #[tokio::test]
+    async fn f32_capture_reaches_the_sink_bit_exact() {
+        let (sender, receiver) = loopback_pair().await;
+        let sink = BufferSink::new();
+        let (tx, _sink) = spawn_sink(sink.clone());
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        // Values an i16 or a rounding step would change, then pseudo-random
+        // ones across the full range
+        let mut samples = vec![
+            -1.0,
+            1.0 - f32::EPSILON,
+            -0.0,
+            f32::MIN_POSITIVE,
+            f32::MIN_POSITIVE / 3.0,
+            1e-30,
+            0.1,
+            1.0 / 3.0,
+        ];
+        let mut state = 0x9e37_79b9u32;
+        while samples.len() < 1080 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            samples.push(state as f32 / u32::MAX as f32 * 2.0 - 1.0);
+        }
+
+        // Through the same processing as the input callback of a stereo
+        // device delivering f32 at the capture rate
+        let capture = AudioCapture::with_config(CaptureConfig {
+            buffer_size: 360,
+            ..CaptureConfig::default()
+        })
+        .unwrap();
+        let mut processor = capture.input_processor(48000, 2);
+        let mut captured = Vec::new();
+        processor.process(&samples, |buffer| captured.push(buffer));
+        let (source_tx, source_rx) = mpsc::channel(8);
+        tokio::spawn(async move { sender.start_sending(source_rx).await });
+        for buffer in captured {
+            source_tx.send(buffer).await.unwrap();
+        }
+
+        let received = time::timeout(Duration::from_secs(2), async {
+            loop {
+                let received = sink.samples();
+                if received.len() >= samples.len() {
+                    return received;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for audio");
+        let bits = |samples: &[f32]| samples.iter().map(|s| s.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&received), bits(&samples));
+    }
+
+    #[tokio::test]
+    async fn sending_finishes_when_the_source_ends() {
+        let (sender, receiver) = loopback_pair().await;
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        let samples: Vec<f32> = (0..1080).map(|i| i as f32 / 1080.0).collect();
+        let source = FileSource::new(samples.clone(), 48000, 2).with_buffer_size(360);
+        time::timeout(Duration::from_secs(2), sender.start_sending(source))
+            .await
+            .expect("sender outlived its source")
+            .unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < samples.len() {
+            let buffer = time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("timed out waiting for audio")
+                .unwrap();
+            received.extend(buffer);
+        }
+        assert_eq!(received, samples);
+    }
+
+    #[tokio::test]
+    async fn metrics_show_audio_waiting_for_the_consumer() {
+        let (sender, receiver) = loopback_pair().await;
+        // Nobody drains this channel, so every buffer stays queued
+        let (tx, _rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        let (source_tx, source_rx) = mpsc::channel(8);
+        tokio::spawn(async move { sender.start_sending(source_rx).await });
+        for _ in 0..3 {
+            source_tx.send(vec![0.5; 360]).await.unwrap();
+        }
+
+        time::timeout(Duration::from_secs(2), async {
+            while receiver.metrics().queued.packets < 3 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("buffers never queued");
+        // Three 360-sample stereo buffers at 48kHz
+        assert_eq!(
+            receiver.metrics().queued.duration,
+            Duration::from_micros(11_250)
+        );
+    }
+
+    #[tokio::test]
+    async fn unannounced_streams_are_measured_in_the_configured_format() {
+        // Never discovered, so the sender's format is never announced
+        let (sender, receiver) = loopback_pair_with(
+            |config| config,
+            |config| config.sample_rate(16_000).channels(1),
+        )
+        .await;
+        let (tx, _rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+        let (source_tx, source_rx) = mpsc::channel(8);
+        tokio::spawn(async move { sender.start_sending(source_rx).await });
+        for _ in 0..3 {
+            source_tx.send(vec![0.5; 360]).await.unwrap();
+        }
+
+        time::timeout(Duration::from_secs(2), async {
+            while receiver.metrics().queued.packets < 3 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("buffers never queued");
+        assert_eq!(receiver.server_format(), None);
+        // Three 360-sample mono buffers at 16kHz
+        assert_eq!(
+            receiver.metrics().queued.duration,
+            Duration::from_micros(67_500)
+        );
+    }
+
+    #[tokio::test]
+    async fn receive_for_returns_stats_after_the_duration() {
+        let (sender, receiver) = loopback_pair().await;
+        let source = SineSource::new(440.0, 0.5, 48000, 2).with_buffer_size(360);
+        tokio::spawn(async move { sender.start_sending(source.spawn()).await });
+
+        let (tx, mut rx) = mpsc::channel(256);
+        let start = Instant::now();
+        let metrics = time::timeout(
+            Duration::from_secs(2),
+            receiver.receive_for(tx, Duration::from_millis(200)),
+        )
+        .await
+        .expect("receive_for didn't stop")
+        .unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(metrics.packets_received > 0);
+        // The output channel is closed once receiving stops
+        let mut buffers = 0;
+        while rx.recv().await.is_some() {
+            buffers += 1;
+        }
+        assert_eq!(buffers, metrics.packets_received);
+    }
+
+    #[tokio::test]
+    async fn buffers_can_be_pulled_one_at_a_time() {
+        let (sender, receiver) = loopback_pair().await;
+
+        // Giving up on a pull loses nothing
+        let abandoned = time::timeout(Duration::from_millis(50), receiver.recv_buffer()).await;
+        assert!(abandoned.is_err());
+
+        let (source_tx, source_rx) = mpsc::channel(8);
+        tokio::spawn(async move { sender.start_sending(source_rx).await });
+        for level in [0.25, 0.5] {
+            source_tx.send(vec![level; 360]).await.unwrap();
+        }
+        for level in [0.25, 0.5] {
+            let received = time::timeout(Duration::from_secs(2), receiver.recv_buffer())
+                .await
+                .expect("timed out waiting for audio")
+                .unwrap();
+            assert_eq!(received, vec![level; 360]);
+        }
+        assert_eq!(receiver.metrics().packets_received, 2);
+    }
+
+    #[tokio::test]
+    async fn received_audio_is_written_to_sinks() {
+        let (sender, receiver) = loopback_pair().await;
+        let (source_tx, source_rx) = mpsc::channel(1);
+        tokio::spawn(async move { sender.start_sending(source_rx).await });
+
+        let sink = BufferSink::new();
+        let receiving = receiver.receive_into(sink.clone(), async {
+            source_tx.send(vec![0.25; 360]).await.unwrap();
+            // Give the packet time to arrive before stopping
+            time::sleep(Duration::from_millis(200)).await;
+        });
+        time::timeout(Duration::from_secs(2), receiving)
+            .await
+            .expect("receive_into didn't stop")
+            .unwrap();
+        assert_eq!(sink.samples(), vec![0.25; 360]);
+    }
+
+    #[tokio::test]
+    async fn oversized_buffers_are_split_into_datagram_sized_packets() {
+        let (sender, receiver) = loopback_pair().await;
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        let buffer: Vec<f32> = (0..1000).map(|i| i as f32 / 1000.0).collect();
+        let (source_tx, source_rx) = mpsc::channel(1);
+        source_tx.send(buffer.clone()).await.unwrap();
+        drop(source_tx);
+        sender.start_sending(source_rx).await.unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < buffer.len() {
+            let chunk = time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("timed out waiting for audio")
+                .unwrap();
+            assert!(chunk.len() <= max_packet_samples(2, MAX_DATAGRAM_SIZE));
+            assert_eq!(chunk.len() % 2, 0);
+            received.extend(chunk);
+        }
+        assert_eq!(received, buffer);
+        assert_eq!(sender.metrics().packets_sent, 3);
+    }
+
+    #[tokio::test]
+    async fn jumbo_datagrams_carry_larger_packets() {
+        let (sender, receiver) = loopback_pair_with(
+            |config| config.max_datagram_size(MAX_JUMBO_DATAGRAM_SIZE),
+            |config| config.max_datagram_size(MAX_JUMBO_DATAGRAM_SIZE),
+        )
+        .await;
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        let (source_tx, source_rx) = mpsc::channel(1);
+        source_tx.send(vec![0.5; 2000]).await.unwrap();
+        drop(source_tx);
+        sender.start_sending(source_rx).await.unwrap();
+
+        let received = time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("timed out waiting for audio")
+            .unwrap();
+        assert_eq!(received.len(), 2000);
+        assert_eq!(sender.metrics().packets_sent, 1);
+
+        let oversized = ReceiverConfig::builder()
+            .bind_addr("127.0.0.1:0")
+            .max_datagram_size(65_507)
+            .build();
+        assert!(matches!(
+            AudioReceiver::with_config(oversized).await,
+            Err(AudioStreamerError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn datagrams_over_the_limit_are_dropped() {
+        let (sender, receiver) = loopback_pair().await;
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        // 2001 samples is more than a default-sized listener can take
+        sender
+            .send_to_clients(&build_packet(0, 0, &[0.5; 2001]))
+            .await;
+        sender
+            .send_to_clients(&build_packet(0, 0, &[0.25, -0.25]))
+            .await;
+
+        let received = time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("timed out waiting for audio")
+            .unwrap();
+        assert_eq!(received, vec![0.25, -0.25]);
+        assert_eq!(receiver.metrics().decode_errors, 1);
+    }
+
+    #[tokio::test]
+    async fn packets_carry_the_configured_number_of_samples() {
+        let (sender, receiver) =
+            loopback_pair_with(|config| config.samples_per_packet(101), |config| config).await;
+        let (tx, mut rx) = mpsc::channel(32);
+        tokio::spawn(async move { receiver.start_receiving(tx).await });
+
+        // Odd sizes are rounded down to whole stereo frames
+        let (source_tx, source_rx) = mpsc::channel(8);
+        tokio::spawn(async move { sender.start_sending(source_rx).await });
+        for _ in 0..3 {
+            source_tx.send(vec![0.25; 360]).await.unwrap();
+        }
+
+        for _ in 0..10 {
+            let received = time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("timed out waiting for audio")
+                .unwrap();
+            assert_eq!(received.len(), 100);
+        }
+    }
+
+    #[tokio::test]
+    async fn muted_senders_keep_sending_silence() {
+        let (sender, receiver) = loopback_pair_with(
+            |config| {
+                // Would swallow the silence if muting didn't bypass it
+                config.silence_gate(SilenceGateConfig {
+                    hold: Duration::ZERO,
+                    keepalive_interval: None,
+                    ..Default::default()
+                })
+            },
+            |config| config,
+        )
+        .await;
+        let sender = Arc::new(sender);
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+        let (source_tx, source_rx) = mpsc::channel(32);
+        let sending = sender.clone();
+        tokio::spawn(async move { sending.start_sending(source_rx).await });
+
+        sender.mute();
+        assert!(sender.is_muted());
+        for expected in [0.0, 0.5] {
+            source_tx.send(vec![0.5; 360]).await.unwrap();
+            let received = time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("timed out waiting for audio")
+                .unwrap();
+            assert_eq!(received, vec![expected; 360]);
+            sender.unmute();
+        }
+    }
+
+    #[tokio::test]
+    async fn ping_ignores_replies_from_other_hosts_and_future_timestamps() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_port = server.local_addr().unwrap().port();
+        let receiver = loopback_receiver(|config| config.discovery_port(server_port)).await;
+        let listener = SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            receiver.discovery_socket.local_addr().unwrap().port(),
+        );
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, _) = server.recv_from(&mut buf).await.unwrap();
+            let payload = std::str::from_utf8(&buf[..len])
+                .unwrap()
+                .strip_prefix("PING:")
+                .unwrap()
+                .to_string();
+            // A stranger answering first, then the server echoing from the
+            // future, before the real reply arrives late enough to tell apart
+            let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            stranger.send_to(b"PONG:0:1", listener).await.unwrap();
+            server
+                .send_to(format!("PONG:0:{}", u64::MAX / 2).as_bytes(), listener)
+                .await
+                .unwrap();
+            time::sleep(Duration::from_millis(50)).await;
+            server
+                .send_to(format!("PONG:{}", payload).as_bytes(), listener)
+                .await
+                .unwrap();
+        });
+
+        let stats = receiver.ping(Ipv4Addr::LOCALHOST.into(), 1).await.unwrap();
+        assert_eq!(stats.received, 1);
+        assert!(stats.min >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn paused_senders_keep_listeners_connected() {
+        let sender = Arc::new(loopback_sender(|config| config).await);
+        let discovery_port = sender.discovery_socket.local_addr().unwrap().port();
+        let control_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), discovery_port);
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        listener.send_to(b"DISCOVER", control_addr).await.unwrap();
+        let mut buf = [0u8; 128];
+        for _ in 0..3 {
+            listener.recv_from(&mut buf).await.unwrap();
+        }
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.add_client(client.local_addr().unwrap()).await;
+
+        // Paused before sending starts, so the source is never read
+        sender.pause().await;
+        assert!(sender.is_paused());
+        let (len, _) = listener.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"PAUSED");
+        let (source_tx, source_rx) = mpsc::channel(8);
+        let sending = sender.clone();
+        tokio::spawn(async move { sending.start_sending(source_rx).await });
+        source_tx.send(vec![0.5; 360]).await.unwrap();
+
+        async fn next_samples(client: &UdpSocket) -> Vec<f32> {
+            let mut packet = vec![0u8; MAX_DATAGRAM_SIZE];
+            let (len, _) = time::timeout(Duration::from_secs(2), client.recv_from(&mut packet))
+                .await
+                .expect("timed out waiting for a packet")
+                .unwrap();
+            decode_packet(&packet[..len]).unwrap().1
+        }
+        for _ in 0..3 {
+            assert!(next_samples(&client).await.is_empty());
+        }
+
+        sender.resume().await;
+        let (len, _) = listener.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"RESUMED");
+        let mut received = next_samples(&client).await;
+        while received.is_empty() {
+            received = next_samples(&client).await;
+        }
+        assert_eq!(received, vec![0.5; 360]);
+    }
+
+    #[tokio::test]
+    async fn pre_roll_leads_in_when_the_gate_opens() {
+        let (sender, receiver) = loopback_pair_with(
+            |config| {
+                config.silence_gate(SilenceGateConfig {
+                    threshold: 0.1,
+                    hold: Duration::ZERO,
+                    keepalive_interval: None,
+                    // 180 stereo frames at 48kHz
+                    pre_roll: Duration::from_micros(3750),
+                })
+            },
+            |config| config,
+        )
+        .await;
+        let sender = Arc::new(sender);
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+        let (source_tx, source_rx) = mpsc::channel(32);
+        tokio::spawn(async move { sender.start_sending(source_rx).await });
+
+        for level in [0.01, 0.02, 0.5] {
+            source_tx.send(vec![level; 360]).await.unwrap();
+        }
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            received.push(
+                time::timeout(Duration::from_secs(2), rx.recv())
+                    .await
+                    .expect("timed out waiting for audio")
+                    .unwrap(),
+            );
+        }
+        assert_eq!(received, vec![vec![0.02; 360], vec![0.5; 360]]);
+    }
+
+    #[tokio::test]
+    async fn taken_ports_are_reported_or_replaced() {
+        let taken = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let taken_addr = taken.local_addr().unwrap();
+
+        match AudioReceiver::new(Some(&taken_addr.to_string())).await {
+            Err(AudioStreamerError::NetworkError(NetworkError::AddressInUse { port, .. })) => {
+                assert_eq!(port, taken_addr.port())
+            }
+            other => panic!("expected an address in use error, got {:?}", other.err()),
+        }
+
+        let receiver = AudioReceiver::with_config(
+            ReceiverConfig::builder()
+                .bind_addr(taken_addr.to_string())
+                .fallback_to_any_port(true)
+                .build(),
+        )
+        .await
+        .unwrap();
+        let bound = receiver.local_addr().unwrap();
+        assert_eq!(bound.ip(), taken_addr.ip());
+        assert_ne!(bound.port(), taken_addr.port());
+    }
+
+    #[tokio::test]
+    async fn host_names_resolve_as_bind_addresses() {
+        let receiver = AudioReceiver::new(Some("localhost:0")).await.unwrap();
+        assert!(receiver.local_addr().unwrap().ip().is_loopback());
+
+        let unresolvable = AudioReceiver::new(Some("no-such-host.invalid:0")).await;
+        assert!(matches!(
+            unresolvable,
+            Err(AudioStreamerError::NetworkError(
+                NetworkError::BindFailed { .. }
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn taken_discovery_ports_fall_back_to_static_clients() {
+        let taken = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let config = SenderConfig::builder()
+            .bind_addr("127.0.0.1:0")
+            .discovery_port(taken_port);
+
+        assert!(AudioSender::with_config(config.clone().build())
+            .await
+            .is_err());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = AudioSender::with_config(
+            config
+                .static_clients(vec![client.local_addr().unwrap()])
+                .build(),
+        )
+        .await
+        .unwrap();
+        assert!(!sender.discovery_enabled());
+        assert_ne!(
+            sender.discovery_socket.local_addr().unwrap().port(),
+            taken_port
+        );
+
+        let (tx, rx) = mpsc::channel(8);
+        tokio::spawn(async move { sender.start_sending(rx).await });
+        tx.send(vec![0.5; 360]).await.unwrap();
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        time::timeout(Duration::from_secs(2), client.recv(&mut buf))
+            .await
+            .expect("timed out waiting for audio")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn dual_stack_senders_reach_ipv4_listeners() {
+        let (sender, receiver) =
+            loopback_pair_with(|config| config.bind_addr("[::]:0"), |config| config).await;
+        let (tx, mut rx) = mpsc::channel(32);
+        tokio::spawn(async move { receiver.start_receiving(tx).await });
+
+        let (source_tx, source_rx) = mpsc::channel(8);
+        tokio::spawn(async move { sender.start_sending(source_rx).await });
+        source_tx.send(vec![0.5; 360]).await.unwrap();
+
+        let received = time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("timed out waiting for audio")
+            .unwrap();
+        assert_eq!(received, vec![0.5; 360]);
+    }
+
+    #[tokio::test]
+    async fn announcements_go_to_each_chosen_interface() {
+        let available = [
+            (
+                Ipv4Addr::new(192, 168, 1, 20),
+                Ipv4Addr::new(192, 168, 1, 255),
+            ),
+            (Ipv4Addr::new(10, 8, 0, 2), Ipv4Addr::new(10, 8, 255, 255)),
+        ];
+        assert_eq!(
+            broadcast_addrs_for(&[Ipv4Addr::new(192, 168, 1, 20)], &available).unwrap(),
+            vec![Ipv4Addr::new(192, 168, 1, 255)]
+        );
+        assert_eq!(
+            broadcast_addrs_for(
+                &[Ipv4Addr::new(10, 8, 0, 2), Ipv4Addr::new(192, 168, 1, 20)],
+                &available
+            )
+            .unwrap(),
+            vec![
+                Ipv4Addr::new(10, 8, 255, 255),
+                Ipv4Addr::new(192, 168, 1, 255)
+            ]
+        );
+        assert!(broadcast_addrs_for(&[Ipv4Addr::new(172, 16, 0, 1)], &available).is_err());
+
+        // TEST-NET-1 is never assigned to a real interface
+        let unknown = AudioSender::with_config(
+            SenderConfig::builder()
+                .bind_addr("127.0.0.1:0")
+                .discovery_port(0)
+                .announce_interfaces(vec![Ipv4Addr::new(192, 0, 2, 1)])
+                .build(),
+        )
+        .await;
+        assert!(matches!(unknown, Err(AudioStreamerError::ConfigError(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_broadcast_capable_interfaces_are_listed() {
+        // Loopback can't broadcast, so only real interfaces are listed
+        let real = interface_broadcasts().unwrap();
+        for &(addr, broadcast) in &real {
+            assert!(!addr.is_loopback());
+            assert_eq!(
+                broadcast_addrs_for(&[addr], &real).unwrap(),
+                vec![broadcast]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn discovery_needs_an_ipv4_reachable_stream_socket() {
+        let config = SenderConfig::builder()
+            .bind_addr("[::1]:0")
+            .discovery_port(0)
+            .build();
+        assert!(matches!(
+            AudioSender::with_config(config).await,
+            Err(AudioStreamerError::ConfigError(_))
+        ));
+
+        let config = SenderConfig::builder()
+            .bind_addr("[::1]:0")
+            .discovery(false)
+            .build();
+        assert!(AudioSender::with_config(config).await.is_ok());
+
+        let receiver = AudioReceiver::new(Some("[::1]:0")).await.unwrap();
+        assert!(matches!(
+            receiver.discover_server().await,
+            Err(AudioStreamerError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn scans_find_servers_without_registering() {
+        let sender = loopback_sender(|config| config.sample_rate(16000).name("Living room")).await;
+        let receiver = loopback_receiver(|config| config).await;
+
+        let discovery_addr = sender.discovery_socket.local_addr().unwrap();
+        let control_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), discovery_addr.port());
+        // Scan the sender directly; broadcasts may not be routable here
+        let servers = receiver
+            .scan(control_addr, Duration::from_millis(200))
+            .await
+            .unwrap();
+
+        let format = StreamFormat {
+            sample_rate: 16000,
+            channels: 2,
+            codec: Codec::Pcm,
+        };
+        assert_eq!(
+            servers,
+            vec![DiscoveredServer {
+                addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), sender.stream_port),
+                name: Some("Living room".into()),
+                format: Some(format),
+                capabilities: Some(Capabilities {
+                    codecs: Codec::available(),
+                    encryption_required: false,
+                    transport: Some(Transport::UdpV4),
+                    format,
+                    multicast: None,
+                    websocket: None,
+                    not_understood: Vec::new(),
+                }),
+            }]
+        );
+        assert!(sender.clients.lock().await.is_empty());
+        assert_eq!(receiver.state(), ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn registry_follows_announcements_and_shutdowns() {
+        let sender: SocketAddr = "192.168.1.5:50000".parse().unwrap();
+        let mut registry = ServerRegistry::default();
+        assert_eq!(registry.record(sender, b"NAME:Kitchen"), None);
+        assert_eq!(registry.record(sender, b"FORMAT:48000:2:pcm"), None);
+        let found = registry.record(sender, b"SERVER:50001").unwrap();
+        assert_eq!(found.addr, "192.168.1.5:50001".parse().unwrap());
+        assert_eq!(found.name.as_deref(), Some("Kitchen"));
+        assert!(found.format.is_some());
+        assert_eq!(found.capabilities, None);
+
+        registry.record(
+            sender,
+            b"CAPS:codecs=pcm;encryption=none;transport=udp4;format=48000:2:pcm",
+        );
+        let found = registry.record(sender, b"SERVER:50001").unwrap();
+        assert_eq!(found.capabilities.unwrap().codecs, vec![Codec::Pcm]);
+
+        // Discovery replies don't repeat the name
+        registry.record(sender, b"FORMAT:44100:1:pcm");
+        let found = registry.record(sender, b"SERVER:50001").unwrap();
+        assert_eq!(found.name.as_deref(), Some("Kitchen"));
+        assert_eq!(found.format.unwrap().sample_rate, 44100);
+        assert_eq!(registry.servers, vec![found]);
+
+        registry.record(sender, b"SERVER_DOWN");
+        assert!(registry.servers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn switching_servers_drops_audio_from_the_others() {
+        let wanted = loopback_sender(|config| config).await;
+        let discovery_port = wanted.discovery_socket.local_addr().unwrap().port();
+        let receiver = loopback_receiver(|config| config.discovery_port(discovery_port)).await;
+        let receiver_addr = receiver.local_addr().unwrap();
+        // Discovery registers the sender's own port, so add the receiver directly
+        wanted.add_client(receiver_addr).await;
+        let other = loopback_sender(|config| config).await;
+        other.add_client(receiver_addr).await;
+
+        let wanted_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), wanted.stream_port);
+        receiver.switch_to(wanted_addr).await.unwrap();
+        assert_eq!(receiver.server_addr().await.unwrap(), wanted_addr);
+        assert_eq!(receiver.servers()[0].addr, wanted_addr);
+
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+        let mut sources = Vec::new();
+        for (sender, level) in [(other, 0.75), (wanted, 0.25)] {
+            let (source_tx, source_rx) = mpsc::channel(8);
+            tokio::spawn(async move { sender.start_sending(source_rx).await });
+            source_tx.send(vec![level; 360]).await.unwrap();
+            sources.push(source_tx);
+        }
+
+        let received = time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("timed out waiting for audio")
+            .unwrap();
+        assert_eq!(received, vec![0.25; 360]);
+        assert!(time::timeout(Duration::from_millis(200), rx.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn pre_emphasis_is_undone_by_static_listeners() {
+        // No discovery, so the packet headers are all the listener has to go on
+        let (sender, receiver) =
+            loopback_pair_with(|config| config.pre_emphasis(0.9), |config| config).await;
+        assert_eq!(sender.config.pre_emphasis, Some(9_000));
+        assert_eq!(receiver.server_format(), None);
+
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+        let source = SineSource::new(440.0, 0.5, 48000, 2).with_buffer_size(360);
+        let mut expected = source.clone();
+        tokio::spawn(async move { sender.start_sending(source.spawn()).await });
+
+        for _ in 0..5 {
+            let received = time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("timed out waiting for audio")
+                .unwrap();
+            let reference = expected.next_buffer();
+            assert_eq!(received.len(), reference.len());
+            for (a, b) in received.iter().zip(&reference) {
+                assert!((a - b).abs() < 1e-4);
+            }
+        }
+
+        assert!(AudioSender::with_config(
+            SenderConfig::builder()
+                .bind_addr("127.0.0.1:0")
+                .discovery(false)
+                .pre_emphasis(1.0)
+                .build(),
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn discovery_finds_a_sender_on_loopback() {
+        let sender = loopback_sender(|config| config.name("Loopback")).await;
+        let discovery_port = sender.discovery_socket.local_addr().unwrap().port();
+        // Broadcasts may not loop back, so discovery is directed at the sender
+        let receiver = loopback_receiver(|config| {
+            config
+                .discovery_addr(Ipv4Addr::LOCALHOST)
+                .discovery_port(discovery_port)
+                .discovery_timeout(Duration::from_secs(2))
+        })
+        .await;
+        let stream_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), sender.stream_port);
+
+        let servers = receiver
+            .discover_servers(Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].addr, stream_addr);
+        assert_eq!(servers[0].name.as_deref(), Some("Loopback"));
+        assert!(sender.clients.lock().await.is_empty());
+
+        receiver.discover_server().await.unwrap();
+        assert_eq!(receiver.server_addr().await.unwrap(), stream_addr);
+        assert_eq!(receiver.server_format(), Some(sender.format()));
+        assert_eq!(receiver.state(), ConnectionState::Connected);
+        assert!(sender.clients.lock().await.contains(&stream_addr));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs multicast routing"]
+    async fn multicast_members_share_one_copy() {
+        // Multicast arrives on the listener's port, so the group takes it
+        let receiver = Arc::new(
+            AudioReceiver::with_config(ReceiverConfig::builder().bind_addr("0.0.0.0:0").build())
+                .await
+                .unwrap(),
+        );
+        let group = SocketAddrV4::new(
+            Ipv4Addr::new(239, 255, 77, 1),
+            receiver.local_addr().unwrap().port(),
+        );
+        let sender = loopback_sender(|config| config.multicast_group(group)).await;
+        assert_eq!(sender.capabilities().multicast, Some(group));
+        let control_addr = SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            sender.discovery_socket.local_addr().unwrap().port(),
+        );
+        receiver.discover_at(control_addr, None).await.unwrap();
+        assert!(
+            receiver.multicast_group.lock().unwrap().is_some(),
+            "this host can't join multicast groups"
+        );
+        let client = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), sender.stream_port);
+        time::timeout(Duration::from_secs(2), async {
+            while !sender.multicast_members.lock().await.contains(&client) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("listener never joined the group");
+
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+        let sender = Arc::new(sender);
+        let sending = sender.clone();
+        let source = SineSource::new(440.0, 0.5, 48000, 2).with_buffer_size(360);
+        tokio::spawn(async move { sending.start_sending(source.spawn()).await });
+        time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("timed out waiting for multicast audio")
+            .unwrap();
+
+        // Everything went to the group, none to the member itself
+        let metrics = sender.metrics();
+        assert!(metrics.packets_sent > 0);
+        assert_eq!(metrics.clients[0].addr, client);
+        assert_eq!(metrics.clients[0].packets_sent, 0);
+    }
+
+    #[tokio::test]
+    async fn multicast_needs_a_group_and_an_ipv4_stream_socket() {
+        let unicast = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 50001);
+        let refused =
+            AudioSender::with_config(SenderConfig::builder().multicast_group(unicast).build())
+                .await;
+        assert!(refused.is_err());
+
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 1), 50001);
+        let dual_stack = SenderConfig::builder()
+            .bind_addr("[::]:0")
+            .discovery(false)
+            .multicast_group(group)
+            .build();
+        assert!(matches!(
+            AudioSender::with_config(dual_stack).await,
+            Err(AudioStreamerError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs multicast routing"]
+    async fn stalled_multicast_falls_back_to_unicast() {
+        let receiver = Arc::new(
+            AudioReceiver::with_config(
+                ReceiverConfig::builder()
+                    .bind_addr("0.0.0.0:0")
+                    .stall_timeout(Duration::from_millis(100))
+                    .build(),
+            )
+            .await
+            .unwrap(),
+        );
+        let group = SocketAddrV4::new(
+            Ipv4Addr::new(239, 255, 77, 2),
+            receiver.local_addr().unwrap().port(),
+        );
+        // Never sends, like a group the network doesn't forward
+        let sender = loopback_sender(|config| config.multicast_group(group)).await;
+        let control_addr = SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            sender.discovery_socket.local_addr().unwrap().port(),
+        );
+        receiver.discover_at(control_addr, None).await.unwrap();
+        assert!(
+            receiver.multicast_group.lock().unwrap().is_some(),
+            "this host can't join multicast groups"
+        );
+        let client = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), sender.stream_port);
+
+        let (tx, _rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+        time::timeout(Duration::from_secs(2), async {
+            while receiver.multicast_group.lock().unwrap().is_some()
+                || sender.multicast_members.lock().await.contains(&client)
+            {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("listener never fell back to unicast");
+        assert!(sender.clients.lock().await.contains(&client));
+
+        // Registering with the same server again doesn't rejoin the group
+        receiver.discover_at(control_addr, None).await.unwrap();
+        assert!(receiver.multicast_group.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn audio_is_accepted_from_every_address_the_server_answered_from() {
+        let sender = loopback_sender(|config| config.name("Studio")).await;
+        let discovery_port = sender.discovery_socket.local_addr().unwrap().port();
+        let receiver = loopback_receiver(|config| config.discovery_port(discovery_port)).await;
+        sender.add_client(receiver.local_addr().unwrap()).await;
+        let control_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), discovery_port);
+        receiver.discover_at(control_addr, None).await.unwrap();
+
+        // As if it had first answered from another of its interfaces
+        receiver.stream_source.send_modify(|source| {
+            source.as_mut().unwrap().addrs = vec![Ipv4Addr::new(127, 0, 0, 2).into()];
+        });
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+        sender.send_to_clients(&build_packet(0, 0, &[0.25])).await;
+        assert!(time::timeout(Duration::from_millis(200), rx.recv())
+            .await
+            .is_err());
+
+        // Answering again from this one adds it rather than starting over
+        receiver.discover_at(control_addr, None).await.unwrap();
+        assert_eq!(
+            receiver.stream_source.borrow().as_ref().unwrap().addrs,
+            vec![
+                IpAddr::from(Ipv4Addr::new(127, 0, 0, 2)),
+                Ipv4Addr::LOCALHOST.into()
+            ]
+        );
+        sender.send_to_clients(&build_packet(1, 0, &[0.5])).await;
+        let received = time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("audio from a known address was dropped")
+            .unwrap();
+        assert_eq!(received, vec![0.5]);
+    }
+
+    #[tokio::test]
+    async fn session_info_describes_the_chosen_server() {
+        let sender =
+            loopback_sender(|config| config.name("Living Room").websocket_port(50002)).await;
+        let discovery_port = sender.discovery_socket.local_addr().unwrap().port();
+        let receiver = loopback_receiver(|config| config.discovery_port(discovery_port)).await;
+        assert!(receiver.session_info().await.is_err());
+
+        let server_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), sender.stream_port);
+        receiver.switch_to(server_addr).await.unwrap();
+        let info = receiver.session_info().await.unwrap();
+        assert_eq!(info.server_addr, server_addr);
+        assert_eq!(info.server_name.as_deref(), Some("Living Room"));
+        assert!(info.announced);
+        assert_eq!(info.format.channels, 2);
+        assert_eq!(info.transport, Transport::UdpV4);
+        assert_eq!(
+            info.websocket_addr,
+            Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 50002))
+        );
+        assert_eq!(
+            info.to_string(),
+            format!("Living Room ({}), 48000Hz stereo pcm", server_addr)
+        );
+    }
+
+    #[tokio::test]
+    async fn listeners_are_told_when_the_server_shuts_down() {
+        let (sender, receiver) = loopback_pair().await;
+        let discovery_addr = sender.discovery_socket.local_addr().unwrap();
+        let control_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), discovery_addr.port());
+
+        // Discover the sender directly; broadcasts may not be routable here
+        receiver
+            .discovery_socket
+            .send_to(b"DISCOVER", control_addr)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 128];
+        let (len, _) = receiver.discovery_socket.recv_from(&mut buf).await.unwrap();
+        assert!(buf[..len].starts_with(b"FORMAT:"));
+        let (len, _) = receiver.discovery_socket.recv_from(&mut buf).await.unwrap();
+        assert!(buf[..len].starts_with(b"CAPS:"));
+        let (len, _) = receiver.discovery_socket.recv_from(&mut buf).await.unwrap();
+        assert!(buf[..len].starts_with(b"SERVER:"));
+        *receiver.server_addr.lock().await = Some(control_addr);
+        receiver.set_state(ConnectionState::Connected);
+
+        sender.shutdown().await;
+        time::timeout(Duration::from_secs(2), receiver.wait_for_server_down())
+            .await
+            .expect("timed out waiting for SERVER_DOWN")
+            .unwrap();
+        assert_eq!(receiver.state(), ConnectionState::Disconnected);
+        assert!(matches!(
+            receiver.server_addr().await,
+            Err(crate::AudioStreamerError::NetworkError(
+                NetworkError::ServerNotFound
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn raw_packets_are_passed_through_undecoded() {
+        let (sender, receiver) = loopback_pair().await;
+        let mut raw = receiver.raw_packets();
+        let (tx, _rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        sender
+            .send_to_clients(&build_packet(0, 0, &[0.25, -0.25]))
+            .await;
+
+        let packet = time::timeout(Duration::from_secs(2), raw.recv())
+            .await
+            .expect("timed out waiting for raw packet")
+            .unwrap();
+        assert_eq!(packet.data.len(), crate::protocol::HEADER_SIZE + 8);
+        let (_, samples) = decode_packet(&packet.data).unwrap();
+        assert_eq!(samples, vec![0.25, -0.25]);
+    }
+
+    #[tokio::test]
+    async fn discovery_ttl_is_applied_to_the_discovery_socket() {
+        let sender = loopback_sender(|config| config.discovery_ttl(1)).await;
+        assert_eq!(sender.discovery_socket.ttl().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn timed_buffers_advance_by_their_frame_count() {
+        let (sender, receiver) = loopback_pair().await;
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving_timed(tx).await });
+
+        let source = SineSource::new(440.0, 0.5, 48000, 2).with_buffer_size(360);
+        tokio::spawn(async move { sender.start_sending(source.spawn()).await });
+
+        let mut previous: Option<SystemTime> = None;
+        for _ in 0..3 {
+            let buffer = time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("timed out waiting for audio")
+                .unwrap();
+            // 180 stereo frames at 48kHz
+            if let Some(previous) = previous {
+                let step = buffer.presentation_time.duration_since(previous).unwrap();
+                assert_eq!(step, Duration::from_micros(3750));
+            }
+            previous = Some(buffer.presentation_time);
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_receiving_when_the_player_is_full() {
+        let (sender, receiver) = loopback_pair_with(
+            |config| config,
+            |config| config.overflow_policy(OverflowPolicy::DropOldest),
+        )
+        .await;
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        for i in 0..5 {
+            sender
+                .send_to_clients(&build_packet(0, 0, &[i as f32]))
+                .await;
+        }
+        time::timeout(Duration::from_secs(2), async {
+            while receiver.metrics().packets_received < 5 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("receiver blocked on the full channel");
+
+        // At most one buffer in the channel and one held back survive, and
+        // the held-back newest follows as room appears with nothing more
+        // arriving
+        let mut received = Vec::new();
+        while let Ok(Some(buffer)) = time::timeout(Duration::from_millis(200), rx.recv()).await {
+            received.push(buffer);
+        }
+        assert!(received.len() <= 2);
+        assert_eq!(received.last(), Some(&vec![4.0]));
+        assert_eq!(
+            receiver.metrics().dropped_buffers as usize + received.len(),
+            5
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_within_max_queued() {
+        // Two-sample packets last one stereo frame at 48kHz, so two fit
+        let (sender, receiver) = loopback_pair_with(
+            |config| config,
+            |config| {
+                config
+                    .overflow_policy(OverflowPolicy::DropOldest)
+                    .max_queued(Duration::from_micros(50))
+            },
+        )
+        .await;
+
+        let (tx, mut rx) = mpsc::channel(8);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        for i in 0..6 {
+            let sample = i as f32;
+            sender
+                .send_to_clients(&build_packet(0, 0, &[sample, sample]))
+                .await;
+        }
+        time::timeout(Duration::from_secs(2), async {
+            while receiver.metrics().packets_received < 6 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("receiver stopped receiving");
+
+        // Two in the channel and the newest held back, despite its room for
+        // eight
+        let mut received = Vec::new();
+        while let Ok(Some(buffer)) = time::timeout(Duration::from_millis(200), rx.recv()).await {
+            received.push(buffer);
+        }
+        assert!(received.len() <= 3);
+        assert_eq!(received.last(), Some(&vec![5.0, 5.0]));
+        assert_eq!(
+            receiver.metrics().dropped_buffers as usize + received.len(),
+            6
+        );
+    }
+
+    #[tokio::test]
+    async fn static_clients_receive_audio_without_discovery() {
+        let receiver = loopback_receiver(|config| config).await;
+        let sender = loopback_sender(|config| {
+            config
+                .static_clients(vec![receiver.local_addr().unwrap()])
+                .discovery(false)
+        })
+        .await;
+
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        sender.send_to_clients(&build_packet(0, 0, &[0.5])).await;
+        let received = time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("timed out waiting for audio");
+        assert_eq!(received, Some(vec![0.5]));
+    }
+
+    #[tokio::test]
+    async fn session_metrics_track_traffic_and_client_lifetimes() {
+        let (sender, receiver) = loopback_pair().await;
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        let packet = build_packet(0, 0, &[0.5, -0.5]);
+        sender.send_to_clients(&packet).await;
+        time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("timed out waiting for audio");
+        sender.shutdown().await;
+
+        let metrics = sender.metrics();
+        assert_eq!(metrics.packets_sent, 1);
+        assert_eq!(metrics.bytes_sent, packet.len() as u64);
+        assert_eq!(metrics.clients.len(), 1);
+        assert_eq!(metrics.clients[0].addr, receiver.local_addr().unwrap());
+        assert!(metrics.clients[0].left.is_some());
+        assert_eq!(metrics.clients[0].packets_sent, 1);
+        assert_eq!(metrics.clients[0].send_errors, 0);
+
+        let path = std::env::temp_dir().join(format!("receiver-{}.json", std::process::id()));
+        receiver.export_metrics(&path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(json["packets_received"], 1);
+        assert_eq!(json["bytes_received"], packet.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn batched_sends_report_each_destination() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // An IPv4 socket can't send to an IPv6 address
+        let destinations = [
+            first.local_addr().unwrap(),
+            "[::1]:9".parse().unwrap(),
+            second.local_addr().unwrap(),
+        ];
+
+        for batched in [false, true] {
+            let results = send_to_all(&socket, b"packet", &destinations, batched).await;
+            assert_eq!(results.len(), 3);
+            assert_eq!(results[0].as_ref().unwrap(), &6);
+            assert!(results[1].is_err());
+            assert_eq!(results[2].as_ref().unwrap(), &6);
+            for client in [&first, &second] {
+                let mut buf = [0u8; 16];
+                let len = time::timeout(Duration::from_secs(2), client.recv(&mut buf))
+                    .await
+                    .expect("timed out waiting for the packet")
+                    .unwrap();
+                assert_eq!(&buf[..len], b"packet");
+            }
+        }
+
+        let sender = loopback_sender(|config| {
+            config.batch_sends(true).static_clients(vec![
+                first.local_addr().unwrap(),
+                second.local_addr().unwrap(),
+            ])
+        })
+        .await;
+        sender.send_to_clients(&build_packet(0, 0, &[0.5])).await;
+        let metrics = sender.metrics();
+        assert_eq!(metrics.packets_sent, 2);
+        assert_eq!(metrics.send_errors, 0);
+    }
+
+    #[test]
+    fn sequence_window_spots_repeats() {
+        let sender: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+        let mut window = SequenceWindow::default();
+        for sequence in [1, 3, 2] {
+            assert!(!window.is_duplicate(sender, sequence));
+        }
+        assert!(window.is_duplicate(sender, 2));
+        assert!(window.is_duplicate(sender, 3));
+        // Unnumbered packets are never duplicates
+        assert!(!window.is_duplicate(sender, 0));
+        assert!(!window.is_duplicate(sender, 0));
+
+        // A restarted sender counts from 1 again
+        assert!(!window.is_duplicate(sender, 1_000));
+        assert!(!window.is_duplicate(sender, 1));
+        let other: SocketAddr = "127.0.0.1:50002".parse().unwrap();
+        assert!(!window.is_duplicate(other, 1));
+
+        let mut window = SequenceWindow::default();
+        assert!(!window.is_duplicate(sender, u32::MAX));
+        assert!(!window.is_duplicate(sender, 1));
+        assert!(window.is_duplicate(sender, u32::MAX));
+    }
+
+    #[tokio::test]
+    async fn duplicated_packets_play_once() {
+        let (sender, receiver) = loopback_pair().await;
+        assert_eq!(sender.next_header(0, 0).sequence, 1);
+        assert_eq!(sender.next_header(0, 0).sequence, 2);
+
+        let (tx, mut rx) = mpsc::channel(32);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+        for (sequence, level) in [(7, 0.25), (7, 0.25), (8, 0.5)] {
+            let header = PacketHeader {
+                sequence,
+                ..PacketHeader::default()
+            };
+            sender
+                .send_to_clients(&encode_packet(&header, &[level; 360]))
+                .await;
+        }
+
+        for level in [0.25, 0.5] {
+            let received = time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("timed out waiting for audio")
+                .unwrap();
+            assert_eq!(received, vec![level; 360]);
+        }
+        assert_eq!(receiver.metrics().duplicates, 1);
+    }
+
+    #[test]
+    fn backoff_strategies_grow_up_to_their_cap() {
+        let ms = Duration::from_millis;
+        assert_eq!(Backoff::Fixed(ms(100)).delay(7), ms(100));
+
+        let linear = Backoff::Linear {
+            initial: ms(100),
+            step: ms(50),
+            max: ms(200),
+        };
+        let delays: Vec<_> = (0..4).map(|attempt| linear.delay(attempt)).collect();
+        assert_eq!(delays, vec![ms(100), ms(150), ms(200), ms(200)]);
+
+        let exponential = Backoff::Exponential {
+            initial: ms(100),
+            max: ms(1000),
+        };
+        let delays: Vec<_> = (0..5).map(|attempt| exponential.delay(attempt)).collect();
+        assert_eq!(delays, vec![ms(100), ms(200), ms(400), ms(800), ms(1000)]);
+        assert_eq!(exponential.delay(u32::MAX), ms(1000));
+
+        let jittered = ReconnectConfig {
+            backoff: Backoff::Fixed(ms(100)),
+            jitter: 0.5,
+            max_attempts: None,
+        };
+        for attempt in 0..20 {
+            let delay = jittered.jittered_delay(attempt);
+            assert!(delay > ms(50) && delay <= ms(100));
+        }
+    }
+
+    #[tokio::test]
+    async fn incompatible_servers_are_refused() {
+        let receiver = loopback_receiver(|config| {
+            config
+                .discovery_port(0)
+                .discovery_timeout(Duration::from_millis(200))
+        })
+        .await;
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (_, listener) = server.recv_from(&mut buf).await.unwrap();
+            for reply in [
+                "CAPS:codecs=pcm;encryption=required;transport=udp4;format=48000:2:pcm",
+                "SERVER:50001",
+            ] {
+                server.send_to(reply.as_bytes(), listener).await.unwrap();
+            }
+        });
+
+        let result = receiver.discover_at(server_addr, None).await;
+        assert!(matches!(
+            result,
+            Err(AudioStreamerError::NetworkError(
+                NetworkError::Incompatible { .. }
+            ))
+        ));
+        assert_eq!(receiver.state(), ConnectionState::Disconnected);
+        assert!(receiver.server_addr().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reconnect_backs_off_then_gives_up() {
+        let receiver = AudioReceiver::with_config(
+            ReceiverConfig::builder()
+                .bind_addr("127.0.0.1:0")
+                .discovery_port(9)
+                .discovery_timeout(Duration::from_millis(10))
+                .reconnect(ReconnectConfig {
+                    backoff: Backoff::Fixed(Duration::from_millis(20)),
+                    jitter: 0.0,
+                    max_attempts: Some(3),
+                })
+                .build(),
+        )
+        .await
+        .unwrap();
+        let mut states = receiver.subscribe_state();
+        let watching = tokio::spawn(async move {
+            let mut backoffs = Vec::new();
+            while states.changed().await.is_ok() {
+                if let ConnectionState::Backoff { attempt, retry_in } = *states.borrow() {
+                    backoffs.push((attempt, retry_in));
+                }
+            }
+            backoffs
+        });
+
+        assert!(receiver.reconnect().await.is_err());
+        assert_eq!(receiver.state(), ConnectionState::Disconnected);
+
+        drop(receiver);
+        let backoffs = watching.await.unwrap();
+        let retry_in = Duration::from_millis(20);
+        assert_eq!(backoffs, vec![(1, retry_in), (2, retry_in)]);
+    }
+
+    #[tokio::test]
+    async fn format_changes_reach_discovered_listeners() {
+        let (sender, receiver) = loopback_pair().await;
+        let discovery_addr = sender.discovery_socket.local_addr().unwrap();
+        let control_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), discovery_addr.port());
+
+        receiver
+            .discovery_socket
+            .send_to(b"DISCOVER", control_addr)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 64];
+        receiver.discovery_socket.recv_from(&mut buf).await.unwrap();
+        *receiver.server_addr.lock().await = Some(control_addr);
+
+        let format = StreamFormat {
+            sample_rate: 44_100,
+            channels: 1,
+            codec: Codec::Pcm,
+        };
+        sender.change_format(format).await.unwrap();
+        assert_eq!(sender.format(), format);
+
+        let message = time::timeout(Duration::from_secs(2), receiver.next_control_message())
+            .await
+            .expect("timed out waiting for FORMAT")
+            .unwrap();
+        assert_eq!(message, ControlMessage::FormatChanged(format));
+    }
+
+    #[tokio::test]
+    async fn discovery_announces_the_transport_format() {
+        let sender = loopback_sender(|config| config.sample_rate(16_000).channels(1)).await;
+        let discovery_addr = sender.discovery_socket.local_addr().unwrap();
+        let control_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), discovery_addr.port());
+
+        // Discover the sender directly; broadcasts may not be routable here
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(b"DISCOVER", control_addr).await.unwrap();
+        let mut buf = [0u8; 128];
+        let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+        let format = StreamFormat {
+            sample_rate: 16_000,
+            channels: 1,
+            codec: Codec::Pcm,
+        };
+        assert_eq!(StreamFormat::parse_message(&buf[..len]), Some(format));
+        let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(
+            Capabilities::parse_message(&buf[..len]),
+            Some(sender.capabilities())
+        );
+        assert_eq!(sender.capabilities().format, format);
+        let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+        assert!(buf[..len].starts_with(b"SERVER:"));
+
+        let unsupported = AudioSender::with_config(
+            SenderConfig::builder()
+                .bind_addr("127.0.0.1:0")
+                .discovery(false)
+                .sample_rate(22_050)
+                .build(),
+        )
+        .await;
+        assert!(matches!(
+            unsupported,
+            Err(AudioStreamerError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unenforceable_sender_options_are_refused() {
+        let config = || {
+            SenderConfig::builder()
+                .bind_addr("127.0.0.1:0")
+                .discovery(false)
+        };
+        for refused in [
+            config().encryption(true).build(),
+            config().heartbeat_timeout(Duration::from_secs(5)).build(),
+            // Stereo f32 at 48kHz needs 3072 kbit/s
+            config().max_bitrate(1_000_000).build(),
+        ] {
+            assert!(matches!(
+                AudioSender::with_config(refused).await,
+                Err(AudioStreamerError::ConfigError(_))
+            ));
+        }
+
+        let capped = config().codec(Codec::PcmI16).max_bitrate(1_536_000).build();
+        assert!(AudioSender::with_config(capped).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn fixed_ports_are_used_and_conflicts_rejected() {
+        // Find a free port to fix the control socket to
+        let port = std::net::UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let receiver = loopback_receiver(|config| config.control_port(port)).await;
+        assert_eq!(receiver.discovery_socket.local_addr().unwrap().port(), port);
+
+        let conflicting = AudioReceiver::with_config(
+            ReceiverConfig::builder()
+                .bind_addr(format!("127.0.0.1:{}", port))
+                .control_port(port)
+                .build(),
+        )
+        .await;
+        assert!(matches!(
+            conflicting,
+            Err(AudioStreamerError::ConfigError(_))
+        ));
+
+        let conflicting = AudioSender::with_config(
+            SenderConfig::builder()
+                .bind_addr("127.0.0.1:50123")
+                .discovery_port(50123)
+                .build(),
+        )
+        .await;
+        assert!(matches!(
+            conflicting,
+            Err(AudioStreamerError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn client_filter_rejects_unwanted_listeners() {
+        async fn discover_with_filter(filter: fn(SocketAddr) -> bool) -> (AudioSender, String) {
+            let sender = loopback_sender(|config| config.client_filter(filter)).await;
+            let discovery_addr = sender.discovery_socket.local_addr().unwrap();
+            let control_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), discovery_addr.port());
+
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.send_to(b"DISCOVER", control_addr).await.unwrap();
+            let mut buf = [0u8; 128];
+            loop {
+                let (len, _) = time::timeout(Duration::from_secs(2), socket.recv_from(&mut buf))
+                    .await
+                    .expect("timed out waiting for a discovery reply")
+                    .unwrap();
+                // Accepted listeners get the stream format and capabilities
+                // ahead of the address
+                let reply = &buf[..len];
+                if StreamFormat::parse_message(reply).is_none()
+                    && Capabilities::parse_message(reply).is_none()
+                {
+                    break (sender, String::from_utf8_lossy(&buf[..len]).into_owned());
+                }
+            }
+        }
+
+        let (sender, reply) = discover_with_filter(|addr| addr.ip().is_loopback()).await;
+        assert!(reply.starts_with("SERVER:"));
+        assert_eq!(sender.clients.lock().await.len(), 1);
+
+        let (sender, reply) = discover_with_filter(|_| false).await;
+        assert_eq!(reply, "REJECTED");
+        assert!(sender.clients.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bench_reports_received_throughput() {
+        let sender = loopback_sender(|config| config).await;
+        let discovery_port = sender.discovery_socket.local_addr().unwrap().port();
+        let receiver = loopback_receiver(|config| config.discovery_port(discovery_port)).await;
+
+        let config = BenchConfig {
+            rates: vec![500_000, 1_000_000],
+            step_duration: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let report = receiver
+            .bench(Ipv4Addr::LOCALHOST.into(), &config)
+            .await
+            .unwrap();
+
+        assert_eq!(report.steps.len(), 2);
+        let step = report.steps[1];
+        // 1 Mbit/s of 1468-byte packets for 200ms
+        assert_eq!(step.packets_sent, 17);
+        assert_eq!(step.packets_received, step.packets_sent);
+        assert_eq!(step.loss(), 0.0);
+        assert_eq!(report.sustainable_bps(0.0), Some(step.received_bps));
+
+        let too_big = BenchConfig {
+            packet_size: 9000,
+            ..Default::default()
+        };
+        assert!(receiver
+            .bench(Ipv4Addr::LOCALHOST.into(), &too_big)
+            .await
+            .is_err());
+    }
+}
@@ -1,50 +1,1110 @@
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Sample, SampleFormat, SizedSample};
+use cpal::{Sample, SampleFormat, SizedSample, SupportedStreamConfigRange};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{mpsc, watch};
 
+use crate::capture::select_host;
+use crate::dsp::{EqConfig, Equalizer, Resampler};
+use crate::metrics::{BufferGauge, BufferLevel};
+pub use crate::sample::f32_to_sample;
+use crate::sample::sample_to_f32;
+use crate::sink::{AudioSink, BufferSink};
+use crate::wav::{numbered_path, BitDepth, WavWriter};
 use crate::Result;
 
 pub struct AudioPlayer {
     host: cpal::Host,
+    config: PlayerConfig,
+    stats: Arc<Mutex<PlaybackStats>>,
+    // Set by `flush`, cleared by the output callback once it has flushed
+    flush_requested: Arc<AtomicBool>,
+    // Band gains in dB as f32 bits, picked up by the output callback when
+    // `eq_changed` is set
+    eq_gains: Arc<Vec<AtomicU32>>,
+    eq_changed: Arc<AtomicBool>,
+    // Stereo width as f32 bits, read by the output callback
+    width: Arc<AtomicU32>,
+    // Output delay in microseconds, read by the output callback
+    output_delay: Arc<AtomicU64>,
+    levels: Arc<Mutex<Vec<ChannelLevel>>>,
+    buffer_gauge: BufferGauge,
+    // Receiving end of the playback channel, shared with the output stream
+    // and kept so `reconfigure` can open a new stream on it
+    playback_rx: Mutex<Option<PlaybackReceiver>>,
+    // Rate and channels of the current stream, for `reopen`
+    stream_format: Mutex<(u32, u16)>,
+    // Streams opened so far, numbering the files of the File backend
+    streams_opened: AtomicU32,
+    device_name: Mutex<Option<String>>,
+    // Set by the stream's error callback when its device goes away
+    device_lost: Arc<AtomicBool>,
+    // Set by the output callback once the playback channel is closed and
+    // everything sent through it has played
+    playback_ended: Arc<watch::Sender<bool>>,
+}
+
+type PlaybackReceiver = Arc<Mutex<Option<mpsc::Receiver<Vec<f32>>>>>;
+
+// Length of the fade-out applied when flushing
+const FLUSH_FADE: Duration = Duration::from_millis(5);
+
+// Audio played per callback by the virtual backends
+const VIRTUAL_PERIOD: Duration = Duration::from_millis(10);
+
+/// Widest stereo image `AudioPlayer::set_width` allows. Beyond it the side
+/// signal swamps the mid and most of the output is clipped.
+pub const MAX_STEREO_WIDTH: f32 = 2.0;
+
+/// Longest delay `AudioPlayer::set_output_delay` allows.
+pub const MAX_OUTPUT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct PlayerConfig {
+    /// Format of the audio sent to the player. The output stream runs at the
+    /// same rate when the device supports it and resamples otherwise, see
+    /// `negotiate_output`.
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Number of received buffers that can queue up ahead of the output device.
+    /// Larger values tolerate network bursts but every queued buffer adds its
+    /// duration to playback latency; smaller values keep latency low but the
+    /// receiver will wait on a full channel sooner.
+    pub channel_capacity: usize,
+    /// Maximum amount of audio allowed to queue ahead of the device. When the
+    /// producer outruns playback, whole buffers are skipped to get back under it.
+    pub max_latency: Duration,
+    /// Force the output stream to use this sample format. Playback fails with a
+    /// config error if the device can't provide it with the stream's channel
+    /// count; at another rate the stream is resampled.
+    pub output_format: Option<SampleFormat>,
+    /// Grow the amount buffered ahead of the device after underruns and
+    /// shrink it again once playback has been stable. `None` starts playing
+    /// as soon as any audio arrives.
+    pub adaptive_buffer: Option<AdaptiveBufferConfig>,
+    /// Blend the first frames of each buffer from the last played frame to
+    /// hide clicks at buffer boundaries. `None` plays buffers untouched.
+    pub crossfade_frames: Option<usize>,
+    /// Tone control applied in the output callback. Band gains can be
+    /// changed during playback with `AudioPlayer::set_band_gain`. `None`
+    /// leaves the audio untouched.
+    pub equalizer: Option<EqConfig>,
+    /// Measure peak and RMS of each output channel, read with
+    /// `AudioPlayer::levels`. Costs one pass over every device buffer.
+    pub metering: bool,
+    /// Downmix every output frame and play the result on all of its
+    /// channels, so audio panned to one side isn't lost on a single
+    /// speaker. Applied after the equalizer.
+    pub mono: bool,
+    /// How `mono` makes one channel out of several, by default averaging
+    pub downmix: Downmix,
+    /// Stereo width to start at, see `AudioPlayer::set_width`
+    pub width: f32,
+    /// Fixed delay to start at, see `AudioPlayer::set_output_delay`
+    pub output_delay: Duration,
+    /// Output devices to play on in order of preference, e.g. a USB DAC,
+    /// then HDMI. The first one present is used, matched by name ignoring
+    /// case, either exactly or as part of the device name. The default
+    /// device is used when none is present or the list is empty. Tried
+    /// again by `AudioPlayer::reopen`, e.g. on device loss.
+    pub output_devices: Vec<String>,
+    /// Audio host to play through, e.g. "JACK" or "ASIO", see
+    /// `capture::select_host`. `None` uses the platform's default host.
+    pub host: Option<String>,
+    /// Where the audio is played
+    pub backend: PlayerBackend,
+}
+
+/// How a frame is reduced to mono.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Downmix {
+    /// The average of all channels
+    #[default]
+    Average,
+    /// The first channel alone, e.g. a mono mic plugged into the left input
+    /// of a stereo interface
+    LeftOnly,
+    /// The second channel alone
+    RightOnly,
+}
+
+impl FromStr for Downmix {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "average" | "sum" => Ok(Downmix::Average),
+            "left" => Ok(Downmix::LeftOnly),
+            "right" => Ok(Downmix::RightOnly),
+            _ => Err(format!(
+                "Invalid downmix '{}': expected average, left or right",
+                s
+            )),
+        }
+    }
+}
+
+/// What the player plays into. Apart from `Device` these need no audio
+/// hardware: the output is paced in real time by a thread instead of a
+/// device, so the whole receive and playback path runs on a headless
+/// machine, e.g. in CI.
+#[derive(Clone, Debug, Default)]
+pub enum PlayerBackend {
+    /// An output device, chosen by `PlayerConfig::output_devices`
+    #[default]
+    Device,
+    /// Plays into nothing
+    Null,
+    /// Collects everything played, e.g. for a test to check what would have
+    /// been heard
+    Buffer(BufferSink),
+    /// Records everything played to a 32-bit float WAV file. A WAV file
+    /// holds one format, so each later stream, e.g. from
+    /// `AudioPlayer::reconfigure`, rolls over to a numbered file next to it
+    /// rather than overwriting it: `out.wav`, then `out-2.wav`, `out-3.wav`.
+    File(PathBuf),
+}
+
+/// A running output stream from `AudioPlayer::start_playback`. Playback
+/// stops when it is dropped.
+///
+/// `start_playback`, `reconfigure` and `reopen` used to return and take a
+/// `cpal::Stream`. Code that only holds the stream to keep playback going
+/// just changes the type; code that calls cpal on it, e.g. `pause`, gets
+/// the cpal stream from `device_stream`.
+pub struct PlaybackStream {
+    device: Option<cpal::Stream>,
+    // Stops the virtual backend's thread when dropped
+    _output: Option<VirtualOutput>,
+}
+
+impl PlaybackStream {
+    /// The underlying cpal stream, `None` for the virtual backends.
+    pub fn device_stream(&self) -> Option<&cpal::Stream> {
+        self.device.as_ref()
+    }
+}
+
+// Thread pacing one of the virtual backends
+struct VirtualOutput {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for VirtualOutput {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 48000,
+            channels: 2,
+            channel_capacity: 32,
+            max_latency: Duration::from_millis(200),
+            output_format: None,
+            adaptive_buffer: None,
+            crossfade_frames: None,
+            equalizer: None,
+            metering: false,
+            mono: false,
+            downmix: Downmix::Average,
+            width: 1.0,
+            output_delay: Duration::ZERO,
+            output_devices: Vec::new(),
+            host: None,
+            backend: PlayerBackend::Device,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AdaptiveBufferConfig {
+    /// Audio to buffer before playback starts
+    pub initial_target: Duration,
+    pub min_target: Duration,
+    /// Should stay below `PlayerConfig::max_latency`, or trimming will fight the target
+    pub max_target: Duration,
+    /// Amount the target grows by on underrun and shrinks by when stable
+    pub step: Duration,
+    /// Underrun-free playback time after which the target shrinks one step
+    pub relax_after: Duration,
+}
+
+impl Default for AdaptiveBufferConfig {
+    fn default() -> Self {
+        Self {
+            initial_target: Duration::from_millis(40),
+            min_target: Duration::from_millis(10),
+            max_target: Duration::from_millis(150),
+            step: Duration::from_millis(10),
+            relax_after: Duration::from_secs(10),
+        }
+    }
+}
+
+impl AdaptiveBufferConfig {
+    /// Buffering sized to keep playback latency within `budget`, meant for a
+    /// `PlayerConfig::max_latency` of the same budget. The target starts at a
+    /// quarter of it and never grows past three quarters, leaving headroom
+    /// before buffers are skipped to catch up.
+    pub fn within(budget: Duration) -> Self {
+        Self {
+            initial_target: budget / 4,
+            min_target: budget / 10,
+            max_target: budget * 3 / 4,
+            step: (budget / 10).max(Duration::from_millis(1)),
+            ..Self::default()
+        }
+    }
+}
+
+/// Fails unless a latency budget can hold one `packet` of the stream and one
+/// device `period`: audio arrives and is played in those units, so a smaller
+/// budget is exceeded on every buffer and playback would be skipped away.
+pub fn check_latency_budget(budget: Duration, packet: Duration, period: Duration) -> Result<()> {
+    let floor = packet.max(period);
+    if budget.is_zero() || budget < floor {
+        return Err(crate::AudioStreamerError::ConfigError(format!(
+            "Latency budget of {:?} is below one packet ({:?}) or device period ({:?}); \
+             it must be at least {}ms",
+            budget,
+            packet,
+            period,
+            floor.as_micros().div_ceil(1000).max(1)
+        )));
+    }
+    Ok(())
+}
+
+/// Playback health as seen from the output callback.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PlaybackStats {
+    /// Times the queue ran dry while playing
+    pub underruns: u64,
+    /// Times queued audio exceeded `max_latency` and buffers were skipped
+    pub overruns: u64,
+    /// Audio currently buffered before playback (re)starts
+    pub target_buffer: Duration,
+    /// Delay between the callback and the samples reaching the device, when
+    /// the backend reports it
+    pub device_latency: Option<Duration>,
+    /// Audio the device asked for in the latest callback, `None` before the
+    /// first
+    pub device_period: Option<Duration>,
+    /// Audio queued for playback after the latest callback
+    pub buffered: BufferLevel,
+}
+
+impl PlaybackStats {
+    /// How long audio handed to the player takes to be heard: what's queued
+    /// plus the device latency, when reported. Doesn't include
+    /// `AudioPlayer::output_delay`.
+    pub fn latency(&self) -> Duration {
+        self.buffered.duration + self.device_latency.unwrap_or_default()
+    }
+}
+
+/// Level of one output channel over the most recent device buffer, on a
+/// 0.0 to 1.0 full-scale range.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelLevel {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+/// Received buffers waiting to be played, consumed sample by sample by the
+/// output callback.
+#[derive(Default)]
+pub struct PlaybackQueue {
+    buffers: VecDeque<Vec<f32>>,
+    // Read position inside the front buffer
+    offset: usize,
+    // Total unread samples across all buffers
+    queued: usize,
+    crossfade: Option<Crossfade>,
+    // Played buffers kept for `spare_buffer`
+    spare: Vec<Vec<f32>>,
+}
+
+// Most played buffers a queue keeps for reuse
+const SPARE_BUFFERS: usize = 4;
+
+// De-click state: the last output frame and whether the current buffer's head
+// is still being blended from it
+struct Crossfade {
+    frames: usize,
+    last_frame: Vec<f32>,
+    fading: bool,
+}
+
+impl PlaybackQueue {
+    /// A queue that blends the first `frames` frames of each buffer with the
+    /// previous buffer's last frame.
+    pub fn with_crossfade(channels: u16, frames: usize) -> Self {
+        Self {
+            crossfade: Some(Crossfade {
+                frames,
+                last_frame: vec![0.0; channels.max(1) as usize],
+                fading: false,
+            }),
+            ..Self::default()
+        }
+    }
+
+    pub fn push(&mut self, samples: Vec<f32>) {
+        self.queued += samples.len();
+        self.buffers.push_back(samples);
+    }
+
+    /// An empty buffer to fill and `push`, reusing one already played when
+    /// there is one, so the output callback needn't allocate.
+    pub fn spare_buffer(&mut self) -> Vec<f32> {
+        self.spare.pop().unwrap_or_default()
+    }
+
+    // Keeps a played buffer for `spare_buffer`
+    fn recycle(&mut self, mut buffer: Vec<f32>) {
+        if self.spare.len() < SPARE_BUFFERS {
+            buffer.clear();
+            self.spare.push(buffer);
+        }
+    }
+
+    /// Unplayed samples across all buffers.
+    pub fn queued_samples(&self) -> usize {
+        self.queued
+    }
+
+    /// Buffers not yet fully played.
+    pub fn buffer_count(&self) -> usize {
+        match self.buffers.front() {
+            // An exhausted front buffer is only dropped by the next `pop`
+            Some(front) if self.offset >= front.len() => self.buffers.len() - 1,
+            _ => self.buffers.len(),
+        }
+    }
+
+    /// Drops everything queued.
+    pub fn clear(&mut self) {
+        self.buffers.clear();
+        self.offset = 0;
+        self.queued = 0;
+        if let Some(crossfade) = &mut self.crossfade {
+            crossfade.last_frame.fill(0.0);
+            crossfade.fading = true;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<f32> {
+        loop {
+            let Some(front) = self.buffers.front() else {
+                // Silence is played on underrun, so fade in from zero
+                if let Some(crossfade) = &mut self.crossfade {
+                    crossfade.last_frame.fill(0.0);
+                    crossfade.fading = true;
+                }
+                return None;
+            };
+            if let Some(&sample) = front.get(self.offset) {
+                let sample = match &mut self.crossfade {
+                    Some(crossfade) => crossfade.blend(sample, self.offset),
+                    None => sample,
+                };
+                self.offset += 1;
+                self.queued -= 1;
+                return Some(sample);
+            }
+            if let Some(played) = self.buffers.pop_front() {
+                self.recycle(played);
+            }
+            self.offset = 0;
+            if let Some(crossfade) = &mut self.crossfade {
+                crossfade.fading = true;
+            }
+        }
+    }
+
+    // Drop whole buffers from the front until at most `max_samples` remain queued.
+    // Returns the number of buffers skipped.
+    fn trim_to(&mut self, max_samples: usize) -> usize {
+        let mut skipped = 0;
+        while self.queued > max_samples {
+            let Some(front) = self.buffers.pop_front() else {
+                break;
+            };
+            self.queued -= front.len() - self.offset;
+            self.offset = 0;
+            skipped += 1;
+            self.recycle(front);
+        }
+        if let Some(crossfade) = &mut self.crossfade {
+            crossfade.fading |= skipped > 0;
+        }
+        skipped
+    }
+}
+
+impl Crossfade {
+    fn blend(&mut self, sample: f32, offset: usize) -> f32 {
+        let channels = self.last_frame.len();
+        let frame = offset / channels;
+        let channel = offset % channels;
+
+        let mut output = sample;
+        if self.fading {
+            if frame < self.frames {
+                let weight = (frame + 1) as f32 / (self.frames + 1) as f32;
+                output = sample * weight + self.last_frame[channel] * (1.0 - weight);
+            } else {
+                self.fading = false;
+            }
+        }
+        self.last_frame[channel] = output;
+        output
+    }
+}
+
+// Decides when the output callback plays from the queue and adapts how much
+// is buffered before playback resumes after an underrun
+struct BufferController {
+    adaptive: Option<AdaptiveBufferConfig>,
+    samples_per_second: f64,
+    target: Duration,
+    buffering: bool,
+    // Playback time since the last underrun or target change
+    stable: Duration,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct BufferDecision {
+    play: bool,
+    underrun: bool,
+}
+
+impl BufferController {
+    fn new(adaptive: Option<AdaptiveBufferConfig>, samples_per_second: f64) -> Self {
+        let target = adaptive
+            .as_ref()
+            .map_or(Duration::ZERO, |config| config.initial_target);
+        Self {
+            adaptive,
+            samples_per_second,
+            target,
+            buffering: true,
+            stable: Duration::ZERO,
+        }
+    }
+
+    // Start over as if playback had just begun, keeping the learned target
+    fn reset(&mut self) {
+        self.buffering = true;
+        self.stable = Duration::ZERO;
+    }
+
+    fn update(&mut self, queued: usize, needed: usize) -> BufferDecision {
+        if self.buffering {
+            let target_samples = self.target.as_secs_f64() * self.samples_per_second;
+            if queued == 0 || (queued as f64) < target_samples {
+                return BufferDecision {
+                    play: false,
+                    underrun: false,
+                };
+            }
+            self.buffering = false;
+        }
+
+        if queued < needed {
+            self.buffering = true;
+            self.stable = Duration::ZERO;
+            if let Some(config) = &self.adaptive {
+                self.target = (self.target + config.step).min(config.max_target);
+            }
+            return BufferDecision {
+                play: true,
+                underrun: true,
+            };
+        }
+
+        self.stable += Duration::from_secs_f64(needed as f64 / self.samples_per_second);
+        if let Some(config) = &self.adaptive {
+            if self.stable >= config.relax_after {
+                self.target = self
+                    .target
+                    .saturating_sub(config.step)
+                    .max(config.min_target);
+                self.stable = Duration::ZERO;
+            }
+        }
+        BufferDecision {
+            play: true,
+            underrun: false,
+        }
+    }
+}
+
+// Sample formats the output callback can write
+const OUTPUT_FORMATS: [SampleFormat; 3] = [SampleFormat::F32, SampleFormat::I16, SampleFormat::U16];
+
+/// Picks the rate and sample format to open an output device with for a
+/// stream of `sample_rate` and `channels`, from the device's `supported`
+/// configs and its default rate and format. The stream's own rate wins
+/// whenever the device offers it, so nothing is resampled; otherwise the
+/// default rate if offered, otherwise the closest one. The default format is
+/// preferred at the chosen rate, then f32. Only `forced` is considered when
+/// given. `None` when no config has the channel count in a usable format.
+pub fn negotiate_output(
+    supported: &[SupportedStreamConfigRange],
+    default_rate: u32,
+    default_format: SampleFormat,
+    sample_rate: u32,
+    channels: u16,
+    forced: Option<SampleFormat>,
+) -> Option<(u32, SampleFormat)> {
+    let usable: Vec<_> = supported
+        .iter()
+        .filter(|c| c.channels() == channels && OUTPUT_FORMATS.contains(&c.sample_format()))
+        .filter(|c| forced.is_none_or(|format| c.sample_format() == format))
+        .collect();
+    let offers = |rate: u32| {
+        usable
+            .iter()
+            .any(|c| c.min_sample_rate().0 <= rate && rate <= c.max_sample_rate().0)
+    };
+    let rate = if offers(sample_rate) {
+        sample_rate
+    } else if offers(default_rate) {
+        default_rate
+    } else {
+        usable
+            .iter()
+            .map(|c| sample_rate.clamp(c.min_sample_rate().0, c.max_sample_rate().0))
+            .min_by_key(|&rate| rate.abs_diff(sample_rate))?
+    };
+
+    let formats: Vec<_> = usable
+        .iter()
+        .filter(|c| c.min_sample_rate().0 <= rate && rate <= c.max_sample_rate().0)
+        .map(|c| c.sample_format())
+        .collect();
+    let format = [default_format, SampleFormat::F32]
+        .into_iter()
+        .find(|format| formats.contains(format))
+        .unwrap_or(formats[0]);
+    Some((rate, format))
+}
+
+/// Plays queued samples into a device buffer, padding with silence on underrun.
+pub fn fill_output<T>(queue: &mut PlaybackQueue, data: &mut [T])
+where
+    T: Sample + cpal::FromSample<f32>,
+{
+    for sample in data.iter_mut() {
+        *sample = f32_to_sample(queue.pop().unwrap_or(0.0));
+    }
+}
+
+// Measure each channel of interleaved `data` into `levels`, one per channel
+fn measure_levels<T>(data: &[T], levels: &mut [ChannelLevel])
+where
+    T: Sample,
+    f32: cpal::FromSample<T>,
+{
+    let channels = levels.len();
+    levels.fill(ChannelLevel::default());
+    // Sum squares in `rms` and take the root once at the end
+    for frame in data.chunks_exact(channels) {
+        for (level, &sample) in levels.iter_mut().zip(frame) {
+            let value = sample_to_f32(sample);
+            level.peak = level.peak.max(value.abs());
+            level.rms += value * value;
+        }
+    }
+    let frames = (data.len() / channels).max(1) as f32;
+    for level in levels {
+        level.rms = (level.rms / frames).sqrt();
+    }
+}
+
+// Like `fill_output`, running the samples through the equalizer first.
+// `scratch` is reused across callbacks to avoid allocating on the audio thread.
+fn fill_equalized<T>(
+    queue: &mut PlaybackQueue,
+    data: &mut [T],
+    equalizer: &mut Equalizer,
+    scratch: &mut Vec<f32>,
+) where
+    T: Sample + cpal::FromSample<f32>,
+{
+    scratch.clear();
+    scratch.extend((0..data.len()).map(|_| queue.pop().unwrap_or(0.0)));
+    equalizer.process(scratch);
+    for (sample, &value) in data.iter_mut().zip(scratch.iter()) {
+        *sample = f32_to_sample(value);
+    }
+}
+
+// Index in `available` of the first `preferred` device present: an exact
+// name, ignoring case, or failing that one containing it
+fn choose_device(preferred: &[String], available: &[String]) -> Option<usize> {
+    preferred.iter().find_map(|wanted| {
+        let wanted = wanted.to_lowercase();
+        available
+            .iter()
+            .position(|name| name.to_lowercase() == wanted)
+            .or_else(|| {
+                available
+                    .iter()
+                    .position(|name| name.to_lowercase().contains(&wanted))
+            })
+    })
+}
+
+// Replace each interleaved frame of `data` with its mono downmix on every
+// channel. Needs at least two channels.
+fn downmix_output<T>(data: &mut [T], channels: usize, downmix: Downmix)
+where
+    T: Sample + cpal::FromSample<f32>,
+    f32: cpal::FromSample<T>,
+{
+    for frame in data.chunks_exact_mut(channels) {
+        let mono = match downmix {
+            Downmix::Average => {
+                let sum: f32 = frame.iter().copied().map(sample_to_f32).sum();
+                f32_to_sample(sum / channels as f32)
+            }
+            Downmix::LeftOnly => frame[0],
+            Downmix::RightOnly => frame[1],
+        };
+        frame.fill(mono);
+    }
+}
+
+fn clamp_width(width: f32) -> f32 {
+    if width.is_nan() {
+        1.0
+    } else {
+        width.clamp(0.0, MAX_STEREO_WIDTH)
+    }
+}
+
+// Scale the side signal (L-R)/2 of each stereo frame by `width`, keeping the
+// mid (L+R)/2, and clip the result to full scale
+fn widen_output<T>(data: &mut [T], width: f32)
+where
+    T: Sample + cpal::FromSample<f32>,
+    f32: cpal::FromSample<T>,
+{
+    for frame in data.chunks_exact_mut(2) {
+        let (left, right) = (sample_to_f32(frame[0]), sample_to_f32(frame[1]));
+        let mid = (left + right) / 2.0;
+        let side = (left - right) / 2.0 * width;
+        frame[0] = f32_to_sample((mid + side).clamp(-1.0, 1.0));
+        frame[1] = f32_to_sample((mid - side).clamp(-1.0, 1.0));
+    }
+}
+
+// Delays output by a whole number of frames, holding exactly that many
+// samples between calls
+struct DelayLine<T> {
+    line: VecDeque<T>,
+}
+
+impl<T: Sample> DelayLine<T> {
+    fn new() -> Self {
+        Self {
+            line: VecDeque::new(),
+        }
+    }
+
+    // A longer delay than last time starts with silence, a shorter one drops
+    // the oldest held samples
+    fn process(&mut self, data: &mut [T], delay_samples: usize) {
+        if delay_samples == 0 && self.line.is_empty() {
+            return;
+        }
+        while self.line.len() < delay_samples {
+            self.line.push_front(T::EQUILIBRIUM);
+        }
+        let excess = self.line.len() - delay_samples;
+        self.line.drain(..excess);
+        for sample in data {
+            self.line.push_back(*sample);
+            *sample = self.line.pop_front().unwrap();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.line
+            .iter_mut()
+            .for_each(|sample| *sample = T::EQUILIBRIUM);
+    }
+}
+
+// Play the start of the queue faded out over `fade_frames`, then silence, and
+// empty the queue
+fn fill_flushing<T>(queue: &mut PlaybackQueue, data: &mut [T], channels: usize, fade_frames: usize)
+where
+    T: Sample + cpal::FromSample<f32>,
+{
+    let fade_frames = fade_frames.max(1);
+    for (frame_index, frame) in data.chunks_mut(channels).enumerate() {
+        let gain = 1.0 - (frame_index + 1) as f32 / fade_frames as f32;
+        for sample in frame {
+            let value = if gain > 0.0 {
+                queue.pop().unwrap_or(0.0) * gain
+            } else {
+                0.0
+            };
+            *sample = f32_to_sample(value);
+        }
+    }
+    queue.clear();
 }
 
 impl AudioPlayer {
     pub fn new() -> Result<Self> {
-        let host = cpal::default_host();
-        Ok(Self { host })
+        Self::with_config(PlayerConfig::default())
     }
 
-    pub fn start_playback(&self) -> Result<(mpsc::Sender<Vec<f32>>, cpal::Stream)> {
-        let device = self.host.default_output_device().ok_or_else(|| {
-            crate::AudioStreamerError::DeviceError("No output device found".into())
+    pub fn with_config(config: PlayerConfig) -> Result<Self> {
+        let host = select_host(config.host.as_deref())?;
+        let eq_gains = config
+            .equalizer
+            .iter()
+            .flat_map(|eq| &eq.bands)
+            .map(|band| AtomicU32::new(band.gain_db.to_bits()))
+            .collect();
+        Ok(Self {
+            host,
+            stream_format: Mutex::new((config.sample_rate, config.channels)),
+            streams_opened: AtomicU32::new(0),
+            width: Arc::new(AtomicU32::new(clamp_width(config.width).to_bits())),
+            output_delay: Arc::new(AtomicU64::new(
+                config.output_delay.min(MAX_OUTPUT_DELAY).as_micros() as u64,
+            )),
+            config,
+            stats: Arc::new(Mutex::new(PlaybackStats::default())),
+            flush_requested: Arc::new(AtomicBool::new(false)),
+            eq_gains: Arc::new(eq_gains),
+            eq_changed: Arc::new(AtomicBool::new(false)),
+            levels: Arc::new(Mutex::new(Vec::new())),
+            buffer_gauge: BufferGauge::default(),
+            playback_rx: Mutex::new(None),
+            device_name: Mutex::new(None),
+            device_lost: Arc::new(AtomicBool::new(false)),
+            playback_ended: Arc::new(watch::Sender::new(false)),
+        })
+    }
+
+    /// Peak and RMS of each output channel over the last device buffer.
+    /// Empty unless `PlayerConfig::metering` is set and playback has started.
+    pub fn levels(&self) -> Vec<ChannelLevel> {
+        self.levels.lock().unwrap().clone()
+    }
+
+    /// Live view of the audio queued for playback, updated by every output
+    /// callback. Can be handed to `AudioReceiver::track_playback`.
+    pub fn buffer_gauge(&self) -> BufferGauge {
+        self.buffer_gauge.clone()
+    }
+
+    /// Sets an equalizer band's gain in dB, 0.0 being flat. Safe to call
+    /// while the stream is running; takes effect on the next device callback.
+    /// Fails if the player has no equalizer or the band doesn't exist.
+    pub fn set_band_gain(&self, band: usize, db: f32) -> Result<()> {
+        let gain = self.eq_gains.get(band).ok_or_else(|| {
+            crate::AudioStreamerError::ConfigError(format!(
+                "EQ band {} out of range, the player has {} bands",
+                band,
+                self.eq_gains.len()
+            ))
         })?;
+        gain.store(db.to_bits(), Ordering::Relaxed);
+        self.eq_changed.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Sets the stereo width: 0.0 plays mono, 1.0 leaves the audio as it is
+    /// and larger values widen the image by boosting the difference between
+    /// the channels. Clamped to 0.0..=`MAX_STEREO_WIDTH`. Only affects stereo
+    /// output; takes effect on the next device callback.
+    pub fn set_width(&self, width: f32) {
+        self.width
+            .store(clamp_width(width).to_bits(), Ordering::Relaxed);
+    }
 
-        log::info!("Starting audio playback on device: {}", device.name()?);
+    pub fn width(&self) -> f32 {
+        f32::from_bits(self.width.load(Ordering::Relaxed))
+    }
+
+    /// Delays everything played by a fixed amount, e.g. to line audio up
+    /// with video. Applied to the output itself, on top of and unaffected by
+    /// any buffering against network jitter, and exact to the sample.
+    /// Capped at `MAX_OUTPUT_DELAY`. Takes effect on the next device
+    /// callback: a longer delay inserts silence, a shorter one skips ahead.
+    pub fn set_output_delay(&self, delay: Duration) {
+        let delay = delay.min(MAX_OUTPUT_DELAY);
+        self.output_delay
+            .store(delay.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn output_delay(&self) -> Duration {
+        Duration::from_micros(self.output_delay.load(Ordering::Relaxed))
+    }
+
+    /// Current gain of an equalizer band in dB.
+    pub fn band_gain(&self, band: usize) -> Option<f32> {
+        self.eq_gains
+            .get(band)
+            .map(|gain| f32::from_bits(gain.load(Ordering::Relaxed)))
+    }
+
+    /// Discards all buffered audio, both queued in the channel and waiting in
+    /// the output callback, after a short fade-out so playback stops without
+    /// a pop. Safe to call while the stream is running; takes effect on the
+    /// next device callback.
+    pub fn flush(&self) {
+        self.flush_requested.store(true, Ordering::Release);
+    }
+
+    /// Returns a snapshot of the playback statistics.
+    pub fn stats(&self) -> PlaybackStats {
+        *self.stats.lock().unwrap()
+    }
+
+    pub fn start_playback(&self) -> Result<(mpsc::Sender<Vec<f32>>, PlaybackStream)> {
+        let (tx, rx) = mpsc::channel(self.config.channel_capacity);
+        let rx = Arc::new(Mutex::new(Some(rx)));
+        *self.playback_rx.lock().unwrap() = Some(rx.clone());
+        self.playback_ended.send_replace(false);
+
+        let stream = self.open_stream(self.config.sample_rate, self.config.channels, rx)?;
+        Ok((tx, stream))
+    }
+
+    /// Replaces a running output stream with one for a new format, e.g. after
+    /// the sender announces a format change. The channel returned by
+    /// `start_playback` keeps working; audio still queued in the old format
+    /// is discarded. Pass `None` when a failed `reconfigure` or `reopen` left
+    /// no stream; the format is kept for `reopen` either way.
+    pub fn reconfigure(
+        &self,
+        stream: impl Into<Option<PlaybackStream>>,
+        sample_rate: u32,
+        channels: u16,
+    ) -> Result<PlaybackStream> {
+        let rx = self.playback_rx.lock().unwrap().clone().ok_or_else(|| {
+            crate::AudioStreamerError::ConfigError("Playback has not been started".into())
+        })?;
+        // The old stream must stop pulling from the channel before the new one starts
+        drop(stream.into());
+        *self.stream_format.lock().unwrap() = (sample_rate, channels);
+        self.flush_requested.store(true, Ordering::Release);
+        self.open_stream(sample_rate, channels, rx)
+    }
+
+    /// Whether the current stream's device has gone away, e.g. unplugged.
+    /// Playback stays silent until `reopen`.
+    pub fn device_lost(&self) -> bool {
+        self.device_lost.load(Ordering::Acquire)
+    }
+
+    /// Whether every sender returned by `start_playback` has been dropped
+    /// and the audio sent through them has played, e.g. at the end of a
+    /// file. The stream then only plays silence, so drop it to release the
+    /// device; the virtual backends stop and flush their output themselves.
+    pub fn playback_ended(&self) -> bool {
+        *self.playback_ended.borrow()
+    }
+
+    /// Waits until `playback_ended`.
+    pub async fn wait_for_playback_end(&self) {
+        let mut ended = self.playback_ended.subscribe();
+        // The sender lives as long as the player, so this can't fail
+        let _ = ended.wait_for(|&ended| ended).await;
+    }
+
+    /// Name of the device the current stream plays on.
+    pub fn device_name(&self) -> Option<String> {
+        self.device_name.lock().unwrap().clone()
+    }
+
+    /// Replaces the stream with one on the most preferred device present,
+    /// going through `PlayerConfig::output_devices` again, e.g. after
+    /// `device_lost`. Audio still queued is discarded. Pass `None` to retry
+    /// after a failed attempt left no stream.
+    pub fn reopen(&self, stream: impl Into<Option<PlaybackStream>>) -> Result<PlaybackStream> {
+        let (sample_rate, channels) = *self.stream_format.lock().unwrap();
+        self.reconfigure(stream, sample_rate, channels)
+    }
+
+    // First device of `PlayerConfig::output_devices` that is present,
+    // otherwise the default device
+    fn output_device(&self) -> Result<cpal::Device> {
+        let preferred = &self.config.output_devices;
+        if !preferred.is_empty() {
+            let devices: Vec<_> = self.host.output_devices()?.collect();
+            let names: Vec<String> = devices
+                .iter()
+                .map(|device| device.name().unwrap_or_default())
+                .collect();
+            match choose_device(preferred, &names) {
+                Some(index) => return Ok(devices.into_iter().nth(index).unwrap()),
+                None => log::warn!(
+                    "None of the output devices {:?} is present, using the default",
+                    preferred
+                ),
+            }
+        }
+        self.host
+            .default_output_device()
+            .ok_or_else(|| crate::AudioStreamerError::DeviceError("No output device found".into()))
+    }
+
+    fn open_stream(
+        &self,
+        sample_rate: u32,
+        channels: u16,
+        rx: PlaybackReceiver,
+    ) -> Result<PlaybackStream> {
+        let stream = match &self.config.backend {
+            PlayerBackend::Device => PlaybackStream {
+                device: Some(self.open_device_stream(sample_rate, channels, rx)?),
+                _output: None,
+            },
+            _ => PlaybackStream {
+                device: None,
+                _output: Some(self.open_virtual_stream(sample_rate, channels, rx)?),
+            },
+        };
+        self.device_lost.store(false, Ordering::Release);
+        *self.stream_format.lock().unwrap() = (sample_rate, channels);
+        Ok(stream)
+    }
+
+    fn open_virtual_stream(
+        &self,
+        sample_rate: u32,
+        channels: u16,
+        rx: PlaybackReceiver,
+    ) -> Result<VirtualOutput> {
+        let opened = self.streams_opened.fetch_add(1, Ordering::Relaxed);
+        let (name, mut sink): (String, Option<Box<dyn AudioSink + Send>>) =
+            match &self.config.backend {
+                PlayerBackend::Buffer(buffer) => ("buffer".into(), Some(Box::new(buffer.clone()))),
+                PlayerBackend::File(path) => {
+                    let path = numbered_path(path, opened + 1);
+                    let writer =
+                        WavWriter::create(&path, sample_rate, channels, BitDepth::Float32)?;
+                    (path.display().to_string(), Some(Box::new(writer)))
+                }
+                _ => ("null output".into(), None),
+            };
+        log::info!("Starting audio playback into {}", name);
+
+        let period_frames = (sample_rate as f64 * VIRTUAL_PERIOD.as_secs_f64()).max(1.0) as usize;
+        let period = Duration::from_secs_f64(period_frames as f64 / sample_rate as f64);
+        let mut data = vec![0.0f32; period_frames * channels as usize];
+        // The callback ends playback on its own watch, which is passed on only
+        // once the final period has been written, so waiters see all of it
+        let finished = Arc::new(watch::Sender::new(false));
+        let mut callback =
+            self.output_callback::<f32>(sample_rate, channels, rx, None, finished.clone());
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopping = stop.clone();
+        let device_lost = self.device_lost.clone();
+        let ended = self.playback_ended.clone();
+        let thread = std::thread::Builder::new()
+            .name("virtual-output".into())
+            .spawn(move || {
+                let mut next = Instant::now();
+                while !stopping.load(Ordering::Acquire) {
+                    callback(&mut data, Some(Duration::ZERO));
+                    if let Some(sink) = sink.as_mut() {
+                        if let Err(e) = sink.write(&data) {
+                            log::error!("Playback error: {}", e);
+                            device_lost.store(true, Ordering::Release);
+                            return;
+                        }
+                    }
+                    if *finished.borrow() {
+                        ended.send_replace(true);
+                        break;
+                    }
+                    next += period;
+                    std::thread::sleep(next.saturating_duration_since(Instant::now()));
+                }
+                if let Some(Err(e)) = sink.as_mut().map(|sink| sink.flush()) {
+                    log::error!("Playback error: {}", e);
+                }
+            })?;
+
+        *self.device_name.lock().unwrap() = Some(name);
+        Ok(VirtualOutput {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    fn open_device_stream(
+        &self,
+        sample_rate: u32,
+        channels: u16,
+        rx: PlaybackReceiver,
+    ) -> Result<cpal::Stream> {
+        let device = self.output_device()?;
+        let device_name = device.name()?;
+        log::info!("Starting audio playback on device: {}", device_name);
+
+        // Devices that can't run at the stream's rate, e.g. 16kHz voice, play it resampled
+        let (device_rate, format) = self.output_config(&device, sample_rate, channels)?;
+        let resampler = (device_rate != sample_rate).then(|| {
+            log::info!(
+                "Resampling playback from {}Hz to {}Hz",
+                sample_rate,
+                device_rate
+            );
+            Resampler::new(sample_rate, device_rate, channels)
+        });
 
         // Use the lowest possible buffer size for minimum latency
         let config = cpal::StreamConfig {
-            channels: 2,
-            sample_rate: cpal::SampleRate(48000),
+            channels,
+            sample_rate: cpal::SampleRate(device_rate),
             buffer_size: cpal::BufferSize::Default, // Let the system choose the lowest safe value
         };
 
         log::info!("Using output config: {:?}", config);
 
-        let (tx, rx) = mpsc::channel(32);
-        let rx = Arc::new(Mutex::new(Some(rx)));
-
-        let err_fn = |err| log::error!("Playback error: {}", err);
+        let device_lost = self.device_lost.clone();
+        let err_fn = move |err| {
+            if matches!(err, cpal::StreamError::DeviceNotAvailable) {
+                device_lost.store(true, Ordering::Release);
+            }
+            log::error!("Playback error: {}", err);
+        };
 
-        let stream = match device.default_output_config()?.sample_format() {
+        let stream = match format {
             SampleFormat::F32 => {
-                self.build_output_stream::<f32>(&device, &config, rx.clone(), err_fn)?
+                self.build_output_stream::<f32>(&device, &config, rx, resampler, err_fn)?
             }
             SampleFormat::I16 => {
-                self.build_output_stream::<i16>(&device, &config, rx.clone(), err_fn)?
+                self.build_output_stream::<i16>(&device, &config, rx, resampler, err_fn)?
             }
             SampleFormat::U16 => {
-                self.build_output_stream::<u16>(&device, &config, rx.clone(), err_fn)?
+                self.build_output_stream::<u16>(&device, &config, rx, resampler, err_fn)?
             }
             _ => {
                 return Err(crate::AudioStreamerError::DeviceError(
@@ -54,47 +1114,665 @@ impl AudioPlayer {
         };
 
         stream.play()?;
-        Ok((tx, stream))
+        *self.device_name.lock().unwrap() = Some(device_name);
+        Ok(stream)
+    }
+
+    // Negotiates the output rate and sample format with the device
+    fn output_config(
+        &self,
+        device: &cpal::Device,
+        sample_rate: u32,
+        channels: u16,
+    ) -> Result<(u32, SampleFormat)> {
+        let default = device.default_output_config()?;
+        // Devices that can't list their configs are asked for the stream's rate
+        let supported: Vec<_> = device
+            .supported_output_configs()
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        let negotiated = negotiate_output(
+            &supported,
+            default.sample_rate().0,
+            default.sample_format(),
+            sample_rate,
+            channels,
+            self.config.output_format,
+        );
+        if negotiated.is_none() {
+            if let Some(format) = self.config.output_format {
+                return Err(crate::AudioStreamerError::ConfigError(format!(
+                    "Output device does not support {:?} with {} channels",
+                    format, channels
+                )));
+            }
+        }
+        let (rate, format) = negotiated.unwrap_or_else(|| {
+            // Devices listing configs, none of them with our channel count,
+            // run at their default rate
+            let rate = if supported.is_empty() {
+                sample_rate
+            } else {
+                default.sample_rate().0
+            };
+            (rate, default.sample_format())
+        });
+        if self.config.output_format.is_some() {
+            log::info!("Using forced output sample format {:?}", format);
+        }
+        Ok((rate, format))
     }
 
     fn build_output_stream<T>(
         &self,
         device: &cpal::Device,
         config: &cpal::StreamConfig,
-        rx: Arc<Mutex<Option<mpsc::Receiver<Vec<f32>>>>>,
+        rx: PlaybackReceiver,
+        resampler: Option<Resampler>,
         error_fn: impl FnMut(cpal::StreamError) + Send + 'static + 'static,
     ) -> Result<cpal::Stream>
     where
-        T: Sample + SizedSample + cpal::FromSample<f32>,
+        T: Sample + SizedSample + Send + 'static + cpal::FromSample<f32>,
+        f32: cpal::FromSample<T>,
     {
+        let mut callback = self.output_callback::<T>(
+            config.sample_rate.0,
+            config.channels,
+            rx,
+            resampler,
+            self.playback_ended.clone(),
+        );
         let stream = device.build_output_stream(
             config,
-            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                // Try to get new samples without blocking
-                let mut rx_lock = rx.lock().unwrap();
-                if let Some(rx) = rx_lock.as_mut() {
-                    if let Ok(samples) = rx.try_recv() {
-                        // We have new samples, play them
-                        for (i, &sample) in samples.iter().take(data.len()).enumerate() {
-                            data[i] = T::from_sample(sample);
+            move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+                let timestamp = info.timestamp();
+                callback(data, timestamp.playback.duration_since(&timestamp.callback));
+            },
+            error_fn,
+            None,
+        )?;
+
+        Ok(stream)
+    }
+
+    // Fills a device buffer from the playback channel, given the device's
+    // latency, for either a device stream or a virtual backend
+    fn output_callback<T>(
+        &self,
+        sample_rate: u32,
+        output_channels: u16,
+        rx: PlaybackReceiver,
+        mut resampler: Option<Resampler>,
+        playback_ended: Arc<watch::Sender<bool>>,
+    ) -> impl FnMut(&mut [T], Option<Duration>) + Send + 'static
+    where
+        T: Sample + Send + 'static + cpal::FromSample<f32>,
+        f32: cpal::FromSample<T>,
+    {
+        let mut queue = match self.config.crossfade_frames {
+            Some(frames) => PlaybackQueue::with_crossfade(output_channels, frames),
+            None => PlaybackQueue::default(),
+        };
+        let max_latency = self.config.max_latency;
+        let samples_per_second = sample_rate as f64 * output_channels as f64;
+        let max_queued = (max_latency.as_secs_f64() * samples_per_second) as usize;
+        let mut controller =
+            BufferController::new(self.config.adaptive_buffer.clone(), samples_per_second);
+        let stats = self.stats.clone();
+        let flush_requested = self.flush_requested.clone();
+        let channels = output_channels as usize;
+        let fade_frames = (FLUSH_FADE.as_secs_f64() * sample_rate as f64) as usize;
+        let mut equalizer = self
+            .config
+            .equalizer
+            .as_ref()
+            .map(|eq| Equalizer::new(eq, sample_rate, output_channels));
+        let eq_gains = self.eq_gains.clone();
+        let eq_changed = self.eq_changed.clone();
+        let mut scratch = Vec::new();
+        let metering = self.config.metering;
+        let mono = self.config.mono && channels > 1;
+        let downmix = self.config.downmix;
+        let stereo = channels == 2;
+        let width = self.width.clone();
+        let output_delay = self.output_delay.clone();
+        let mut delay_line = DelayLine::new();
+        let mut measured = vec![ChannelLevel::default(); channels];
+        let levels = self.levels.clone();
+        let buffer_gauge = self.buffer_gauge.clone();
+        // Samples played since the channel closed and the queue ran dry,
+        // which end playback once the output delay has played out too
+        let mut tail = 0;
+        let mut signalled = false;
+
+        move |data: &mut [T], device_latency: Option<Duration>| {
+            let flushing = flush_requested.swap(false, Ordering::Acquire);
+
+            // Pull everything that has arrived without blocking
+            let mut closed = false;
+            if let Some(rx) = rx.lock().unwrap().as_mut() {
+                loop {
+                    match rx.try_recv() {
+                        Ok(samples) if !flushing => match resampler.as_mut() {
+                            Some(resampler) => {
+                                let mut resampled = queue.spare_buffer();
+                                resampler.process_into(&samples, &mut resampled);
+                                queue.push(resampled);
+                            }
+                            None => queue.push(samples),
+                        },
+                        Ok(_) => {}
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            closed = true;
+                            break;
                         }
-                        // Fill any remaining space with silence
-                        for sample in data.iter_mut().skip(samples.len()) {
-                            *sample = T::from_sample(0.0f32);
+                    }
+                }
+            }
+
+            if flushing {
+                fill_flushing(&mut queue, data, channels, fade_frames);
+                controller.reset();
+                delay_line.clear();
+                return;
+            }
+
+            let skipped = queue.trim_to(max_queued);
+            if skipped > 0 {
+                log::warn!(
+                    "Playback latency above {:?}, skipped {} buffers to catch up",
+                    max_latency,
+                    skipped
+                );
+            }
+
+            // Whatever is left plays out without waiting to buffer more
+            let mut decision = controller.update(queue.queued, data.len());
+            if closed {
+                decision.play = true;
+                decision.underrun = false;
+            }
+            if decision.play {
+                match equalizer.as_mut() {
+                    Some(eq) => {
+                        if eq_changed.swap(false, Ordering::Acquire) {
+                            for (band, gain) in eq_gains.iter().enumerate() {
+                                let db = f32::from_bits(gain.load(Ordering::Relaxed));
+                                // Indices come from the same config, so this can't fail
+                                let _ = eq.set_band_gain(band, db);
+                            }
                         }
-                        return;
+                        fill_equalized(&mut queue, data, eq, &mut scratch);
+                    }
+                    None => fill_output(&mut queue, data),
+                }
+                if mono {
+                    downmix_output(data, channels, downmix);
+                } else if stereo {
+                    let width = f32::from_bits(width.load(Ordering::Relaxed));
+                    if width != 1.0 {
+                        widen_output(data, width);
+                    }
+                }
+            } else {
+                data.fill(T::EQUILIBRIUM);
+            }
+
+            let delay_us = output_delay.load(Ordering::Relaxed) as u128;
+            let delay_frames = (delay_us * sample_rate as u128 + 500_000) / 1_000_000;
+            delay_line.process(data, delay_frames as usize * channels);
+
+            if closed && queue.queued == 0 {
+                if tail >= delay_frames as usize * channels && !signalled {
+                    signalled = true;
+                    if playback_ended.send_if_modified(|ended| !std::mem::replace(ended, true)) {
+                        log::info!("Playback channel closed, playback ended");
                     }
                 }
+                tail += data.len();
+            }
 
-                // If we couldn't get new samples, output silence
-                for sample in data.iter_mut() {
-                    *sample = T::from_sample(0.0f32);
+            if metering {
+                measure_levels(data, &mut measured);
+                // Never wait on a reader; a skipped update is replaced next callback
+                if let Ok(mut levels) = levels.try_lock() {
+                    levels.clone_from(&measured);
                 }
+            }
+
+            let buffered = BufferLevel::new(
+                queue.buffer_count(),
+                queue.queued,
+                sample_rate,
+                output_channels,
+            );
+            buffer_gauge.set(buffered);
+
+            let mut stats = stats.lock().unwrap();
+            stats.buffered = buffered;
+            stats.underruns += decision.underrun as u64;
+            stats.overruns += (skipped > 0) as u64;
+            stats.target_buffer = controller.target;
+            stats.device_latency = device_latency;
+            stats.device_period =
+                Some(BufferLevel::new(1, data.len(), sample_rate, output_channels).duration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_spans_buffers_and_pads_with_silence() {
+        let mut queue = PlaybackQueue::default();
+        queue.push(vec![0.1, 0.2]);
+        queue.push(vec![0.3]);
+
+        let mut out = [1.0f32; 5];
+        fill_output(&mut queue, &mut out);
+        assert_eq!(out, [0.1, 0.2, 0.3, 0.0, 0.0]);
+        assert_eq!(queue.queued, 0);
+    }
+
+    #[test]
+    fn fill_level_counts_unplayed_buffers() {
+        let mut queue = PlaybackQueue::default();
+        queue.push(vec![0.1, 0.2]);
+        queue.push(vec![0.3, 0.4]);
+
+        let mut out = [0.0f32; 2];
+        fill_output(&mut queue, &mut out);
+        assert_eq!(queue.buffer_count(), 1);
+        assert_eq!(queue.queued_samples(), 2);
+
+        fill_output(&mut queue, &mut out[..1]);
+        assert_eq!(queue.buffer_count(), 1);
+        assert_eq!(queue.queued_samples(), 1);
+    }
+
+    #[test]
+    fn played_buffers_are_reused() {
+        let mut queue = PlaybackQueue::default();
+        let buffer = vec![0.1, 0.2];
+        let address = buffer.as_ptr();
+        queue.push(buffer);
+        queue.push(vec![0.3]);
+
+        let mut out = [0.0f32; 3];
+        fill_output(&mut queue, &mut out);
+        let spare = queue.spare_buffer();
+        assert!(spare.is_empty());
+        assert_eq!(spare.as_ptr(), address);
+    }
+
+    #[test]
+    fn fill_converts_to_output_format() {
+        let mut queue = PlaybackQueue::default();
+        queue.push(vec![0.0, -1.0, 0.5]);
+
+        let mut out = [0i16; 3];
+        fill_output(&mut queue, &mut out);
+        assert_eq!(out, [0, i16::MIN, 16384]);
+    }
+
+    #[test]
+    fn trim_drops_whole_buffers_from_the_front() {
+        let mut queue = PlaybackQueue::default();
+        for i in 0..4 {
+            queue.push(vec![i as f32; 4]);
+        }
+        queue.pop();
+
+        // 15 samples queued; dropping the partial front buffer leaves 12
+        assert_eq!(queue.trim_to(12), 1);
+        assert_eq!(queue.queued, 12);
+        assert_eq!(queue.pop(), Some(1.0));
+    }
+
+    #[test]
+    fn output_negotiation_avoids_resampling_when_it_can() {
+        use cpal::{SampleRate, SupportedBufferSize};
+        let range = |channels, min, max, format| {
+            SupportedStreamConfigRange::new(
+                channels,
+                SampleRate(min),
+                SampleRate(max),
+                SupportedBufferSize::Unknown,
+                format,
+            )
+        };
+        let supported = [
+            range(2, 44100, 48000, SampleFormat::F32),
+            range(2, 8000, 96000, SampleFormat::I16),
+            range(6, 48000, 48000, SampleFormat::F32),
+        ];
+        let negotiate = |rate, channels, forced| {
+            negotiate_output(&supported, 48000, SampleFormat::F32, rate, channels, forced)
+        };
+
+        // The stream's rate, in the default format where it's offered
+        assert_eq!(negotiate(44100, 2, None), Some((44100, SampleFormat::F32)));
+        assert_eq!(negotiate(16000, 2, None), Some((16000, SampleFormat::I16)));
+        assert_eq!(
+            negotiate(16000, 2, Some(SampleFormat::F32)),
+            Some((48000, SampleFormat::F32))
+        );
+        assert_eq!(negotiate(16000, 6, None), Some((48000, SampleFormat::F32)));
+        assert_eq!(negotiate(48000, 1, None), None);
+
+        // Without the default rate, the closest one
+        let narrow = [range(2, 88200, 96000, SampleFormat::I16)];
+        assert_eq!(
+            negotiate_output(&narrow, 48000, SampleFormat::F32, 44100, 2, None),
+            Some((88200, SampleFormat::I16))
+        );
+    }
+
+    #[test]
+    fn latency_budgets_leave_headroom_below_the_maximum() {
+        let config = AdaptiveBufferConfig::within(Duration::from_millis(100));
+        assert_eq!(config.initial_target, Duration::from_millis(25));
+        assert_eq!(config.min_target, Duration::from_millis(10));
+        assert_eq!(config.max_target, Duration::from_millis(75));
+        assert_eq!(config.step, Duration::from_millis(10));
+
+        let stats = PlaybackStats {
+            buffered: BufferLevel {
+                packets: 3,
+                duration: Duration::from_millis(30),
             },
-            error_fn,
-            None,
-        )?;
+            device_latency: Some(Duration::from_millis(5)),
+            ..PlaybackStats::default()
+        };
+        assert_eq!(stats.latency(), Duration::from_millis(35));
 
-        Ok(stream)
+        let packet = Duration::from_micros(7_500);
+        let period = Duration::from_millis(5);
+        assert!(check_latency_budget(Duration::from_millis(8), packet, period).is_ok());
+        assert!(check_latency_budget(Duration::from_millis(7), packet, period).is_err());
+        assert!(check_latency_budget(Duration::ZERO, Duration::ZERO, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn controller_grows_target_on_underrun_and_relaxes_when_stable() {
+        let config = AdaptiveBufferConfig {
+            initial_target: Duration::from_millis(20),
+            min_target: Duration::from_millis(10),
+            max_target: Duration::from_millis(30),
+            step: Duration::from_millis(10),
+            relax_after: Duration::from_millis(30),
+        };
+        // 1000 samples per second, so 1 sample per millisecond
+        let mut controller = BufferController::new(Some(config), 1000.0);
+
+        // Waits for the initial target before playing
+        assert!(!controller.update(19, 10).play);
+        assert!(controller.update(20, 10).play);
+
+        // Running dry grows the target and rebuffers
+        let decision = controller.update(5, 10);
+        assert!(decision.play && decision.underrun);
+        assert_eq!(controller.target, Duration::from_millis(30));
+        assert!(!controller.update(25, 10).play);
+
+        // 30ms of clean playback shrinks it one step
+        for _ in 0..3 {
+            assert_eq!(
+                controller.update(30, 10),
+                BufferDecision {
+                    play: true,
+                    underrun: false
+                }
+            );
+        }
+        assert_eq!(controller.target, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn crossfade_blends_buffer_heads_from_the_last_frame() {
+        let mut queue = PlaybackQueue::with_crossfade(2, 1);
+        queue.push(vec![1.0, -1.0]);
+        queue.push(vec![0.0, 0.0, 0.0, 0.0]);
+
+        let mut out = [9.0f32; 6];
+        fill_output(&mut queue, &mut out);
+        // First frame of the second buffer is halfway between the two
+        assert_eq!(out, [1.0, -1.0, 0.5, -0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn flushing_fades_out_and_empties_the_queue() {
+        let mut queue = PlaybackQueue::default();
+        queue.push(vec![1.0; 8]);
+        queue.push(vec![1.0; 8]);
+
+        let mut out = [9.0f32; 8];
+        fill_flushing(&mut queue, &mut out, 2, 2);
+        assert_eq!(out, [0.5, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(queue.queued, 0);
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn equalized_fill_converts_processed_samples() {
+        let mut config = EqConfig::default();
+        config.bands[2].gain_db = 6.0;
+        let mut equalizer = Equalizer::new(&config, 48_000, 1);
+        let mut scratch = Vec::new();
+
+        let mut queue = PlaybackQueue::default();
+        queue.push(vec![0.25]);
+        let mut out = [0i16; 3];
+        fill_equalized(&mut queue, &mut out, &mut equalizer, &mut scratch);
+
+        // The boosted band's first output is the input scaled by b0, then
+        // the filter rings out on the silence padding
+        assert!(out[0] > f32_to_sample::<i16>(0.25));
+        assert_ne!(out[1], 0);
+    }
+
+    #[test]
+    fn downmix_plays_the_channel_average_everywhere() {
+        let mut data = [1.0f32, 0.0, 0.25, 0.75];
+        downmix_output(&mut data, 2, Downmix::Average);
+        assert_eq!(data, [0.5, 0.5, 0.5, 0.5]);
+
+        let mut data = [i16::MAX, 0];
+        downmix_output(&mut data, 2, Downmix::Average);
+        assert_eq!(data[0], data[1]);
+        assert!((data[0] as i32 - i16::MAX as i32 / 2).abs() <= 1);
+    }
+
+    #[test]
+    fn downmix_can_take_one_side_alone() {
+        let mut data = [1.0f32, 0.0, 0.25, 0.75];
+        downmix_output(&mut data, 2, Downmix::LeftOnly);
+        assert_eq!(data, [1.0, 1.0, 0.25, 0.25]);
+
+        let mut data = [0.1f32, 0.2, 0.3, 0.4, 0.5, 0.6];
+        downmix_output(&mut data, 3, Downmix::RightOnly);
+        assert_eq!(data, [0.2, 0.2, 0.2, 0.5, 0.5, 0.5]);
+
+        assert_eq!("left".parse(), Ok(Downmix::LeftOnly));
+        assert_eq!("Sum".parse(), Ok(Downmix::Average));
+        assert!("centre".parse::<Downmix>().is_err());
+    }
+
+    #[test]
+    fn width_scales_the_side_signal() {
+        let mut data = [1.0f32, 0.0, 0.5, 0.5];
+        widen_output(&mut data, 0.0);
+        assert_eq!(data, [0.5, 0.5, 0.5, 0.5]);
+
+        let mut data = [0.5f32, 0.0, 0.25, -0.25];
+        widen_output(&mut data, 2.0);
+        assert_eq!(data, [0.75, -0.25, 0.5, -0.5]);
+
+        let mut data = [1.0f32, -1.0];
+        widen_output(&mut data, 2.0);
+        assert_eq!(data, [1.0, -1.0]);
+
+        assert_eq!(clamp_width(-1.0), 0.0);
+        assert_eq!(clamp_width(10.0), MAX_STEREO_WIDTH);
+        assert_eq!(clamp_width(f32::NAN), 1.0);
+    }
+
+    #[test]
+    fn delay_line_shifts_output_by_whole_frames() {
+        let mut delay = DelayLine::new();
+        let mut data = [1.0f32, 2.0, 3.0, 4.0];
+        delay.process(&mut data, 0);
+        assert_eq!(data, [1.0, 2.0, 3.0, 4.0]);
+
+        delay.process(&mut data, 2);
+        assert_eq!(data, [0.0, 0.0, 1.0, 2.0]);
+        let mut data = [5.0f32, 6.0, 7.0, 8.0];
+        delay.process(&mut data, 2);
+        assert_eq!(data, [3.0, 4.0, 5.0, 6.0]);
+
+        // Shortening the delay skips the oldest held samples
+        let mut data = [9.0f32, 10.0];
+        delay.process(&mut data, 0);
+        assert_eq!(data, [9.0, 10.0]);
+
+        let mut data = [1i16, 2];
+        let mut delay = DelayLine::new();
+        delay.process(&mut data, 1);
+        assert_eq!(data, [0, 1]);
+        delay.clear();
+        delay.process(&mut data, 1);
+        assert_eq!(data, [0, 0]);
+    }
+
+    #[test]
+    fn levels_are_measured_per_channel() {
+        let mut levels = [ChannelLevel::default(); 2];
+        // Left alternates at half scale, right is silent
+        measure_levels(&[0.5f32, 0.0, -0.5, 0.0], &mut levels);
+        assert_eq!(
+            levels[0],
+            ChannelLevel {
+                peak: 0.5,
+                rms: 0.5
+            }
+        );
+        assert_eq!(levels[1], ChannelLevel::default());
+
+        measure_levels(&[i16::MIN, 0, 0, 0], &mut levels);
+        assert_eq!(levels[0].peak, 1.0);
+        assert!((levels[0].rms - 0.5f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn virtual_backends_play_without_hardware() {
+        let buffer = BufferSink::new();
+        let path = std::env::temp_dir().join(format!("virtual-{}.wav", std::process::id()));
+        let played = |samples: Vec<f32>| samples.iter().filter(|&&x| x == 0.5).count();
+        for backend in [
+            PlayerBackend::Buffer(buffer.clone()),
+            PlayerBackend::File(path.clone()),
+        ] {
+            let player = AudioPlayer::with_config(PlayerConfig {
+                backend: backend.clone(),
+                ..PlayerConfig::default()
+            })
+            .unwrap();
+            let (tx, stream) = player.start_playback().unwrap();
+            assert!(stream.device_stream().is_none());
+            for _ in 0..3 {
+                tx.try_send(vec![0.5; 960]).unwrap();
+            }
+            drop(tx);
+            // 30ms of audio, played in real time. A file can't be read back
+            // until it is flushed, so there the end of playback is awaited.
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while !match &backend {
+                PlayerBackend::Buffer(buffer) => played(buffer.samples()) == 2880,
+                _ => player.playback_ended(),
+            } {
+                assert!(Instant::now() < deadline, "timed out waiting for playback");
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            drop(stream);
+        }
+
+        assert_eq!(played(buffer.samples()), 2880);
+        let recorded = crate::wav::WavReader::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recorded.channels(), 2);
+        assert_eq!(played(recorded.into_samples()), 2880);
+    }
+
+    #[test]
+    fn file_backend_rolls_over_on_reconfigure() {
+        let path = std::env::temp_dir().join(format!("rollover-{}.wav", std::process::id()));
+        let player = AudioPlayer::with_config(PlayerConfig {
+            backend: PlayerBackend::File(path.clone()),
+            ..PlayerConfig::default()
+        })
+        .unwrap();
+        let (_tx, stream) = player.start_playback().unwrap();
+        let stream = player.reconfigure(stream, 16_000, 1).unwrap();
+        drop(stream);
+
+        let second = path.with_file_name(format!("rollover-{}-2.wav", std::process::id()));
+        assert_eq!(numbered_path(&path, 2), second);
+        let first_channels = crate::wav::WavReader::open(&path).unwrap().channels();
+        let second_channels = crate::wav::WavReader::open(&second).unwrap().channels();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&second).unwrap();
+        assert_eq!(first_channels, 2);
+        assert_eq!(second_channels, 1);
+    }
+
+    #[tokio::test]
+    async fn closing_the_channel_ends_playback() {
+        let buffer = BufferSink::new();
+        let player = AudioPlayer::with_config(PlayerConfig {
+            backend: PlayerBackend::Buffer(buffer.clone()),
+            ..PlayerConfig::default()
+        })
+        .unwrap();
+        let (tx, _stream) = player.start_playback().unwrap();
+        tx.send(vec![0.5; 960]).await.unwrap();
+        drop(tx);
+
+        tokio::time::timeout(Duration::from_secs(2), player.wait_for_playback_end())
+            .await
+            .expect("playback never ended");
+        // Nothing was cut short, and the virtual output stopped with the audio
+        let played = buffer.samples();
+        assert_eq!(played.iter().filter(|&&x| x == 0.5).count(), 960);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(buffer.samples().len(), played.len());
+    }
+
+    #[test]
+    fn output_devices_are_chosen_in_order_of_preference() {
+        let available: Vec<String> = ["HDMI Output", "Built-in Speakers", "USB DAC (Stereo)"]
+            .map(String::from)
+            .into();
+        let preferred =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+
+        assert_eq!(
+            choose_device(&preferred(&["usb dac", "HDMI"]), &available),
+            Some(2)
+        );
+        // An absent device falls through to the next one
+        assert_eq!(
+            choose_device(&preferred(&["Headphones", "hdmi"]), &available),
+            Some(0)
+        );
+        assert_eq!(choose_device(&preferred(&["Headphones"]), &available), None);
+        // An exact name beats a longer one containing it
+        let available: Vec<String> = ["Speakers (2)", "Speakers"].map(String::from).into();
+        assert_eq!(
+            choose_device(&preferred(&["speakers"]), &available),
+            Some(1)
+        );
     }
 }
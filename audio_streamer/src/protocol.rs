@@ -0,0 +1,856 @@
+//! Wire format of audio packets.
+//!
+//! Every packet starts with a fixed header followed by the payload, interleaved
+//! f32 samples or, with the FLAC flag set, one FLAC frame or, with the zstd
+//! flag set, the f32 samples as one zstd frame or, with the i16 flag set,
+//! interleaved i16 samples:
+//!
+//! | offset | size | field                                               |
+//! |--------|------|-----------------------------------------------------|
+//! | 0      | 1    | protocol version (`PROTOCOL_VERSION`)               |
+//! | 1      | 1    | flags, bit 0 set = little-endian payload            |
+//! |        |      | bit 1 set = FLAC payload                            |
+//! |        |      | bit 2 set = zstd-compressed payload                 |
+//! |        |      | bit 3 set = i16 samples                             |
+//! | 2      | 2    | pre-emphasis coefficient, 0 = none (see below)      |
+//! | 4      | 4    | sequence number from 1, wrapping; 0 = unnumbered    |
+//! | 8      | 4    | sender wall clock in milliseconds (wrapping)        |
+//! | 12     | 8    | session epoch, microseconds since the Unix epoch    |
+//! | 20     | 8    | frame position of the first sample in the session   |
+//!
+//! All multi-byte fields and samples are little endian regardless of host byte
+//! order. Receivers reject packets from other versions or that don't declare a
+//! little-endian payload rather than silently playing corrupted audio.
+//!
+//! The pre-emphasis coefficient is in units of `dsp::EMPHASIS_SCALE`, e.g.
+//! 9500 for 0.95. Receivers undo it with a matching `dsp::DeEmphasis`. It was
+//! reserved and zero in earlier releases, which still play such streams, just
+//! without de-emphasis.
+//!
+//! # A/V sync
+//!
+//! The epoch is the sender's wall clock when the session started, and the
+//! frame position counts frames (one sample per channel) produced since then,
+//! including any withheld by the silence gate. The presentation time of a
+//! packet's first frame is therefore `epoch + position / sample_rate`, on the
+//! sender's clock. A video player aligning to it must share that clock, e.g.
+//! both hosts synchronized with NTP or PTP; the receiver does not correct for
+//! clock offset or network delay.
+
+use std::fmt;
+use std::net::SocketAddrV4;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::dsp::check_emphasis;
+#[cfg(feature = "flac")]
+use crate::flac::FlacEncoder;
+use crate::sample::{f32_to_sample, sample_to_f32};
+use crate::{AudioStreamerError, Result};
+
+pub const PROTOCOL_VERSION: u8 = 2;
+pub const HEADER_SIZE: usize = 28;
+
+/// Sample rates a stream can be sent at. Lower rates trade treble for
+/// bandwidth: 16kHz carries speech in a third of the data of 48kHz.
+pub const SUPPORTED_SAMPLE_RATES: [u32; 6] = [8000, 16000, 24000, 32000, 44100, 48000];
+
+const FLAG_LITTLE_ENDIAN: u8 = 0x01;
+const FLAG_FLAC: u8 = 0x02;
+const FLAG_ZSTD: u8 = 0x04;
+const FLAG_I16: u8 = 0x08;
+// Level 1 keeps compression well inside a packet's real-time budget; higher
+// levels gain little on audio
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 1;
+// Upper bound on a decompressed payload, far above any datagram's worth of
+// samples, so a malicious packet can't make us allocate without limit
+#[cfg(feature = "zstd")]
+const MAX_ZSTD_PAYLOAD: usize = 1 << 20;
+
+/// Payload encoding used by the sender. Every packet declares its own codec,
+/// so receivers need no negotiation and can decode any mix of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// Raw f32 samples, also accepted as `raw-f32`
+    #[default]
+    Pcm,
+    /// Raw samples quantized to i16, also accepted as `raw-i16`. Half the
+    /// bandwidth of `Pcm` at 16-bit precision, with no encoding cost.
+    PcmI16,
+    /// Lossless 16-bit FLAC, requires the `flac` feature
+    Flac,
+    /// Lossless zstd compression of the f32 samples, requires the `zstd`
+    /// feature. Payloads from 16-bit sources typically shrink by a quarter,
+    /// full-precision noise barely at all, and silence to almost nothing.
+    /// Packets it doesn't shrink go out as plain PCM.
+    Zstd,
+    /// Lossy Opus. Known so that choosing it, or a server streaming it,
+    /// fails with a clear error: no build compiles Opus in yet.
+    Opus,
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "pcm" | "raw-f32" => Ok(Codec::Pcm),
+            "i16" | "raw-i16" => Ok(Codec::PcmI16),
+            "flac" => Ok(Codec::Flac),
+            "zstd" => Ok(Codec::Zstd),
+            "opus" => Ok(Codec::Opus),
+            other => Err(format!(
+                "unknown codec '{}', expected raw-f32, raw-i16, flac, zstd or opus",
+                other
+            )),
+        }
+    }
+}
+
+impl Codec {
+    /// Codecs this build can encode and decode.
+    pub fn available() -> Vec<Codec> {
+        let mut codecs = vec![Codec::Pcm, Codec::PcmI16];
+        if cfg!(feature = "flac") {
+            codecs.push(Codec::Flac);
+        }
+        if cfg!(feature = "zstd") {
+            codecs.push(Codec::Zstd);
+        }
+        codecs
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::Pcm => "pcm",
+            Codec::PcmI16 => "i16",
+            Codec::Flac => "flac",
+            Codec::Zstd => "zstd",
+            Codec::Opus => "opus",
+        })
+    }
+}
+
+/// Format of the audio a sender is streaming. Announced to listeners with a
+/// `FORMAT:<rate>:<channels>:<codec>` control message on the discovery
+/// socket, e.g. `FORMAT:44100:1:pcm`: just before each `SERVER` reply and
+/// announcement, and whenever it changes mid-session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub codec: Codec,
+}
+
+impl StreamFormat {
+    pub fn to_message(&self) -> String {
+        format!(
+            "FORMAT:{}:{}:{}",
+            self.sample_rate, self.channels, self.codec
+        )
+    }
+
+    /// Parses a `FORMAT` control message, returning `None` for anything else.
+    pub fn parse_message(message: &[u8]) -> Option<Self> {
+        let message = std::str::from_utf8(message).ok()?;
+        let mut fields = message.strip_prefix("FORMAT:")?.split(':');
+        let format = Self {
+            sample_rate: fields.next()?.parse().ok()?,
+            channels: fields.next()?.parse().ok()?,
+            codec: fields.next()?.parse().ok()?,
+        };
+        if fields.next().is_some() || format.sample_rate == 0 || format.channels == 0 {
+            return None;
+        }
+        Some(format)
+    }
+}
+
+/// How audio reaches a listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    UdpV4,
+    UdpV6,
+    /// Packets carried in WebSocket binary messages, for browsers; see
+    /// `crate::websocket`
+    WebSocket,
+}
+
+impl Transport {
+    // Name in a `CAPS` message
+    fn token(&self) -> &'static str {
+        match self {
+            Transport::UdpV4 => "udp4",
+            Transport::UdpV6 => "udp6",
+            Transport::WebSocket => "ws",
+        }
+    }
+
+    fn from_token(token: &str) -> Option<Self> {
+        [Transport::UdpV4, Transport::UdpV6, Transport::WebSocket]
+            .into_iter()
+            .find(|transport| transport.token() == token)
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::UdpV4 => write!(f, "UDP/IPv4"),
+            Transport::UdpV6 => write!(f, "UDP/IPv6"),
+            Transport::WebSocket => write!(f, "WebSocket"),
+        }
+    }
+}
+
+/// What a sender supports, announced with a
+/// `CAPS:<field>=<value>;...` control message on the discovery socket just
+/// before each `SERVER` reply and announcement, e.g.
+/// `CAPS:codecs=pcm,i16,flac;encryption=none;transport=udp4;format=48000:2:flac`.
+/// `format` takes the fields of a `FORMAT` message. A sender offering
+/// multicast delivery adds `multicast=<group>:<port>`, and one also serving
+/// the stream to browsers adds `ws=<port>`. Listeners ignore fields
+/// and codecs they don't know, and keep the rest of the message when a known
+/// field has a value they don't, so later versions can add to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Codecs the sender can stream, including ones it isn't using
+    pub codecs: Vec<Codec>,
+    /// Whether listeners must encrypt to be served
+    pub encryption_required: bool,
+    /// `None` when the sender uses a transport this build doesn't know
+    pub transport: Option<Transport>,
+    /// What the sender is streaming right now
+    pub format: StreamFormat,
+    /// Multicast group the sender streams to once for every listener that
+    /// joins it, instead of sending each its own copy
+    pub multicast: Option<SocketAddrV4>,
+    /// Port of a [`Transport::WebSocket`] endpoint serving the same stream,
+    /// on the sender's address
+    pub websocket: Option<u16>,
+    /// Known fields whose values this build doesn't understand, as
+    /// `(field, value)`, e.g. a transport added by a later version. Such a
+    /// sender is reported by `incompatibility`.
+    pub not_understood: Vec<(String, String)>,
+}
+
+impl Capabilities {
+    pub fn to_message(&self) -> String {
+        let codecs: Vec<String> = self.codecs.iter().map(Codec::to_string).collect();
+        let mut encryption = if self.encryption_required {
+            "required"
+        } else {
+            "none"
+        };
+        let mut transport = self.transport.as_ref().map_or("", Transport::token);
+        // Values that weren't understood are passed on as they came
+        for (field, value) in &self.not_understood {
+            match field.as_str() {
+                "encryption" => encryption = value,
+                "transport" => transport = value,
+                _ => {}
+            }
+        }
+        let message = format!(
+            "CAPS:codecs={};encryption={};transport={};format={}",
+            codecs.join(","),
+            encryption,
+            transport,
+            self.format
+                .to_message()
+                .strip_prefix("FORMAT:")
+                .unwrap_or_default(),
+        );
+        let message = match self.multicast {
+            Some(group) => format!("{};multicast={}", message, group),
+            None => message,
+        };
+        match self.websocket {
+            Some(port) => format!("{};{}={}", message, Transport::WebSocket.token(), port),
+            None => message,
+        }
+    }
+
+    /// Parses a `CAPS` control message, returning `None` for anything else or
+    /// when a known field is missing or malformed. Unknown values of the
+    /// `encryption` and `transport` fields are kept in `not_understood`.
+    pub fn parse_message(message: &[u8]) -> Option<Self> {
+        let message = std::str::from_utf8(message).ok()?;
+        let (mut codecs, mut encryption_required, mut transport, mut format) =
+            (None, None, None, None);
+        let (mut multicast, mut websocket) = (None, None);
+        let mut not_understood = Vec::new();
+        for field in message.strip_prefix("CAPS:")?.split(';') {
+            // Later versions may add flags without a value
+            let Some((key, value)) = field.split_once('=') else {
+                continue;
+            };
+            match key {
+                "codecs" => {
+                    codecs = Some(
+                        value
+                            .split(',')
+                            .filter_map(|codec| codec.parse().ok())
+                            .collect(),
+                    )
+                }
+                "encryption" => {
+                    encryption_required = Some(match value {
+                        "none" => false,
+                        "required" => true,
+                        // Some scheme we can't speak, which is as good as required
+                        _ => {
+                            not_understood.push((key.to_string(), value.to_string()));
+                            true
+                        }
+                    })
+                }
+                "transport" => {
+                    let known = Transport::from_token(value);
+                    if known.is_none() {
+                        not_understood.push((key.to_string(), value.to_string()));
+                    }
+                    transport = Some(known);
+                }
+                "format" => {
+                    format = Some(StreamFormat::parse_message(
+                        format!("FORMAT:{}", value).as_bytes(),
+                    )?)
+                }
+                "multicast" => multicast = Some(value.parse().ok()?),
+                "ws" => websocket = Some(value.parse().ok()?),
+                _ => {}
+            }
+        }
+        Some(Self {
+            codecs: codecs?,
+            encryption_required: encryption_required?,
+            transport: transport?,
+            format: format?,
+            multicast,
+            websocket,
+            not_understood,
+        })
+    }
+
+    /// Why a listener of this build can't play the sender, if it can't.
+    pub fn incompatibility(&self) -> Option<String> {
+        if let Some((field, value)) = self.not_understood.first() {
+            return Some(format!(
+                "the server's {} is {}, which this version doesn't understand",
+                field, value
+            ));
+        }
+        if self.encryption_required {
+            return Some("the server requires encryption, which is not supported".into());
+        }
+        if !Codec::available().contains(&self.format.codec) {
+            return Some(format!(
+                "the server streams {}, which is not compiled in",
+                self.format.codec
+            ));
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub sequence: u32,
+    pub timestamp_ms: u32,
+    pub epoch_us: u64,
+    pub sample_position: u64,
+    /// Fixed-point coefficient of the `dsp::PreEmphasis` the sender applied
+    pub pre_emphasis: Option<u16>,
+}
+
+impl PacketHeader {
+    /// Sender wall-clock time at which the packet's first frame should be
+    /// presented. See the module docs for the sync contract.
+    pub fn presentation_time(&self, sample_rate: u32) -> SystemTime {
+        let offset_us = self.sample_position as u128 * 1_000_000 / sample_rate as u128;
+        UNIX_EPOCH + Duration::from_micros(self.epoch_us) + Duration::from_micros(offset_us as u64)
+    }
+}
+
+pub fn encode_packet(header: &PacketHeader, samples: &[f32]) -> Vec<u8> {
+    let mut packet = encode_header(header, FLAG_LITTLE_ENDIAN, samples.len() * 4);
+    for sample in samples {
+        packet.extend_from_slice(&sample.to_le_bytes());
+    }
+    packet
+}
+
+fn encode_header(header: &PacketHeader, flags: u8, payload_len: usize) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_SIZE + payload_len);
+    packet.push(PROTOCOL_VERSION);
+    packet.push(flags);
+    packet.extend_from_slice(&header.pre_emphasis.unwrap_or(0).to_le_bytes());
+    packet.extend_from_slice(&header.sequence.to_le_bytes());
+    packet.extend_from_slice(&header.timestamp_ms.to_le_bytes());
+    packet.extend_from_slice(&header.epoch_us.to_le_bytes());
+    packet.extend_from_slice(&header.sample_position.to_le_bytes());
+    packet
+}
+
+/// Encodes packets with a sender's codec. Buffers the codec can't represent,
+/// such as empty keepalives or blocks too short for FLAC, go out as PCM.
+pub struct PacketEncoder {
+    pcm_i16: bool,
+    #[cfg(feature = "flac")]
+    flac: Option<FlacEncoder>,
+    #[cfg(feature = "zstd")]
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+}
+
+pub fn validate_sample_rate(sample_rate: u32) -> Result<()> {
+    if SUPPORTED_SAMPLE_RATES.contains(&sample_rate) {
+        return Ok(());
+    }
+    Err(AudioStreamerError::ConfigError(format!(
+        "Unsupported sample rate {}Hz, expected one of {:?}",
+        sample_rate, SUPPORTED_SAMPLE_RATES
+    )))
+}
+
+impl PacketEncoder {
+    /// Fails with a config error when the sample rate isn't one of
+    /// [`SUPPORTED_SAMPLE_RATES`] or the codec isn't compiled in.
+    #[cfg_attr(not(feature = "flac"), allow(unused_variables))]
+    pub fn new(codec: Codec, channels: u16, sample_rate: u32) -> Result<Self> {
+        validate_sample_rate(sample_rate)?;
+        match codec {
+            Codec::Pcm | Codec::PcmI16 => Ok(Self {
+                pcm_i16: codec == Codec::PcmI16,
+                #[cfg(feature = "flac")]
+                flac: None,
+                #[cfg(feature = "zstd")]
+                zstd: None,
+            }),
+            #[cfg(feature = "flac")]
+            Codec::Flac => Ok(Self {
+                pcm_i16: false,
+                flac: Some(FlacEncoder::new(channels, sample_rate)?),
+                #[cfg(feature = "zstd")]
+                zstd: None,
+            }),
+            #[cfg(not(feature = "flac"))]
+            Codec::Flac => Err(AudioStreamerError::ConfigError(
+                "FLAC support is not compiled in, enable the `flac` feature".into(),
+            )),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Ok(Self {
+                pcm_i16: false,
+                #[cfg(feature = "flac")]
+                flac: None,
+                zstd: Some(zstd::bulk::Compressor::new(ZSTD_LEVEL)?),
+            }),
+            #[cfg(not(feature = "zstd"))]
+            Codec::Zstd => Err(AudioStreamerError::ConfigError(
+                "zstd support is not compiled in, enable the `zstd` feature".into(),
+            )),
+            Codec::Opus => Err(AudioStreamerError::ConfigError(
+                "Opus support is not compiled in; use raw-f32, raw-i16, flac or zstd".into(),
+            )),
+        }
+    }
+
+    pub fn encode(&mut self, header: &PacketHeader, samples: &[f32]) -> Vec<u8> {
+        if self.pcm_i16 {
+            let mut packet =
+                encode_header(header, FLAG_LITTLE_ENDIAN | FLAG_I16, samples.len() * 2);
+            for &sample in samples {
+                packet.extend_from_slice(&f32_to_sample::<i16>(sample).to_le_bytes());
+            }
+            return packet;
+        }
+        #[cfg(feature = "flac")]
+        if let Some(encoder) = &mut self.flac {
+            if encoder.can_encode(samples) {
+                match encoder.encode(samples) {
+                    Ok(frame) => {
+                        let mut packet =
+                            encode_header(header, FLAG_LITTLE_ENDIAN | FLAG_FLAC, frame.len());
+                        packet.extend_from_slice(&frame);
+                        return packet;
+                    }
+                    Err(e) => log::debug!("Sending buffer as PCM: {}", e),
+                }
+            }
+        }
+        #[cfg(feature = "zstd")]
+        if let Some(compressor) = &mut self.zstd {
+            let pcm: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+            match compressor.compress(&pcm) {
+                Ok(compressed) if compressed.len() < pcm.len() => {
+                    let mut packet =
+                        encode_header(header, FLAG_LITTLE_ENDIAN | FLAG_ZSTD, compressed.len());
+                    packet.extend_from_slice(&compressed);
+                    return packet;
+                }
+                Ok(_) => {}
+                Err(e) => log::debug!("Sending buffer as PCM: {}", e),
+            }
+        }
+        encode_packet(header, samples)
+    }
+}
+
+pub fn decode_packet(packet: &[u8]) -> Result<(PacketHeader, Vec<f32>)> {
+    if packet.len() < HEADER_SIZE {
+        return Err(AudioStreamerError::EncodingError(format!(
+            "Packet too short: {} bytes",
+            packet.len()
+        )));
+    }
+    if packet[0] != PROTOCOL_VERSION {
+        return Err(AudioStreamerError::EncodingError(format!(
+            "Unsupported protocol version {}",
+            packet[0]
+        )));
+    }
+    if packet[1] & FLAG_LITTLE_ENDIAN == 0 {
+        return Err(AudioStreamerError::EncodingError(
+            "Packet payload is not little endian".into(),
+        ));
+    }
+
+    let header = PacketHeader {
+        sequence: u32::from_le_bytes([packet[4], packet[5], packet[6], packet[7]]),
+        timestamp_ms: u32::from_le_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        epoch_us: u64::from_le_bytes(packet[12..20].try_into().unwrap()),
+        sample_position: u64::from_le_bytes(packet[20..28].try_into().unwrap()),
+        pre_emphasis: Some(u16::from_le_bytes([packet[2], packet[3]]))
+            .filter(|&coefficient| check_emphasis(coefficient).is_ok()),
+    };
+    let payload = &packet[HEADER_SIZE..];
+    let samples = if packet[1] & FLAG_FLAC != 0 {
+        decode_flac(payload)?
+    } else if packet[1] & FLAG_ZSTD != 0 {
+        decode_pcm(&decompress_zstd(payload)?)
+    } else if packet[1] & FLAG_I16 != 0 {
+        payload
+            .chunks_exact(2)
+            .map(|chunk| sample_to_f32(i16::from_le_bytes([chunk[0], chunk[1]])))
+            .collect()
+    } else {
+        decode_pcm(payload)
+    };
+
+    Ok((header, samples))
+}
+
+fn decode_pcm(payload: &[u8]) -> Vec<f32> {
+    payload
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_zstd(payload: &[u8]) -> Result<Vec<u8>> {
+    zstd::bulk::decompress(payload, MAX_ZSTD_PAYLOAD)
+        .map_err(|e| AudioStreamerError::EncodingError(format!("Invalid zstd payload: {}", e)))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_zstd(_payload: &[u8]) -> Result<Vec<u8>> {
+    Err(AudioStreamerError::EncodingError(
+        "Received a zstd packet but zstd support is not compiled in".into(),
+    ))
+}
+
+#[cfg(feature = "flac")]
+fn decode_flac(payload: &[u8]) -> Result<Vec<f32>> {
+    crate::flac::decode(payload)
+}
+
+#[cfg(not(feature = "flac"))]
+fn decode_flac(_payload: &[u8]) -> Result<Vec<f32>> {
+    Err(AudioStreamerError::EncodingError(
+        "Received a FLAC packet but FLAC support is not compiled in".into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_round_trip_through_packets() {
+        let header = PacketHeader {
+            sequence: 0xDEAD_BEEF,
+            timestamp_ms: 123_456,
+            epoch_us: 1_700_000_000_000_000,
+            sample_position: u64::MAX - 1,
+            pre_emphasis: Some(9_500),
+        };
+        let samples = [0.0, 1.0, -1.0, 0.5, f32::MIN_POSITIVE, -0.123_456_79];
+
+        let packet = encode_packet(&header, &samples);
+        assert_eq!(packet.len(), HEADER_SIZE + samples.len() * 4);
+        assert_eq!(&packet[2..4], &9_500u16.to_le_bytes());
+
+        let (decoded_header, decoded) = decode_packet(&packet).unwrap();
+        assert_eq!(decoded_header, header);
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn payload_is_little_endian_on_the_wire() {
+        let packet = encode_packet(&PacketHeader::default(), &[1.0]);
+        assert_eq!(&packet[HEADER_SIZE..], &[0x00, 0x00, 0x80, 0x3F]);
+    }
+
+    #[test]
+    fn rejects_foreign_packets() {
+        let mut packet = encode_packet(&PacketHeader::default(), &[0.25]);
+        packet[1] = 0;
+        assert!(decode_packet(&packet).is_err());
+
+        packet[0] = PROTOCOL_VERSION + 1;
+        assert!(decode_packet(&packet).is_err());
+
+        assert!(decode_packet(&packet[..HEADER_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn i16_packets_halve_the_payload() {
+        let mut encoder = PacketEncoder::new(Codec::PcmI16, 2, 48_000).unwrap();
+        let samples = [0.0, 0.5, -0.5, -1.0, 1.0, 0.123];
+        let packet = encoder.encode(&PacketHeader::default(), &samples);
+        assert_eq!(packet.len(), HEADER_SIZE + samples.len() * 2);
+
+        let (_, decoded) = decode_packet(&packet).unwrap();
+        assert_eq!(decoded.len(), samples.len());
+        for (a, b) in decoded.iter().zip(&samples) {
+            assert!((a - b).abs() <= 1.0 / 32768.0);
+        }
+    }
+
+    #[test]
+    fn codec_parses_case_insensitively() {
+        assert_eq!("PCM".parse::<Codec>(), Ok(Codec::Pcm));
+        assert_eq!("raw-f32".parse::<Codec>(), Ok(Codec::Pcm));
+        assert_eq!("raw-i16".parse::<Codec>(), Ok(Codec::PcmI16));
+        assert_eq!("i16".parse::<Codec>(), Ok(Codec::PcmI16));
+        assert_eq!("flac".parse::<Codec>(), Ok(Codec::Flac));
+        assert_eq!("zstd".parse::<Codec>(), Ok(Codec::Zstd));
+        assert_eq!("Opus".parse::<Codec>(), Ok(Codec::Opus));
+        assert!("aac".parse::<Codec>().is_err());
+    }
+
+    #[test]
+    fn opus_is_refused_as_not_compiled_in() {
+        assert!(!Codec::available().contains(&Codec::Opus));
+        match PacketEncoder::new(Codec::Opus, 2, 48_000) {
+            Err(AudioStreamerError::ConfigError(message)) => {
+                assert!(message.contains("Opus support is not compiled in"))
+            }
+            other => panic!("expected a config error, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn encoder_rejects_unsupported_sample_rates() {
+        assert!(PacketEncoder::new(Codec::Pcm, 1, 16_000).is_ok());
+        assert!(matches!(
+            PacketEncoder::new(Codec::Pcm, 2, 22_050),
+            Err(AudioStreamerError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn format_messages_round_trip() {
+        let format = StreamFormat {
+            sample_rate: 44_100,
+            channels: 1,
+            codec: Codec::Flac,
+        };
+        assert_eq!(format.to_message(), "FORMAT:44100:1:flac");
+        assert_eq!(
+            StreamFormat::parse_message(format.to_message().as_bytes()),
+            Some(format)
+        );
+
+        assert_eq!(StreamFormat::parse_message(b"SERVER_DOWN"), None);
+        assert_eq!(StreamFormat::parse_message(b"FORMAT:0:2:pcm"), None);
+        assert_eq!(StreamFormat::parse_message(b"FORMAT:48000:2:pcm:x"), None);
+    }
+
+    #[test]
+    fn capabilities_messages_round_trip_and_ignore_unknown_fields() {
+        let capabilities = Capabilities {
+            codecs: vec![Codec::Pcm, Codec::Flac],
+            encryption_required: false,
+            transport: Some(Transport::UdpV6),
+            format: StreamFormat {
+                sample_rate: 16_000,
+                channels: 1,
+                codec: Codec::Pcm,
+            },
+            multicast: None,
+            websocket: None,
+            not_understood: Vec::new(),
+        };
+        assert_eq!(
+            capabilities.to_message(),
+            "CAPS:codecs=pcm,flac;encryption=none;transport=udp6;format=16000:1:pcm"
+        );
+        assert_eq!(
+            Capabilities::parse_message(capabilities.to_message().as_bytes()),
+            Some(capabilities.clone())
+        );
+        assert_eq!(capabilities.incompatibility(), None);
+
+        let multicast = Capabilities {
+            multicast: Some("239.255.77.1:50001".parse().unwrap()),
+            ..capabilities.clone()
+        };
+        assert!(multicast
+            .to_message()
+            .ends_with(";multicast=239.255.77.1:50001"));
+        assert_eq!(
+            Capabilities::parse_message(multicast.to_message().as_bytes()),
+            Some(multicast)
+        );
+
+        let browsers = Capabilities {
+            websocket: Some(50002),
+            ..capabilities.clone()
+        };
+        assert!(browsers.to_message().ends_with(";ws=50002"));
+        assert_eq!(
+            Capabilities::parse_message(browsers.to_message().as_bytes()),
+            Some(browsers)
+        );
+        let websocket_only = Capabilities::parse_message(
+            b"CAPS:codecs=pcm;encryption=none;transport=ws;format=48000:2:pcm",
+        )
+        .unwrap();
+        assert_eq!(websocket_only.transport, Some(Transport::WebSocket));
+
+        let newer = Capabilities::parse_message(
+            b"CAPS:codecs=aac,pcm;encryption=required;transport=udp4;\
+              format=48000:2:pcm;latency=low",
+        )
+        .unwrap();
+        assert_eq!(newer.codecs, vec![Codec::Pcm]);
+        assert!(newer.incompatibility().is_some());
+
+        let flagged = Capabilities::parse_message(
+            b"CAPS:codecs=pcm;future_flag;transport=udp4;encryption=none;format=48000:2:pcm",
+        )
+        .unwrap();
+        assert_eq!(flagged.transport, Some(Transport::UdpV4));
+        assert!(flagged.incompatibility().is_none());
+
+        assert_eq!(Capabilities::parse_message(b"FORMAT:48000:2:pcm"), None);
+        assert_eq!(
+            Capabilities::parse_message(b"CAPS:codecs=pcm;encryption=none;transport=udp4"),
+            None
+        );
+
+        // Values from a later version keep the rest of the message
+        let message = "CAPS:codecs=pcm;encryption=none;transport=quic;format=48000:2:pcm";
+        let future = Capabilities::parse_message(message.as_bytes()).unwrap();
+        assert_eq!(future.transport, None);
+        assert_eq!(future.codecs, vec![Codec::Pcm]);
+        assert_eq!(
+            future.not_understood,
+            vec![("transport".to_string(), "quic".to_string())]
+        );
+        assert!(future.incompatibility().unwrap().contains("quic"));
+        assert_eq!(future.to_message(), message);
+        let future = Capabilities::parse_message(
+            b"CAPS:codecs=pcm;encryption=aes;transport=udp4;format=48000:2:pcm",
+        )
+        .unwrap();
+        assert!(future.encryption_required);
+        assert!(future.incompatibility().unwrap().contains("aes"));
+    }
+
+    #[cfg(feature = "flac")]
+    #[test]
+    fn flac_packets_round_trip_and_short_buffers_fall_back_to_pcm() {
+        let mut encoder = PacketEncoder::new(Codec::Flac, 2, 48_000).unwrap();
+        let samples: Vec<f32> = (0..720)
+            .map(|i| ((i / 2) as f32 * 0.05).sin() * 0.5)
+            .collect();
+
+        let packet = encoder.encode(&PacketHeader::default(), &samples);
+        assert_ne!(packet[1] & FLAG_FLAC, 0);
+        assert!(packet.len() < HEADER_SIZE + samples.len() * 4);
+        let (_, decoded) = decode_packet(&packet).unwrap();
+        assert_eq!(decoded.len(), samples.len());
+        for (a, b) in decoded.iter().zip(&samples) {
+            assert!((a - b).abs() <= 1.0 / 32768.0);
+        }
+
+        let keepalive = encoder.encode(&PacketHeader::default(), &[]);
+        assert_eq!(keepalive.len(), HEADER_SIZE);
+        assert_eq!(keepalive[1] & FLAG_FLAC, 0);
+    }
+
+    #[test]
+    fn presentation_time_offsets_epoch_by_frame_position() {
+        let header = PacketHeader {
+            epoch_us: 1_000_000,
+            sample_position: 72_000,
+            ..PacketHeader::default()
+        };
+        assert_eq!(
+            header.presentation_time(48_000),
+            UNIX_EPOCH + Duration::from_millis(2_500)
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    fn zstd_ratio(samples: &[f32]) -> f64 {
+        let mut encoder = PacketEncoder::new(Codec::Zstd, 2, 48_000).unwrap();
+        let packet = encoder.encode(&PacketHeader::default(), samples);
+        let (_, decoded) = decode_packet(&packet).unwrap();
+        assert_eq!(decoded, samples);
+        (packet.len() - HEADER_SIZE) as f64 / (samples.len() * 4) as f64
+    }
+
+    // Typical payload ratios for a 360-sample packet at ZSTD_LEVEL, as
+    // measured: silence ~0.01, 16-bit audio ~0.75, full-precision noise ~0.96
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_is_lossless_and_shrinks_typical_audio() {
+        let mut seed = 12345u32;
+        let noise: Vec<f32> = (0..360)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (seed >> 8) as f32 / (1 << 24) as f32 * 0.2 - 0.1
+            })
+            .collect();
+        // Tone plus noise as captured from a 16-bit device
+        let captured: Vec<f32> = noise
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                let tone = ((i / 2) as f32 * 0.0576).sin() * 0.3;
+                ((tone + n * 0.3) * 32767.0).round() / 32768.0
+            })
+            .collect();
+
+        assert!(zstd_ratio(&[0.0; 360]) < 0.05);
+        assert!(zstd_ratio(&captured) < 0.85);
+        assert!(zstd_ratio(&noise) < 1.0);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_falls_back_to_pcm_when_it_does_not_help() {
+        let mut encoder = PacketEncoder::new(Codec::Zstd, 2, 48_000).unwrap();
+        let samples = [0.123, -0.456];
+        let packet = encoder.encode(&PacketHeader::default(), &samples);
+        assert_eq!(packet[1] & FLAG_ZSTD, 0);
+        assert_eq!(decode_packet(&packet).unwrap().1, samples);
+    }
+}
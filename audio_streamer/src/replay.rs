@@ -0,0 +1,490 @@
+//! Recording of received datagrams and their replay at the recorded timing,
+//! to reproduce glitchy sessions offline.
+//!
+//! A recording starts with a header:
+//!
+//! | size | field                                                        |
+//! |------|--------------------------------------------------------------|
+//! | 4    | magic, `ASPK`                                                |
+//! | 1    | file version (`RECORDING_VERSION`)                           |
+//! | 2    | length of the format message, 0 when unknown                 |
+//! | n    | the server's `FORMAT` message, e.g. `FORMAT:48000:2:pcm`     |
+//!
+//! followed by records in arrival order, each starting with its kind and its
+//! arrival time:
+//!
+//! | size | field                                                        |
+//! |------|--------------------------------------------------------------|
+//! | 1    | record kind, 0 for a datagram or 1 for a format change       |
+//! | 8    | arrival time, microseconds since the Unix epoch              |
+//!
+//! A datagram continues with:
+//!
+//! | size   | field                                                      |
+//! |--------|------------------------------------------------------------|
+//! | 1      | source address family, 4 or 6                              |
+//! | 4 / 16 | source IP address                                          |
+//! | 2      | source port                                                |
+//! | 4      | datagram length                                            |
+//! | n      | the datagram exactly as received                           |
+//!
+//! and a format change with the new format, like the header:
+//!
+//! | size | field                                                        |
+//! |------|--------------------------------------------------------------|
+//! | 2    | length of the format message                                 |
+//! | n    | the server's new `FORMAT` message                            |
+//!
+//! Version 1 recordings have no format changes and no kind byte. All
+//! integers are little endian.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+
+use crate::network::RawPacket;
+use crate::protocol::StreamFormat;
+use crate::{AudioStreamerError, NetworkError, Result};
+
+pub const RECORDING_VERSION: u8 = 2;
+const MAGIC: &[u8; 4] = b"ASPK";
+
+const DATAGRAM: u8 = 0;
+const FORMAT_CHANGE: u8 = 1;
+
+// No UDP datagram is longer, so longer records are corrupt
+const MAX_DATAGRAM_LEN: usize = u16::MAX as usize;
+
+/// An entry of a recording.
+#[derive(Clone, Debug)]
+pub enum Record {
+    Packet(RawPacket),
+    /// The server switched to `format` at `at`; the datagrams after it use
+    /// the new format.
+    FormatChange {
+        at: SystemTime,
+        format: StreamFormat,
+    },
+}
+
+/// Writes received datagrams, e.g. from `AudioReceiver::raw_packets`, to a
+/// recording. Call `finish` when done so everything is flushed.
+pub struct PacketRecorder<W: Write> {
+    writer: W,
+}
+
+impl PacketRecorder<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, format: Option<StreamFormat>) -> Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), format)
+    }
+}
+
+impl<W: Write> PacketRecorder<W> {
+    /// Starts a recording of a stream in `format`, when known, so replay can
+    /// play it without asking the server.
+    pub fn new(mut writer: W, format: Option<StreamFormat>) -> Result<Self> {
+        let format = format.map(|format| format.to_message()).unwrap_or_default();
+        writer.write_all(MAGIC)?;
+        writer.write_u8(RECORDING_VERSION)?;
+        writer.write_u16::<LittleEndian>(format.len() as u16)?;
+        writer.write_all(format.as_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn record(&mut self, packet: &RawPacket) -> Result<()> {
+        self.writer.write_u8(DATAGRAM)?;
+        self.write_time(packet.arrival)?;
+        match packet.source.ip() {
+            IpAddr::V4(ip) => {
+                self.writer.write_u8(4)?;
+                self.writer.write_all(&ip.octets())?;
+            }
+            IpAddr::V6(ip) => {
+                self.writer.write_u8(6)?;
+                self.writer.write_all(&ip.octets())?;
+            }
+        }
+        self.writer
+            .write_u16::<LittleEndian>(packet.source.port())?;
+        self.writer
+            .write_u32::<LittleEndian>(packet.data.len() as u32)?;
+        self.writer.write_all(&packet.data)?;
+        Ok(())
+    }
+
+    /// Records that the server switched to `format` at `at`, so replay can
+    /// follow the switch.
+    pub fn record_format(&mut self, at: SystemTime, format: StreamFormat) -> Result<()> {
+        let format = format.to_message();
+        self.writer.write_u8(FORMAT_CHANGE)?;
+        self.write_time(at)?;
+        self.writer.write_u16::<LittleEndian>(format.len() as u16)?;
+        self.writer.write_all(format.as_bytes())?;
+        Ok(())
+    }
+
+    fn write_time(&mut self, time: SystemTime) -> Result<()> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.writer
+            .write_u64::<LittleEndian>(since_epoch.as_micros() as u64)?;
+        Ok(())
+    }
+
+    /// Flushes the recording and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads back a recording made by `PacketRecorder`.
+pub struct PacketReader<R: Read> {
+    reader: R,
+    version: u8,
+    format: Option<StreamFormat>,
+}
+
+impl PacketReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a packet recording"));
+        }
+        let version = reader.read_u8()?;
+        if !(1..=RECORDING_VERSION).contains(&version) {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        let format = read_format(&mut reader)?;
+        Ok(Self {
+            reader,
+            version,
+            format,
+        })
+    }
+
+    /// Format of the recorded stream at the start, when it was known.
+    pub fn format(&self) -> Option<StreamFormat> {
+        self.format
+    }
+
+    /// The next record, or `None` at the end of the recording. A record cut
+    /// short, e.g. by a crash while recording, ends it too.
+    pub fn next_record(&mut self) -> Result<Option<Record>> {
+        let kind = match self.version {
+            1 => DATAGRAM,
+            _ => match self.reader.read_u8() {
+                Ok(kind) => kind,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            },
+        };
+        let arrival_us = match self.reader.read_u64::<LittleEndian>() {
+            Ok(arrival_us) => arrival_us,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && self.version == 1 => return Ok(None),
+            Err(e) => return truncated(e.into()).map(|()| None),
+        };
+        let at = UNIX_EPOCH + Duration::from_micros(arrival_us);
+        let record = match kind {
+            DATAGRAM => self.read_datagram(at).map(Record::Packet),
+            FORMAT_CHANGE => read_format(&mut self.reader).and_then(|format| {
+                let format = format.ok_or_else(|| invalid("empty format change"))?;
+                Ok(Record::FormatChange { at, format })
+            }),
+            kind => return Err(invalid(&format!("unknown record kind {}", kind))),
+        };
+        match record {
+            Err(e) => truncated(e).map(|()| None),
+            Ok(record) => Ok(Some(record)),
+        }
+    }
+
+    /// The next datagram, skipping format changes, or `None` at the end of
+    /// the recording.
+    pub fn next_packet(&mut self) -> Result<Option<RawPacket>> {
+        loop {
+            match self.next_record()? {
+                Some(Record::Packet(packet)) => return Ok(Some(packet)),
+                Some(Record::FormatChange { .. }) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Every remaining datagram, in arrival order.
+    pub fn packets(mut self) -> Result<Vec<RawPacket>> {
+        let mut packets = Vec::new();
+        while let Some(packet) = self.next_packet()? {
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+
+    fn read_datagram(&mut self, arrival: SystemTime) -> Result<RawPacket> {
+        let ip = match self.reader.read_u8()? {
+            4 => {
+                let mut octets = [0u8; 4];
+                self.reader.read_exact(&mut octets)?;
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            6 => {
+                let mut octets = [0u8; 16];
+                self.reader.read_exact(&mut octets)?;
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            family => return Err(invalid(&format!("unknown address family {}", family))),
+        };
+        let port = self.reader.read_u16::<LittleEndian>()?;
+        let len = self.reader.read_u32::<LittleEndian>()? as usize;
+        if len > MAX_DATAGRAM_LEN {
+            return Err(invalid(&format!("datagram length {} is too long", len)));
+        }
+        let mut data = vec![0u8; len];
+        self.reader.read_exact(&mut data)?;
+        Ok(RawPacket {
+            data,
+            source: SocketAddr::new(ip, port),
+            arrival,
+        })
+    }
+}
+
+fn read_format(reader: &mut impl Read) -> Result<Option<StreamFormat>> {
+    let mut format = vec![0u8; reader.read_u16::<LittleEndian>()? as usize];
+    reader.read_exact(&mut format)?;
+    match format.is_empty() {
+        true => Ok(None),
+        false => StreamFormat::parse_message(&format)
+            .map(Some)
+            .ok_or_else(|| invalid("malformed stream format")),
+    }
+}
+
+// Treats a record cut short as the end of the recording
+fn truncated(error: AudioStreamerError) -> Result<()> {
+    match error {
+        AudioStreamerError::IoError(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            log::warn!("Packet recording ends with a truncated record");
+            Ok(())
+        }
+        e => Err(e),
+    }
+}
+
+/// Sends the datagrams of a recording from `socket` to `target`, e.g. a
+/// receiver bound to loopback, spaced as they originally arrived, and calls
+/// `on_format_change` at each recorded format change. The recording is read
+/// on a blocking thread as it plays, so it needn't fit in memory. `speed`
+/// scales the timing: 2.0 replays twice as fast. Every datagram now comes
+/// from `socket`, so the receiver must not be connected to another server.
+/// Returns the number of datagrams sent.
+pub async fn replay<R, F>(
+    mut reader: PacketReader<R>,
+    socket: &UdpSocket,
+    target: SocketAddr,
+    speed: f64,
+    mut on_format_change: F,
+) -> Result<u64>
+where
+    R: Read + Send + 'static,
+    F: FnMut(StreamFormat) -> Result<()>,
+{
+    if !(speed > 0.0 && speed.is_finite()) {
+        return Err(AudioStreamerError::ConfigError(format!(
+            "Replay speed must be positive, got {}",
+            speed
+        )));
+    }
+    let (tx, mut records) = mpsc::channel(64);
+    let reading = tokio::task::spawn_blocking(move || -> Result<()> {
+        while let Some(record) = reader.next_record()? {
+            if tx.blocking_send(record).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    // Offsets count from the first record
+    let mut origin = None;
+    let mut sent = 0;
+    while let Some(record) = records.recv().await {
+        let at = match &record {
+            Record::Packet(packet) => packet.arrival,
+            Record::FormatChange { at, .. } => *at,
+        };
+        let (start, first) = *origin.get_or_insert((Instant::now(), at));
+        let offset = at.duration_since(first).unwrap_or_default();
+        time::sleep_until(start + offset.div_f64(speed)).await;
+        match record {
+            Record::Packet(packet) => {
+                socket
+                    .send_to(&packet.data, target)
+                    .await
+                    .map_err(|source| NetworkError::SendFailed {
+                        addr: target,
+                        source,
+                    })?;
+                sent += 1;
+            }
+            Record::FormatChange { format, .. } => on_format_change(format)?,
+        }
+    }
+    reading.await.map_err(|e| {
+        AudioStreamerError::StreamError(format!("Reading the recording failed: {}", e))
+    })??;
+    Ok(sent)
+}
+
+fn invalid(reason: &str) -> AudioStreamerError {
+    AudioStreamerError::EncodingError(format!("Invalid packet recording: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::AudioReceiver;
+    use crate::protocol::{encode_packet, Codec, PacketHeader};
+    use std::io::Cursor;
+
+    fn packet(arrival_ms: u64, source: &str, samples: &[f32]) -> RawPacket {
+        RawPacket {
+            data: encode_packet(&PacketHeader::default(), samples),
+            source: source.parse().unwrap(),
+            arrival: UNIX_EPOCH + Duration::from_millis(arrival_ms),
+        }
+    }
+
+    #[test]
+    fn recordings_round_trip() {
+        let format = StreamFormat {
+            sample_rate: 16000,
+            channels: 1,
+            codec: Codec::Pcm,
+        };
+        let packets = [
+            packet(1_000, "192.168.1.5:50001", &[0.5, -0.5]),
+            packet(1_012, "[fe80::1]:50001", &[]),
+        ];
+        let switched = StreamFormat {
+            sample_rate: 48000,
+            ..format
+        };
+        let switched_at = UNIX_EPOCH + Duration::from_millis(1_006);
+        let mut recorder = PacketRecorder::new(Vec::new(), Some(format)).unwrap();
+        recorder.record(&packets[0]).unwrap();
+        recorder.record_format(switched_at, switched).unwrap();
+        recorder.record(&packets[1]).unwrap();
+        let mut bytes = recorder.finish().unwrap();
+
+        let mut reader = PacketReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(reader.format(), Some(format));
+        reader.next_record().unwrap();
+        match reader.next_record().unwrap() {
+            Some(Record::FormatChange { at, format }) => {
+                assert_eq!(at, switched_at);
+                assert_eq!(format, switched);
+            }
+            other => panic!("expected a format change, got {:?}", other),
+        }
+        let reader = PacketReader::new(Cursor::new(bytes.clone())).unwrap();
+        let read = reader.packets().unwrap();
+        assert_eq!(read.len(), 2);
+        for (read, written) in read.iter().zip(&packets) {
+            assert_eq!(read.data, written.data);
+            assert_eq!(read.source, written.source);
+            assert_eq!(read.arrival, written.arrival);
+        }
+
+        // A record cut short ends the recording
+        bytes.truncate(bytes.len() - 3);
+        let read = PacketReader::new(Cursor::new(bytes)).unwrap().packets();
+        assert_eq!(read.unwrap().len(), 1);
+
+        assert!(PacketReader::new(Cursor::new(b"RIFF....".to_vec())).is_err());
+    }
+
+    #[test]
+    fn corrupt_datagram_lengths_are_rejected() {
+        let written = packet(1_000, "192.168.1.5:50001", &[0.5, -0.5]);
+        let mut recorder = PacketRecorder::new(Vec::new(), None).unwrap();
+        recorder.record(&written).unwrap();
+        let mut bytes = recorder.finish().unwrap();
+
+        let len_at = bytes.len() - written.data.len() - 4;
+        bytes[len_at..len_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let read = PacketReader::new(Cursor::new(bytes)).unwrap().packets();
+        assert!(read.unwrap_err().to_string().contains("datagram length"));
+    }
+
+    #[tokio::test]
+    async fn replay_keeps_the_recorded_spacing() {
+        let receiver = AudioReceiver::new(Some("127.0.0.1:0")).await.unwrap();
+        let target = receiver.local_addr().unwrap();
+        let mut raw = receiver.raw_packets();
+        let (tx, mut rx) = mpsc::channel(8);
+        let receiver = std::sync::Arc::new(receiver);
+        let receiving = receiver.clone();
+        tokio::spawn(async move { receiving.start_receiving(tx).await });
+
+        let switched = StreamFormat {
+            sample_rate: 16000,
+            channels: 1,
+            codec: Codec::Pcm,
+        };
+        let mut recorder = PacketRecorder::new(Vec::new(), None).unwrap();
+        recorder
+            .record(&packet(0, "10.0.0.1:50001", &[0.25; 4]))
+            .unwrap();
+        recorder
+            .record_format(UNIX_EPOCH + Duration::from_millis(20), switched)
+            .unwrap();
+        recorder
+            .record(&packet(40, "10.0.0.1:50001", &[0.5; 4]))
+            .unwrap();
+        let recording = recorder.finish().unwrap();
+        let reader = || PacketReader::new(Cursor::new(recording.clone())).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let started = Instant::now();
+        let mut format_changes = Vec::new();
+        let sent = replay(reader(), &socket, target, 2.0, |format| {
+            format_changes.push((format, started.elapsed()));
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(sent, 2);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(format_changes.len(), 1);
+        assert_eq!(format_changes[0].0, switched);
+        assert!(format_changes[0].1 >= Duration::from_millis(10));
+
+        for expected in [0.25, 0.5] {
+            let samples = time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("timed out waiting for replayed audio")
+                .unwrap();
+            assert_eq!(samples, vec![expected; 4]);
+        }
+        let first = raw.recv().await.unwrap();
+        let second = raw.recv().await.unwrap();
+        let gap = second.arrival.duration_since(first.arrival).unwrap();
+        assert!(gap >= Duration::from_millis(15));
+
+        let replayed = replay(reader(), &socket, target, 0.0, |_| Ok(())).await;
+        assert!(replayed.is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! Playback through [rodio](https://docs.rs/rodio) instead of [`crate::player`].
+//!
+//! [`RodioSource`] turns the channel filled by `AudioReceiver::start_receiving`
+//! into a rodio `Source`, so received audio can join an existing rodio
+//! pipeline, with rodio handling the device, mixing and resampling:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! use audio_streamer::network::AudioReceiver;
+//! use audio_streamer::rodio::RodioSource;
+//!
+//! let receiver = AudioReceiver::new(None).await?;
+//! receiver.discover_server().await?;
+//! let format = receiver.server_format();
+//! let sample_rate = format.map_or(48000, |format| format.sample_rate);
+//! let channels = format.map_or(2, |format| format.channels);
+//!
+//! let (_stream, handle) = rodio::OutputStream::try_default()?;
+//! let sink = rodio::Sink::try_new(&handle)?;
+//! let (tx, rx) = tokio::sync::mpsc::channel(32);
+//! sink.append(RodioSource::new(rx, sample_rate, channels));
+//! receiver.start_receiving(tx).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+
+/// A rodio `Source` yielding the interleaved samples received on a channel.
+///
+/// rodio pulls samples on its audio thread, which must not wait, so when no
+/// buffer has arrived yet the source plays a frame of silence and checks
+/// again. It ends once the channel closes and everything received has played.
+pub struct RodioSource {
+    rx: mpsc::Receiver<Vec<f32>>,
+    current: std::vec::IntoIter<f32>,
+    // Samples of silence left in the frame filling an underrun
+    silence: usize,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl RodioSource {
+    /// `sample_rate` and `channels` describe the received audio, e.g. from
+    /// `AudioReceiver::server_format`.
+    pub fn new(rx: mpsc::Receiver<Vec<f32>>, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            rx,
+            current: Vec::new().into_iter(),
+            silence: 0,
+            sample_rate,
+            channels: channels.max(1),
+        }
+    }
+}
+
+impl Iterator for RodioSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(sample) = self.current.next() {
+            return Some(sample);
+        }
+        if self.silence == 0 {
+            loop {
+                match self.rx.try_recv() {
+                    // Skip empty buffers rather than end the frame early
+                    Ok(buffer) if buffer.is_empty() => continue,
+                    Ok(buffer) => {
+                        self.current = buffer.into_iter();
+                        return self.current.next();
+                    }
+                    Err(TryRecvError::Empty) => {
+                        self.silence = self.channels as usize;
+                        break;
+                    }
+                    Err(TryRecvError::Disconnected) => return None,
+                }
+            }
+        }
+        self.silence -= 1;
+        Some(0.0)
+    }
+}
+
+impl ::rodio::Source for RodioSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        match self.current.len() + self.silence {
+            // The next sample starts a buffer or a silent frame
+            0 => Some(self.channels as usize),
+            remaining => Some(remaining),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::rodio::Source;
+
+    #[test]
+    fn received_samples_play_in_order_with_silence_for_gaps() {
+        let (tx, rx) = mpsc::channel(4);
+        let mut source = RodioSource::new(rx, 48000, 2);
+        assert_eq!(source.channels(), 2);
+        assert_eq!(source.sample_rate(), 48000);
+
+        // Nothing received yet: one stereo frame of silence
+        assert_eq!(source.next(), Some(0.0));
+        assert_eq!(source.current_frame_len(), Some(1));
+        assert_eq!(source.next(), Some(0.0));
+
+        tx.try_send(vec![0.25, 0.5, 0.75, 1.0]).unwrap();
+        assert_eq!(source.next(), Some(0.25));
+        assert_eq!(source.current_frame_len(), Some(3));
+        let rest: Vec<f32> = source.by_ref().take(3).collect();
+        assert_eq!(rest, vec![0.5, 0.75, 1.0]);
+
+        tx.try_send(vec![-0.5, -0.5]).unwrap();
+        drop(tx);
+        let tail: Vec<f32> = source.collect();
+        assert_eq!(tail, vec![-0.5, -0.5]);
+    }
+}
@@ -0,0 +1,205 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::{Builder, Handle};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::{AudioStreamerError, Result};
+
+// How long a stopped runtime waits for its blocking tasks before leaving them
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A single-threaded tokio runtime on a thread of its own, so the audio path
+/// isn't delayed by unrelated tasks on the application's runtime.
+///
+/// A sender or receiver configured with the runtime, e.g. with
+/// `SenderConfigBuilder::runtime`, registers its sockets with it and spawns
+/// its background tasks, such as discovery, there too. Run its send or
+/// receive loop there as well:
+///
+/// ```no_run
+/// # async fn example() -> audio_streamer::Result<()> {
+/// use audio_streamer::network::{AudioSender, SenderConfig};
+/// use audio_streamer::runtime::AudioRuntime;
+/// use std::sync::Arc;
+///
+/// let runtime = AudioRuntime::new("audio-network")?;
+/// let config = SenderConfig::builder().runtime(&runtime).build();
+/// let sender = Arc::new(AudioSender::with_config(config).await?);
+/// let (tx, rx) = tokio::sync::mpsc::channel(32);
+/// # drop(tx);
+/// let sending = sender.clone();
+/// runtime.run(async move { sending.start_sending(rx).await }).await??;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Dropping the runtime stops every task still running on it. The drop
+/// doesn't wait for them to wind down, so it never blocks the caller, even
+/// on an async runtime.
+pub struct AudioRuntime {
+    handle: Handle,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl AudioRuntime {
+    /// Starts the runtime on a new thread called `name`.
+    pub fn new(name: &str) -> Result<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let handle = runtime.handle().clone();
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        // Detached: the thread winds the runtime down by itself once stopped
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                // Drives spawned tasks and socket readiness until shutdown
+                runtime.block_on(async {
+                    let _ = stopped.await;
+                });
+                runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+            })?;
+        Ok(Self {
+            handle,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// Runs `future` on the runtime's thread and waits for its output. As
+    /// when awaiting it directly, the future is cancelled if the caller stops
+    /// waiting.
+    pub async fn run<F>(&self, future: F) -> Result<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        run_on(&self.handle, future).await
+    }
+}
+
+impl Drop for AudioRuntime {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+// `AudioRuntime::run` for the runtime behind `handle`
+pub(crate) async fn run_on<F>(handle: &Handle, future: F) -> Result<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    AbortOnDrop(handle.spawn(future))
+        .await
+        .map_err(|e| AudioStreamerError::StreamError(format!("Audio runtime task failed: {}", e)))
+}
+
+// Cancels the task when the waiting future is dropped
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = std::result::Result<T, tokio::task::JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{AudioReceiver, ReceiverConfig};
+    use crate::protocol::{encode_packet, PacketHeader};
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn futures_run_on_the_runtime_thread() {
+        let runtime = AudioRuntime::new("audio-test").unwrap();
+        let name = runtime
+            .run(async { thread::current().name().map(str::to_string) })
+            .await
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("audio-test"));
+    }
+
+    #[tokio::test]
+    async fn abandoned_futures_are_cancelled() {
+        let runtime = AudioRuntime::new("audio-test").unwrap();
+        let (held, mut released) = oneshot::channel::<()>();
+        let waiting = runtime.run(async move {
+            let _held = held;
+            std::future::pending::<()>().await
+        });
+        // Gives up waiting before the future can finish
+        assert!(time::timeout(Duration::from_millis(50), waiting)
+            .await
+            .is_err());
+
+        let closed = time::timeout(Duration::from_secs(2), async {
+            while let Err(oneshot::error::TryRecvError::Empty) = released.try_recv() {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await;
+        assert!(closed.is_ok());
+    }
+
+    #[tokio::test]
+    async fn receivers_built_on_the_runtime_receive_there() {
+        let runtime = AudioRuntime::new("audio-test").unwrap();
+        let config = ReceiverConfig::builder()
+            .bind_addr("127.0.0.1:0")
+            .runtime(&runtime)
+            .build();
+        let receiver = Arc::new(AudioReceiver::with_config(config).await.unwrap());
+        let receiver_addr = receiver.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        let receiving = receiver.clone();
+        tokio::spawn(async move {
+            let _ = runtime
+                .run(async move { receiving.start_receiving(tx).await })
+                .await;
+        });
+
+        let packet = encode_packet(&PacketHeader::default(), &[0.5, -0.5]);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(&packet, receiver_addr).await.unwrap();
+        let received = time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("timed out waiting for audio")
+            .unwrap();
+        assert_eq!(received, vec![0.5, -0.5]);
+    }
+
+    #[tokio::test]
+    async fn dropping_does_not_wait_for_busy_tasks() {
+        let runtime = AudioRuntime::new("audio-test").unwrap();
+        // Holds the runtime's thread, so it can't act on the shutdown for a while
+        runtime
+            .handle()
+            .spawn(async { thread::sleep(Duration::from_millis(500)) });
+        time::sleep(Duration::from_millis(20)).await;
+
+        let started = Instant::now();
+        drop(runtime);
+        assert!(started.elapsed() < Duration::from_millis(250));
+    }
+}
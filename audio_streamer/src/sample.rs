@@ -0,0 +1,70 @@
+use cpal::{FromSample, Sample};
+
+/// Converts a device sample to f32 in [-1.0, 1.0). Signed formats scale by
+/// their full range, so `i16::MIN` is -1.0, and unsigned ones are offset first,
+/// so the `u16` midpoint 32768 is silence.
+pub fn sample_to_f32<T>(sample: T) -> f32
+where
+    T: Sample,
+    f32: FromSample<T>,
+{
+    f32::from_sample(sample)
+}
+
+/// Converts an f32 sample to the device format, the inverse of
+/// `sample_to_f32`. Values outside [-1.0, 1.0) saturate at the format's limits,
+/// and 0.0 becomes the `u16` midpoint 32768.
+pub fn f32_to_sample<T>(value: f32) -> T
+where
+    T: Sample + FromSample<f32>,
+{
+    T::from_sample(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_samples_convert_to_the_unit_range() {
+        assert_eq!(sample_to_f32(0i16), 0.0);
+        assert_eq!(sample_to_f32(i16::MIN), -1.0);
+        assert_eq!(sample_to_f32(i16::MAX), 32767.0 / 32768.0);
+        assert_eq!(sample_to_f32(16384i16), 0.5);
+
+        // Unsigned samples are offset so the midpoint is silence
+        assert_eq!(sample_to_f32(32768u16), 0.0);
+        assert_eq!(sample_to_f32(0u16), -1.0);
+        assert_eq!(sample_to_f32(u16::MAX), 32767.0 / 32768.0);
+        assert_eq!(sample_to_f32(49152u16), 0.5);
+
+        assert_eq!(sample_to_f32(-0.25f32), -0.25);
+    }
+
+    #[test]
+    fn f32_converts_to_integer_formats() {
+        assert_eq!(f32_to_sample::<i16>(0.0), 0);
+        assert_eq!(f32_to_sample::<i16>(-1.0), i16::MIN);
+        assert_eq!(f32_to_sample::<i16>(0.5), 16384);
+        // Full scale and beyond saturate instead of wrapping
+        assert_eq!(f32_to_sample::<i16>(1.0), i16::MAX);
+        assert_eq!(f32_to_sample::<i16>(-1.5), i16::MIN);
+
+        assert_eq!(f32_to_sample::<u16>(0.0), 32768);
+        assert_eq!(f32_to_sample::<u16>(-1.0), 0);
+        assert_eq!(f32_to_sample::<u16>(1.0), u16::MAX);
+        assert_eq!(f32_to_sample::<u16>(0.5), 49152);
+
+        assert_eq!(f32_to_sample::<f32>(-0.25), -0.25);
+    }
+
+    #[test]
+    fn integer_samples_survive_a_round_trip_through_f32() {
+        for sample in i16::MIN..=i16::MAX {
+            assert_eq!(f32_to_sample::<i16>(sample_to_f32(sample)), sample);
+        }
+        for sample in u16::MIN..=u16::MAX {
+            assert_eq!(f32_to_sample::<u16>(sample_to_f32(sample)), sample);
+        }
+    }
+}
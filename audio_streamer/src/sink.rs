@@ -0,0 +1,266 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::dsp::Resampler;
+use crate::source::PcmFormat;
+use crate::{AudioStreamerError, Result};
+
+/// Destination for received audio: the player, a recording, a pipe, or
+/// anything else that consumes interleaved f32 buffers.
+///
+/// Writes are blocking, so sinks run on a blocking thread; use
+/// [`spawn_sink`] to drive one from the receiver.
+pub trait AudioSink {
+    fn write(&mut self, samples: &[f32]) -> Result<()>;
+
+    /// Called once after the last buffer so the sink can make its output
+    /// complete, e.g. patch a file header.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Forwards buffers to a channel, such as the one returned by
+/// `AudioPlayer::start_playback`, waiting while it is full.
+impl AudioSink for mpsc::Sender<Vec<f32>> {
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        self.blocking_send(samples.to_vec())
+            .map_err(|_| AudioStreamerError::StreamError("Sink channel closed".into()))
+    }
+}
+
+impl<S: AudioSink + ?Sized> AudioSink for Box<S> {
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        (**self).write(samples)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+/// Writes every buffer to each sink in turn, e.g. to play and record at once.
+/// Stops at the first sink that fails.
+impl<S: AudioSink> AudioSink for Vec<S> {
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        self.iter_mut().try_for_each(|sink| sink.write(samples))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.iter_mut().try_for_each(|sink| sink.flush())
+    }
+}
+
+/// Writes raw, headerless PCM to any writer, e.g. stdout for piping into
+/// `sox` or `ffmpeg`.
+pub struct PcmSink<W: Write> {
+    writer: W,
+    format: PcmFormat,
+    bytes: Vec<u8>,
+}
+
+impl<W: Write> PcmSink<W> {
+    pub fn new(writer: W, format: PcmFormat) -> Self {
+        Self {
+            writer,
+            format,
+            bytes: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> AudioSink for PcmSink<W> {
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        self.bytes.clear();
+        self.format.encode(samples, &mut self.bytes);
+        self.writer.write_all(&self.bytes)?;
+        // Downstream tools should see audio as it arrives
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Keeps a sink that can't change format mid-stream, like raw PCM on stdout,
+/// at the format it started with: buffers are converted from the (sample
+/// rate, channels) `formats` announces for them. Mono is copied to every
+/// channel and averaged from several; otherwise channels are kept in order,
+/// extra ones dropped and missing ones silent.
+pub struct Reformat<S> {
+    sink: S,
+    sample_rate: u32,
+    channels: u16,
+    formats: watch::Receiver<(u32, u16)>,
+    // Channels of the incoming buffers
+    from_channels: u16,
+    resampler: Option<Resampler>,
+    remixed: Vec<f32>,
+    resampled: Vec<f32>,
+}
+
+impl<S: AudioSink> Reformat<S> {
+    /// Writes to `sink` in the format `formats` holds now.
+    pub fn new(sink: S, mut formats: watch::Receiver<(u32, u16)>) -> Self {
+        let (sample_rate, channels) = *formats.borrow_and_update();
+        Self {
+            sink,
+            sample_rate,
+            channels,
+            formats,
+            from_channels: channels,
+            resampler: None,
+            remixed: Vec::new(),
+            resampled: Vec::new(),
+        }
+    }
+}
+
+impl<S: AudioSink> AudioSink for Reformat<S> {
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        if self.formats.has_changed().unwrap_or(false) {
+            let (sample_rate, channels) = *self.formats.borrow_and_update();
+            self.from_channels = channels;
+            self.resampler = (sample_rate != self.sample_rate)
+                .then(|| Resampler::new(sample_rate, self.sample_rate, self.channels));
+        }
+        let samples = if self.from_channels == self.channels {
+            samples
+        } else {
+            remix(
+                samples,
+                self.from_channels,
+                self.channels,
+                &mut self.remixed,
+            );
+            &self.remixed
+        };
+        match self.resampler.as_mut() {
+            Some(resampler) => {
+                resampler.process_into(samples, &mut self.resampled);
+                self.sink.write(&self.resampled)
+            }
+            None => self.sink.write(samples),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sink.flush()
+    }
+}
+
+// Converts interleaved `input` from `from` to `to` channels into `output`
+fn remix(input: &[f32], from: u16, to: u16, output: &mut Vec<f32>) {
+    let (from, to) = (from.max(1) as usize, to.max(1) as usize);
+    output.clear();
+    for frame in input.chunks_exact(from) {
+        if from == 1 {
+            output.extend(std::iter::repeat_n(frame[0], to));
+        } else if to == 1 {
+            output.push(frame.iter().sum::<f32>() / from as f32);
+        } else {
+            output.extend((0..to).map(|channel| frame.get(channel).copied().unwrap_or(0.0)));
+        }
+    }
+}
+
+/// Collects everything written to it, for tests and inspection. Clones
+/// share the same buffer, so keep one to read the samples back.
+#[derive(Clone, Debug, Default)]
+pub struct BufferSink {
+    samples: Arc<Mutex<Vec<f32>>>,
+}
+
+impl BufferSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn samples(&self) -> Vec<f32> {
+        self.samples.lock().unwrap().clone()
+    }
+}
+
+impl AudioSink for BufferSink {
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        self.samples.lock().unwrap().extend_from_slice(samples);
+        Ok(())
+    }
+}
+
+/// Runs `sink` on a blocking thread fed by the returned channel, which can
+/// be passed to `AudioReceiver::start_receiving` or its bounded variants.
+/// Once the channel closes the sink is flushed and handed back. A failing
+/// sink closes the channel, which stops the receiver.
+pub fn spawn_sink<S>(mut sink: S) -> (mpsc::Sender<Vec<f32>>, JoinHandle<Result<S>>)
+where
+    S: AudioSink + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel::<Vec<f32>>(32);
+    let handle = tokio::task::spawn_blocking(move || {
+        while let Some(samples) = rx.blocking_recv() {
+            if let Err(e) = sink.write(&samples) {
+                log::error!("Audio sink failed: {}", e);
+                return Err(e);
+            }
+        }
+        sink.flush()?;
+        Ok(sink)
+    });
+    (tx, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spawned_sinks_receive_every_buffer_and_flush() {
+        let buffer = BufferSink::new();
+        let pcm = PcmSink::new(Vec::new(), PcmFormat::S16Le);
+        let sinks: Vec<Box<dyn AudioSink + Send>> = vec![Box::new(buffer.clone()), Box::new(pcm)];
+
+        let (tx, handle) = spawn_sink(sinks);
+        tx.send(vec![0.5, -0.5]).await.unwrap();
+        tx.send(vec![1.0]).await.unwrap();
+        drop(tx);
+        handle.await.unwrap().unwrap();
+
+        assert_eq!(buffer.samples(), vec![0.5, -0.5, 1.0]);
+    }
+
+    #[test]
+    fn reformatted_sinks_keep_their_original_format() {
+        let buffer = BufferSink::new();
+        let formats = watch::Sender::new((48000, 2));
+        let mut sink = Reformat::new(buffer.clone(), formats.subscribe());
+        sink.write(&[0.5, -0.5]).unwrap();
+
+        formats.send_replace((48000, 1));
+        sink.write(&[0.25]).unwrap();
+        formats.send_replace((24000, 2));
+        sink.write(&[0.5; 8]).unwrap();
+        let samples = buffer.samples();
+        assert_eq!(samples[..4], [0.5, -0.5, 0.25, 0.25]);
+        // Twice the frames at twice the rate, less those waiting for the next buffer
+        assert_eq!(samples.len() - 4, 12);
+
+        let mono = BufferSink::new();
+        let formats = watch::Sender::new((48000, 1));
+        let mut sink = Reformat::new(mono.clone(), formats.subscribe());
+        formats.send_replace((48000, 2));
+        sink.write(&[0.5, 0.25]).unwrap();
+        assert_eq!(mono.samples(), vec![0.375]);
+    }
+
+    #[tokio::test]
+    async fn failing_sinks_close_their_channel() {
+        let (player_tx, player_rx) = mpsc::channel(1);
+        drop(player_rx);
+        let (tx, handle) = spawn_sink(player_tx);
+
+        tx.send(vec![0.0]).await.unwrap();
+        assert!(handle.await.unwrap().is_err());
+        assert!(tx.send(vec![0.0]).await.is_err());
+    }
+}
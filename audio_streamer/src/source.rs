@@ -0,0 +1,520 @@
+use std::collections::VecDeque;
+use std::f64::consts::TAU;
+use std::future::Future;
+use std::io::{ErrorKind, Read};
+use std::str::FromStr;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
+
+use crate::capture::{Accumulator, BufferPool};
+use crate::wav::WavReader;
+
+/// Producer of interleaved f32 buffers for the sender: capture, a file, a
+/// generated tone, stdin, or anything else.
+///
+/// `next_buffer` resolves to `None` once the source has ended, after which
+/// the sender finishes. Sources are polled inside `select!` loops, so the
+/// returned future should be cancel safe: dropping it must not lose audio.
+///
+/// Consumers hand each buffer back through `recycle` once done with it, so
+/// sources that keep spare buffers can refill them instead of allocating.
+/// By default the buffer is simply dropped.
+pub trait AudioSource {
+    fn next_buffer(&mut self) -> impl Future<Output = Option<Vec<f32>>> + Send;
+
+    fn recycle(&mut self, buffer: Vec<f32>) {
+        drop(buffer);
+    }
+}
+
+/// Channels are sources, which covers capture (`AudioCapture::start_capture`),
+/// [`spawn_pcm_reader`] and any other producer task. The source ends when
+/// every sender has been dropped.
+impl AudioSource for mpsc::Receiver<Vec<f32>> {
+    async fn next_buffer(&mut self) -> Option<Vec<f32>> {
+        self.recv().await
+    }
+}
+
+/// Returns consumed buffers to `pool`, typically the capture's
+/// (`AudioCapture::buffer_pool`), so a capture channel stops allocating a
+/// buffer per callback once the sender hands its buffers back.
+pub struct Recycling<S> {
+    source: S,
+    pool: BufferPool,
+}
+
+impl<S: AudioSource> Recycling<S> {
+    pub fn new(source: S, pool: BufferPool) -> Self {
+        Self { source, pool }
+    }
+}
+
+impl<S: AudioSource + Send> AudioSource for Recycling<S> {
+    fn next_buffer(&mut self) -> impl Future<Output = Option<Vec<f32>>> + Send {
+        self.source.next_buffer()
+    }
+
+    fn recycle(&mut self, buffer: Vec<f32>) {
+        self.pool.recycle(buffer);
+    }
+}
+
+/// Runs `source` on a background task and emits its buffers on a channel,
+/// usable anywhere a capture receiver is expected. The channel closes when
+/// the source ends.
+pub fn spawn_source<S>(mut source: S) -> mpsc::Receiver<Vec<f32>>
+where
+    S: AudioSource + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(32);
+    tokio::spawn(async move {
+        while let Some(buffer) = source.next_buffer().await {
+            if tx.send(buffer).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Regroups another source's buffers into buffers of exactly `buffer_size`
+/// samples, whatever size they arrive in, e.g. to set network framing
+/// independently of the capture buffer. When the inner source ends, the
+/// samples still pending go out as one short final buffer.
+pub struct Rebuffered<S> {
+    source: S,
+    accumulator: Accumulator,
+    ready: VecDeque<Vec<f32>>,
+    finished: bool,
+}
+
+impl<S: AudioSource> Rebuffered<S> {
+    pub fn new(source: S, buffer_size: usize) -> Self {
+        Self {
+            source,
+            accumulator: Accumulator::new(buffer_size),
+            ready: VecDeque::new(),
+            finished: false,
+        }
+    }
+}
+
+impl<S: AudioSource + Send> AudioSource for Rebuffered<S> {
+    async fn next_buffer(&mut self) -> Option<Vec<f32>> {
+        loop {
+            if let Some(buffer) = self.ready.pop_front() {
+                return Some(buffer);
+            }
+            if self.finished {
+                return None;
+            }
+            match self.source.next_buffer().await {
+                Some(buffer) => {
+                    let ready = &mut self.ready;
+                    self.accumulator
+                        .extend(buffer.iter().copied(), |full| ready.push_back(full));
+                    self.source.recycle(buffer);
+                }
+                None => {
+                    self.finished = true;
+                    let tail = self.accumulator.pending().to_vec();
+                    if !tail.is_empty() {
+                        return Some(tail);
+                    }
+                }
+            }
+        }
+    }
+
+    fn recycle(&mut self, buffer: Vec<f32>) {
+        self.accumulator.pool().recycle(buffer);
+    }
+}
+
+// Holds generated sources to real time: each buffer is due once the frames
+// before it would have finished playing, measured from the first buffer
+#[derive(Clone, Debug)]
+struct Pacer {
+    sample_rate: u32,
+    start: Option<time::Instant>,
+    frames: u64,
+}
+
+impl Pacer {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            start: None,
+            frames: 0,
+        }
+    }
+
+    async fn wait(&mut self) {
+        let start = *self.start.get_or_insert_with(time::Instant::now);
+        let due = Duration::from_secs_f64(self.frames as f64 / self.sample_rate as f64);
+        time::sleep_until(start + due).await;
+    }
+
+    fn advance(&mut self, samples: usize, channels: u16) {
+        self.frames += (samples / channels.max(1) as usize) as u64;
+    }
+}
+
+/// Raw interleaved PCM sample encodings for piping audio in and out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PcmFormat {
+    /// 32-bit float, little endian
+    F32Le,
+    /// 16-bit signed integer, little endian
+    S16Le,
+}
+
+impl PcmFormat {
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            PcmFormat::F32Le => 4,
+            PcmFormat::S16Le => 2,
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Vec<f32> {
+        match self {
+            PcmFormat::F32Le => bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            PcmFormat::S16Le => bytes
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+                .collect(),
+        }
+    }
+
+    pub fn encode(self, samples: &[f32], out: &mut Vec<u8>) {
+        for &sample in samples {
+            match self {
+                PcmFormat::F32Le => out.extend_from_slice(&sample.to_le_bytes()),
+                PcmFormat::S16Le => {
+                    let value = (sample * 32767.0).round().clamp(-32768.0, 32767.0) as i16;
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for PcmFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "f32le" | "f32" => Ok(PcmFormat::F32Le),
+            "s16le" | "i16" => Ok(PcmFormat::S16Le),
+            _ => Err(format!(
+                "Invalid PCM format '{}': expected f32le or s16le",
+                s
+            )),
+        }
+    }
+}
+
+/// Reads raw interleaved PCM from `reader` on a dedicated thread and emits
+/// `buffer_size`-sample buffers, paced to real time for the given rate and
+/// channel count so a fast producer (a file, `sox`, `ffmpeg`) doesn't flood
+/// the network. The channel closes at end of input.
+pub fn spawn_pcm_reader<R>(
+    mut reader: R,
+    format: PcmFormat,
+    sample_rate: u32,
+    channels: u16,
+    buffer_size: usize,
+) -> mpsc::Receiver<Vec<f32>>
+where
+    R: Read + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(32);
+    std::thread::spawn(move || {
+        let mut bytes = vec![0u8; buffer_size * format.bytes_per_sample()];
+        let start = Instant::now();
+        let mut frames_sent = 0u64;
+
+        let mut finished = false;
+        while !finished {
+            let mut filled = 0;
+            while filled < bytes.len() {
+                match reader.read(&mut bytes[filled..]) {
+                    Ok(0) => {
+                        finished = true;
+                        break;
+                    }
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        log::error!("Failed to read PCM input: {}", e);
+                        finished = true;
+                        break;
+                    }
+                }
+            }
+
+            let samples = format.decode(&bytes[..filled]);
+            if samples.is_empty() {
+                break;
+            }
+
+            let due = Duration::from_secs_f64(frames_sent as f64 / sample_rate as f64);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+            frames_sent += (samples.len() / channels as usize) as u64;
+
+            if tx.blocking_send(samples).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Deterministic sine tone generator producing interleaved buffers in the same
+/// shape as capture, for tests and tone broadcasts.
+#[derive(Clone, Debug)]
+pub struct SineSource {
+    frequency: f32,
+    amplitude: f32,
+    sample_rate: u32,
+    channels: u16,
+    buffer_size: usize,
+    // Frame index of the next generated sample, so buffers join without phase jumps
+    position: u64,
+    pacer: Pacer,
+}
+
+impl SineSource {
+    pub fn new(frequency: f32, amplitude: f32, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            frequency,
+            amplitude,
+            sample_rate,
+            channels,
+            buffer_size: 480,
+            position: 0,
+            pacer: Pacer::new(sample_rate),
+        }
+    }
+
+    /// Sets the number of interleaved samples per buffer (default 480, matching
+    /// capture). Raised to one frame if smaller, so buffers never take no time.
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size.max(self.channels.max(1) as usize);
+        self
+    }
+
+    /// Real-time duration of one buffer.
+    pub fn buffer_duration(&self) -> Duration {
+        let frames = self.buffer_size / self.channels as usize;
+        Duration::from_secs_f64(frames as f64 / self.sample_rate as f64)
+    }
+
+    pub fn next_buffer(&mut self) -> Vec<f32> {
+        let frames = self.buffer_size / self.channels as usize;
+        let mut buffer = Vec::with_capacity(frames * self.channels as usize);
+
+        for _ in 0..frames {
+            let t = self.position as f64 / self.sample_rate as f64;
+            let value = (self.amplitude as f64 * (TAU * self.frequency as f64 * t).sin()) as f32;
+            buffer.extend(std::iter::repeat_n(value, self.channels as usize));
+            self.position += 1;
+        }
+        buffer
+    }
+
+    /// Emits buffers at real-time cadence on a background task, usable anywhere
+    /// a capture receiver is expected.
+    pub fn spawn(self) -> mpsc::Receiver<Vec<f32>> {
+        spawn_source(self)
+    }
+}
+
+/// Generates buffers at real-time cadence, indefinitely.
+impl AudioSource for SineSource {
+    async fn next_buffer(&mut self) -> Option<Vec<f32>> {
+        self.pacer.wait().await;
+        let buffer = SineSource::next_buffer(self);
+        self.pacer.advance(buffer.len(), self.channels);
+        Some(buffer)
+    }
+}
+
+/// Plays back decoded interleaved samples (e.g. from a
+/// [`WavReader`]) in capture-shaped buffers.
+///
+/// When looping, the end of the file joins straight onto its start inside a
+/// single buffer, so every buffer is full-length and the repeat is
+/// sample-accurate: no short final buffer, gap or restart click.
+#[derive(Clone, Debug)]
+pub struct FileSource {
+    samples: Vec<f32>,
+    sample_rate: u32,
+    channels: u16,
+    buffer_size: usize,
+    looping: bool,
+    // Index of the next sample to emit
+    position: usize,
+    pacer: Pacer,
+}
+
+impl FileSource {
+    pub fn new(mut samples: Vec<f32>, sample_rate: u32, channels: u16) -> Self {
+        // A trailing partial frame would shift the channels on every loop
+        samples.truncate(samples.len() / channels as usize * channels as usize);
+        Self {
+            samples,
+            sample_rate,
+            channels,
+            buffer_size: 480,
+            looping: false,
+            position: 0,
+            pacer: Pacer::new(sample_rate),
+        }
+    }
+
+    /// Plays a decoded WAV file at its own rate and channel count.
+    pub fn from_wav(wav: WavReader) -> Self {
+        let (sample_rate, channels) = (wav.sample_rate(), wav.channels());
+        Self::new(wav.into_samples(), sample_rate, channels)
+    }
+
+    /// Sets the number of interleaved samples per buffer (default 480, matching
+    /// capture). Raised to one frame if smaller, so buffers never take no time.
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size.max(self.channels.max(1) as usize);
+        self
+    }
+
+    /// Repeats the file indefinitely instead of ending after one pass.
+    pub fn looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    /// Real-time duration of one buffer.
+    pub fn buffer_duration(&self) -> Duration {
+        let frames = self.buffer_size / self.channels as usize;
+        Duration::from_secs_f64(frames as f64 / self.sample_rate as f64)
+    }
+
+    /// Returns the next buffer, or `None` once a non-looping file is
+    /// exhausted. The last buffer of a non-looping file may be short.
+    pub fn next_buffer(&mut self) -> Option<Vec<f32>> {
+        let len = self.buffer_size / self.channels as usize * self.channels as usize;
+        if self.samples.is_empty() || (!self.looping && self.position >= self.samples.len()) {
+            return None;
+        }
+
+        let mut buffer = Vec::with_capacity(len);
+        while buffer.len() < len {
+            let take = (len - buffer.len()).min(self.samples.len() - self.position);
+            buffer.extend_from_slice(&self.samples[self.position..self.position + take]);
+            self.position += take;
+            if self.position == self.samples.len() {
+                if !self.looping {
+                    break;
+                }
+                self.position = 0;
+            }
+        }
+        Some(buffer)
+    }
+
+    /// Emits buffers at real-time cadence on a background task, usable anywhere
+    /// a capture receiver is expected. The channel closes at the end of a
+    /// non-looping file.
+    pub fn spawn(self) -> mpsc::Receiver<Vec<f32>> {
+        spawn_source(self)
+    }
+}
+
+/// Plays the file at real-time cadence, ending after one pass unless looping.
+impl AudioSource for FileSource {
+    async fn next_buffer(&mut self) -> Option<Vec<f32>> {
+        self.pacer.wait().await;
+        let buffer = FileSource::next_buffer(self)?;
+        self.pacer.advance(buffer.len(), self.channels);
+        Some(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp(frames: usize) -> Vec<f32> {
+        (0..frames * 2).map(|i| (i / 2) as f32).collect()
+    }
+
+    #[tokio::test]
+    async fn generated_sources_are_paced_to_real_time() {
+        // 480 stereo samples at 48kHz is 5ms of audio per buffer
+        let mut source = FileSource::new(ramp(1200), 48000, 2);
+        let start = time::Instant::now();
+        let mut buffers = 0;
+        while AudioSource::next_buffer(&mut source).await.is_some() {
+            buffers += 1;
+        }
+        assert_eq!(buffers, 5);
+        // The first buffer is due immediately, the last after the first four
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn rebuffering_regroups_buffers_and_keeps_the_tail() {
+        let (tx, rx) = mpsc::channel(8);
+        for len in [3, 10, 1, 4] {
+            tx.send(vec![1.0; len]).await.unwrap();
+        }
+        drop(tx);
+
+        let mut source = Rebuffered::new(rx, 4);
+        let mut lengths = Vec::new();
+        while let Some(buffer) = source.next_buffer().await {
+            lengths.push(buffer.len());
+        }
+        assert_eq!(lengths, vec![4, 4, 4, 4, 2]);
+    }
+
+    #[test]
+    fn looping_wraps_inside_a_full_buffer() {
+        // 5 stereo frames read 4 frames at a time
+        let mut source = FileSource::new(ramp(5), 48000, 2)
+            .with_buffer_size(8)
+            .looping(true);
+
+        let mut frames = Vec::new();
+        for _ in 0..4 {
+            let buffer = source.next_buffer().unwrap();
+            assert_eq!(buffer.len(), 8);
+            frames.extend(buffer.chunks(2).map(|frame| frame[0]));
+        }
+        let expected: Vec<f32> = (0..16).map(|i| (i % 5) as f32).collect();
+        assert_eq!(frames, expected);
+    }
+
+    #[test]
+    fn single_pass_ends_with_a_short_buffer() {
+        let mut source = FileSource::new(ramp(5), 48000, 2).with_buffer_size(8);
+        assert_eq!(source.next_buffer().unwrap().len(), 8);
+        assert_eq!(source.next_buffer().unwrap(), vec![4.0, 4.0]);
+        assert!(source.next_buffer().is_none());
+
+        let mut empty = FileSource::new(Vec::new(), 48000, 2).looping(true);
+        assert!(empty.next_buffer().is_none());
+
+        // Too small for a frame, which would never advance
+        let mut tiny = FileSource::new(ramp(5), 48000, 2).with_buffer_size(1);
+        assert!(!tiny.buffer_duration().is_zero());
+        assert_eq!(tiny.next_buffer().unwrap(), vec![0.0, 0.0]);
+    }
+}
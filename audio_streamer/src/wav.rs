@@ -0,0 +1,523 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::sync::watch;
+
+use crate::sink::AudioSink;
+use crate::{AudioStreamerError, Result};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+const HEADER_SIZE: u32 = 44;
+// Most data the 32-bit RIFF size can describe, about 4 GiB
+const MAX_DATA_LEN: u32 = u32::MAX - (HEADER_SIZE - 8);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitDepth {
+    /// 16-bit integer PCM with triangular dither
+    Int16,
+    /// 24-bit integer PCM
+    Int24,
+    /// 32-bit IEEE float, written without conversion
+    Float32,
+}
+
+impl BitDepth {
+    fn bytes_per_sample(self) -> u16 {
+        match self {
+            BitDepth::Int16 => 2,
+            BitDepth::Int24 => 3,
+            BitDepth::Float32 => 4,
+        }
+    }
+
+    fn format_tag(self) -> u16 {
+        match self {
+            BitDepth::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+            _ => WAVE_FORMAT_PCM,
+        }
+    }
+}
+
+impl FromStr for BitDepth {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "16" => Ok(BitDepth::Int16),
+            "24" => Ok(BitDepth::Int24),
+            "32f" | "f32" => Ok(BitDepth::Float32),
+            _ => Err(format!("Invalid bit depth '{}': expected 16, 24 or 32f", s)),
+        }
+    }
+}
+
+// Triangular (TPDF) dither source: the sum of two uniform values gives +/-1 LSB noise
+struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    fn next_uniform(&mut self) -> f32 {
+        // xorshift32, good enough for dither noise
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32 - 0.5
+    }
+
+    fn next(&mut self) -> f32 {
+        self.next_uniform() + self.next_uniform()
+    }
+}
+
+/// Writes interleaved f32 samples to a WAV file at the requested bit depth.
+/// Call `finalize` when done so the header sizes are patched. WAV sizes are
+/// 32-bit, so writes that would take the data past about 4 GiB, some three
+/// hours of stereo 48kHz float, fail and leave the file complete up to there.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    bit_depth: BitDepth,
+    data_len: u32,
+    dither: Dither,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(
+        path: impl AsRef<Path>,
+        sample_rate: u32,
+        channels: u16,
+        bit_depth: BitDepth,
+    ) -> Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Self::new(file, sample_rate, channels, bit_depth)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(
+        mut writer: W,
+        sample_rate: u32,
+        channels: u16,
+        bit_depth: BitDepth,
+    ) -> Result<Self> {
+        let block_align = channels * bit_depth.bytes_per_sample();
+
+        // Sizes are placeholders until finalize
+        writer.write_all(b"RIFF")?;
+        writer.write_u32::<LittleEndian>(HEADER_SIZE - 8)?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_u32::<LittleEndian>(16)?;
+        writer.write_u16::<LittleEndian>(bit_depth.format_tag())?;
+        writer.write_u16::<LittleEndian>(channels)?;
+        writer.write_u32::<LittleEndian>(sample_rate)?;
+        writer.write_u32::<LittleEndian>(sample_rate * block_align as u32)?;
+        writer.write_u16::<LittleEndian>(block_align)?;
+        writer.write_u16::<LittleEndian>(bit_depth.bytes_per_sample() * 8)?;
+        writer.write_all(b"data")?;
+        writer.write_u32::<LittleEndian>(0)?;
+
+        Ok(Self {
+            writer,
+            bit_depth,
+            data_len: 0,
+            dither: Dither { state: 0x9E37_79B9 },
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<()> {
+        let data_len = u32::try_from(samples.len() * self.bit_depth.bytes_per_sample() as usize)
+            .ok()
+            .and_then(|len| self.data_len.checked_add(len))
+            .filter(|&len| len <= MAX_DATA_LEN)
+            .ok_or_else(|| {
+                AudioStreamerError::EncodingError(
+                    "WAV file is full: RIFF sizes cannot describe more than 4 GiB of audio".into(),
+                )
+            })?;
+        for &sample in samples {
+            match self.bit_depth {
+                BitDepth::Int16 => {
+                    let scaled = sample * i16::MAX as f32 + self.dither.next();
+                    let value = scaled.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                    self.writer.write_i16::<LittleEndian>(value)?;
+                }
+                BitDepth::Int24 => {
+                    const MAX_24: f32 = 8_388_607.0;
+                    let value = (sample * MAX_24).round().clamp(-MAX_24 - 1.0, MAX_24) as i32;
+                    self.writer.write_i24::<LittleEndian>(value)?;
+                }
+                BitDepth::Float32 => self.writer.write_f32::<LittleEndian>(sample)?,
+            }
+        }
+        self.data_len = data_len;
+        Ok(())
+    }
+
+    /// Patches the RIFF and data chunk sizes and returns the underlying writer.
+    pub fn finalize(mut self) -> Result<W> {
+        self.patch_header()?;
+        Ok(self.writer)
+    }
+
+    // Writes the sizes so far into the header, leaving the writer at the end
+    fn patch_header(&mut self) -> Result<()> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_u32::<LittleEndian>(HEADER_SIZE - 8 + self.data_len)?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_u32::<LittleEndian>(self.data_len)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Records received audio. Flushing patches the header, so the file is
+/// complete without calling `finalize`.
+impl<W: Write + Seek> AudioSink for WavWriter<W> {
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        self.write_samples(samples)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.patch_header()
+    }
+}
+
+/// Records received audio to a WAV file at `path`, starting a new numbered
+/// file (`out-2.wav`, `out-3.wav`, ...) whenever `formats` announces another
+/// sample rate or channel count, since a WAV file has one format throughout.
+/// `formats` holds the (sample rate, channels) of the buffers written next.
+pub struct WavRecorder {
+    path: PathBuf,
+    bit_depth: BitDepth,
+    formats: watch::Receiver<(u32, u16)>,
+    format: (u32, u16),
+    writer: WavWriter<BufWriter<File>>,
+    files: u32,
+}
+
+impl WavRecorder {
+    pub fn create(
+        path: impl Into<PathBuf>,
+        bit_depth: BitDepth,
+        mut formats: watch::Receiver<(u32, u16)>,
+    ) -> Result<Self> {
+        let path = path.into();
+        let format = *formats.borrow_and_update();
+        let writer = WavWriter::create(&path, format.0, format.1, bit_depth)?;
+        Ok(Self {
+            path,
+            bit_depth,
+            formats,
+            format,
+            writer,
+            files: 1,
+        })
+    }
+
+    // Finishes the current file and carries on in the next one
+    fn roll_over(&mut self, format: (u32, u16)) -> Result<()> {
+        let path = numbered_path(&self.path, self.files + 1);
+        let writer = WavWriter::create(&path, format.0, format.1, self.bit_depth)?;
+        std::mem::replace(&mut self.writer, writer).finalize()?;
+        log::info!(
+            "Audio is now {}Hz, {} channels; recording continues in {}",
+            format.0,
+            format.1,
+            path.display()
+        );
+        self.files += 1;
+        self.format = format;
+        Ok(())
+    }
+}
+
+impl AudioSink for WavRecorder {
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        if self.formats.has_changed().unwrap_or(false) {
+            let format = *self.formats.borrow_and_update();
+            if format != self.format {
+                self.roll_over(format)?;
+            }
+        }
+        self.writer.write_samples(samples)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.patch_header()
+    }
+}
+
+/// `path` for the first file, then `path` with `-<number>` after the stem,
+/// e.g. `out-2.wav`.
+pub fn numbered_path(path: &Path, number: u32) -> PathBuf {
+    if number <= 1 {
+        return path.to_path_buf();
+    }
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!("-{}", number));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// A WAV file decoded into memory as interleaved f32 samples. Reads 16, 24
+/// and 32-bit integer PCM and 32-bit float, including the extensible format
+/// header written by most editors.
+#[derive(Clone, Debug)]
+pub struct WavReader {
+    sample_rate: u32,
+    channels: u16,
+    samples: Vec<f32>,
+}
+
+impl WavReader {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+
+    pub fn new(mut reader: impl Read) -> Result<Self> {
+        let mut tag = [0u8; 4];
+        reader.read_exact(&mut tag)?;
+        let _riff_len = reader.read_u32::<LittleEndian>()?;
+        let mut wave = [0u8; 4];
+        reader.read_exact(&mut wave)?;
+        if &tag != b"RIFF" || &wave != b"WAVE" {
+            return Err(invalid("not a RIFF/WAVE file"));
+        }
+
+        // (format tag, channels, sample rate, bits per sample)
+        let mut format = None;
+        loop {
+            let mut id = [0u8; 4];
+            reader.read_exact(&mut id)?;
+            // Lengths aren't trusted to size allocations, since they can be
+            // corrupt or, in streamed files, a 0xFFFFFFFF placeholder
+            let len = reader.read_u32::<LittleEndian>()? as u64;
+            let mut chunk = reader.by_ref().take(len);
+
+            match &id {
+                b"fmt " => {
+                    let mut body = Vec::new();
+                    chunk.read_to_end(&mut body)?;
+                    if body.len() < 16 {
+                        return Err(invalid("fmt chunk is too short"));
+                    }
+                    let mut tag = u16::from_le_bytes([body[0], body[1]]);
+                    // The real format is the first two bytes of the sub-format GUID
+                    if tag == WAVE_FORMAT_EXTENSIBLE && body.len() >= 26 {
+                        tag = u16::from_le_bytes([body[24], body[25]]);
+                    }
+                    format = Some((
+                        tag,
+                        u16::from_le_bytes([body[2], body[3]]),
+                        u32::from_le_bytes([body[4], body[5], body[6], body[7]]),
+                        u16::from_le_bytes([body[14], body[15]]),
+                    ));
+                }
+                b"data" => {
+                    let Some((tag, channels, sample_rate, bits)) = format else {
+                        return Err(invalid("data chunk before fmt chunk"));
+                    };
+                    if channels == 0 {
+                        return Err(invalid("zero channels"));
+                    }
+                    // Up to the end of the file when it's shorter than stated
+                    let mut body = Vec::new();
+                    chunk.read_to_end(&mut body)?;
+                    let samples = decode_samples(tag, bits, &body)?;
+                    return Ok(Self {
+                        sample_rate,
+                        channels,
+                        samples,
+                    });
+                }
+                _ => {
+                    io::copy(&mut chunk, &mut io::sink())?;
+                }
+            }
+            // Chunks are padded to an even length
+            if len % 2 == 1 {
+                let mut pad = [0u8; 1];
+                let _ = reader.read(&mut pad)?;
+            }
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Number of frames (samples per channel) in the file.
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    pub fn into_samples(self) -> Vec<f32> {
+        self.samples
+    }
+}
+
+fn decode_samples(tag: u16, bits: u16, data: &[u8]) -> Result<Vec<f32>> {
+    let samples = match (tag, bits) {
+        (WAVE_FORMAT_PCM, 16) => data
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+            .collect(),
+        (WAVE_FORMAT_PCM, 24) => data
+            .chunks_exact(3)
+            .map(|b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.0)
+            .collect(),
+        (WAVE_FORMAT_PCM, 32) => data
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0)
+            .collect(),
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        _ => {
+            return Err(invalid(&format!(
+                "unsupported sample format (tag {}, {} bits)",
+                tag, bits
+            )))
+        }
+    };
+    Ok(samples)
+}
+
+fn invalid(reason: &str) -> AudioStreamerError {
+    AudioStreamerError::EncodingError(format!("Invalid WAV file: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn write(bit_depth: BitDepth, samples: &[f32]) -> Vec<u8> {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 48000, 2, bit_depth).unwrap();
+        writer.write_samples(samples).unwrap();
+        writer.finalize().unwrap().into_inner()
+    }
+
+    #[test]
+    fn header_sizes_are_patched() {
+        let bytes = write(BitDepth::Int24, &[0.0; 4]);
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(&bytes[4..8], &(36u32 + 12).to_le_bytes());
+        assert_eq!(&bytes[40..44], &12u32.to_le_bytes());
+        assert_eq!(&bytes[34..36], &24u16.to_le_bytes());
+    }
+
+    #[test]
+    fn int_depths_scale_full_range() {
+        let bytes = write(BitDepth::Int24, &[1.0, -1.0]);
+        assert_eq!(&bytes[44..47], &[0xFF, 0xFF, 0x7F]);
+        assert_eq!(&bytes[47..50], &[0x01, 0x00, 0x80]);
+
+        // Dither stays within one LSB of the exact value
+        let bytes = write(BitDepth::Int16, &[0.5, -1.0]);
+        let half = i16::from_le_bytes([bytes[44], bytes[45]]) as i32;
+        let min = i16::from_le_bytes([bytes[46], bytes[47]]) as i32;
+        assert!((16382..=16385).contains(&half));
+        assert!((-32768..=-32766).contains(&min));
+    }
+
+    #[test]
+    fn float_samples_are_written_verbatim() {
+        let bytes = write(BitDepth::Float32, &[0.25]);
+        assert_eq!(&bytes[20..22], &WAVE_FORMAT_IEEE_FLOAT.to_le_bytes());
+        assert_eq!(&bytes[44..48], &0.25f32.to_le_bytes());
+    }
+
+    #[test]
+    fn flushing_as_a_sink_patches_the_header() {
+        let mut writer =
+            WavWriter::new(Cursor::new(Vec::new()), 48000, 2, BitDepth::Float32).unwrap();
+        AudioSink::write(&mut writer, &[0.5, 0.5]).unwrap();
+        AudioSink::flush(&mut writer).unwrap();
+        let bytes = writer.finalize().unwrap().into_inner();
+        assert_eq!(&bytes[40..44], &8u32.to_le_bytes());
+        assert_eq!(bytes.len(), 44 + 8);
+    }
+
+    #[test]
+    fn writes_past_the_riff_limit_fail() {
+        let mut writer =
+            WavWriter::new(Cursor::new(Vec::new()), 48000, 2, BitDepth::Float32).unwrap();
+        writer.data_len = MAX_DATA_LEN - 8;
+        writer.write_samples(&[0.5, 0.5]).unwrap();
+        let full = writer.write_samples(&[0.5]).unwrap_err();
+        assert!(full.to_string().contains("WAV file is full"));
+
+        let bytes = writer.finalize().unwrap().into_inner();
+        assert_eq!(&bytes[4..8], &u32::MAX.to_le_bytes());
+        assert_eq!(&bytes[40..44], &MAX_DATA_LEN.to_le_bytes());
+        assert_eq!(bytes.len(), 44 + 8);
+    }
+
+    #[test]
+    fn recorders_start_a_new_file_for_each_format() {
+        let path = std::env::temp_dir().join(format!("recorder-{}.wav", std::process::id()));
+        let second = numbered_path(&path, 2);
+        let formats = watch::Sender::new((48000, 2));
+        let mut recorder =
+            WavRecorder::create(&path, BitDepth::Float32, formats.subscribe()).unwrap();
+        recorder.write(&[0.5, 0.5]).unwrap();
+        formats.send_replace((16000, 1));
+        recorder.write(&[0.25]).unwrap();
+        recorder.flush().unwrap();
+        drop(recorder);
+
+        let first = WavReader::open(&path).unwrap();
+        let next = WavReader::open(&second).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&second).unwrap();
+        assert_eq!((first.sample_rate(), first.channels()), (48000, 2));
+        assert_eq!(first.into_samples(), vec![0.5, 0.5]);
+        assert_eq!((next.sample_rate(), next.channels()), (16000, 1));
+        assert_eq!(next.into_samples(), vec![0.25]);
+    }
+
+    #[test]
+    fn reader_round_trips_written_files() {
+        let samples = [0.5, -0.5, 0.25, -1.0];
+        for bit_depth in [BitDepth::Int16, BitDepth::Int24, BitDepth::Float32] {
+            let reader = WavReader::new(Cursor::new(write(bit_depth, &samples))).unwrap();
+            assert_eq!(reader.sample_rate(), 48000);
+            assert_eq!(reader.channels(), 2);
+            assert_eq!(reader.frames(), 2);
+            for (read, written) in reader.into_samples().iter().zip(samples) {
+                assert!((read - written).abs() < 1e-3, "{:?}", bit_depth);
+            }
+        }
+
+        assert!(WavReader::new(Cursor::new(b"RIFF\0\0\0\0AVI ".to_vec())).is_err());
+    }
+
+    #[test]
+    fn reader_skips_unknown_chunks_and_reads_placeholder_lengths() {
+        let mut bytes = write(BitDepth::Float32, &[0.5, -0.5]);
+        // A streamed file's data length, never patched
+        bytes[40..44].copy_from_slice(&u32::MAX.to_le_bytes());
+        // An odd-length chunk ahead of the data
+        let list = [b"LIST".as_slice(), &3u32.to_le_bytes(), b"abc\0"].concat();
+        bytes.splice(36..36, list);
+
+        let reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.into_samples(), vec![0.5, -0.5]);
+    }
+}
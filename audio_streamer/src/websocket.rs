@@ -0,0 +1,188 @@
+//! WebSocket transport for browser listeners.
+//!
+//! Each binary message carries exactly one packet in the UDP wire format
+//! described in [`crate::protocol`]: the 28-byte little-endian header followed
+//! by interleaved f32 PCM samples. Packets are always PCM, since browsers have
+//! no FLAC frame decoder. A browser client can decode them with:
+//!
+//! ```js
+//! const ws = new WebSocket("ws://sender:50002");
+//! ws.binaryType = "arraybuffer";
+//! ws.onmessage = (event) => {
+//!   const view = new DataView(event.data);
+//!   if (view.getUint8(0) !== 2 || !(view.getUint8(1) & 1)) return;
+//!   const position = view.getBigUint64(20, true);
+//!   // The header is a multiple of 4 bytes, so the samples are aligned
+//!   const samples = new Float32Array(event.data, 28);
+//! };
+//! ```
+//!
+//! `Float32Array` uses the host byte order, which is little endian on every
+//! platform browsers ship on. Messages from clients are ignored. A client too
+//! slow to keep up skips packets instead of holding back the others.
+//!
+//! An [`AudioSender`](crate::network::AudioSender) streaming the same audio
+//! announces the endpoint in discovery when given its port with
+//! `SenderConfigBuilder::websocket_port`, as [`Transport::WebSocket`].
+//!
+//! [`Transport::WebSocket`]: crate::protocol::Transport::WebSocket
+
+use futures_util::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::network::{bind_error, packet_header};
+use crate::protocol::encode_packet;
+use crate::source::AudioSource;
+use crate::Result;
+
+pub const DEFAULT_WEBSOCKET_PORT: u16 = 50002;
+
+// Packets queued per client before it starts skipping
+const CLIENT_QUEUE: usize = 32;
+
+pub struct WebSocketSender {
+    listener: TcpListener,
+    channels: u16,
+}
+
+impl WebSocketSender {
+    /// Listens for browser connections on `addr`, sending audio of
+    /// `channels` interleaved channels. This must match the buffers the
+    /// source emits, e.g. the capture's channel count, or packet positions
+    /// drift from the audio.
+    pub async fn bind(addr: &str, channels: u16) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|source| bind_error(addr, source))?;
+        Ok(Self { listener, channels })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accepts clients and sends every buffer from `source` to all of them
+    /// until it ends.
+    pub async fn start_sending<S: AudioSource>(&self, mut source: S) -> Result<()> {
+        log::info!(
+            "Starting WebSocket sender on {}",
+            self.listener.local_addr()?
+        );
+
+        let (packets, _) = broadcast::channel::<Arc<Vec<u8>>>(CLIENT_QUEUE);
+        let epoch_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let channels = self.channels.max(1) as u64;
+        let mut position = 0u64;
+
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, addr)) => {
+                        tokio::spawn(serve_client(stream, addr, packets.subscribe()));
+                    }
+                    Err(e) => log::warn!("Failed to accept WebSocket connection: {}", e),
+                },
+                samples = source.next_buffer() => {
+                    let Some(samples) = samples else {
+                        return Ok(());
+                    };
+                    let header = packet_header(epoch_us, position);
+                    position += samples.len() as u64 / channels;
+                    // Fails only when nobody is connected
+                    let _ = packets.send(Arc::new(encode_packet(&header, &samples)));
+                    source.recycle(samples);
+                }
+            }
+        }
+    }
+}
+
+async fn serve_client(
+    stream: TcpStream,
+    addr: SocketAddr,
+    mut packets: broadcast::Receiver<Arc<Vec<u8>>>,
+) {
+    let ws = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            log::warn!("WebSocket handshake with {} failed: {}", addr, e);
+            return;
+        }
+    };
+    log::info!("WebSocket listener connected: {}", addr);
+    let (mut sink, mut source) = ws.split();
+
+    loop {
+        tokio::select! {
+            packet = packets.recv() => match packet {
+                Ok(packet) => {
+                    if let Err(e) = sink.send(Message::Binary(packet.to_vec())).await {
+                        log::debug!("WebSocket send to {} failed: {}", addr, e);
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::debug!("WebSocket listener {} skipped {} packets", addr, skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = source.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    let _ = sink.close().await;
+    log::info!("WebSocket listener disconnected: {}", addr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::decode_packet;
+    use tokio::sync::mpsc;
+    use tokio::time::{timeout, Duration};
+
+    #[tokio::test]
+    async fn browser_clients_receive_packets_as_binary_messages() {
+        let sender = Arc::new(WebSocketSender::bind("127.0.0.1:0", 2).await.unwrap());
+        let url = format!("ws://{}", sender.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel(32);
+        let task = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.start_sending(rx).await }
+        });
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        // Keep sending until the client has subscribed and a packet arrives
+        let message = timeout(Duration::from_secs(5), async {
+            loop {
+                tx.send(vec![0.25, -0.25]).await.unwrap();
+                if let Ok(Some(message)) = timeout(Duration::from_millis(50), ws.next()).await {
+                    break message.unwrap();
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        let Message::Binary(packet) = message else {
+            panic!("expected a binary message, got {:?}", message);
+        };
+        let (_, samples) = decode_packet(&packet).unwrap();
+        assert_eq!(samples, vec![0.25, -0.25]);
+
+        drop(tx);
+        task.await.unwrap().unwrap();
+    }
+}
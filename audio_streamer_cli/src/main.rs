@@ -1,11 +1,45 @@
+#[cfg(feature = "websocket")]
+use audio_streamer::websocket::WebSocketSender;
 use audio_streamer::{
-    capture::{AudioCapture, DeviceType},
-    network::{AudioReceiver, AudioSender},
-    player::AudioPlayer,
+    capture::{available_hosts, fan_out, select_host, AudioCapture, CaptureConfig, DeviceType},
+    dsp::{EqConfig, Resampler},
+    network::{
+        AudioReceiver, AudioSender, BenchConfig, ConnectionState, ControlMessage, DiscoveredServer,
+        OverflowPolicy, ReceiverConfig, SenderConfig,
+    },
+    player::{
+        check_latency_budget, AdaptiveBufferConfig, AudioPlayer, Downmix, PlayerBackend,
+        PlayerConfig,
+    },
+    protocol::{validate_sample_rate, Codec, StreamFormat},
+    replay::{replay, PacketReader, PacketRecorder},
+    runtime::AudioRuntime,
+    sink::{spawn_sink, AudioSink, PcmSink, Reformat},
+    source::{spawn_pcm_reader, FileSource, PcmFormat, Recycling, SineSource},
+    wav::{BitDepth, WavReader, WavRecorder},
 };
 use clap::{Parser, Subcommand};
+use std::collections::VecDeque;
 use std::error::Error;
+use std::future::Future;
 use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+// Status lines go to stderr when stdout is carrying audio
+macro_rules! status {
+    ($to_stderr:expr, $($arg:tt)*) => {
+        if $to_stderr {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -18,29 +52,361 @@ struct Cli {
 enum Commands {
     /// Start capturing and broadcasting audio
     Broadcast {
-        /// Optional address to bind to (default: "0.0.0.0:50001")
+        /// Optional address to bind to (default: "0.0.0.0:50001"); "[::]:50001"
+        /// covers IPv4 and IPv6
         #[arg(short, long)]
         bind: Option<String>,
 
         /// Skip device selection prompt and use default input device
         #[arg(short, long)]
         use_default: bool,
+
+        /// Audio host to capture and monitor through, e.g. JACK or ASIO where
+        /// compiled in (default: the platform's default; see `hosts`)
+        #[arg(long, value_name = "NAME")]
+        host: Option<String>,
+
+        /// Broadcast a generated test tone at this frequency (Hz) instead of capturing
+        #[arg(long)]
+        tone: Option<f32>,
+
+        /// Amplitude of the test tone (0.0-1.0)
+        #[arg(long, default_value_t = 0.5)]
+        amplitude: f32,
+
+        /// Broadcast raw stereo interleaved PCM read from stdin, at --sample-rate
+        #[arg(long, conflicts_with = "tone")]
+        stdin: bool,
+
+        /// Sample format read with --stdin: f32le or s16le
+        #[arg(long, default_value = "f32le")]
+        stdin_format: PcmFormat,
+
+        /// Broadcast a stereo WAV file instead of capturing
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["tone", "stdin"])]
+        file: Option<PathBuf>,
+
+        /// Repeat the --file seamlessly until stopped
+        #[arg(long = "loop", requires = "file")]
+        looping: bool,
+
+        /// Also play the broadcast audio on the local output device
+        #[arg(long)]
+        monitor: bool,
+
+        /// Volume of the local monitor (0.0-1.0)
+        #[arg(long, default_value_t = 1.0, requires = "monitor")]
+        monitor_volume: f32,
+
+        /// Linear gain applied to the captured input, e.g. 2.0 for quiet line inputs
+        #[arg(long, default_value_t = 1.0)]
+        gain: f32,
+
+        /// If the capture device can't be opened in its chosen format, retry
+        /// with its default config before giving up
+        #[arg(long, conflicts_with_all = ["tone", "stdin", "file"])]
+        format_fallback: bool,
+
+        /// Volume of the audio sent to listeners (0.0-1.0)
+        #[arg(long, default_value_t = 1.0)]
+        volume: f32,
+
+        /// Always send to this listener address (repeatable)
+        #[arg(long = "client", value_name = "ADDR")]
+        clients: Vec<SocketAddr>,
+
+        /// Don't answer discovery requests; only --client listeners receive audio
+        #[arg(long, requires = "clients")]
+        no_discovery: bool,
+
+        /// Only let listeners at this IP register through discovery (repeatable)
+        #[arg(long = "allow", value_name = "IP")]
+        allowed: Vec<IpAddr>,
+
+        /// Announce the server only on the interface with this IPv4 address,
+        /// e.g. the LAN rather than a VPN (repeatable)
+        #[arg(
+            long = "announce-on",
+            value_name = "IP",
+            conflicts_with = "no_discovery"
+        )]
+        announce_on: Vec<Ipv4Addr>,
+
+        /// Name shown to listeners scanning the network, e.g. "Living room"
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Sample rate sent to listeners: 8000, 16000, 24000, 32000, 44100 or 48000.
+        /// Lower rates cut bandwidth, e.g. 16000 for voice
+        #[arg(long, default_value_t = 48000)]
+        sample_rate: u32,
+
+        /// Wire format, announced to listeners: raw-f32, raw-i16 for half the
+        /// bandwidth at 16-bit precision, flac for 16-bit lossless compression
+        /// (needs the `flac` feature), or zstd for lossless f32 compression
+        /// (needs the `zstd` feature). Opus is recognized but not compiled in
+        /// yet, so it is refused
+        #[arg(
+            long = "format",
+            visible_alias = "codec",
+            value_name = "FORMAT",
+            default_value = "raw-f32"
+        )]
+        codec: Codec,
+
+        /// Boost treble before encoding so it survives lossy links; listeners
+        /// undo it. Optional coefficient between 0 and 1, default 0.95
+        #[arg(long, value_name = "COEFF", num_args = 0..=1, default_missing_value = "0.95")]
+        pre_emphasis: Option<f32>,
+
+        /// Samples per packet across both channels, independent of the capture
+        /// buffer: smaller for lower latency, larger for less overhead
+        #[arg(long, value_name = "SAMPLES")]
+        samples_per_packet: Option<usize>,
+
+        /// Also serve the audio to browsers over WebSocket on this address,
+        /// e.g. 0.0.0.0:50002
+        #[cfg(feature = "websocket")]
+        #[arg(long, value_name = "ADDR")]
+        websocket: Option<String>,
+
+        /// Write session statistics to this JSON file on exit
+        #[arg(long, value_name = "PATH")]
+        stats_out: Option<PathBuf>,
+
+        /// Run the network I/O on a thread of its own, away from other work
+        #[arg(long)]
+        dedicated_runtime: bool,
+
+        /// Largest datagram in bytes, up to 8972 on jumbo-frame networks;
+        /// broadcaster and listeners need the same value (default: 1472)
+        #[arg(long, value_name = "BYTES")]
+        max_datagram_size: Option<usize>,
+
+        /// Send each packet to all listeners in one system call where the OS
+        /// supports it (Linux), for less overhead with many listeners
+        #[arg(long)]
+        batch_sends: bool,
+
+        /// Stream once to this multicast group, e.g. 239.255.77.1:50001, for
+        /// every listener that joins it instead of a copy each. The port is
+        /// the listeners' stream port
+        #[arg(long, value_name = "GROUP:PORT", conflicts_with = "no_discovery")]
+        multicast: Option<SocketAddrV4>,
     },
 
     /// Start receiving and playing audio (auto-discovers server)
     Listen {
-        /// Optional address to bind to (default: "0.0.0.0:50001")
+        /// Optional address to bind to (default: "0.0.0.0:50001"); "[::]:50001"
+        /// covers IPv4 and IPv6
         #[arg(short, long)]
         bind: Option<String>,
+
+        /// Also record the received audio to a WAV file. When the server
+        /// changes format the recording continues in a new numbered file,
+        /// e.g. session-2.wav
+        #[arg(short, long)]
+        record: Option<PathBuf>,
+
+        /// Bit depth of the recorded WAV file: 16 (dithered), 24 or 32f
+        #[arg(long, default_value = "16")]
+        bit_depth: BitDepth,
+
+        /// Also write received audio to stdout as raw interleaved PCM, at the
+        /// server's sample rate and channel count (48kHz stereo by default).
+        /// Audio after a format change is converted back to that format
+        #[arg(long)]
+        stdout: bool,
+
+        /// Sample format written with --stdout: f32le or s16le
+        #[arg(long, default_value = "f32le")]
+        stdout_format: PcmFormat,
+
+        /// Grow the playback buffer after underruns and shrink it when stable
+        #[arg(long)]
+        adaptive_buffer: bool,
+
+        /// Keep playback latency within this many milliseconds: the receive
+        /// queue and playback buffer are sized to fit, and audio is skipped to
+        /// catch up when it falls behind.
+        /// Must hold at least one packet and one output device period
+        #[arg(
+            long,
+            value_name = "MS",
+            conflicts_with = "adaptive_buffer",
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        target_latency_ms: Option<u64>,
+
+        /// Smooth clicks at buffer boundaries by crossfading this many frames
+        #[arg(long, value_name = "FRAMES")]
+        crossfade: Option<usize>,
+
+        /// Play a mono downmix on every output channel, e.g. for a single
+        /// speaker, so audio panned to one side isn't lost: the average of
+        /// the channels, or with `left` or `right` that channel alone
+        #[arg(long, value_name = "MODE", num_args = 0..=1)]
+        mono: Option<Option<Downmix>>,
+
+        /// Stereo width: 0 plays mono, 1 as sent, up to 2 widens the image
+        #[arg(long, value_name = "WIDTH", default_value_t = 1.0)]
+        width: f32,
+
+        /// Delay playback by this many milliseconds, e.g. to line audio up
+        /// with video shown elsewhere
+        #[arg(long, value_name = "MS", default_value_t = 0)]
+        output_delay: u64,
+
+        /// Audio host to play through, e.g. JACK or ASIO where compiled in
+        /// (default: the platform's default; see `hosts`)
+        #[arg(long, value_name = "NAME")]
+        host: Option<String>,
+
+        /// Play on the output device with this name, or part of it; repeat to
+        /// give fallbacks in order of preference, ending with the default.
+        /// Playback moves down the list if the device goes away
+        #[arg(long = "output-device", value_name = "NAME")]
+        output_devices: Vec<String>,
+
+        /// Play without an audio device, e.g. on a headless machine: into
+        /// nothing, or recorded to a WAV file after all playback processing
+        #[arg(
+            long,
+            value_name = "FILE",
+            num_args = 0..=1,
+            conflicts_with = "output_devices"
+        )]
+        virtual_output: Option<Option<PathBuf>>,
+
+        /// Tone control gains in dB for the 100Hz, 300Hz, 1kHz, 3kHz and
+        /// 8kHz bands, e.g. --eq 3,0,0,-2,1
+        #[arg(
+            long,
+            value_name = "DB",
+            value_delimiter = ',',
+            allow_hyphen_values = true
+        )]
+        eq: Vec<f32>,
+
+        /// Keep retrying discovery, backing off exponentially, until a server appears
+        #[arg(long)]
+        retry: bool,
+
+        /// Fixed local port for discovery and control messages, so a firewall
+        /// can allow the server's replies (default: ephemeral)
+        #[arg(long, value_name = "PORT")]
+        control_port: Option<u16>,
+
+        /// Keep receiving a copy of our own when the server offers multicast
+        #[arg(long)]
+        no_multicast: bool,
+
+        /// Stop after this many seconds, e.g. for scripted recordings
+        #[arg(long, value_name = "SECS")]
+        duration: Option<f64>,
+
+        /// Write session statistics to this JSON file on exit
+        #[arg(long, value_name = "PATH")]
+        stats_out: Option<PathBuf>,
+
+        /// Record every received datagram with its arrival time to this file,
+        /// to reproduce a session later with `replay`
+        #[arg(long, value_name = "FILE")]
+        record_packets: Option<PathBuf>,
+
+        /// Run the network I/O on a thread of its own, away from other work
+        #[arg(long)]
+        dedicated_runtime: bool,
+
+        /// Largest datagram in bytes, up to 8972 on jumbo-frame networks;
+        /// broadcaster and listeners need the same value (default: 1472)
+        #[arg(long, value_name = "BYTES")]
+        max_datagram_size: Option<usize>,
+
+        /// Sample rate the server sends at, for servers that don't announce
+        /// their format, e.g. third-party or older senders (default: 48000).
+        /// Takes precedence over an announced rate
+        #[arg(long, value_name = "HZ", value_parser = clap::value_parser!(u32).range(1..))]
+        source_rate: Option<u32>,
+
+        /// Channel count the server sends, for servers that don't announce
+        /// their format (default: 2). Takes precedence over an announced count
+        #[arg(long, value_name = "CHANNELS", value_parser = clap::value_parser!(u16).range(1..))]
+        source_channels: Option<u16>,
+    },
+
+    /// Play back datagrams recorded with `listen --record-packets`, at
+    /// their recorded timing, through the same receiving and playback path
+    Replay {
+        /// Recording to play
+        file: PathBuf,
+
+        /// Replay speed: 2 plays twice as fast, 0.5 at half speed
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+
+        /// Audio host to play through (default: the platform's default; see
+        /// `hosts`)
+        #[arg(long, value_name = "NAME")]
+        host: Option<String>,
+
+        /// Play on the output device with this name, or part of it
+        #[arg(long = "output-device", value_name = "NAME")]
+        output_devices: Vec<String>,
+
+        /// Play without an audio device: into nothing, or recorded to a WAV
+        /// file after all playback processing
+        #[arg(
+            long,
+            value_name = "FILE",
+            num_args = 0..=1,
+            conflicts_with = "output_devices"
+        )]
+        virtual_output: Option<Option<PathBuf>>,
     },
+
+    /// Measure round-trip time to a broadcasting server
+    Ping {
+        /// IP address of the server
+        server: IpAddr,
+
+        /// Number of pings to send
+        #[arg(short, long, default_value_t = 10)]
+        count: u32,
+    },
+
+    /// Measure the throughput and packet loss of the link to a broadcasting server
+    Bench {
+        /// IP address of the server
+        server: IpAddr,
+
+        /// Send rates to try, in Mbit/s
+        #[arg(long, value_delimiter = ',', default_value = "1,2,4,8,16,32")]
+        rates: Vec<f64>,
+
+        /// Seconds to hold each rate
+        #[arg(long, default_value_t = 1.0)]
+        duration: f64,
+    },
+
+    /// List the servers visible on the network without listening to any
+    Scan {
+        /// Seconds to wait for replies
+        #[arg(long, default_value_t = 3.0)]
+        duration: f64,
+    },
+
+    /// List the audio hosts usable with --host, e.g. ALSA and JACK
+    Hosts,
 }
 
 fn select_input_device(capture: &AudioCapture) -> Result<usize, Box<dyn Error>> {
-    let devices = capture.list_input_devices()?;
+    let devices = capture.list_input_devices_detailed()?;
 
     println!("\nAvailable input devices:");
     println!("------------------------");
-    for device in &devices {
+    for detailed in &devices {
+        let device = &detailed.info;
         let device_type = match device.device_type {
             DeviceType::SystemAudio => "(System Audio)",
             DeviceType::Virtual => "(Virtual Device)",
@@ -54,6 +420,21 @@ fn select_input_device(capture: &AudioCapture) -> Result<usize, Box<dyn Error>>
             if device.is_default { "(Default)" } else { "" },
             device_type
         );
+
+        for config in &detailed.supported_configs {
+            let buffer = match config.buffer_size {
+                Some((min, max)) => format!(", buffer {}-{} frames", min, max),
+                None => String::new(),
+            };
+            println!(
+                "     {}ch {}-{}Hz {:?}{}",
+                config.channels,
+                config.min_sample_rate,
+                config.max_sample_rate,
+                config.sample_format,
+                buffer
+            );
+        }
     }
 
     println!("------------------------");
@@ -77,52 +458,885 @@ fn select_input_device(capture: &AudioCapture) -> Result<usize, Box<dyn Error>>
     Ok(selected)
 }
 
+// Runs `future` on the dedicated network runtime when there is one
+async fn run_audio<F>(
+    runtime: Option<&AudioRuntime>,
+    future: F,
+) -> audio_streamer::Result<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match runtime {
+        Some(runtime) => runtime.run(future).await,
+        None => Ok(future.await),
+    }
+}
+
+// One line describing a discovered server, as listed by `scan` and `listen`
+fn describe_server(server: &DiscoveredServer) -> String {
+    let format = match server.format {
+        Some(format) => format!(
+            "{}Hz, {} channels, {}",
+            format.sample_rate, format.channels, format.codec
+        ),
+        None => "format unknown".to_string(),
+    };
+    let format = match &server.capabilities {
+        Some(capabilities) => {
+            let codecs: Vec<String> = capabilities.codecs.iter().map(|c| c.to_string()).collect();
+            let format = format!("{} (supports {})", format, codecs.join(", "));
+            match capabilities.websocket {
+                Some(port) => format!("{}, WebSocket on port {}", format, port),
+                None => format,
+            }
+        }
+        None => format,
+    };
+    match &server.name {
+        Some(name) => format!("{} ({}): {}", server.addr, name, format),
+        None => format!("{}: {}", server.addr, format),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Broadcast { bind, use_default } => {
-            println!("Starting audio capture...");
-            let capture = AudioCapture::new()?;
+        Commands::Broadcast {
+            bind,
+            use_default,
+            host,
+            tone,
+            amplitude,
+            stdin,
+            stdin_format,
+            file,
+            looping,
+            monitor,
+            monitor_volume,
+            gain,
+            format_fallback,
+            volume,
+            clients,
+            no_discovery,
+            allowed,
+            announce_on,
+            name,
+            sample_rate,
+            codec,
+            pre_emphasis,
+            samples_per_packet,
+            #[cfg(feature = "websocket")]
+            websocket,
+            stats_out,
+            dedicated_runtime,
+            max_datagram_size,
+            batch_sends,
+            multicast,
+        } => {
+            validate_sample_rate(sample_rate)?;
 
-            let (_tx, rx, _stream) = if use_default {
-                capture.start_capture()?
+            // Every source is read or generated with the capture's channel
+            // count, which the outputs are set up for before it starts
+            let capture_config = CaptureConfig {
+                sample_rate,
+                format_fallback,
+                host: host.clone(),
+                ..CaptureConfig::default()
+            };
+            let channels = capture_config.channels;
+
+            // Split the audio between the network and the local monitor
+            let (network_tx, rx) = mpsc::channel(32);
+            let mut outputs = vec![(network_tx, volume.clamp(0.0, 1.0))];
+            let _monitor_stream = if monitor {
+                println!("Monitoring locally...");
+                let player = AudioPlayer::with_config(PlayerConfig {
+                    sample_rate,
+                    host: host.clone(),
+                    ..PlayerConfig::default()
+                })?;
+                let (player_tx, stream) = player.start_playback()?;
+                outputs.push((player_tx, monitor_volume.clamp(0.0, 1.0)));
+                Some(stream)
             } else {
-                let device_index = select_input_device(&capture)?;
-                println!("Using selected input device... {}", device_index + 1);
-                capture.start_capture_with_device(device_index)?
+                None
+            };
+            // Announced in discovery alongside the UDP stream
+            #[cfg(feature = "websocket")]
+            let websocket_port = match websocket {
+                Some(addr) => {
+                    let ws_sender = WebSocketSender::bind(&addr, channels).await?;
+                    let local_addr = ws_sender.local_addr()?;
+                    println!("Serving browsers on ws://{}", local_addr);
+                    let (ws_tx, ws_rx) = mpsc::channel(32);
+                    outputs.push((ws_tx, volume.clamp(0.0, 1.0)));
+                    tokio::spawn(async move {
+                        if let Err(e) = ws_sender.start_sending(ws_rx).await {
+                            log::error!("WebSocket sender failed: {}", e);
+                        }
+                    });
+                    Some(local_addr.port())
+                }
+                None => None,
+            };
+            #[cfg(not(feature = "websocket"))]
+            let websocket_port = None;
+
+            // Each source feeds the outputs; the capture stream must stay alive
+            // for as long as we broadcast
+            let capture = if stdin {
+                println!("Reading {:?} PCM from stdin...", stdin_format);
+                // 360 samples per buffer keeps each packet within a single datagram
+                fan_out(
+                    spawn_pcm_reader(io::stdin(), stdin_format, sample_rate, channels, 360),
+                    outputs,
+                );
+                None
+            } else if let Some(path) = file {
+                let wav = WavReader::open(&path)?;
+                if wav.channels() != channels {
+                    return Err(format!(
+                        "{} has {} channels; --file needs {}",
+                        path.display(),
+                        wav.channels(),
+                        channels
+                    )
+                    .into());
+                }
+                let file_rate = wav.sample_rate();
+                let mut samples = wav.into_samples();
+                if file_rate != sample_rate {
+                    samples = Resampler::new(file_rate, sample_rate, channels).process(&samples);
+                }
+                println!(
+                    "Playing {}{}...",
+                    path.display(),
+                    if looping { " on a loop" } else { "" }
+                );
+                let source = FileSource::new(samples, sample_rate, channels)
+                    .with_buffer_size(360)
+                    .looping(looping);
+                fan_out(source, outputs);
+                None
+            } else if let Some(frequency) = tone {
+                println!("Generating {}Hz test tone...", frequency);
+                let source =
+                    SineSource::new(frequency, amplitude.clamp(0.0, 1.0), sample_rate, channels);
+                fan_out(source, outputs);
+                None
+            } else {
+                println!("Starting audio capture...");
+                let capture = AudioCapture::with_config(capture_config)?;
+                capture.set_input_gain(gain);
+
+                let (_tx, rx, stream) = if use_default {
+                    capture.start_capture()?
+                } else {
+                    let device_index = select_input_device(&capture)?;
+                    println!("Using selected input device... {}", device_index + 1);
+                    capture.start_capture_with_device(device_index)?
+                };
+                fan_out(Recycling::new(rx, capture.buffer_pool()), outputs);
+                Some((capture, stream))
             };
 
             println!("Starting audio broadcaster...");
-            println!("Clients can now connect automatically via the 'listen' command");
-            let sender = AudioSender::new(bind.as_deref()).await?;
-            sender.start_sending(rx).await?;
+            let mut config = SenderConfig::builder()
+                .static_clients(clients)
+                .discovery(!no_discovery)
+                .announce_interfaces(announce_on)
+                .sample_rate(sample_rate)
+                .channels(channels)
+                .codec(codec)
+                .batch_sends(batch_sends);
+            if let Some(bind) = bind {
+                config = config.bind_addr(bind);
+            }
+            if let Some(name) = name {
+                config = config.name(name);
+            }
+            if let Some(group) = multicast {
+                config = config.multicast_group(group);
+            }
+            if let Some(port) = websocket_port {
+                config = config.websocket_port(port);
+            }
+            if let Some(coefficient) = pre_emphasis {
+                config = config.pre_emphasis(coefficient);
+            }
+            if let Some(samples) = samples_per_packet {
+                config = config.samples_per_packet(samples);
+            }
+            if let Some(bytes) = max_datagram_size {
+                config = config.max_datagram_size(bytes);
+            }
+            if !allowed.is_empty() {
+                config = config.client_filter(move |addr| allowed.contains(&addr.ip()));
+            }
+            let runtime = dedicated_runtime
+                .then(|| AudioRuntime::new("audio-network"))
+                .transpose()?;
+            if let Some(runtime) = runtime.as_ref() {
+                config = config.runtime(runtime);
+            }
+            let sender = Arc::new(AudioSender::with_config(config.build()).await?);
+            if sender.discovery_enabled() {
+                println!("Clients can now connect automatically via the 'listen' command");
+            } else if !no_discovery {
+                println!("Discovery port unavailable, streaming to --client listeners only");
+            }
+
+            // Enter toggles mute, "p" pause and "b SAMPLES" sets the capture
+            // buffer size, unless stdin is carrying the audio
+            let (command_tx, mut command_rx) = mpsc::channel(4);
+            if !stdin {
+                println!("Press Enter to mute or unmute, or p and Enter to pause or resume.");
+                if capture.is_some() {
+                    println!(
+                        "Type b and a sample count, e.g. b 240, to change the capture buffer size."
+                    );
+                }
+                std::thread::spawn(move || {
+                    for line in io::stdin().lines() {
+                        let command = line.map(|line| line.trim().to_string()).unwrap_or_default();
+                        if command_tx.blocking_send(command).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+
+            let sending_sender = sender.clone();
+            let mut sending = Box::pin(run_audio(runtime.as_ref(), async move {
+                sending_sender.start_sending(rx).await
+            }));
+            loop {
+                tokio::select! {
+                    result = &mut sending => {
+                        result??;
+                        break;
+                    }
+                    Some(command) = command_rx.recv() => {
+                        if let Some(samples) = command.strip_prefix("b ") {
+                            let Some((capture, _)) = &capture else {
+                                println!("Not capturing from a device.");
+                                continue;
+                            };
+                            match samples.trim().parse() {
+                                Ok(samples) => match capture.set_buffer_size(samples) {
+                                    Ok(()) => println!("Capture buffer size is now {} samples.", samples),
+                                    Err(e) => println!("{}", e),
+                                },
+                                Err(_) => println!("Expected a sample count, e.g. b 240"),
+                            }
+                        } else if command == "p" {
+                            if sender.is_paused() {
+                                sender.resume().await;
+                                println!("Resumed.");
+                            } else {
+                                sender.pause().await;
+                                println!("Paused, listeners stay connected. Type p and Enter to resume.");
+                            }
+                        } else if sender.is_muted() {
+                            sender.unmute();
+                            println!("Unmuted.");
+                        } else {
+                            sender.mute();
+                            println!("Muted, sending silence. Press Enter to unmute.");
+                        }
+                    }
+                    _ = tokio::signal::ctrl_c() => {
+                        println!("Stopping...");
+                        break;
+                    }
+                }
+            }
+            drop(sending);
+            sender.shutdown().await;
+
+            if let Some(path) = stats_out {
+                sender.export_metrics(&path)?;
+                println!("Wrote session statistics to {}", path.display());
+            }
         }
 
-        Commands::Listen { bind } => {
-            println!("Starting audio receiver...");
-            let receiver = AudioReceiver::new(bind.as_deref()).await?;
-            println!("Listening on {}", receiver.local_addr()?);
+        Commands::Listen {
+            bind,
+            record,
+            bit_depth,
+            stdout,
+            stdout_format,
+            adaptive_buffer,
+            target_latency_ms,
+            crossfade,
+            mono,
+            width,
+            output_delay,
+            host,
+            output_devices,
+            virtual_output,
+            eq,
+            retry,
+            control_port,
+            no_multicast,
+            duration,
+            stats_out,
+            record_packets,
+            dedicated_runtime,
+            max_datagram_size,
+            source_rate,
+            source_channels,
+        } => {
+            // An unknown host fails now rather than once a server is found
+            select_host(host.as_deref())?;
+            status!(stdout, "Starting audio receiver...");
+            let mut config = ReceiverConfig::builder().multicast(!no_multicast);
+            if let Some(rate) = source_rate {
+                config = config.sample_rate(rate);
+            }
+            if let Some(channels) = source_channels {
+                config = config.channels(channels);
+            }
+            if let Some(bind) = bind {
+                config = config.bind_addr(bind);
+            }
+            if let Some(port) = control_port {
+                config = config.control_port(port);
+            }
+            if let Some(bytes) = max_datagram_size {
+                config = config.max_datagram_size(bytes);
+            }
+            let latency_budget = target_latency_ms.map(std::time::Duration::from_millis);
+            if let Some(budget) = latency_budget {
+                // Falling behind drops audio rather than backing it up
+                config = config
+                    .overflow_policy(OverflowPolicy::DropOldest)
+                    .max_queued(budget);
+            }
+            let runtime = dedicated_runtime
+                .then(|| AudioRuntime::new("audio-network"))
+                .transpose()?;
+            if let Some(runtime) = runtime.as_ref() {
+                config = config.runtime(runtime);
+            }
+            let receiver = Arc::new(AudioReceiver::with_config(config.build()).await?);
+            status!(stdout, "Listening on {}", receiver.local_addr()?);
+
+            status!(stdout, "Discovering audio server...");
+            if retry {
+                receiver.reconnect().await?;
+            } else {
+                receiver.discover_server().await?;
+            }
+            let session = receiver.session_info().await?;
+            status!(stdout, "Connected to {}. Starting playback...", session);
 
-            println!("Discovering audio server...");
-            receiver.discover_server().await?;
-            let server_addr = receiver.server_addr().await?;
-            println!("Server found at {}! Starting playback...", server_addr);
+            // Servers that don't announce a format send the defaults, unless overridden
+            let format = receiver.server_format();
+            let sample_rate = source_rate
+                .or(format.map(|format| format.sample_rate))
+                .unwrap_or(48000);
+            let channels = source_channels
+                .or(format.map(|format| format.channels))
+                .unwrap_or(2);
+            if let Some(format) = format
+                .filter(|format| (format.sample_rate, format.channels) != (sample_rate, channels))
+            {
+                log::warn!(
+                    "Server announced {}Hz, {} channels; playing as {}Hz, {} channels as requested",
+                    format.sample_rate,
+                    format.channels,
+                    sample_rate,
+                    channels
+                );
+            }
+            if sample_rate != 48000 {
+                status!(stdout, "Server is sending {}Hz audio", sample_rate);
+            }
 
-            let player = AudioPlayer::new()?;
+            let player = AudioPlayer::with_config(PlayerConfig {
+                sample_rate,
+                channels,
+                max_latency: latency_budget.unwrap_or(PlayerConfig::default().max_latency),
+                adaptive_buffer: match latency_budget {
+                    Some(budget) => Some(AdaptiveBufferConfig::within(budget)),
+                    None => adaptive_buffer.then(AdaptiveBufferConfig::default),
+                },
+                crossfade_frames: crossfade,
+                equalizer: (!eq.is_empty()).then(EqConfig::default),
+                mono: mono.is_some(),
+                downmix: mono.flatten().unwrap_or_default(),
+                width,
+                output_delay: std::time::Duration::from_millis(output_delay),
+                host,
+                output_devices,
+                backend: match virtual_output {
+                    Some(Some(path)) => PlayerBackend::File(path),
+                    Some(None) => PlayerBackend::Null,
+                    None => PlayerBackend::Device,
+                },
+                ..PlayerConfig::default()
+            })?;
+            for (band, db) in eq.into_iter().enumerate() {
+                player.set_band_gain(band, db)?;
+            }
             let (tx, stream) = player.start_playback()?;
+            // `None` while no output device could be opened, retried every device check
+            let mut stream = Some(stream);
+            if let Some(name) = player.device_name() {
+                status!(stdout, "Playing on {}", name);
+            }
+            // Statistics then show how much audio the player is holding
+            receiver.track_playback(player.buffer_gauge());
+
+            status!(stdout, "Audio playback started. Waiting for audio data...");
+            status!(stdout, "Press Ctrl+C to stop.");
+            status!(
+                stdout,
+                "Press Enter to list servers, or type a server's number and Enter to switch to it."
+            );
+            // Replies fill in the server list while listening
+            if let Err(e) = receiver.refresh_servers().await {
+                log::warn!("Failed to look for other servers: {}", e);
+            }
+            let mut commands = Some(BufReader::new(tokio::io::stdin()).lines());
+
+            // Every received buffer goes to each sink, ending at the player.
+            // On format changes stdout is converted back to the format it
+            // started with, and the recording goes on in a new numbered file.
+            let formats = tokio::sync::watch::Sender::new((sample_rate, channels));
+            let mut sinks: Vec<Box<dyn AudioSink + Send>> = Vec::new();
+            if stdout {
+                sinks.push(Box::new(Reformat::new(
+                    PcmSink::new(io::stdout(), stdout_format),
+                    formats.subscribe(),
+                )));
+            }
+            if let Some(path) = &record {
+                sinks.push(Box::new(WavRecorder::create(
+                    path,
+                    bit_depth,
+                    formats.subscribe(),
+                )?));
+            }
+            sinks.push(Box::new(tx));
+            let (tx, sink) = spawn_sink(sinks);
+
+            // Datagrams are written on a thread of their own as they arrive,
+            // until the receiving stops. Format changes come in separately and
+            // go in before the first datagram that arrived after them.
+            let (packet_recorder, format_changes) = match &record_packets {
+                Some(path) => {
+                    let recorded_format = format.map(|format| StreamFormat {
+                        sample_rate,
+                        channels,
+                        ..format
+                    });
+                    let mut recorder = PacketRecorder::create(path, recorded_format)?;
+                    let mut packets = receiver.raw_packets();
+                    let (format_changes, changed_formats) =
+                        std::sync::mpsc::channel::<(SystemTime, StreamFormat)>();
+                    let recording = tokio::task::spawn_blocking(move || {
+                        let mut pending = VecDeque::new();
+                        while let Some(packet) = packets.blocking_recv() {
+                            pending.extend(changed_formats.try_iter());
+                            while pending.front().is_some_and(|(at, _)| *at <= packet.arrival) {
+                                let (at, format) = pending.pop_front().unwrap();
+                                recorder.record_format(at, format)?;
+                            }
+                            recorder.record(&packet)?;
+                        }
+                        for (at, format) in pending.into_iter().chain(changed_formats.try_iter()) {
+                            recorder.record_format(at, format)?;
+                        }
+                        recorder.finish().map(drop)
+                    });
+                    (Some(recording), Some(format_changes))
+                }
+                None => (None, None),
+            };
+            let note_format_change = |format: StreamFormat| {
+                formats.send_replace((format.sample_rate, format.channels));
+                if let Some(format_changes) = &format_changes {
+                    let _ = format_changes.send((SystemTime::now(), format));
+                }
+            };
+
+            // Keep the stream alive and handle the receiving until Ctrl+C
+            let duration = duration.map(|secs| std::time::Duration::from_secs_f64(secs.max(0.0)));
+            let stop = async move {
+                match duration {
+                    Some(duration) => tokio::time::sleep(duration).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let receiving_receiver = receiver.clone();
+            let mut receiving = Box::pin(run_audio(runtime.as_ref(), async move {
+                receiving_receiver.receive_until(tx, stop).await
+            }));
+            let mut device_check = tokio::time::interval(std::time::Duration::from_secs(1));
+            // Whether playback was within --target-latency-ms at the last check
+            let mut within_budget = None;
+            // Packet and device period sizes are only known once audio flows
+            let mut budget_checked = false;
+            loop {
+                tokio::select! {
+                    result = &mut receiving => {
+                        let metrics = result??;
+                        if let Some(duration) = duration {
+                            status!(
+                                stdout,
+                                "Stopped after {:?}, {} packets received.",
+                                duration,
+                                metrics.packets_received
+                            );
+                        }
+                        break;
+                    }
+                    message = receiver.next_control_message() => match message? {
+                        ControlMessage::ServerDown => {
+                            status!(stdout, "Server went offline.");
+                            break;
+                        }
+                        ControlMessage::FormatChanged(format) => {
+                            status!(
+                                stdout,
+                                "Server switched to {}Hz, {} channels",
+                                format.sample_rate,
+                                format.channels
+                            );
+                            note_format_change(format);
+                            stream = Some(player.reconfigure(
+                                stream.take(),
+                                format.sample_rate,
+                                format.channels,
+                            )?);
+                        }
+                        ControlMessage::Paused => status!(stdout, "Server paused the broadcast."),
+                        ControlMessage::Resumed => status!(stdout, "Server resumed the broadcast."),
+                    },
+                    _ = device_check.tick() => {
+                        if let Some(budget) = latency_budget {
+                            let stats = player.stats();
+                            let packet = receiver.metrics().packet_duration;
+                            if let Some(period) = stats.device_period.filter(|_| !budget_checked && !packet.is_zero()) {
+                                check_latency_budget(budget, packet, period)
+                                    .map_err(|e| format!("--target-latency-ms: {}", e))?;
+                                budget_checked = true;
+                            }
+                            let latency = stats.latency();
+                            let within = latency <= budget;
+                            if within_budget != Some(within) {
+                                if within {
+                                    status!(stdout, "Playback latency {:?} is within the {:?} target.", latency, budget);
+                                } else {
+                                    status!(stdout, "Playback latency {:?} is over the {:?} target.", latency, budget);
+                                }
+                                within_budget = Some(within);
+                            }
+                        }
+                        if player.device_lost() || stream.is_none() {
+                            if stream.is_some() {
+                                status!(stdout, "Output device lost, switching to another...");
+                            }
+                            match player.reopen(stream.take()) {
+                                Ok(reopened) => {
+                                    stream = Some(reopened);
+                                    if let Some(name) = player.device_name() {
+                                        status!(stdout, "Playing on {}", name);
+                                    }
+                                }
+                                Err(e) => log::warn!("No output device to play on, retrying: {}", e),
+                            }
+                        }
+                    }
+                    line = async { commands.as_mut().unwrap().next_line().await }, if commands.is_some() => {
+                        let Ok(Some(line)) = line else {
+                            // Stdin is closed, e.g. when running in the background
+                            commands = None;
+                            continue;
+                        };
+                        let servers = receiver.servers();
+                        let current = receiver.server_addr().await.ok();
+                        if line.trim().is_empty() {
+                            for (i, server) in servers.iter().enumerate() {
+                                let marker = if Some(server.addr) == current { "*" } else { " " };
+                                status!(stdout, "{}{}. {}", marker, i + 1, describe_server(server));
+                            }
+                            if let Err(e) = receiver.refresh_servers().await {
+                                log::warn!("Failed to look for other servers: {}", e);
+                            }
+                            continue;
+                        }
+                        let chosen = line
+                            .trim()
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|number| servers.get(number.checked_sub(1)?));
+                        let Some(server) = chosen else {
+                            status!(stdout, "No server numbered {}, press Enter to list them", line.trim());
+                            continue;
+                        };
+                        let previous = receiver.server_format();
+                        if let Err(e) = receiver.switch_to(server.addr).await {
+                            status!(stdout, "Failed to switch to {}: {}", server.addr, e);
+                            continue;
+                        }
+                        status!(stdout, "Switched to {}", describe_server(server));
+                        // Drop the old server's queued audio before the new one plays
+                        match receiver.server_format().filter(|format| Some(*format) != previous) {
+                            Some(format) => {
+                                note_format_change(format);
+                                stream = Some(player.reconfigure(stream.take(), format.sample_rate, format.channels)?);
+                            }
+                            None => player.flush(),
+                        }
+                    }
+                    _ = tokio::signal::ctrl_c() => {
+                        status!(stdout, "Stopping...");
+                        break;
+                    }
+                }
+            }
+            // Releases the sink channel so the sinks below can flush
+            drop(receiving);
 
-            println!("Audio playback started. Waiting for audio data...");
-            println!("Press Ctrl+C to stop.");
+            // No point telling a server that has already gone away
+            if receiver.state() != ConnectionState::Disconnected {
+                if let Err(e) = receiver.leave().await {
+                    log::warn!("Failed to notify server: {}", e);
+                }
+            }
 
-            // Keep the stream alive and handle the receiving
-            receiver.start_receiving(tx).await?;
+            // The receiver has dropped its sender, so the sinks can flush and finish
+            match sink.await? {
+                Ok(_) => {
+                    if let Some(path) = record {
+                        status!(stdout, "Recording saved to {}", path.display());
+                    }
+                }
+                Err(e) => log::error!("Failed to write received audio: {}", e),
+            }
+            if let Some(packet_recorder) = packet_recorder {
+                drop(format_changes);
+                match packet_recorder.await? {
+                    Ok(()) => {
+                        if let Some(path) = record_packets {
+                            status!(stdout, "Packets saved to {}", path.display());
+                        }
+                    }
+                    Err(e) => log::error!("Failed to record packets: {}", e),
+                }
+                let missing = receiver.metrics().raw_packets_dropped;
+                if missing > 0 {
+                    log::warn!(
+                        "{} packets are missing from the recording, writing it fell behind",
+                        missing
+                    );
+                }
+            }
+
+            let stats = player.stats();
+            status!(
+                stdout,
+                "Playback: {} underruns, {} overruns, buffer target {:?}",
+                stats.underruns,
+                stats.overruns,
+                stats.target_buffer
+            );
+
+            if let Some(path) = stats_out {
+                receiver.export_metrics(&path)?;
+                status!(stdout, "Wrote session statistics to {}", path.display());
+            }
 
             // Keep the stream variable to prevent it from being dropped
             drop(stream);
         }
+
+        Commands::Replay {
+            file,
+            speed,
+            host,
+            output_devices,
+            virtual_output,
+        } => {
+            let reader = PacketReader::open(&file)?;
+            let format = reader.format();
+            println!("Replaying {}...", file.display());
+
+            // Recordings of servers that didn't announce a format get the defaults
+            let sample_rate = format.map_or(48000, |format| format.sample_rate);
+            let channels = format.map_or(2, |format| format.channels);
+            if format.is_none() {
+                log::warn!("The recording has no format; playing as 48kHz stereo");
+            }
+
+            // The datagrams go through a receiver of their own over loopback
+            let receiver = Arc::new(AudioReceiver::new(Some("127.0.0.1:0")).await?);
+            let target = receiver.local_addr()?;
+            let player = AudioPlayer::with_config(PlayerConfig {
+                sample_rate,
+                channels,
+                host,
+                output_devices,
+                backend: match virtual_output {
+                    Some(Some(path)) => PlayerBackend::File(path),
+                    Some(None) => PlayerBackend::Null,
+                    None => PlayerBackend::Device,
+                },
+                ..PlayerConfig::default()
+            })?;
+            let (tx, stream) = player.start_playback()?;
+            // Taken out and put back by each recorded format change
+            let mut stream = Some(stream);
+            // Receives until every datagram replay sent has been taken in,
+            // decodable or not, or nothing more arrives for the stall timeout
+            let (sent_tx, mut sent_rx) = tokio::sync::watch::channel(None);
+            let receiving_receiver = receiver.clone();
+            let mut receiving = tokio::spawn(async move {
+                let receiver = receiving_receiver;
+                let mut check = tokio::time::interval(std::time::Duration::from_millis(10));
+                loop {
+                    let sent = *sent_rx.borrow_and_update();
+                    tokio::select! {
+                        buffer = receiver.recv_buffer() => {
+                            if tx.send(buffer?).await.is_err() {
+                                break;
+                            }
+                        }
+                        _ = sent_rx.changed(), if sent.is_none() => {}
+                        _ = check.tick(), if sent.is_some() => {}
+                    }
+                    if let Some(sent) = *sent_rx.borrow() {
+                        let metrics = receiver.metrics();
+                        let taken_in =
+                            metrics.packets_received + metrics.decode_errors + metrics.duplicates;
+                        if taken_in >= sent || receiver.state() == ConnectionState::Stalled {
+                            break;
+                        }
+                    }
+                }
+                audio_streamer::Result::Ok(())
+            });
+
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let replaying = async {
+                let sent = replay(reader, &socket, target, speed, |format| {
+                    println!(
+                        "Server switched to {}Hz, {} channels",
+                        format.sample_rate, format.channels
+                    );
+                    stream = Some(player.reconfigure(
+                        stream.take(),
+                        format.sample_rate,
+                        format.channels,
+                    )?);
+                    Ok(())
+                })
+                .await?;
+                let _ = sent_tx.send(Some(sent));
+                // Closing the player's channel lets it play out what it holds
+                (&mut receiving).await??;
+                player.wait_for_playback_end().await;
+                Ok::<_, Box<dyn Error>>(())
+            };
+            tokio::select! {
+                result = replaying => {
+                    result?;
+                    println!("Replay finished.");
+                }
+                _ = tokio::signal::ctrl_c() => println!("Stopping..."),
+            }
+            receiving.abort();
+
+            let metrics = receiver.metrics();
+            let stats = player.stats();
+            println!(
+                "{} packets received, {} undecodable, {} duplicates; {} underruns, {} overruns",
+                metrics.packets_received,
+                metrics.decode_errors,
+                metrics.duplicates,
+                stats.underruns,
+                stats.overruns
+            );
+            drop(stream);
+        }
+
+        Commands::Ping { server, count } => {
+            let receiver = AudioReceiver::new(Some("0.0.0.0:0")).await?;
+            println!("Pinging {}...", server);
+
+            let stats = receiver.ping(server, count).await?;
+            println!(
+                "{} sent, {} received, RTT min/avg/max = {:.2}/{:.2}/{:.2} ms",
+                stats.sent,
+                stats.received,
+                stats.min.as_secs_f64() * 1000.0,
+                stats.avg.as_secs_f64() * 1000.0,
+                stats.max.as_secs_f64() * 1000.0
+            );
+        }
+        Commands::Bench {
+            server,
+            rates,
+            duration,
+        } => {
+            let receiver = AudioReceiver::new(Some("0.0.0.0:0")).await?;
+            println!("Measuring throughput to {}...", server);
+
+            let config = BenchConfig {
+                rates: rates.iter().map(|mbps| (mbps * 1e6) as u64).collect(),
+                step_duration: std::time::Duration::from_secs_f64(duration.max(0.1)),
+                ..Default::default()
+            };
+            let report = receiver.bench(server, &config).await?;
+            for step in &report.steps {
+                println!(
+                    "{:>7.1} Mbit/s sent, {:>7.1} Mbit/s received, {:.1}% loss",
+                    step.target_bps as f64 / 1e6,
+                    step.received_bps as f64 / 1e6,
+                    step.loss() * 100.0
+                );
+            }
+
+            match report.sustainable_bps(0.01) {
+                Some(bps) => println!(
+                    "Sustainable throughput: {:.1} Mbit/s with under 1% loss",
+                    bps as f64 / 1e6
+                ),
+                None => println!("Every rate lost more than 1% of packets"),
+            }
+            // 48kHz stereo f32 in 360-sample packets, headers included
+            println!("Uncompressed 48kHz stereo audio needs about 3.2 Mbit/s");
+        }
+
+        Commands::Scan { duration } => {
+            let receiver = AudioReceiver::new(Some("0.0.0.0:0")).await?;
+            println!("Scanning for servers...");
+
+            let duration = std::time::Duration::from_secs_f64(duration.max(0.1));
+            let servers = receiver.discover_servers(duration).await?;
+            if servers.is_empty() {
+                println!(
+                    "No servers found. Check that the server is running with discovery enabled"
+                );
+                println!("and that UDP port 50000 is not blocked by a firewall.");
+            }
+            for server in &servers {
+                println!("{}", describe_server(server));
+            }
+        }
+
+        Commands::Hosts => {
+            for (i, name) in available_hosts().iter().enumerate() {
+                println!("{}{}", name, if i == 0 { " (Default)" } else { "" });
+            }
+        }
     }
 
     Ok(())